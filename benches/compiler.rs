@@ -0,0 +1,38 @@
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+use green::compiler::compiler::Compiler;
+use green::compiler::options::{CompileOptions, OptLevel};
+use green::syntax::parser::GreenParser;
+
+const SOURCE: &str = include_str!("data/sample.green");
+
+fn compile(c: &mut Criterion, name: &str, opt_level: OptLevel) {
+    // Repeating the sample more often overflows the script's constant pool.
+    let source = SOURCE.repeat(10);
+
+    c.bench_function(name, |b| {
+        b.iter_batched(
+            || GreenParser::parse(&source).unwrap(),
+            |module| {
+                Compiler::compile_with(
+                    module,
+                    CompileOptions {
+                        opt_level,
+                        ..CompileOptions::default()
+                    },
+                )
+            },
+            BatchSize::SmallInput,
+        )
+    });
+}
+
+fn compile_optimized(c: &mut Criterion) {
+    compile(c, "compile", OptLevel::O2);
+}
+
+fn compile_unoptimized(c: &mut Criterion) {
+    compile(c, "compile_unoptimized", OptLevel::O0);
+}
+
+criterion_group!(benches, compile_optimized, compile_unoptimized);
+criterion_main!(benches);
@@ -0,0 +1,15 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use green::syntax::lexer::Lexer;
+
+const SOURCE: &str = include_str!("data/sample.green");
+
+fn lex(c: &mut Criterion) {
+    let source = SOURCE.repeat(200);
+
+    c.bench_function("lex", |b| {
+        b.iter(|| Lexer::parse(black_box(&source)).unwrap())
+    });
+}
+
+criterion_group!(benches, lex);
+criterion_main!(benches);
@@ -0,0 +1,65 @@
+use criterion::{criterion_group, criterion_main, Criterion};
+use green::vm::VM;
+
+const LOCAL_ADD: &str = r#"
+def main()
+    var total = 0
+    var i = 0
+    while i < 10000 do
+        total = total + 2
+        i = i + 1
+    end
+    return total
+end
+"#;
+
+const CONSTANT_CALL: &str = r#"
+def twice(n)
+    return n * 2
+end
+
+def main()
+    var total = 0
+    var i = 0
+    while i < 10000 do
+        total = twice(3)
+        i = i + 1
+    end
+    return total
+end
+"#;
+
+const COMPARE_BRANCH: &str = r#"
+def main()
+    var hits = 0
+    var i = 0
+    while i < 10000 do
+        if i == 5000 do hits = hits + 1 end
+        if i > 9000 do hits = hits + 1 end
+        i = i + 1
+    end
+    return hits
+end
+"#;
+
+fn bench_script(c: &mut Criterion, name: &str, source: &str) {
+    let mut vm = VM::new();
+    vm.interpret(source);
+
+    c.bench_function(name, |b| b.iter(|| vm.call_main(vec![]).unwrap()));
+}
+
+fn local_add(c: &mut Criterion) {
+    bench_script(c, "local_add", LOCAL_ADD);
+}
+
+fn constant_call(c: &mut Criterion) {
+    bench_script(c, "constant_call", CONSTANT_CALL);
+}
+
+fn compare_branch(c: &mut Criterion) {
+    bench_script(c, "compare_branch", COMPARE_BRANCH);
+}
+
+criterion_group!(benches, local_add, constant_call, compare_branch);
+criterion_main!(benches);
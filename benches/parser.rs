@@ -0,0 +1,15 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use green::syntax::parser::GreenParser;
+
+const SOURCE: &str = include_str!("data/sample.green");
+
+fn parse(c: &mut Criterion) {
+    let source = SOURCE.repeat(20);
+
+    c.bench_function("parse", |b| {
+        b.iter(|| GreenParser::parse(black_box(&source)).unwrap())
+    });
+}
+
+criterion_group!(benches, parse);
+criterion_main!(benches);
@@ -0,0 +1,75 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use green::compiler::value::Value;
+use green::vm::VM;
+
+const ARITHMETIC: &str = r#"
+def main()
+    var x = 1.5
+    var total = 0
+    var i = 0
+    while i < 10000 do
+        total = total + x * 2 - i / 4 % 3
+        i = i + 1
+    end
+    return total
+end
+"#;
+
+const STACK_CHURN: &str = r#"
+def pick(a, b, c, d)
+    return c
+end
+
+def main()
+    var total = 0
+    var i = 0
+    while i < 10000 do
+        total = total + pick(i, "two", i, [])
+        i = i + 1
+    end
+    return total
+end
+"#;
+
+/// Pushes and pops a mix of values the way the VM's stack does, without running any code.
+fn push_pop(c: &mut Criterion) {
+    let values = [
+        Value::Number(1.0),
+        Value::True,
+        Value::Nil,
+        Value::string("green".to_string()),
+        Value::array(vec![]),
+    ];
+    let mut stack: Vec<Value> = Vec::with_capacity(1024);
+
+    c.bench_function("value_push_pop", |b| {
+        b.iter(|| {
+            for _ in 0..200 {
+                for value in &values {
+                    stack.push(*value);
+                }
+                for _ in 0..values.len() {
+                    black_box(stack.pop());
+                }
+            }
+        })
+    });
+}
+
+fn bench_script(c: &mut Criterion, name: &str, source: &str) {
+    let mut vm = VM::new();
+    vm.interpret(source);
+
+    c.bench_function(name, |b| b.iter(|| vm.call_main(vec![]).unwrap()));
+}
+
+fn arithmetic(c: &mut Criterion) {
+    bench_script(c, "value_arithmetic", ARITHMETIC);
+}
+
+fn stack_churn(c: &mut Criterion) {
+    bench_script(c, "value_stack_churn", STACK_CHURN);
+}
+
+criterion_group!(benches, push_pop, arithmetic, stack_churn);
+criterion_main!(benches);
@@ -0,0 +1,86 @@
+use criterion::{criterion_group, criterion_main, Criterion};
+use green::vm::VM;
+
+const FIB: &str = include_str!("green/fib.green");
+const SIEVE: &str = include_str!("green/sieve.green");
+const STRINGS: &str = include_str!("green/strings.green");
+
+const WHILE_COUNT: &str = r#"
+def main()
+    var i = 0
+    while i < 100000 do
+        i = i + 1
+    end
+    return i
+end
+"#;
+
+const ARRAY_INDEX: &str = r#"
+var items = [0 to 1000]
+
+def main()
+    var total = 0
+    var i = 0
+    while i < 1000 do
+        total = total + items[i]
+        i = i + 1
+    end
+    return total
+end
+"#;
+
+const STRING_COPY: &str = r#"
+var greeting = "a reasonably long string that gets copied around on every access"
+
+def main()
+    var s = ""
+    var i = 0
+    while i < 10000 do
+        s = greeting
+        i = i + 1
+    end
+    return i
+end
+"#;
+
+fn bench_script(c: &mut Criterion, name: &str, source: &str) {
+    let mut vm = VM::new();
+    vm.interpret(source);
+
+    c.bench_function(name, |b| b.iter(|| vm.call_main(vec![]).unwrap()));
+}
+
+fn fib(c: &mut Criterion) {
+    bench_script(c, "fib", FIB);
+}
+
+fn sieve(c: &mut Criterion) {
+    bench_script(c, "sieve", SIEVE);
+}
+
+fn string_building(c: &mut Criterion) {
+    bench_script(c, "string_building", STRINGS);
+}
+
+fn while_count(c: &mut Criterion) {
+    bench_script(c, "while_count", WHILE_COUNT);
+}
+
+fn array_index(c: &mut Criterion) {
+    bench_script(c, "array_index", ARRAY_INDEX);
+}
+
+fn string_copy(c: &mut Criterion) {
+    bench_script(c, "string_copy", STRING_COPY);
+}
+
+criterion_group!(
+    benches,
+    fib,
+    sieve,
+    string_building,
+    while_count,
+    array_index,
+    string_copy
+);
+criterion_main!(benches);
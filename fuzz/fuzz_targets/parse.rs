@@ -0,0 +1,11 @@
+#![no_main]
+
+use green::syntax::parser::GreenParser;
+use libfuzzer_sys::fuzz_target;
+
+// Parsing must give a module or an error for any source, never panic.
+fuzz_target!(|data: &[u8]| {
+    if let Ok(source) = std::str::from_utf8(data) {
+        let _ = GreenParser::parse(source);
+    }
+});
@@ -0,0 +1,137 @@
+use crate::vm::{VmOptions, VM};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+/// How long each script's `main` is called over and over for.
+const BUDGET: Duration = Duration::from_secs(1);
+
+/// Scripts are timed at least this often, however slow they are.
+const MIN_ITERATIONS: u32 = 5;
+
+/// Timings of repeatedly calling the `main` function of a benchmark script.
+#[derive(Debug)]
+pub struct BenchResult {
+    pub name: String,
+    pub iterations: u32,
+    pub mean: Duration,
+    pub min: Duration,
+}
+
+impl fmt::Display for BenchResult {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:<24} mean {:>12.3?}  min {:>12.3?}  ({} runs)",
+            self.name, self.mean, self.min, self.iterations
+        )
+    }
+}
+
+/// Runs a benchmark script once to define its functions, then calls its `main()` until
+/// `budget` has passed. Its output is discarded. Returns None if the script doesn't define a
+/// `main`, and the error report if `main` fails.
+pub fn bench_script(
+    name: &str,
+    source: &str,
+    budget: Duration,
+) -> Result<Option<BenchResult>, String> {
+    let mut vm = VM::with_options(VmOptions {
+        quiet: true,
+        ..VmOptions::default()
+    });
+    vm.interpret(source);
+
+    // Warm up, which also checks that there is a main to call.
+    let report = |vm: &VM, err| vm.error_report(&err);
+    if vm
+        .call_main(vec![])
+        .map_err(|err| report(&vm, err))?
+        .is_none()
+    {
+        return Ok(None);
+    }
+
+    let mut iterations = 0;
+    let mut total = Duration::ZERO;
+    let mut min = Duration::MAX;
+    while total < budget || iterations < MIN_ITERATIONS {
+        let start = Instant::now();
+        vm.call_main(vec![]).map_err(|err| report(&vm, err))?;
+        let elapsed = start.elapsed();
+
+        iterations += 1;
+        total += elapsed;
+        min = min.min(elapsed);
+    }
+
+    Ok(Some(BenchResult {
+        name: name.to_string(),
+        iterations,
+        mean: total / iterations,
+        min,
+    }))
+}
+
+/// The benchmark scripts at `path`: the file itself, or the `.green` files in a directory.
+pub fn find_scripts(path: &Path) -> io::Result<Vec<PathBuf>> {
+    if !path.is_dir() {
+        return Ok(vec![path.to_path_buf()]);
+    }
+
+    let mut scripts = vec![];
+    for entry in fs::read_dir(path)? {
+        let script = entry?.path();
+        if script.extension().is_some_and(|ext| ext == "green") {
+            scripts.push(script);
+        }
+    }
+    scripts.sort();
+    Ok(scripts)
+}
+
+/// Benchmarks every script found at `paths`, printing each result as it finishes.
+pub fn run(paths: &[PathBuf]) -> io::Result<Vec<BenchResult>> {
+    let mut results = vec![];
+
+    for path in paths {
+        for script in find_scripts(path)? {
+            let source = fs::read_to_string(&script)?;
+            let name = script.file_stem().unwrap_or_default().to_string_lossy();
+
+            match bench_script(&name, &source, BUDGET) {
+                Ok(Some(result)) => {
+                    println!("{}", result);
+                    results.push(result);
+                }
+                Ok(None) => eprintln!("{} defines no main function", script.display()),
+                Err(report) => eprint!("{} failed:\n{}", script.display(), report),
+            }
+        }
+    }
+
+    Ok(results)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn benches_call_main_repeatedly() {
+        let source = "var calls = 0\ndef main()\n    calls = calls + 1\nend\n";
+
+        let result = bench_script("calls", source, Duration::ZERO)
+            .unwrap()
+            .unwrap();
+        assert_eq!(result.name, "calls");
+        assert_eq!(result.iterations, MIN_ITERATIONS);
+        assert!(result.min <= result.mean);
+
+        assert!(bench_script("no_main", "var x = 1\n", Duration::ZERO)
+            .unwrap()
+            .is_none());
+    }
+}
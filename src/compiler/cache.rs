@@ -0,0 +1,545 @@
+use crate::compiler::chunk::{Chunk, LocalDebug};
+use crate::compiler::compiler::link_strings;
+use crate::compiler::jump_table::{Cases, JumpTable};
+use crate::compiler::object::{GreenFunction, Protocol, Struct};
+use crate::compiler::options::CompileOptions;
+use crate::compiler::strings::StringTable;
+use crate::compiler::value::Value;
+use crate::source_map::{FileId, SourceMap};
+use crate::stdlib::sha256_hex;
+use crate::vm::obj::Gc;
+use std::collections::BTreeMap;
+use std::convert::{TryFrom, TryInto};
+use std::env;
+use std::ffi::OsString;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::rc::Rc;
+
+const MAGIC: &[u8] = b"GREENC";
+/// Bumped whenever the layout of cache files or the meaning of the bytecode changes. The
+/// version of Green is part of every key as well.
+const FORMAT_VERSION: u32 = 4;
+
+/// A directory of compiled modules, so running a script that hasn't changed skips parsing and
+/// compiling it. Entries are keyed by a hash of the script, and remember the modules it
+/// imported by a hash of theirs, so changing either compiles the script again. A script loaded
+/// from the cache doesn't repeat the compiler's warnings.
+#[derive(Debug, Clone)]
+pub struct ModuleCache {
+    dir: PathBuf,
+}
+
+impl ModuleCache {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        ModuleCache { dir: dir.into() }
+    }
+
+    /// `$XDG_CACHE_HOME/green` if that is set, otherwise `$HOME/.cache/green`. None if neither
+    /// is set, as there's nowhere to cache to.
+    pub fn default_dir() -> Option<PathBuf> {
+        cache_dir(env::var_os("XDG_CACHE_HOME"), env::var_os("HOME"))
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Deletes every cached module.
+    pub fn clear(&self) -> io::Result<()> {
+        match fs::remove_dir_all(&self.dir) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err),
+            _ => Ok(()),
+        }
+    }
+
+    /// The compiled form of the script `file`, if it was cached and neither it nor the modules
+    /// it imports changed since. The imported modules are added to `sources`.
+    pub(crate) fn load(
+        &self,
+        file: FileId,
+        options: CompileOptions,
+        sources: &mut SourceMap,
+    ) -> Option<Gc<GreenFunction>> {
+        let bytes = fs::read(self.path(file, options, sources)).ok()?;
+        let mut reader = Reader::new(&bytes);
+        if reader.bytes(MAGIC.len())? != MAGIC || reader.u32()? != FORMAT_VERSION {
+            return None;
+        }
+
+        let mut imports = vec![];
+        for _ in 0..reader.u32()? {
+            let (name, hash) = (reader.string()?, reader.string()?);
+            let text = fs::read_to_string(&name).ok()?;
+            if sha256_hex(text.as_bytes()) != hash {
+                return None;
+            }
+            imports.push((name, text));
+        }
+
+        let mut files = vec![file];
+        for (name, text) in imports {
+            files.push(sources.add(name, text));
+        }
+        let mut strings = StringTable::new();
+        for _ in 0..reader.u32()? {
+            strings.intern(&reader.string()?)?;
+        }
+
+        let mut function = reader.function(&files)?;
+        link_strings(&mut function, &Rc::new(strings));
+        Some(function)
+    }
+
+    /// Caches the compiled form of the script `file`. Failing to is not an error: the script is
+    /// compiled again next time.
+    pub(crate) fn store(
+        &self,
+        file: FileId,
+        options: CompileOptions,
+        sources: &SourceMap,
+        function: &GreenFunction,
+    ) {
+        let files: Vec<FileId> = sources.files_from(file).collect();
+        let mut writer = Writer::default();
+        writer.bytes(MAGIC);
+        writer.u32(FORMAT_VERSION);
+
+        writer.u32(files.len() as u32 - 1);
+        for &import in &files[1..] {
+            let import = sources.get(import);
+            writer.string(import.name());
+            writer.string(&sha256_hex(import.text().as_bytes()));
+        }
+        let strings = function.chunk().strings();
+        writer.u32(strings.len() as u32);
+        for index in 0..strings.len() {
+            writer.string(strings.get(index).map_or("", |s| s.as_str()));
+        }
+        if writer.function(function, &files).is_none() {
+            return;
+        }
+
+        let path = self.path(file, options, sources);
+        let partial = path.with_extension(format!("{}.tmp", process::id()));
+        let _ = fs::create_dir_all(&self.dir)
+            .and_then(|_| fs::write(&partial, &writer.out))
+            .and_then(|_| fs::rename(&partial, &path));
+    }
+
+    fn path(&self, file: FileId, options: CompileOptions, sources: &SourceMap) -> PathBuf {
+        let source = sources.get(file);
+        let key = format!(
+            "{}\0{}\0{:?}\0{}\0{}\0{}",
+            env!("CARGO_PKG_VERSION"),
+            FORMAT_VERSION,
+            options.opt_level,
+            options.lossy_utf8,
+            source.name(),
+            source.text()
+        );
+        self.dir
+            .join(sha256_hex(key.as_bytes()))
+            .with_extension("greenc")
+    }
+}
+
+/// The cache directory `default_dir` picks for the given environment variables.
+fn cache_dir(xdg_cache_home: Option<OsString>, home: Option<OsString>) -> Option<PathBuf> {
+    match (xdg_cache_home, home) {
+        (Some(dir), _) if !dir.is_empty() => Some(Path::new(&dir).join("green")),
+        (_, Some(home)) if !home.is_empty() => Some(Path::new(&home).join(".cache").join("green")),
+        _ => None,
+    }
+}
+
+#[derive(Default)]
+struct Writer {
+    out: Vec<u8>,
+}
+
+impl Writer {
+    fn bytes(&mut self, bytes: &[u8]) {
+        self.out.extend_from_slice(bytes);
+    }
+
+    fn u8(&mut self, n: u8) {
+        self.out.push(n);
+    }
+
+    fn u32(&mut self, n: u32) {
+        self.bytes(&n.to_le_bytes());
+    }
+
+    fn u64(&mut self, n: usize) {
+        self.bytes(&(n as u64).to_le_bytes());
+    }
+
+    fn string(&mut self, s: &str) {
+        self.u32(s.len() as u32);
+        self.bytes(s.as_bytes());
+    }
+
+    fn file(&mut self, file: Option<FileId>, files: &[FileId]) -> Option<()> {
+        match file {
+            Some(file) => self.u32(files.iter().position(|f| *f == file)? as u32 + 1),
+            None => self.u32(0),
+        }
+        Some(())
+    }
+
+    /// Writes a function and the functions among its constants. Returns `None` for constants
+    /// that can't be cached.
+    fn function(&mut self, function: &GreenFunction, files: &[FileId]) -> Option<()> {
+        self.string(function.name());
+        self.u8(*function.arity());
+
+        let chunk = function.chunk();
+        match chunk.name() {
+            Some(name) => {
+                self.u8(1);
+                self.string(name);
+            }
+            None => self.u8(0),
+        }
+        self.file(chunk.file(), files)?;
+        self.u32(chunk.code().len() as u32);
+        self.bytes(chunk.code());
+
+        self.u32(chunk.constants().len() as u32);
+        for constant in chunk.constants() {
+            self.constant(constant, files)?;
+        }
+
+        self.u32(chunk.jump_tables().len() as u32);
+        for table in chunk.jump_tables() {
+            match table.cases() {
+                Cases::Dense { min, targets } => {
+                    self.u8(0);
+                    self.bytes(&min.to_le_bytes());
+                    self.u32(targets.len() as u32);
+                    targets.iter().for_each(|target| self.u64(*target));
+                }
+                Cases::Strings(targets) => {
+                    self.u8(1);
+                    self.u32(targets.len() as u32);
+                    for (key, target) in targets {
+                        self.string(key);
+                        self.u64(*target);
+                    }
+                }
+            }
+            self.u64(table.default_target());
+            self.u64(table.fallback_target());
+        }
+
+        self.u32(chunk.lines().len() as u32);
+        for (line, bytes) in chunk.lines() {
+            self.u64(*line);
+            self.u64(*bytes);
+        }
+
+        self.u32(chunk.locals().len() as u32);
+        for local in chunk.locals() {
+            self.string(&local.name);
+            self.u64(local.slot);
+            self.u64(local.start);
+            self.u64(local.end);
+        }
+
+        self.u32(chunk.property_cache_count() as u32);
+        Some(())
+    }
+
+    fn constant(&mut self, constant: &Value, files: &[FileId]) -> Option<()> {
+        match constant {
+            Value::Number(n) => {
+                self.u8(0);
+                self.bytes(&n.to_le_bytes());
+            }
+            Value::True => self.u8(1),
+            Value::False => self.u8(2),
+            Value::Nil => self.u8(3),
+            Value::String(s) => {
+                self.u8(4);
+                self.string(s);
+            }
+            Value::Function(function) => {
+                self.u8(5);
+                self.function(function, files)?;
+            }
+            Value::Struct(def) => {
+                self.u8(6);
+                self.string(def.name());
+                self.u32(def.fields().len() as u32);
+                def.fields().iter().for_each(|field| self.string(field));
+            }
+            Value::Protocol(protocol) => {
+                self.u8(7);
+                self.string(protocol.name());
+                self.u32(protocol.methods().len() as u32);
+                for (name, arity) in protocol.methods() {
+                    self.string(name);
+                    self.u8(*arity);
+                }
+            }
+            _ => return None,
+        }
+        Some(())
+    }
+}
+
+/// Reads back what `Writer` wrote, giving `None` for anything cut short or malformed.
+struct Reader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(bytes: &'a [u8]) -> Self {
+        Reader { bytes, position: 0 }
+    }
+
+    fn bytes(&mut self, len: usize) -> Option<&'a [u8]> {
+        let bytes = self
+            .bytes
+            .get(self.position..self.position.checked_add(len)?)?;
+        self.position += len;
+        Some(bytes)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        Some(self.bytes(1)?[0])
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        Some(u32::from_le_bytes(self.bytes(4)?.try_into().ok()?))
+    }
+
+    fn u64(&mut self) -> Option<usize> {
+        let n = u64::from_le_bytes(self.bytes(8)?.try_into().ok()?);
+        usize::try_from(n).ok()
+    }
+
+    fn f64(&mut self) -> Option<f64> {
+        Some(f64::from_le_bytes(self.bytes(8)?.try_into().ok()?))
+    }
+
+    fn string(&mut self) -> Option<String> {
+        let len = self.u32()? as usize;
+        String::from_utf8(self.bytes(len)?.to_vec()).ok()
+    }
+
+    fn file(&mut self, files: &[FileId]) -> Option<Option<FileId>> {
+        match self.u32()? as usize {
+            0 => Some(None),
+            index => files.get(index - 1).copied().map(Some),
+        }
+    }
+
+    fn function(&mut self, files: &[FileId]) -> Option<Gc<GreenFunction>> {
+        let mut function = GreenFunction::new();
+        *function.name_mut() = self.string()?;
+        *function.arity_mut() = self.u8()?;
+
+        let name = match self.u8()? {
+            0 => None,
+            _ => Some(self.string()?),
+        };
+        let file = self.file(files)?;
+        let len = self.u32()? as usize;
+        let code = self.bytes(len)?.to_vec();
+
+        let mut constants = vec![];
+        for _ in 0..self.u32()? {
+            constants.push(self.constant(files)?);
+        }
+
+        let mut jump_tables = vec![];
+        for _ in 0..self.u32()? {
+            let cases = match self.u8()? {
+                0 => {
+                    let min = i64::from_le_bytes(self.bytes(8)?.try_into().ok()?);
+                    let mut targets = vec![];
+                    for _ in 0..self.u32()? {
+                        targets.push(self.u64()?);
+                    }
+                    Cases::Dense { min, targets }
+                }
+                1 => {
+                    let mut targets = BTreeMap::new();
+                    for _ in 0..self.u32()? {
+                        targets.insert(self.string()?, self.u64()?);
+                    }
+                    Cases::Strings(targets)
+                }
+                _ => return None,
+            };
+            jump_tables.push(JumpTable::new(cases, self.u64()?, self.u64()?));
+        }
+
+        let mut lines = vec![];
+        for _ in 0..self.u32()? {
+            lines.push((self.u64()?, self.u64()?));
+        }
+
+        let mut locals = vec![];
+        for _ in 0..self.u32()? {
+            locals.push(LocalDebug {
+                name: self.string()?,
+                slot: self.u64()?,
+                start: self.u64()?,
+                end: self.u64()?,
+            });
+        }
+
+        let mut chunk = Chunk::from_parts(name, file, code, constants, jump_tables, lines, locals);
+        for _ in 0..self.u32()? {
+            chunk.add_property_cache();
+        }
+        *function.chunk_mut() = chunk;
+        Some(Gc::new(function))
+    }
+
+    fn constant(&mut self, files: &[FileId]) -> Option<Value> {
+        Some(match self.u8()? {
+            0 => Value::Number(self.f64()?),
+            1 => Value::True,
+            2 => Value::False,
+            3 => Value::Nil,
+            4 => Value::string(self.string()?),
+            5 => Value::Function(self.function(files)?),
+            6 => {
+                let name = self.string()?;
+                let mut fields = vec![];
+                for _ in 0..self.u32()? {
+                    fields.push(self.string()?);
+                }
+                Value::Struct(Gc::new(Struct::new(name, fields)))
+            }
+            7 => {
+                let name = self.string()?;
+                let mut methods = vec![];
+                for _ in 0..self.u32()? {
+                    methods.push((self.string()?, self.u8()?));
+                }
+                Value::Protocol(Gc::new(Protocol::new(name, methods)))
+            }
+            _ => return None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::chunk::disassemble;
+    use crate::compiler::compiler::Compiler;
+    use crate::syntax::parser::GreenParser;
+    use crate::vm::VM;
+
+    const SOURCE: &str = r#"
+struct Point(x, y)
+
+def describe(n)
+    return match n
+    case 1 do "one"
+    case 2 do "two"
+    else "many"
+    end
+end
+
+var p = Point(3, 4)
+print describe(2)
+print p.x * p.y
+"#;
+
+    fn compile(source: &str, options: CompileOptions) -> (FileId, SourceMap, Gc<GreenFunction>) {
+        let mut sources = SourceMap::new();
+        let file = sources.add("main.green", source);
+        let module = GreenParser::parse(source).unwrap();
+        let function =
+            Compiler::compile_sources(module, Some(file), options, &mut sources).unwrap();
+        (file, sources, function)
+    }
+
+    #[test]
+    fn cached_modules_load_unless_the_source_changed() {
+        let dir = env::temp_dir().join(format!("green-cache-{}", process::id()));
+        let cache = ModuleCache::new(&dir);
+        let options = CompileOptions {
+            disassemble: false,
+            ..CompileOptions::default()
+        };
+
+        let (file, sources, function) = compile(SOURCE, options);
+        cache.store(file, options, &sources, &function);
+
+        let mut fresh = SourceMap::new();
+        let file = fresh.add("main.green", SOURCE);
+        let loaded = cache
+            .load(file, options, &mut fresh)
+            .expect("a cached module");
+        assert_eq!(disassemble(&loaded), disassemble(&function));
+
+        let mut changed = SourceMap::new();
+        let file = changed.add("main.green", SOURCE.replace("3, 4", "5, 6"));
+        assert!(cache.load(file, options, &mut changed).is_none());
+
+        let run = |cache: ModuleCache| {
+            let mut vm = VM::new();
+            vm.set_cache(cache);
+            vm.capture_output(|vm| vm.interpret_named("main.green", SOURCE))
+                .1
+        };
+        assert_eq!(run(cache.clone()), "two\n12\n");
+        assert_eq!(run(cache.clone()), "two\n12\n");
+
+        cache.clear().unwrap();
+        assert!(!dir.exists());
+    }
+
+    #[test]
+    fn modules_are_cached_per_lossy_utf8_setting() {
+        let dir = env::temp_dir().join(format!("green-cache-lossy-{}", process::id()));
+        let cache = ModuleCache::new(&dir);
+        let options = CompileOptions {
+            disassemble: false,
+            ..CompileOptions::default()
+        };
+
+        let (file, mut sources, function) = compile(SOURCE, options);
+        cache.store(file, options, &sources, &function);
+
+        let lossy = CompileOptions {
+            lossy_utf8: true,
+            ..options
+        };
+        assert!(cache.load(file, lossy, &mut sources).is_none());
+        assert!(cache.load(file, options, &mut sources).is_some());
+
+        cache.clear().unwrap();
+    }
+
+    #[test]
+    fn the_cache_lives_in_the_users_cache_directory() {
+        let var = |value: &str| Some(OsString::from(value));
+
+        assert_eq!(
+            cache_dir(var("/xdg"), var("/home/me")),
+            Some(PathBuf::from("/xdg/green"))
+        );
+        assert_eq!(
+            cache_dir(var(""), var("/home/me")),
+            Some(PathBuf::from("/home/me/.cache/green"))
+        );
+        assert_eq!(
+            cache_dir(None, var("/home/me")),
+            Some(PathBuf::from("/home/me/.cache/green"))
+        );
+        assert_eq!(cache_dir(None, None), None);
+        assert_eq!(cache_dir(var(""), var("")), None);
+    }
+}
@@ -1,38 +1,255 @@
+use crate::compiler::jump_table::JumpTable;
+use crate::compiler::object::GreenFunction;
 use crate::compiler::opcode::Opcode;
+use crate::compiler::strings::StringTable;
 use crate::compiler::value::Value;
+use crate::source_map::FileId;
+use std::cell::Cell;
 use std::fmt;
 use std::fmt::{Display, Formatter};
+use std::rc::Rc;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
 pub struct Chunk {
     name: Option<String>,
+    /// The file the function was written in, if it was compiled from one.
+    file: Option<FileId>,
     code: Vec<u8>,
     constants: Vec<Value>,
-    lines: Vec<usize>,
+    strings: Rc<StringTable>,
+    jump_tables: Vec<JumpTable>,
+    /// Source lines of the code as runs of `(line, bytes)`, covering every byte in order. The
+    /// line is 0 when unknown.
+    lines: Vec<(usize, usize)>,
+    locals: Vec<LocalDebug>,
+    /// What each `GET_PROPERTY` and `SET_PROPERTY` last found, indexed by their second operand.
+    property_caches: Vec<Cell<PropertyCache>>,
+}
+
+/// The class a property instruction last saw an instance of, and the slot the property was
+/// in. Instances of the same class keep a field in the same slot, so the next lookup on one
+/// can skip the hashing.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PropertyCache {
+    /// Id of the class, 0 while the instruction hasn't seen one.
+    pub class: u64,
+    pub slot: usize,
+}
+
+/// Operand of property instructions that don't have a cache, once a chunk has run out.
+pub const NO_PROPERTY_CACHE: u8 = u8::MAX;
+
+/// How many constants a chunk can have. The first 256 are loaded by `CONSTANT` and the rest
+/// by `CONSTANT_LONG`.
+pub const MAX_CONSTANTS: usize = u16::MAX as usize + 1;
+
+/// Where a local variable lives while it's in scope, so a debugger can show it by name.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LocalDebug {
+    pub name: String,
+    /// Stack slot, relative to the start of the call frame.
+    pub slot: usize,
+    /// The code offsets the local is in scope for, `start..end`.
+    pub start: usize,
+    pub end: usize,
 }
 
 impl Chunk {
     pub fn new() -> Self {
         Chunk {
             name: None,
+            file: None,
             code: vec![],
             constants: vec![],
+            strings: Rc::new(StringTable::new()),
+            jump_tables: vec![],
             lines: vec![],
+            locals: vec![],
+            property_caches: vec![],
+        }
+    }
+
+    /// A chunk made from parts read back from the module cache. Its strings are linked after.
+    pub(crate) fn from_parts(
+        name: Option<String>,
+        file: Option<FileId>,
+        code: Vec<u8>,
+        constants: Vec<Value>,
+        jump_tables: Vec<JumpTable>,
+        lines: Vec<(usize, usize)>,
+        locals: Vec<LocalDebug>,
+    ) -> Self {
+        Chunk {
+            name,
+            file,
+            code,
+            constants,
+            strings: Rc::new(StringTable::new()),
+            jump_tables,
+            lines,
+            locals,
+            property_caches: vec![],
         }
     }
 
     pub fn write(&mut self, opcode: Opcode, line: usize) {
-        self.lines.push(line);
-        self.write_byte(opcode as u8);
+        self.code.push(opcode as u8);
+        self.add_line(line);
     }
 
+    /// Writes an operand, on the line of the instruction it belongs to.
     pub fn write_byte(&mut self, byte: u8) {
         self.code.push(byte);
+        let line = self.lines.last().map_or(0, |(line, _)| *line);
+        self.add_line(line);
+    }
+
+    fn add_line(&mut self, line: usize) {
+        match self.lines.last_mut() {
+            Some((last, bytes)) if *last == line => *bytes += 1,
+            _ => self.lines.push((line, 1)),
+        }
+    }
+
+    /// The source line of the code at `offset`, or 0 when unknown.
+    pub fn line_at(&self, offset: usize) -> usize {
+        let mut start = 0;
+        for (line, bytes) in &self.lines {
+            start += bytes;
+            if offset < start {
+                return *line;
+            }
+        }
+        0
+    }
+
+    pub fn file(&self) -> Option<FileId> {
+        self.file
+    }
+
+    pub(crate) fn set_file(&mut self, file: Option<FileId>) {
+        self.file = file;
+    }
+
+    /// Records that a local comes into scope in `slot` from the next instruction on.
+    pub(crate) fn begin_local(&mut self, name: &str, slot: usize) {
+        self.locals.push(LocalDebug {
+            name: name.to_string(),
+            slot,
+            start: self.code.len(),
+            end: usize::MAX,
+        });
+    }
+
+    /// Records that the local in `slot` goes out of scope at the next instruction.
+    pub(crate) fn end_local(&mut self, slot: usize) {
+        let end = self.code.len();
+        if let Some(local) = self
+            .locals
+            .iter_mut()
+            .rev()
+            .find(|local| local.slot == slot && local.end == usize::MAX)
+        {
+            local.end = end;
+        }
+    }
+
+    /// Ends the scope of every local still in scope at the end of the code.
+    pub(crate) fn end_locals(&mut self) {
+        let end = self.code.len();
+        for local in &mut self.locals {
+            local.end = local.end.min(end);
+        }
+    }
+
+    /// Disassembles the instruction at `offset`.
+    pub fn instruction(&self, offset: usize) -> String {
+        struct Instruction<'a>(&'a Chunk, usize);
+
+        impl Display for Instruction<'_> {
+            fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+                disassemble_instruction(f, self.0, &mut self.1.clone()).map(|_| ())
+            }
+        }
+
+        Instruction(self, offset).to_string()
     }
 
-    pub fn add_constant(&mut self, value: Value) -> u8 {
+    /// The locals in scope at `offset`, by slot.
+    pub fn locals_at(&self, offset: usize) -> Vec<&LocalDebug> {
+        let mut locals: Vec<&LocalDebug> = self
+            .locals
+            .iter()
+            .filter(|local| (local.start..local.end).contains(&offset))
+            .collect();
+        locals.sort_by_key(|local| local.slot);
+        locals
+    }
+
+    /// Adds a constant, returning its index, or None if the chunk has as many as an operand can
+    /// address.
+    pub fn add_constant(&mut self, value: Value) -> Option<u16> {
+        if self.constants.len() >= MAX_CONSTANTS {
+            return None;
+        }
+
         self.constants.push(value);
-        self.constants.len() as u8 - 1
+        Some((self.constants.len() - 1) as u16)
+    }
+
+    /// Adds a jump table, returning its index, or None if the chunk has as many as an operand
+    /// can address.
+    pub fn add_jump_table(&mut self, table: JumpTable) -> Option<u8> {
+        if self.jump_tables.len() > u8::MAX as usize {
+            return None;
+        }
+
+        self.jump_tables.push(table);
+        Some((self.jump_tables.len() - 1) as u8)
+    }
+
+    /// Adds an empty property cache, returning its index or `NO_PROPERTY_CACHE` if the chunk
+    /// has as many as an operand can address.
+    pub fn add_property_cache(&mut self) -> u8 {
+        if self.property_caches.len() >= NO_PROPERTY_CACHE as usize {
+            return NO_PROPERTY_CACHE;
+        }
+
+        self.property_caches.push(Cell::default());
+        (self.property_caches.len() - 1) as u8
+    }
+
+    pub fn property_cache(&self, index: u8) -> Option<&Cell<PropertyCache>> {
+        self.property_caches.get(index as usize)
+    }
+
+    pub(crate) fn property_cache_count(&self) -> usize {
+        self.property_caches.len()
+    }
+
+    pub fn jump_table(&self, index: usize) -> &JumpTable {
+        &self.jump_tables[index]
+    }
+
+    pub(crate) fn jump_table_mut(&mut self, index: usize) -> &mut JumpTable {
+        &mut self.jump_tables[index]
+    }
+
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    pub(crate) fn jump_tables(&self) -> &[JumpTable] {
+        &self.jump_tables
+    }
+
+    /// The runs of `(line, bytes)` mapping the code to source lines.
+    pub(crate) fn lines(&self) -> &[(usize, usize)] {
+        &self.lines
+    }
+
+    pub(crate) fn locals(&self) -> &[LocalDebug] {
+        &self.locals
     }
 
     pub fn name_mut(&mut self) -> &mut Option<String> {
@@ -54,41 +271,65 @@ impl Chunk {
     pub fn constants(&self) -> &Vec<Value> {
         &self.constants
     }
+
+    /// The names shared by every chunk of the module, indexed by name operands.
+    pub fn strings(&self) -> &StringTable {
+        &self.strings
+    }
+
+    pub(crate) fn set_strings(&mut self, strings: Rc<StringTable>) {
+        self.strings = strings;
+    }
 }
 
 impl Display for Chunk {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         if let Some(name) = &self.name {
-            writeln!(f, "== <{}> chunk ==", name);
+            writeln!(f, "== <{}> chunk ==", name)?;
         } else {
-            writeln!(f, "== chunk ==");
+            writeln!(f, "== chunk ==")?;
         }
 
         let mut offset = 0;
         while offset < self.code.len() {
-            offset = disassemble_instruction(f, self, &mut offset);
+            offset = disassemble_instruction(f, self, &mut offset)?;
         }
 
-        writeln!(f, "")
+        writeln!(f)
     }
 }
 
-fn disassemble_instruction(f: &mut Formatter<'_>, chunk: &Chunk, offset: &mut usize) -> usize {
-    write!(f, "{:04X}", offset);
+/// Disassembles a function, then every function among its constants, depth first. The output
+/// only depends on the source the function was compiled from, so it can be compared across runs.
+pub fn disassemble(function: &GreenFunction) -> String {
+    let mut out = function.chunk().to_string();
+    for constant in function.chunk().constants() {
+        if let Value::Function(nested) = constant {
+            out.push_str(&disassemble(nested));
+        }
+    }
+    out
+}
 
-    // if *offset > 0 &&
-    //     chunk.lines[*offset] == chunk.lines[*offset - 1] {
-    //     write!(f, "   | ");
-    // } else {
-    //     write!(f, "{:4} ", chunk.lines[*offset]);
-    // }
+fn disassemble_instruction(
+    f: &mut Formatter<'_>,
+    chunk: &Chunk,
+    offset: &mut usize,
+) -> Result<usize, fmt::Error> {
+    write!(f, "{:04X} ", offset)?;
 
-    write!(f, "   | ");
+    let line = chunk.line_at(*offset);
+    if *offset > 0 && line == chunk.line_at(*offset - 1) {
+        write!(f, "   | ")?;
+    } else {
+        write!(f, "{:4} ", line)?;
+    }
 
     let instruction = Opcode::from(chunk.code[*offset]);
     match instruction {
         Opcode::Return => simple_instruction(f, "RETURN", offset),
         Opcode::Constant => constant_instruction(chunk, f, "CONSTANT", offset),
+        Opcode::ConstantLong => constant_long_instruction(chunk, f, "CONSTANT_LONG", offset),
         Opcode::Add => simple_instruction(f, "ADD", offset),
         Opcode::Subtract => simple_instruction(f, "SUBTRACT", offset),
         Opcode::Multiply => simple_instruction(f, "MULTIPLY", offset),
@@ -99,9 +340,9 @@ fn disassemble_instruction(f: &mut Formatter<'_>, chunk: &Chunk, offset: &mut us
         Opcode::Less => simple_instruction(f, "LESS", offset),
         Opcode::Not => simple_instruction(f, "NOT", offset),
         Opcode::Negate => simple_instruction(f, "NOT", offset),
-        Opcode::DefineGlobal => constant_instruction(chunk, f, "DEFINE_GLOBAL", offset),
-        Opcode::GetGlobal => constant_instruction(chunk, f, "GET_GLOBAL", offset),
-        Opcode::SetGlobal => constant_instruction(chunk, f, "SET_GLOBAL", offset),
+        Opcode::DefineGlobal => string_instruction(chunk, f, "DEFINE_GLOBAL", offset),
+        Opcode::GetGlobal => string_instruction(chunk, f, "GET_GLOBAL", offset),
+        Opcode::SetGlobal => string_instruction(chunk, f, "SET_GLOBAL", offset),
         Opcode::JumpIfFalse => jump_instruction(chunk, f, "JUMP_IF_FALSE", 1, offset),
         Opcode::Jump => jump_instruction(chunk, f, "JUMP", 1, offset),
         Opcode::Pop => simple_instruction(f, "POP", offset),
@@ -109,29 +350,56 @@ fn disassemble_instruction(f: &mut Formatter<'_>, chunk: &Chunk, offset: &mut us
         Opcode::SetLocal => byte_instruction(chunk, f, "SET_LOCAL", offset),
         Opcode::Nil => simple_instruction(f, "NIL", offset),
         Opcode::Call => byte_instruction(chunk, f, "CALL", offset),
-        Opcode::Closure => {
-            // TODO
-            *offset += 2;
-
-            let constant = chunk.code[*offset - 1];
-            write!(f, "{:-16} {:4} ", "CLOSURE", constant);
-            writeln!(f, "'{:?}'", chunk.constants()[constant as usize]);
-
-            *offset
-        }
-        Opcode::Loop => jump_instruction(chunk, f, "LOOP", 0, offset), // TODO sign should be -1
+        Opcode::Closure => constant_long_instruction(chunk, f, "CLOSURE", offset),
+        Opcode::Loop => jump_instruction(chunk, f, "LOOP", -1, offset),
         Opcode::NewArray => byte_instruction(chunk, f, "NEW_ARRAY", offset),
         Opcode::IndexSubscript => simple_instruction(f, "INDEX_SUBSCRIPT", offset), // TODO
         Opcode::StoreSubscript => simple_instruction(f, "STORE_SUBSCRIPT", offset), // TODO
-        Opcode::Class => constant_instruction(chunk, f, "CLASS", offset),
-        Opcode::GetProperty => constant_instruction(chunk, f, "GET_PROPERTY", offset),
-        Opcode::SetProperty => constant_instruction(chunk, f, "SET_PROPERTY", offset),
+        Opcode::Class => string_instruction(chunk, f, "CLASS", offset),
+        Opcode::GetProperty => property_instruction(chunk, f, "GET_PROPERTY", offset),
+        Opcode::SetProperty => property_instruction(chunk, f, "SET_PROPERTY", offset),
+        Opcode::Is => simple_instruction(f, "IS", offset),
+        Opcode::AssertFail => constant_long_instruction(chunk, f, "ASSERT_FAIL", offset),
+        Opcode::Method => string_instruction(chunk, f, "METHOD", offset),
+        Opcode::Static => string_instruction(chunk, f, "STATIC", offset),
+        Opcode::NewArrayLong => short_instruction(chunk, f, "NEW_ARRAY_LONG", offset),
+        Opcode::ArrayPush => simple_instruction(f, "ARRAY_PUSH", offset),
+        Opcode::ArrayPushRange => simple_instruction(f, "ARRAY_PUSH_RANGE", offset),
+        Opcode::Modulo => simple_instruction(f, "MODULO", offset),
+        Opcode::NewRange => simple_instruction(f, "NEW_RANGE", offset),
+        Opcode::NewMap => byte_instruction(chunk, f, "NEW_MAP", offset),
+        Opcode::AddLocalConstant => byte_instruction(chunk, f, "ADD_LOCAL_CONSTANT", offset),
+        Opcode::ConstantCall => constant_instruction(chunk, f, "CONSTANT_CALL", offset),
+        Opcode::LessJumpIfFalse => simple_instruction(f, "LESS_JUMP_IF_FALSE", offset),
+        Opcode::GreaterJumpIfFalse => simple_instruction(f, "GREATER_JUMP_IF_FALSE", offset),
+        Opcode::EqualJumpIfFalse => simple_instruction(f, "EQUAL_JUMP_IF_FALSE", offset),
+        Opcode::JumpTable => {
+            let index = chunk.code[*offset + 1];
+            writeln!(
+                f,
+                "{:-16} {:4X} {}",
+                "JUMP_TABLE",
+                index,
+                chunk.jump_table(index as usize)
+            )?;
+            Ok(*offset + 2)
+        }
+        Opcode::Field => string_instruction(chunk, f, "FIELD", offset),
+        Opcode::PopN => byte_instruction(chunk, f, "POP_N", offset),
+        Opcode::NewTuple => byte_instruction(chunk, f, "NEW_TUPLE", offset),
+        Opcode::UnpackTuple => byte_instruction(chunk, f, "UNPACK_TUPLE", offset),
+        Opcode::UnpackArray => byte_instruction(chunk, f, "UNPACK_ARRAY", offset),
+        Opcode::UnpackMap => byte_instruction(chunk, f, "UNPACK_MAP", offset),
     }
 }
 
-fn simple_instruction(f: &mut Formatter<'_>, name: &str, offset: &mut usize) -> usize {
-    writeln!(f, "{}", name);
-    *offset + 1
+fn simple_instruction(
+    f: &mut Formatter<'_>,
+    name: &str,
+    offset: &mut usize,
+) -> Result<usize, fmt::Error> {
+    writeln!(f, "{}", name)?;
+    Ok(*offset + 1)
 }
 
 fn constant_instruction(
@@ -139,20 +407,59 @@ fn constant_instruction(
     f: &mut Formatter<'_>,
     name: &str,
     offset: &mut usize,
-) -> usize {
+) -> Result<usize, fmt::Error> {
     let constant = chunk.code()[*offset + 1];
-    write!(f, "{:-16} {:4} ", name, constant);
-    writeln!(f, "'{:?}'", chunk.constants()[constant as usize]);
-    *offset + 2
+    write!(f, "{:-16} {:4} ", name, constant)?;
+    writeln!(f, "'{:?}'", chunk.constants()[constant as usize])?;
+    Ok(*offset + 2)
+}
+
+/// An instruction whose operand is the two-byte index of a constant.
+fn constant_long_instruction(
+    chunk: &Chunk,
+    f: &mut Formatter<'_>,
+    name: &str,
+    offset: &mut usize,
+) -> Result<usize, fmt::Error> {
+    let constant = u16::from_be_bytes([chunk.code()[*offset + 1], chunk.code()[*offset + 2]]);
+    write!(f, "{:-16} {:4} ", name, constant)?;
+    writeln!(f, "'{:?}'", chunk.constants()[constant as usize])?;
+    Ok(*offset + 3)
+}
+
+fn string_instruction(
+    chunk: &Chunk,
+    f: &mut Formatter<'_>,
+    name: &str,
+    offset: &mut usize,
+) -> Result<usize, fmt::Error> {
+    let index = u16::from_be_bytes([chunk.code()[*offset + 1], chunk.code()[*offset + 2]]);
+    write!(f, "{:-16} {:4} ", name, index)?;
+    match chunk.strings().get(index as usize) {
+        Some(string) => writeln!(f, "'{}'", string)?,
+        // The table is attached once the whole module is compiled.
+        None => writeln!(f)?,
+    }
+    Ok(*offset + 3)
+}
+
+/// A string instruction followed by the index of its property cache, which isn't shown.
+fn property_instruction(
+    chunk: &Chunk,
+    f: &mut Formatter<'_>,
+    name: &str,
+    offset: &mut usize,
+) -> Result<usize, fmt::Error> {
+    Ok(string_instruction(chunk, f, name, offset)? + 1)
 }
 
 fn jump_instruction(
     chunk: &Chunk,
     f: &mut Formatter<'_>,
     name: &str,
-    sign: usize,
+    sign: isize,
     offset: &mut usize,
-) -> usize {
+) -> Result<usize, fmt::Error> {
     let lo = chunk.code[*offset + 2] as u16;
     let hi = chunk.code[*offset + 1] as u16;
 
@@ -163,14 +470,53 @@ fn jump_instruction(
         "{:-16} {:4X} -> {:4X}",
         name,
         offset,
-        *offset + 3 + sign * jump as usize
-    );
+        *offset as isize + 3 + sign * jump as isize
+    )?;
 
-    *offset + 3
+    Ok(*offset + 3)
 }
 
-fn byte_instruction(chunk: &Chunk, f: &mut Formatter<'_>, name: &str, offset: &mut usize) -> usize {
+fn byte_instruction(
+    chunk: &Chunk,
+    f: &mut Formatter<'_>,
+    name: &str,
+    offset: &mut usize,
+) -> Result<usize, fmt::Error> {
     let slot = chunk.code[*offset + 1];
-    writeln!(f, "{:-16} {:4X}", name, slot);
-    *offset + 2
+    writeln!(f, "{:-16} {:4X}", name, slot)?;
+    Ok(*offset + 2)
+}
+
+fn short_instruction(
+    chunk: &Chunk,
+    f: &mut Formatter<'_>,
+    name: &str,
+    offset: &mut usize,
+) -> Result<usize, fmt::Error> {
+    let hi = chunk.code[*offset + 1] as u16;
+    let lo = chunk.code[*offset + 2] as u16;
+    writeln!(f, "{:-16} {:4X}", name, (hi << 8) | lo)?;
+    Ok(*offset + 3)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lines_are_stored_as_runs_and_looked_up_by_offset() {
+        let mut chunk = Chunk::new();
+        chunk.write(Opcode::Constant, 1);
+        chunk.write_byte(0);
+        chunk.write(Opcode::Constant, 1);
+        chunk.write_byte(1);
+        chunk.write(Opcode::Add, 2);
+        chunk.write(Opcode::Jump, 4);
+        chunk.write_byte(0);
+        chunk.write_byte(0);
+
+        assert_eq!(chunk.lines, [(1, 4), (2, 1), (4, 3)]);
+        let lines: Vec<usize> = (0..9).map(|offset| chunk.line_at(offset)).collect();
+        assert_eq!(lines, [1, 1, 1, 1, 2, 4, 4, 4, 0]);
+    }
 }
@@ -1,48 +1,396 @@
-use crate::compiler::chunk::Chunk;
+use crate::compiler::chunk;
+use crate::compiler::chunk::{Chunk, MAX_CONSTANTS};
+use crate::compiler::inliner::{self, InlineFunction};
 use crate::compiler::instance::CompilerInstance;
 use crate::compiler::local::Local;
-use crate::compiler::object::{GreenFunction, GreenFunctionType};
+use crate::compiler::object::{GreenFunction, GreenFunctionType, Protocol};
 use crate::compiler::opcode::Opcode;
+use crate::compiler::optimizer;
+use crate::compiler::options::{CompileOptions, OptLevel};
+use crate::compiler::strings::{StringTable, MAX_STRINGS};
 use crate::compiler::value::Value;
+use crate::source_map::{FileId, SourceMap};
+use crate::syntax::ast::{Ast, ExprId};
+use crate::syntax::expr::{
+    BinaryExpr, BinaryOperator, ClassExpr, Compile, ExprKind, LiteralExpr, Variable,
+};
 use crate::syntax::parser::ModuleAst;
-use crate::syntax::expr::{LiteralExpr, Expr, Compile, Variable};
 use crate::vm::obj::Gc;
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
+use std::fmt;
+use std::mem;
+use std::rc::Rc;
 
 pub struct Compiler {
     pub(crate) current: CompilerInstance,
+    /// The functions the current one is nested in, outermost first.
+    enclosing: Vec<CompilerInstance>,
+    options: CompileOptions,
+    /// The expressions being compiled: the module's, or those of a module it imports or a
+    /// function inlined into it while compiling them.
+    pub(crate) ast: Rc<Ast>,
+    warnings: Vec<Warning>,
+    errors: Vec<CompileError>,
+    strings: StringTable,
+    /// The classes whose methods are being compiled, innermost last.
+    pub(crate) classes: Vec<ClassShape>,
+    /// The files of the modules imported so far, borrowed from the caller while compiling.
+    pub(crate) sources: SourceMap,
+    /// The file and line of the code being compiled, recorded in the chunk for each instruction.
+    pub(crate) file: Option<FileId>,
+    line: usize,
+    /// The small functions of the modules imported so far, compiled in place of calls to them.
+    pub(crate) inline_functions: HashMap<String, Rc<InlineFunction>>,
+    /// The globals assigned anywhere in the modules compiled, whose calls aren't inlined as they
+    /// might not hold the imported function by then.
+    pub(crate) assigned_globals: HashSet<String>,
+    /// The functions being inlined, innermost last, so recursive ones stop.
+    pub(crate) inlining: Vec<String>,
+    /// The protocols declared so far, to check the classes that implement them against.
+    pub(crate) protocols: HashMap<String, Gc<Protocol>>,
 }
 
+/// The declared fields and the methods of a class, for checking the properties its methods use
+/// on `self`.
+pub(crate) struct ClassShape {
+    name: String,
+    fields: Vec<(String, String)>,
+    methods: Vec<String>,
+}
+
+impl ClassShape {
+    pub(crate) fn new(class: &ClassExpr) -> Self {
+        ClassShape {
+            name: class.name.name.clone(),
+            fields: class
+                .fields
+                .iter()
+                .map(|field| (field.name.name.clone(), field.type_name.name.clone()))
+                .collect(),
+            methods: class
+                .methods
+                .iter()
+                .map(|method| method.variable.name.clone())
+                .collect(),
+        }
+    }
+
+    fn field_type(&self, name: &str) -> Option<&str> {
+        self.fields
+            .iter()
+            .find(|(field, _)| field == name)
+            .map(|(_, type_name)| type_name.as_str())
+    }
+}
+
+/// A problem in the source that doesn't stop it from compiling.
+#[derive(Debug, PartialEq)]
+pub struct Warning {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "warning: {}, on line: {}", self.message, self.line)
+    }
+}
+
+/// A problem in the source that stops it from compiling, like a module with more names than
+/// its instructions can refer to.
+#[derive(Debug, PartialEq)]
+pub struct CompileError {
+    /// The file the error is in, if the module was read from one.
+    pub file: Option<FileId>,
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "error: {}, on line: {}", self.message, self.line)
+    }
+}
+
+/// The compiled module, or everything that stopped it from compiling.
+pub type CompileResult<T> = Result<T, Vec<CompileError>>;
+
 impl Compiler {
-    fn new() -> Self {
+    fn new(options: CompileOptions) -> Self {
         Compiler {
             current: CompilerInstance::new(GreenFunctionType::Script),
+            enclosing: vec![],
+            options,
+            ast: Rc::new(Ast::new()),
+            warnings: vec![],
+            errors: vec![],
+            strings: StringTable::new(),
+            classes: vec![],
+            sources: SourceMap::new(),
+            file: None,
+            line: 0,
+            inline_functions: HashMap::new(),
+            assigned_globals: HashSet::new(),
+            inlining: vec![],
+            protocols: HashMap::new(),
+        }
+    }
+
+    pub fn compile(module: ModuleAst) -> CompileResult<Gc<GreenFunction>> {
+        Compiler::compile_with(module, CompileOptions::default())
+    }
+
+    pub fn compile_with(
+        module: ModuleAst,
+        options: CompileOptions,
+    ) -> CompileResult<Gc<GreenFunction>> {
+        Compiler::compile_sources(module, None, options, &mut SourceMap::new())
+    }
+
+    /// Compiles a module, read from `file` of `sources` if it was read from a file, loading the
+    /// modules it imports into `sources`.
+    pub fn compile_sources(
+        module: ModuleAst,
+        file: Option<FileId>,
+        options: CompileOptions,
+        sources: &mut SourceMap,
+    ) -> CompileResult<Gc<GreenFunction>> {
+        let (function, warnings) = Compiler::compile_module_in(module, file, options, sources)?;
+        if options.warnings {
+            for warning in warnings {
+                eprintln!("{}", warning);
+            }
         }
+        Ok(function)
     }
 
-    pub fn compile(
+    /// Compiles a module, returning its warnings instead of printing them.
+    pub(crate) fn compile_module(
         module: ModuleAst,
-    ) -> GreenFunction {
-        let mut compiler = Compiler::new();
+        options: CompileOptions,
+    ) -> CompileResult<(Gc<GreenFunction>, Vec<Warning>)> {
+        Compiler::compile_module_in(module, None, options, &mut SourceMap::new())
+    }
+
+    fn compile_module_in(
+        mut module: ModuleAst,
+        file: Option<FileId>,
+        options: CompileOptions,
+        sources: &mut SourceMap,
+    ) -> CompileResult<(Gc<GreenFunction>, Vec<Warning>)> {
+        if options.opt_level.folds_constants() {
+            optimizer::optimize_module(&mut module);
+        }
+
+        let (ast, exprs) = module.into_parts();
+        let mut compiler = Compiler::new(options);
+        compiler.ast = Rc::new(ast);
+        compiler.sources = mem::take(sources);
+        compiler.file = file;
+        compiler.current_chunk().set_file(file);
+        if options.opt_level.inlines_functions() {
+            let globals = &mut compiler.assigned_globals;
+            inliner::assigned_globals(&compiler.ast, &exprs, true, globals);
+        }
+
+        // Hoist function, class, struct and protocol declarations so they can be used before
+        // the line defining them. Protocols go first, so classes can be checked against them.
+        // Classes with `static var`s stay where they are, as their initializers may use the
+        // variables defined before them.
+        let ast = Rc::clone(&compiler.ast);
+        let (mut declarations, rest): (Vec<ExprId>, Vec<ExprId>) =
+            exprs.into_iter().partition(|expr| match &ast[*expr] {
+                ExprKind::Class(class) => class.statics.is_empty(),
+                ExprKind::Function(_) | ExprKind::Struct(_) | ExprKind::Protocol(_) => true,
+                _ => false,
+            });
+        declarations.sort_by_key(|expr| !matches!(ast[*expr], ExprKind::Protocol(_)));
+
+        let mut exprs: Vec<ExprId> = declarations.into_iter().chain(rest).collect();
+        if let Some(returns) = exprs
+            .iter()
+            .position(|expr| ast[*expr].always_returns(&ast))
+        {
+            if let Some(unreachable) = exprs.get(returns + 1) {
+                compiler.warn_unreachable(*unreachable);
+            }
+            exprs.truncate(returns + 1);
+        }
+
+        // The value of the script's last expression is returned to the embedder.
+        let last = exprs.pop();
+        for expr in exprs {
+            compiler.compile_statement(expr);
+        }
+
+        match last {
+            Some(expr) if ast[expr].leaves_value() => {
+                compiler.compile_expr(expr);
+                compiler.emit(Opcode::Return);
+            }
+            Some(expr) => compiler.compile_statement(expr),
+            None => {}
+        }
+
+        let mut function = compiler.end_compiler();
+        link_strings(&mut function, &Rc::new(compiler.strings));
+        if options.disassemble {
+            print!("{}", chunk::disassemble(&function));
+        }
+        *sources = compiler.sources;
+        if !compiler.errors.is_empty() {
+            return Err(compiler.errors);
+        }
+        Ok((function, compiler.warnings))
+    }
+
+    pub(crate) fn opt_level(&self) -> OptLevel {
+        self.options.opt_level
+    }
+
+    pub(crate) fn lossy_utf8(&self) -> bool {
+        self.options.lossy_utf8
+    }
 
-        for expr in module.exprs() {
-            compiler.compile_expr(expr);
+    /// The line of the code being compiled.
+    pub(crate) fn line(&self) -> usize {
+        self.line
+    }
+
+    pub fn compile_expr(&mut self, expr: ExprId) {
+        let ast = Rc::clone(&self.ast);
+        let line = self.enter_line(ast.line(expr));
+        ast[expr].compile(self);
+        self.line = line;
+    }
+
+    /// Compiles an expression in statement position, discarding its value.
+    pub fn compile_statement(&mut self, expr: ExprId) {
+        if self.ast[expr].is_pure(&self.ast) {
+            self.warn_unused(expr);
+        }
+
+        let line = self.enter_line(self.ast.line(expr));
+        self.compile_expr(expr);
+
+        if self.ast[expr].leaves_value() {
+            self.emit(Opcode::Pop);
+        }
+        self.line = line;
+    }
+
+    /// Compiles with the expressions of `ast`, like those of an imported module, instead of the
+    /// ones being compiled.
+    pub(crate) fn in_ast<T>(&mut self, ast: Rc<Ast>, compile: impl FnOnce(&mut Self) -> T) -> T {
+        let outer = mem::replace(&mut self.ast, ast);
+        let result = compile(self);
+        self.ast = outer;
+        result
+    }
+
+    /// Attributes the code emitted next to `line`, if it's known, returning the previous line to
+    /// restore afterwards.
+    fn enter_line(&mut self, line: usize) -> usize {
+        let previous = self.line;
+        if line != 0 {
+            self.line = line;
         }
+        previous
+    }
+
+    /// The expressions up to and including the first one that always returns. Anything after it
+    /// can never run, so it is dropped with a warning.
+    pub(crate) fn reachable<'a>(&mut self, exprs: &'a [ExprId]) -> &'a [ExprId] {
+        let returns = exprs
+            .iter()
+            .position(|expr| self.ast[*expr].always_returns(&self.ast));
+        match returns {
+            Some(returns) if returns + 1 < exprs.len() => {
+                self.warn_unreachable(exprs[returns + 1]);
+                &exprs[..=returns]
+            }
+            _ => exprs,
+        }
+    }
+
+    fn warn_unreachable(&mut self, expr: ExprId) {
+        self.warn(self.ast.line(expr), "Unreachable code");
+    }
+
+    fn warn_unused(&mut self, expr: ExprId) {
+        let message = match &self.ast[expr] {
+            ExprKind::Binary(BinaryExpr {
+                operator: BinaryOperator::Equal,
+                ..
+            }) => "Unused comparison, did you mean '='",
+            _ => "Unused result of an expression without side effects",
+        };
+        self.warn(self.ast.line(expr), message);
+    }
+
+    /// Checks a property a method reads or, given the assigned value, writes on `self`. Classes
+    /// that declare fields only have those fields, so any other name is an error, as is assigning
+    /// a literal of another type than the field's. Errors are recorded on the property's line.
+    pub(crate) fn check_property(
+        &mut self,
+        receiver: ExprId,
+        property: &str,
+        value: Option<ExprId>,
+    ) {
+        let in_method = matches!(
+            self.current.function_type(),
+            GreenFunctionType::Method | GreenFunctionType::Initializer
+        );
+        let on_self =
+            matches!(&self.ast[receiver], ExprKind::VarGet(get) if get.variable.name == "self");
+        let class = match self.classes.last() {
+            Some(class) if in_method && on_self && !class.fields.is_empty() => class,
+            _ => return,
+        };
+
+        let message = match (class.field_type(property), value) {
+            (Some(field_type), value) => {
+                match value.and_then(|value| literal_type(&self.ast[value])) {
+                    Some(value_type) if value_type != field_type && value_type != "Nil" => format!(
+                        "Field {} of {} is a {}, but is assigned a {}.",
+                        property, class.name, field_type, value_type
+                    ),
+                    _ => return,
+                }
+            }
+            (None, None) if class.methods.iter().any(|method| method == property) => return,
+            (None, None) => format!("{} has no field or method called {}.", class.name, property),
+            (None, Some(_)) => format!("{} has no field called {}.", class.name, property),
+        };
+        self.error(message);
+    }
 
-        compiler.end_compiler()
+    pub(crate) fn warn(&mut self, line: usize, message: &str) {
+        self.warnings.push(Warning {
+            line,
+            message: message.to_string(),
+        });
     }
 
-    pub fn compile_expr(&mut self, expr: &Expr) {
-        expr.node.compile(self);
+    /// Records an error on the line being compiled. Compiling carries on, so every error in the
+    /// module is reported at once, but it won't run.
+    pub(crate) fn error(&mut self, message: String) {
+        self.errors.push(CompileError {
+            file: self.file,
+            line: self.line,
+            message,
+        });
     }
 
     // var x = 10
     pub(crate) fn compile_declare_var(&mut self, var: &Variable) {
-        if *self.current.scope_depth() == 0 as isize {
+        if *self.current.scope_depth() == 0_isize {
             return;
         }
 
         for local in self.current.locals() {
-            if *local.depth() != -1 as isize && local.depth() < &self.current.scope_depth() {
+            if *local.depth() != -1_isize && local.depth() < self.current.scope_depth() {
                 break;
             }
 
@@ -61,11 +409,23 @@ impl Compiler {
             return;
         }
 
-        self.emit(Opcode::DefineGlobal);
-        let constant_id = self
-            .current_chunk()
-            .add_constant(Value::string(var.name.clone()));
-        self.emit_byte(constant_id);
+        self.emit_name(Opcode::DefineGlobal, &var.name);
+    }
+
+    /// Declares a variable for each of the values on top of the stack, the last variable taking
+    /// the topmost value.
+    pub(crate) fn compile_declare_vars(&mut self, vars: &[Variable]) {
+        if *self.current.scope_depth() > 0 {
+            // Locals take the values' slots in order.
+            for var in vars {
+                self.compile_declare_var(var);
+            }
+        } else {
+            // Each global definition pops the last value left.
+            for var in vars.iter().rev() {
+                self.compile_define_var(var);
+            }
+        }
     }
 
     pub(crate) fn emit_loop(&mut self, loop_start: usize) {
@@ -85,7 +445,7 @@ impl Compiler {
         self.emit(instruction);
         self.emit_byte(0xff);
         self.emit_byte(0xff);
-        return self.current_chunk().code().len() - 2;
+        self.current_chunk().code().len() - 2
     }
 
     pub(crate) fn patch_jump(&mut self, offset: usize) {
@@ -96,18 +456,9 @@ impl Compiler {
         self.current_chunk().code_mut()[offset + 1] = (jump & 0xff) as u8;
     }
 
-    fn compile_literal(&mut self, literal: &LiteralExpr) {
-        match literal {
-            LiteralExpr::Number(n) => self.emit_constant(Value::Number(*n)),
-            LiteralExpr::String(s) => self.emit_string(&s),
-            LiteralExpr::True => self.emit_constant(Value::True),
-            LiteralExpr::False => self.emit_constant(Value::False),
-            _ => todo!(), // TODO NilLiteral
-        }
-    }
-
     pub(crate) fn resolve_local(&self, name: &String) -> isize {
-        for (i, local) in self.current.locals().iter().enumerate() {
+        // Search innermost first so that shadowing locals win.
+        for local in self.current.locals().iter().rev() {
             if *name == *local.name() {
                 if *local.depth() == -1 {
                     panic!(
@@ -116,7 +467,7 @@ impl Compiler {
                     );
                 }
 
-                return i as isize;
+                return local.slot() as isize;
             }
         }
 
@@ -124,10 +475,23 @@ impl Compiler {
     }
 
     fn add_local(&mut self, name: String) {
-        let local = Local::new(name, -1);
+        // Temporaries sit between the enclosing locals and a block nested in an expression.
+        let slot = self.current.locals().len() + self.current.temporaries();
+        self.current_chunk().begin_local(&name, slot);
+        let local = Local::new(name, -1, slot);
         self.current.locals_mut().push(local);
     }
 
+    /// Records that the expression being compiled keeps a value on the stack while its next
+    /// operand is compiled, so locals declared in that operand get the right slots.
+    pub(crate) fn push_temporary(&mut self) {
+        *self.current.temporaries_mut() += 1;
+    }
+
+    pub(crate) fn pop_temporaries(&mut self, count: usize) {
+        *self.current.temporaries_mut() -= count;
+    }
+
     fn mark_initialized(&mut self) {
         if *self.current.scope_depth() == 0 {
             return;
@@ -144,26 +508,89 @@ impl Compiler {
     pub(crate) fn end_scope(&mut self) {
         *self.current.scope_depth_mut() -= 1;
 
-        while self.current.locals().len() > 0
+        let mut popped = 0;
+        while !self.current.locals().is_empty()
             && self.current.locals()[self.current.locals().len() - 1].depth()
                 > self.current.scope_depth()
         {
+            let local = self.current.locals_mut().pop().unwrap();
+            self.current_chunk().end_local(local.slot());
+            popped += 1;
+        }
+        self.emit_pops(popped);
+    }
+
+    /// Pops `count` values, with a single instruction when there's more than one.
+    pub(crate) fn emit_pops(&mut self, mut count: usize) {
+        while count > 1 {
+            let n = count.min(u8::MAX as usize);
+            self.emit(Opcode::PopN);
+            self.emit_byte(n as u8);
+            count -= n;
+        }
+        if count == 1 {
             self.emit(Opcode::Pop);
-            self.current.locals_mut().pop();
         }
     }
 
-    pub(crate) fn end_compiler(&mut self) -> GreenFunction {
+    /// Ends the current scope while keeping the value on top of the stack, by moving it into
+    /// the slot of the scope's first local before popping the locals.
+    pub(crate) fn end_scope_with_value(&mut self) {
+        *self.current.scope_depth_mut() -= 1;
+
+        let scope_depth = *self.current.scope_depth();
+        let first_local = self
+            .current
+            .locals()
+            .iter()
+            .position(|local| *local.depth() > scope_depth);
+
+        if let Some(first_local) = first_local {
+            let slot = self.current.locals()[first_local].slot();
+            let slots: Vec<usize> = self.current.locals()[first_local..]
+                .iter()
+                .map(|local| local.slot())
+                .collect();
+            for slot in slots {
+                self.current_chunk().end_local(slot);
+            }
+            self.emit(Opcode::SetLocal);
+            self.emit_byte(slot as u8);
+
+            let popped = self.current.locals().len() - first_local;
+            self.current.locals_mut().truncate(first_local);
+            self.emit_pops(popped);
+        }
+    }
+
+    /// Starts compiling a function nested in the current one, until `end_compiler`.
+    pub(crate) fn begin_compiler(&mut self, function_type: GreenFunctionType) {
+        let enclosing = mem::replace(&mut self.current, CompilerInstance::new(function_type));
+        self.enclosing.push(enclosing);
+        let file = self.file;
+        self.current_chunk().set_file(file);
+    }
+
+    /// Finishes the function being compiled and returns to the enclosing one. The function is
+    /// moved into the one allocation every constant and closure referring to it shares.
+    pub(crate) fn end_compiler(&mut self) -> Gc<GreenFunction> {
         self.emit_return();
-        let fun_copy = self.current.function().clone();
+        self.current_chunk().end_locals();
 
-        println!("{}", self.current_chunk());
+        let opt_level = self.options.opt_level;
+        if opt_level.threads_jumps() {
+            optimizer::thread_jumps(self.current_chunk());
+        }
+        if opt_level.fuses_instructions() {
+            optimizer::fuse_instructions(self.current_chunk());
+        }
 
-        if let Some(enclosing) = *self.current.enclosing().clone() {
+        let function = self.current.finish();
+        if let Some(enclosing) = self.enclosing.pop() {
             self.current = enclosing;
         }
 
-        fun_copy
+        Gc::new(function)
     }
 
     pub(crate) fn current_chunk(&mut self) -> &mut Chunk {
@@ -171,33 +598,137 @@ impl Compiler {
     }
 
     pub(crate) fn emit_return(&mut self) {
-        self.emit(Opcode::Nil);
+        if *self.current.function_type() == GreenFunctionType::Initializer {
+            // Initializers return the new instance.
+            self.emit(Opcode::GetLocal);
+            self.emit_byte(0);
+        } else {
+            self.emit(Opcode::Nil);
+        }
         self.emit(Opcode::Return);
     }
 
+    /// Index of a name in the module's shared string table.
+    pub(crate) fn intern(&mut self, name: &str) -> u16 {
+        match self.strings.intern(name) {
+            Some(index) => index,
+            None => {
+                let message = format!(
+                    "Too many names in one module, it can use at most {}",
+                    MAX_STRINGS
+                );
+                self.error(message);
+                0
+            }
+        }
+    }
+
+    /// Emits an instruction whose operand is a name, like `GET_GLOBAL`.
+    pub(crate) fn emit_name(&mut self, opcode: Opcode, name: &str) {
+        self.emit(opcode);
+        let name = self.intern(name);
+        self.emit_short(name);
+    }
+
     pub(crate) fn emit_string(&mut self, s: &str) {
-        self.emit_constant(Value::String(s.to_string()));
+        self.emit_constant(Value::string(s.to_string()));
     }
 
     pub(crate) fn emit_constant(&mut self, value: Value) {
-        let constant = self.current_chunk().add_constant(value);
-        self.emit(Opcode::Constant);
-        self.emit_byte(constant);
+        let constant = self.add_constant(value);
+        match u8::try_from(constant) {
+            Ok(constant) => {
+                self.emit(Opcode::Constant);
+                self.emit_byte(constant);
+            }
+            Err(_) => {
+                self.emit(Opcode::ConstantLong);
+                self.emit_short(constant);
+            }
+        }
+    }
+
+    /// Adds a constant to the current chunk, returning its index.
+    pub(crate) fn add_constant(&mut self, value: Value) -> u16 {
+        match self.current_chunk().add_constant(value) {
+            Some(index) => index,
+            None => {
+                let message = format!(
+                    "Too many constants in one function, it can have at most {}",
+                    MAX_CONSTANTS
+                );
+                self.error(message);
+                0
+            }
+        }
     }
 
     pub(crate) fn emit(&mut self, opcode: Opcode) {
-        self.current_chunk().write(opcode, 123); // TODO Line
+        // Code inlined from an imported module has no line in the importing file.
+        let line = if self.current.function().chunk().file() == self.file {
+            self.line
+        } else {
+            0
+        };
+        self.current_chunk().write(opcode, line);
     }
 
     pub(crate) fn emit_byte(&mut self, byte: u8) {
         self.current_chunk().write_byte(byte);
     }
+
+    /// Emits a two-byte operand, high byte first.
+    pub(crate) fn emit_short(&mut self, short: u16) {
+        let [hi, lo] = short.to_be_bytes();
+        self.emit_byte(hi);
+        self.emit_byte(lo);
+    }
+
+    /// Emits `GET_PROPERTY` or `SET_PROPERTY` for `name`, with a property cache of its own.
+    pub(crate) fn emit_property(&mut self, opcode: Opcode, name: &str) {
+        self.emit_name(opcode, name);
+        let cache = self.current_chunk().add_property_cache();
+        self.emit_byte(cache);
+    }
+}
+
+/// The type of a literal value, known without running it.
+fn literal_type(node: &ExprKind) -> Option<&'static str> {
+    match node {
+        ExprKind::Literal(LiteralExpr::Number(_)) => Some("Number"),
+        ExprKind::Literal(LiteralExpr::String(_)) => Some("String"),
+        ExprKind::Literal(LiteralExpr::True | LiteralExpr::False) => Some("Bool"),
+        ExprKind::Literal(LiteralExpr::Nil) => Some("Nil"),
+        ExprKind::Array(_) => Some("Array"),
+        ExprKind::Map(_) => Some("Map"),
+        _ => None,
+    }
+}
+
+/// Gives the function, and every function nested in its constants, the module's string table.
+pub(crate) fn link_strings(function: &mut GreenFunction, strings: &Rc<StringTable>) {
+    function.chunk_mut().set_strings(strings.clone());
+
+    let nested: Vec<Gc<GreenFunction>> = function
+        .chunk()
+        .constants()
+        .iter()
+        .filter_map(|constant| match constant {
+            Value::Function(fun) => Some(*fun),
+            _ => None,
+        })
+        .collect();
+    for mut fun in nested {
+        link_strings(&mut fun, strings);
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::compiler::object::GreenClosure;
     use crate::syntax::parser::GreenParser;
+    use crate::vm::VM;
 
     fn parse_source(str: &str) -> ModuleAst {
         GreenParser::parse(str).unwrap()
@@ -210,6 +741,301 @@ mod tests {
         end
         "#;
         let module = parse_source(input);
-        let chunk = Compiler::compile(module);
+        let _chunk = Compiler::compile(module).unwrap();
+    }
+
+    #[test]
+    fn code_after_return_is_dropped_with_warning() {
+        let input = r#"
+        def f(x)
+            if x < 1 do
+                return 1
+            else
+                return 2
+            end
+            print x
+        end
+        "#;
+        let (function, warnings) = Compiler::compile_module(
+            parse_source(input),
+            CompileOptions {
+                opt_level: OptLevel::O0,
+                ..CompileOptions::default()
+            },
+        )
+        .unwrap();
+
+        assert_eq!(
+            warnings,
+            vec![Warning {
+                line: 8,
+                message: "Unreachable code".to_string()
+            }]
+        );
+
+        let fun = match &function.chunk().constants()[0] {
+            Value::Function(fun) => *fun,
+            _ => panic!("Expected function constant"),
+        };
+        let mut offset = 0;
+        let code = fun.chunk().code();
+        while offset < code.len() {
+            let opcode = Opcode::from(code[offset]);
+            assert!(!matches!(opcode, Opcode::Print));
+            offset += 1 + opcode.operand_len();
+        }
+    }
+
+    #[test]
+    fn names_are_shared_across_chunks() {
+        let input = r#"
+        var count = 0
+        def a()
+            count = count + 1
+        end
+        def b()
+            count = count + 2
+        end
+        "#;
+        let (function, _) = Compiler::compile_module(
+            parse_source(input),
+            CompileOptions {
+                opt_level: OptLevel::O0,
+                ..CompileOptions::default()
+            },
+        )
+        .unwrap();
+
+        let functions: Vec<Gc<GreenFunction>> = function
+            .chunk()
+            .constants()
+            .iter()
+            .filter_map(|constant| match constant {
+                Value::Function(fun) => Some(*fun),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(functions.len(), 2);
+
+        // "a", "b" and "count" each appear once, and no chunk stores a name as a constant.
+        assert_eq!(function.chunk().strings().len(), 3);
+        for fun in functions {
+            assert_eq!(fun.chunk().strings().len(), 3);
+            assert!(fun
+                .chunk()
+                .constants()
+                .iter()
+                .all(|constant| !matches!(constant, Value::String(_))));
+        }
+    }
+
+    #[test]
+    fn scopes_pop_their_locals_at_once() {
+        let input = r#"
+        def f(x)
+            if x do
+                var a = 1
+                var b = 2
+                var c = 3
+            end
+            if x do
+                var d = 4
+            end
+        end
+        "#;
+        let function = Compiler::compile(parse_source(input)).unwrap();
+        let f = match function.chunk().constants()[0] {
+            Value::Function(f) => f,
+            _ => panic!("expected a function"),
+        };
+
+        let disassembly = chunk::disassemble(&f);
+        assert!(disassembly.contains("POP_N               3\n"));
+        assert_eq!(disassembly.matches("POP_N").count(), 1);
+    }
+
+    #[test]
+    fn nested_functions_compile_into_their_enclosing_function() {
+        let depth = 200;
+        let mut input = String::new();
+        for i in 0..depth {
+            input += &format!("def f{}()\nvar x = {}\n", i, i);
+        }
+        for _ in 0..depth {
+            input += "end\n";
+        }
+
+        let mut function = Compiler::compile(parse_source(&input)).unwrap();
+        for i in 0..depth {
+            let nested = function
+                .chunk()
+                .constants()
+                .iter()
+                .find_map(|constant| match constant {
+                    Value::Function(fun) => Some(*fun),
+                    _ => None,
+                })
+                .unwrap();
+            assert_eq!(nested.name(), &format!("f{}", i));
+            function = nested;
+        }
+    }
+
+    #[test]
+    fn matches_on_many_constants_use_jump_tables() {
+        let has_jump_table = |source: &str, opt_level: OptLevel| {
+            let function = Compiler::compile_with(
+                parse_source(source),
+                CompileOptions {
+                    opt_level,
+                    ..CompileOptions::default()
+                },
+            )
+            .unwrap();
+            function.chunk().code().contains(&(Opcode::JumpTable as u8))
+        };
+
+        let dense = "match x\ncase 1 do 1\ncase 2, 3 do 2\ncase 5 do 3\nend\n";
+        let strings = "match x\ncase \"a\" do 1\ncase \"b\", \"c\" do 2\ncase \"d\" do 3\nend\n";
+        let sparse = "match x\ncase 1 do 1\ncase 2 do 2\ncase 30 do 3\ncase 40 do 4\nend\n";
+        let few = "match x\ncase 1 do 1\nend\n";
+
+        assert!(has_jump_table(dense, OptLevel::O2));
+        assert!(has_jump_table(strings, OptLevel::O2));
+        assert!(!has_jump_table(dense, OptLevel::O1));
+        assert!(!has_jump_table(dense, OptLevel::O0));
+        assert!(!has_jump_table(sparse, OptLevel::O2));
+        assert!(!has_jump_table(few, OptLevel::O2));
+    }
+
+    #[test]
+    fn matches_past_the_last_jump_table_compare_each_case() {
+        let source: String = (0..300)
+            .map(|i| {
+                format!(
+                    "var r{} = match {}\ncase 0 do 0\ncase 1 do 10\ncase 2 do 20\ncase 3 do 30\nend\n",
+                    i,
+                    i % 4
+                )
+            })
+            .collect();
+        let options = CompileOptions {
+            opt_level: OptLevel::O2,
+            disassemble: false,
+            ..CompileOptions::default()
+        };
+        let function = Compiler::compile_with(parse_source(&source), options).unwrap();
+        assert_eq!(function.chunk().jump_tables().len(), 256);
+
+        let mut vm = VM::new();
+        let closure = vm.alloc(GreenClosure::new(function));
+        vm.call_function(Value::Closure(closure), &[]).unwrap();
+        assert_eq!(vm.global("r254"), Some(&Value::Number(20.0)));
+        assert_eq!(vm.global("r299"), Some(&Value::Number(30.0)));
+    }
+
+    #[test]
+    fn matches_warn_about_cases_that_never_or_may_not_run() {
+        let input = r#"
+        var x = match 1
+        case n do n
+        case 2 do 3
+        end
+        var y = match [1]
+        case [a] do a
+        end
+        var z = match [1]
+        case [a] do a
+        else 0
+        end
+        "#;
+        let (_, warnings) = Compiler::compile_module(
+            parse_source(input),
+            CompileOptions {
+                opt_level: OptLevel::O0,
+                ..CompileOptions::default()
+            },
+        )
+        .unwrap();
+
+        let lines: Vec<_> = warnings.iter().map(|warning| warning.line).collect();
+        assert_eq!(lines, vec![4, 6]);
+        assert_eq!(warnings[0].message, "Unreachable case");
+    }
+
+    #[test]
+    fn discarded_pure_results_warn() {
+        let input = r#"
+        var x = 1
+        x == 1
+        -x + 1
+        print(x)
+        x = [x, 2]
+        x
+        "#;
+        let (_, warnings) =
+            Compiler::compile_module(parse_source(input), CompileOptions::default()).unwrap();
+
+        let lines: Vec<_> = warnings.iter().map(|warning| warning.line).collect();
+        assert_eq!(lines, vec![3, 4]);
+        assert_eq!(warnings[0].message, "Unused comparison, did you mean '='");
+    }
+
+    #[test]
+    fn classes_missing_protocol_methods_warn() {
+        let input = r#"
+        class Square implements Shape
+            def area()
+                return 4
+            end
+        end
+
+        class Blob implements Shape, Unknown
+            def area(scale)
+                return scale
+            end
+        end
+
+        protocol Shape
+            def area()
+        end
+        "#;
+        let (_, warnings) =
+            Compiler::compile_module(parse_source(input), CompileOptions::default()).unwrap();
+
+        let lines: Vec<_> = warnings.iter().map(|warning| warning.line).collect();
+        assert_eq!(lines, vec![8]);
+        assert_eq!(
+            warnings[0].message,
+            "Class Blob implements Shape but doesn't define Shape.area"
+        );
+    }
+
+    #[test]
+    fn names_beyond_the_table_are_a_compile_error() {
+        let declare = |count: usize| {
+            let vars: Vec<String> = (0..count).map(|i| format!("var v{} = []\n", i)).collect();
+            parse_source(&vars.concat())
+        };
+
+        let mut vm = VM::new();
+        let function = Compiler::compile(declare(300)).unwrap();
+        let closure = vm.alloc(GreenClosure::new(function));
+        vm.call_function(Value::Closure(closure), &[]).unwrap();
+        assert_eq!(vm.global("v299").unwrap().to_string(), "[]");
+
+        let options = CompileOptions {
+            disassemble: false,
+            ..CompileOptions::default()
+        };
+        let errors = Compiler::compile_with(declare(MAX_STRINGS + 1), options).unwrap_err();
+        assert_eq!(
+            errors,
+            vec![CompileError {
+                file: None,
+                line: MAX_STRINGS + 1,
+                message: "Too many names in one module, it can use at most 65536".to_string(),
+            }]
+        );
     }
 }
@@ -0,0 +1,358 @@
+use crate::compiler::object::Map;
+use crate::compiler::value::Value;
+use crate::vm::errors::RuntimeError;
+use crate::vm::vm::RunResult;
+use std::collections::HashMap;
+use std::convert::{Infallible, TryFrom};
+
+// Rust values into Green ones, for `bind_global` and the arguments of `call_function`. Green
+// numbers are all `f64`, so integers past 2^53 lose precision.
+
+impl From<f64> for Value {
+    fn from(n: f64) -> Self {
+        Value::Number(n)
+    }
+}
+
+impl From<i32> for Value {
+    fn from(n: i32) -> Self {
+        Value::Number(n as f64)
+    }
+}
+
+impl From<i64> for Value {
+    fn from(n: i64) -> Self {
+        Value::Number(n as f64)
+    }
+}
+
+impl From<u32> for Value {
+    fn from(n: u32) -> Self {
+        Value::Number(n as f64)
+    }
+}
+
+impl From<usize> for Value {
+    fn from(n: usize) -> Self {
+        Value::Number(n as f64)
+    }
+}
+
+impl From<&str> for Value {
+    fn from(s: &str) -> Self {
+        Value::string(s.to_string())
+    }
+}
+
+impl From<String> for Value {
+    fn from(s: String) -> Self {
+        Value::string(s)
+    }
+}
+
+impl From<()> for Value {
+    fn from(_: ()) -> Self {
+        Value::Nil
+    }
+}
+
+/// `None` becomes `nil`.
+impl<T: Into<Value>> From<Option<T>> for Value {
+    fn from(value: Option<T>) -> Self {
+        value.map_or(Value::Nil, Into::into)
+    }
+}
+
+impl<T: Into<Value>> From<Vec<T>> for Value {
+    fn from(items: Vec<T>) -> Self {
+        Value::array(items.into_iter().map(Into::into).collect())
+    }
+}
+
+/// A map with string keys.
+impl<T: Into<Value>> From<HashMap<String, T>> for Value {
+    fn from(entries: HashMap<String, T>) -> Self {
+        let mut map = Map::new();
+        for (key, value) in entries {
+            map.insert(Value::string(key), value.into())
+                .expect("strings to be hashable");
+        }
+        Value::map(map)
+    }
+}
+
+// Green values into Rust ones, for reading what `interpret` and `call_function` return. A value
+// of the wrong type fails with `RuntimeError::WrongType`.
+
+impl From<Infallible> for RuntimeError {
+    fn from(never: Infallible) -> Self {
+        match never {}
+    }
+}
+
+fn wrong_type(expected: &'static str, value: &Value) -> RuntimeError {
+    RuntimeError::WrongType(expected, value.type_name())
+}
+
+impl TryFrom<Value> for f64 {
+    type Error = RuntimeError;
+
+    fn try_from(value: Value) -> RunResult<Self> {
+        match value {
+            Value::Number(n) => Ok(n),
+            value => Err(wrong_type("Number", &value)),
+        }
+    }
+}
+
+/// Only whole numbers convert to integers.
+impl TryFrom<Value> for i64 {
+    type Error = RuntimeError;
+
+    fn try_from(value: Value) -> RunResult<Self> {
+        match value {
+            Value::Number(n) if n.fract() == 0.0 => Ok(n as i64),
+            value => Err(wrong_type("whole Number", &value)),
+        }
+    }
+}
+
+impl TryFrom<Value> for usize {
+    type Error = RuntimeError;
+
+    fn try_from(value: Value) -> RunResult<Self> {
+        match value {
+            Value::Number(n) if n.fract() == 0.0 && n >= 0.0 => Ok(n as usize),
+            value => Err(wrong_type("whole Number", &value)),
+        }
+    }
+}
+
+/// Only `true` and `false` convert, see `From<&Value> for bool` for truthiness instead.
+impl TryFrom<Value> for bool {
+    type Error = RuntimeError;
+
+    fn try_from(value: Value) -> RunResult<Self> {
+        match value {
+            Value::True => Ok(true),
+            Value::False => Ok(false),
+            value => Err(wrong_type("Bool", &value)),
+        }
+    }
+}
+
+impl TryFrom<Value> for String {
+    type Error = RuntimeError;
+
+    fn try_from(value: Value) -> RunResult<Self> {
+        match value {
+            Value::String(s) => Ok(s.to_string()),
+            value => Err(wrong_type("String", &value)),
+        }
+    }
+}
+
+/// Arrays and tuples convert, when each of their elements does.
+impl<T> TryFrom<Value> for Vec<T>
+where
+    T: TryFrom<Value>,
+    RuntimeError: From<T::Error>,
+{
+    type Error = RuntimeError;
+
+    fn try_from(value: Value) -> RunResult<Self> {
+        match value {
+            Value::Array(items) | Value::Tuple(items) => {
+                items.iter().map(|item| Ok(T::try_from(*item)?)).collect()
+            }
+            value => Err(wrong_type("Array", &value)),
+        }
+    }
+}
+
+/// Maps with string keys convert, and instances of classes and structs by their fields.
+impl<T> TryFrom<Value> for HashMap<String, T>
+where
+    T: TryFrom<Value>,
+    RuntimeError: From<T::Error>,
+{
+    type Error = RuntimeError;
+
+    fn try_from(value: Value) -> RunResult<Self> {
+        let entries: Vec<(String, Value)> = match value {
+            Value::Map(map) => map
+                .entries()
+                .iter()
+                .map(|(key, value)| Ok((String::try_from(*key)?, *value)))
+                .collect::<RunResult<_>>()?,
+            Value::Instance(instance) => instance
+                .fields()
+                .map(|(name, value)| (name.to_string(), value))
+                .collect(),
+            Value::StructInstance(instance) => instance
+                .def
+                .fields()
+                .iter()
+                .cloned()
+                .zip(instance.fields.iter().copied())
+                .collect(),
+            value => return Err(wrong_type("Map", &value)),
+        };
+
+        entries
+            .into_iter()
+            .map(|(name, value)| Ok((name, T::try_from(value)?)))
+            .collect()
+    }
+}
+
+/// Named values, to convert Rust structs to and from Green without deriving anything. A struct
+/// becomes a map of its fields:
+///
+/// ```
+/// # use green::compiler::convert::Fields;
+/// # use green::compiler::value::Value;
+/// # use std::convert::TryFrom;
+/// let point: Value = Fields::new().with("x", 3.0).with("y", 4.0).into();
+///
+/// let fields = Fields::try_from(point).unwrap();
+/// let x: f64 = fields.get("x").unwrap();
+/// assert_eq!(x, 3.0);
+/// ```
+///
+/// and any map with string keys or class or struct instance converts back.
+#[derive(Debug, Clone, Default)]
+pub struct Fields(HashMap<String, Value>);
+
+impl Fields {
+    pub fn new() -> Self {
+        Fields::default()
+    }
+
+    pub fn with(mut self, name: &str, value: impl Into<Value>) -> Self {
+        self.0.insert(name.to_string(), value.into());
+        self
+    }
+
+    /// The field called `name`, converted to `T`.
+    pub fn get<T>(&self, name: &str) -> RunResult<T>
+    where
+        T: TryFrom<Value>,
+        RuntimeError: From<T::Error>,
+    {
+        match self.0.get(name) {
+            Some(value) => Ok(T::try_from(*value)?),
+            None => Err(RuntimeError::UndefinedProperty(
+                name.to_string(),
+                "Fields".to_string(),
+            )),
+        }
+    }
+
+    /// The field called `name` converted to `T`, or `None` if there's no such field or it's
+    /// `nil`.
+    pub fn optional<T>(&self, name: &str) -> RunResult<Option<T>>
+    where
+        T: TryFrom<Value>,
+        RuntimeError: From<T::Error>,
+    {
+        match self.0.get(name) {
+            None | Some(Value::Nil) => Ok(None),
+            Some(value) => Ok(Some(T::try_from(*value)?)),
+        }
+    }
+}
+
+impl From<Fields> for Value {
+    fn from(fields: Fields) -> Self {
+        Value::from(fields.0)
+    }
+}
+
+impl TryFrom<Value> for Fields {
+    type Error = RuntimeError;
+
+    fn try_from(value: Value) -> RunResult<Self> {
+        Ok(Fields(HashMap::try_from(value)?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::VM;
+
+    struct Config {
+        name: String,
+        retries: i64,
+        tags: Vec<String>,
+        timeout: Option<f64>,
+    }
+
+    impl From<Config> for Value {
+        fn from(config: Config) -> Self {
+            Fields::new()
+                .with("name", config.name)
+                .with("retries", config.retries)
+                .with("tags", config.tags)
+                .with("timeout", config.timeout)
+                .into()
+        }
+    }
+
+    impl TryFrom<Value> for Config {
+        type Error = RuntimeError;
+
+        fn try_from(value: Value) -> RunResult<Self> {
+            let fields = Fields::try_from(value)?;
+            Ok(Config {
+                name: fields.get("name")?,
+                retries: fields.get("retries")?,
+                tags: fields.get("tags")?,
+                timeout: fields.optional("timeout")?,
+            })
+        }
+    }
+
+    #[test]
+    fn converts_values_both_ways() {
+        let config = Config {
+            name: "api".to_string(),
+            retries: 3,
+            tags: vec!["a".to_string(), "b".to_string()],
+            timeout: None,
+        };
+
+        let mut vm = VM::new();
+        vm.bind_global("config", config.into());
+        vm.bind_global("limit", Value::from(Some(2.5)));
+        vm.bind_global("missing", Value::from(None::<f64>));
+
+        let retries = vm.interpret(r#"config["retries"] * limit"#);
+        assert_eq!(f64::try_from(retries).unwrap(), 7.5);
+        assert_eq!(vm.global("missing"), Some(&Value::Nil));
+        let timeout = vm.interpret(r#"config["timeout"]"#);
+        assert_eq!(timeout, Value::Nil);
+
+        let script = r#"
+        struct Settings(name, retries, tags)
+        Settings("web", 5, ["x"])
+        "#;
+        let settings = Config::try_from(vm.interpret(script)).unwrap();
+        assert_eq!(settings.name, "web");
+        assert_eq!(settings.retries, 5);
+        assert_eq!(settings.tags, ["x"]);
+        assert_eq!(settings.timeout, None);
+
+        let sizes = Vec::<usize>::try_from(vm.interpret("[1, 2, 3]")).unwrap();
+        assert_eq!(sizes, [1, 2, 3]);
+
+        let err = i64::try_from(Value::from(1.5)).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Expected a value of type whole Number, not Number."
+        );
+        assert!(Vec::<Value>::try_from(Value::from("text")).is_err());
+        assert!(Fields::try_from(Value::from(1)).is_err());
+    }
+}
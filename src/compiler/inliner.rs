@@ -0,0 +1,278 @@
+use crate::compiler::compiler::Compiler;
+use crate::source_map::FileId;
+use crate::syntax::ast::{Ast, ExprId};
+use crate::syntax::expr::{CallExpr, ExprKind, FunctionExpr, Variable};
+use std::collections::HashSet;
+use std::mem;
+use std::rc::Rc;
+
+/// How many expressions the returned expression of a function may have for it to be inlined.
+const MAX_INLINED_SIZE: usize = 16;
+
+/// An imported function small enough to compile in place of calls to it: one that only returns
+/// an expression of operators, property reads and calls.
+pub(crate) struct InlineFunction {
+    parameters: Vec<Variable>,
+    /// The expressions of the module defining the function, which `body` is one of.
+    ast: Rc<Ast>,
+    body: ExprId,
+    /// The variables the body reads other than its parameters, all globals of the module.
+    globals: Vec<String>,
+    file: Option<FileId>,
+}
+
+impl InlineFunction {
+    fn new(ast: &Rc<Ast>, function: &FunctionExpr, file: Option<FileId>) -> Option<Self> {
+        let body = match function.declaration.body.exprs.as_slice() {
+            [expr] => match &ast[*expr] {
+                ExprKind::Return(r) => r.expr?,
+                _ => return None,
+            },
+            _ => return None,
+        };
+
+        let parameters = function.declaration.parameters.clone();
+        let mut names = vec![];
+        let mut size = 0;
+        if !inlinable(ast, body, &mut names, &mut size) || size > MAX_INLINED_SIZE {
+            return None;
+        }
+        names.retain(|name| parameters.iter().all(|parameter| parameter.name != *name));
+
+        Some(InlineFunction {
+            parameters,
+            ast: Rc::clone(ast),
+            body,
+            globals: names,
+            file,
+        })
+    }
+}
+
+/// Whether `expr` can be inlined, collecting the variables it reads and counting its size.
+fn inlinable(ast: &Ast, expr: ExprId, names: &mut Vec<String>, size: &mut usize) -> bool {
+    *size += 1;
+    match &ast[expr] {
+        ExprKind::Literal(_) => true,
+        ExprKind::VarGet(v) => {
+            names.push(v.variable.name.clone());
+            true
+        }
+        ExprKind::Binary(b) => {
+            inlinable(ast, b.lhs, names, size) && inlinable(ast, b.rhs, names, size)
+        }
+        ExprKind::Unary(u) => inlinable(ast, u.expr, names, size),
+        ExprKind::Grouping(g) => inlinable(ast, g.expr, names, size),
+        ExprKind::GetProperty(g) => inlinable(ast, g.expr, names, size),
+        ExprKind::Call(c) => {
+            inlinable(ast, c.callee, names, size)
+                && c.args.iter().all(|arg| inlinable(ast, *arg, names, size))
+        }
+        _ => false,
+    }
+}
+
+impl Compiler {
+    /// Remembers the small functions an imported module defines, to inline calls to them
+    /// compiled from now on. `exprs` are the module's, in the AST being compiled.
+    pub(crate) fn add_inline_functions(&mut self, exprs: &[ExprId], file: Option<FileId>) {
+        if !self.opt_level().inlines_functions() {
+            return;
+        }
+
+        let ast = Rc::clone(&self.ast);
+        assigned_globals(&ast, exprs, false, &mut self.assigned_globals);
+        for expr in exprs {
+            if let ExprKind::Function(function) = &ast[*expr] {
+                let name = &function.variable.name;
+                if self.assigned_globals.contains(name) {
+                    continue;
+                }
+                if let Some(inline) = InlineFunction::new(&ast, function, file) {
+                    self.inline_functions.insert(name.clone(), Rc::new(inline));
+                }
+            }
+        }
+    }
+
+    /// Compiles `call` as the body of the function it calls, if that's an inlinable one, with
+    /// its arguments in locals named after the parameters. Returns whether it did.
+    pub(crate) fn compile_inlined(&mut self, call: &CallExpr) -> bool {
+        let name = match &self.ast[call.callee] {
+            ExprKind::VarGet(v) => v.variable.name.clone(),
+            _ => return false,
+        };
+        let function = match self.inline_functions.get(&name) {
+            Some(function) => Rc::clone(function),
+            None => return false,
+        };
+        let shadowed = |compiler: &Compiler, name: &String| compiler.resolve_local(name) != -1;
+        if function.parameters.len() != call.args.len()
+            || self.inlining.contains(&name)
+            || shadowed(self, &name)
+            || function.globals.iter().any(|global| shadowed(self, global))
+        {
+            return false;
+        }
+
+        // The arguments are evaluated in order before any parameter is in scope, as they
+        // would be for a call.
+        self.begin_scope();
+        for arg in &call.args {
+            self.compile_expr(*arg);
+            self.push_temporary();
+        }
+        self.pop_temporaries(call.args.len());
+        for parameter in &function.parameters {
+            self.compile_declare_var(parameter);
+        }
+
+        self.inlining.push(name);
+        let importer = mem::replace(&mut self.file, function.file);
+        self.in_ast(Rc::clone(&function.ast), |compiler| {
+            compiler.compile_expr(function.body)
+        });
+        self.file = importer;
+        self.inlining.pop();
+
+        self.end_scope_with_value();
+        true
+    }
+}
+
+/// Adds the names of the globals `exprs` assign to `names`, and with `declarations` set, the
+/// names they declare at the top level too.
+pub(crate) fn assigned_globals(
+    ast: &Ast,
+    exprs: &[ExprId],
+    declarations: bool,
+    names: &mut HashSet<String>,
+) {
+    for expr in exprs {
+        if declarations {
+            match &ast[*expr] {
+                ExprKind::VarAssign(v) => names.insert(v.variable.name.clone()),
+                ExprKind::Function(f) => names.insert(f.variable.name.clone()),
+                ExprKind::Class(c) => names.insert(c.name.name.clone()),
+                ExprKind::Struct(s) => names.insert(s.name.name.clone()),
+                ExprKind::Protocol(p) => names.insert(p.name.name.clone()),
+                ExprKind::VarUnpack(v) => {
+                    names.extend(v.variables.iter().map(|v| v.name.clone()));
+                    true
+                }
+                ExprKind::Destructure(d) => {
+                    names.extend(d.pattern.variables().iter().map(|v| v.name.clone()));
+                    true
+                }
+                _ => false,
+            };
+        }
+        assignments(ast, *expr, names);
+    }
+}
+
+/// Adds the names of the variables `expr` assigns to, wherever it does.
+fn assignments(ast: &Ast, expr: ExprId, names: &mut HashSet<String>) {
+    if let ExprKind::VarSet(v) = &ast[expr] {
+        names.insert(v.variable.name.clone());
+    }
+    ast[expr].for_each_child(&mut |child| assignments(ast, child, names));
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::compiler::compiler::Compiler;
+    use crate::compiler::module_resolver::WORKING_DIR;
+    use crate::compiler::opcode::Opcode;
+    use crate::compiler::options::{CompileOptions, OptLevel};
+    use crate::source_map::SourceMap;
+    use crate::syntax::parser::GreenParser;
+    use crate::vm::{VmOptions, VM};
+    use std::env;
+    use std::fs;
+
+    const LIBRARY: &str = r#"
+var factor = 10
+
+def square(x) do return x * x end
+def lerp(a, b, t) do return a + (b - a) * t end
+def scaled(x) do return square(x) * factor end
+def countdown(n) do return countdown(n - 1) end
+def noisy(x)
+    print x
+    return x
+end
+"#;
+
+    const SOURCE: &str = r#"
+import inline_util
+
+def shadowing()
+    var factor = 1
+    return scaled(2)
+end
+
+var a = 1
+print square(a + 2)
+print lerp(a, 3, 0.5)
+print scaled(3)
+print shadowing()
+print noisy(4)
+"#;
+
+    fn calls(options: CompileOptions) -> usize {
+        let module = GreenParser::parse(SOURCE).unwrap();
+        let function =
+            Compiler::compile_sources(module, None, options, &mut SourceMap::new()).unwrap();
+        let code = function.chunk().code();
+
+        let (mut calls, mut offset) = (0, 0);
+        while offset < code.len() {
+            let opcode = Opcode::from(code[offset]);
+            if opcode == Opcode::Call {
+                calls += 1;
+            }
+            offset += 1 + opcode.operand_len();
+        }
+        calls
+    }
+
+    #[test]
+    fn inlines_small_imported_functions() {
+        // Imports are read from `lib/` in the working directory.
+        let _cwd = WORKING_DIR.lock().unwrap_or_else(|err| err.into_inner());
+        let dir = env::temp_dir().join(format!("green-inline-{}", std::process::id()));
+        fs::create_dir_all(dir.join("lib")).unwrap();
+        fs::write(dir.join("lib/inline_util.green"), LIBRARY).unwrap();
+        let cwd = env::current_dir().unwrap();
+        env::set_current_dir(&dir).unwrap();
+
+        let run = |opt_level| {
+            let compile = CompileOptions {
+                opt_level,
+                warnings: false,
+                disassemble: false,
+                ..CompileOptions::default()
+            };
+            let options = VmOptions {
+                compile,
+                ..VmOptions::default()
+            };
+            let output = VM::with_options(options).capture_output(|vm| {
+                vm.interpret(SOURCE);
+            });
+            (calls(compile), output.1)
+        };
+        let (inlined, optimized) = run(OptLevel::O2);
+        let (called, unoptimized) = run(OptLevel::O1);
+
+        env::set_current_dir(cwd).unwrap();
+        fs::remove_dir_all(dir).unwrap();
+
+        assert_eq!(optimized, "9\n2\n90\n40\n4\n4\n");
+        assert_eq!(optimized, unoptimized);
+        // `shadowing` is compiled before the import, and `noisy` is too big to inline.
+        assert_eq!(called, 5);
+        assert_eq!(inlined, 2);
+    }
+}
@@ -1,13 +1,14 @@
 use crate::compiler::local::Local;
 use crate::compiler::object::{GreenFunction, GreenFunctionType};
+use std::mem;
 
-#[derive(Debug, Clone)]
+#[derive(Debug)]
 pub struct CompilerInstance {
     function: GreenFunction,
     function_type: GreenFunctionType,
     locals: Vec<Local>,
     scope_depth: isize,
-    enclosing: Box<Option<CompilerInstance>>,
+    temporaries: usize,
 }
 
 impl CompilerInstance {
@@ -17,9 +18,9 @@ impl CompilerInstance {
             function_type,
             locals: Vec::with_capacity(u8::MAX as usize),
             scope_depth: 0,
-            enclosing: Box::new(None),
+            temporaries: 0,
         };
-        compiler.locals.push(Local::new("".to_string(), 0));
+        compiler.locals.push(Local::new("".to_string(), 0, 0));
 
         compiler
     }
@@ -52,11 +53,17 @@ impl CompilerInstance {
         &mut self.scope_depth
     }
 
-    pub fn enclosing(&self) -> &Box<Option<CompilerInstance>> {
-        &self.enclosing
+    /// Number of values expressions being compiled keep on the stack above the locals.
+    pub fn temporaries(&self) -> &usize {
+        &self.temporaries
     }
 
-    pub fn enclosing_mut(&mut self) -> &mut Box<Option<CompilerInstance>> {
-        &mut self.enclosing
+    pub fn temporaries_mut(&mut self) -> &mut usize {
+        &mut self.temporaries
+    }
+
+    /// Takes the finished function out, leaving an empty one.
+    pub fn finish(&mut self) -> GreenFunction {
+        mem::take(&mut self.function)
     }
 }
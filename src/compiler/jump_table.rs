@@ -0,0 +1,108 @@
+use crate::compiler::value::Value;
+use std::collections::BTreeMap;
+use std::fmt;
+
+/// Where a `match` on constant cases jumps for each subject, so finding the matching arm takes
+/// one lookup instead of comparing against every case in turn. Targets are chunk offsets.
+#[derive(Debug, Clone)]
+pub struct JumpTable {
+    cases: Cases,
+    /// Where numbers, strings and other plain values matching no case go.
+    default: usize,
+    /// Where any other value goes. It might define an `eq` method, which only comparing
+    /// against each case calls.
+    fallback: usize,
+}
+
+#[derive(Debug, Clone)]
+pub enum Cases {
+    /// Integer cases `min`, `min + 1`, ..., indexed by the subject minus `min`.
+    Dense { min: i64, targets: Vec<usize> },
+    /// Kept sorted, so compiling the same `match` always gives the same table.
+    Strings(BTreeMap<String, usize>),
+}
+
+impl Cases {
+    pub fn map_targets(self, f: impl Fn(usize) -> usize) -> Cases {
+        match self {
+            Cases::Dense { min, targets } => Cases::Dense {
+                min,
+                targets: targets.into_iter().map(f).collect(),
+            },
+            Cases::Strings(targets) => Cases::Strings(
+                targets
+                    .into_iter()
+                    .map(|(key, target)| (key, f(target)))
+                    .collect(),
+            ),
+        }
+    }
+}
+
+impl JumpTable {
+    pub fn new(cases: Cases, default: usize, fallback: usize) -> Self {
+        JumpTable {
+            cases,
+            default,
+            fallback,
+        }
+    }
+
+    pub fn cases(&self) -> &Cases {
+        &self.cases
+    }
+
+    pub fn default_target(&self) -> usize {
+        self.default
+    }
+
+    pub fn fallback_target(&self) -> usize {
+        self.fallback
+    }
+
+    pub fn target(&self, subject: &Value) -> usize {
+        match (&self.cases, subject) {
+            (Cases::Dense { min, targets }, Value::Number(n)) if n.fract() == 0.0 => {
+                let index = n - *min as f64;
+                if index >= 0.0 && index < targets.len() as f64 {
+                    targets[index as usize]
+                } else {
+                    self.default
+                }
+            }
+            (Cases::Strings(targets), Value::String(s)) => {
+                targets.get(s.as_str()).copied().unwrap_or(self.default)
+            }
+            (_, Value::Number(_) | Value::String(_) | Value::True | Value::False | Value::Nil) => {
+                self.default
+            }
+            _ => self.fallback,
+        }
+    }
+}
+
+/// Shows the targets in hex like the disassembler does: `{1: 1A, 2: 22} else 2A other 30`.
+impl fmt::Display for JumpTable {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{{")?;
+        match &self.cases {
+            Cases::Dense { min, targets } => {
+                for (i, target) in targets.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}: {:X}", min + i as i64, target)?;
+                }
+            }
+            Cases::Strings(targets) => {
+                for (i, (key, target)) in targets.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{:?}: {:X}", key, target)?;
+                }
+            }
+        }
+        write!(f, "}} else {:X} other {:X}", self.default, self.fallback)
+    }
+}
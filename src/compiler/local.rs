@@ -2,11 +2,12 @@
 pub struct Local {
     name: String,
     depth: isize,
+    slot: usize,
 }
 
 impl Local {
-    pub fn new(name: String, depth: isize) -> Self {
-        Local { name, depth }
+    pub fn new(name: String, depth: isize, slot: usize) -> Self {
+        Local { name, depth, slot }
     }
 
     pub fn name(&self) -> &String {
@@ -20,4 +21,9 @@ impl Local {
     pub fn depth_mut(&mut self) -> &mut isize {
         &mut self.depth
     }
+
+    /// Stack slot of the local, relative to the start of its call frame.
+    pub fn slot(&self) -> usize {
+        self.slot
+    }
 }
@@ -1,8 +1,15 @@
+pub mod cache;
 pub mod chunk;
 pub mod compiler;
+pub mod convert;
+pub(crate) mod inliner;
 pub(crate) mod instance;
-mod local;
+pub mod jump_table;
+pub(crate) mod local;
 pub(crate) mod module_resolver;
 pub mod object;
 pub mod opcode;
+pub mod optimizer;
+pub mod options;
+pub mod strings;
 pub mod value;
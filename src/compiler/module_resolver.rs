@@ -1,21 +1,87 @@
+use crate::error::ParserError;
+use crate::source_map::{read_source_lossy, FileId, ReadError, SourceMap};
 use crate::syntax::parser::{GreenParser, ModuleAst};
 use std::env::current_dir;
-use std::path::Path;
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
 
 #[derive(Debug)]
 pub enum ImportModuleError {
-    FailedImport, // TODO
+    Read(PathBuf, ReadError),
+    /// The module was read into the source map, but doesn't parse.
+    Parse(FileId, ParserError),
 }
 
-pub fn get_module_ast(module: &String) -> Result<ModuleAst, ImportModuleError> {
+impl ImportModuleError {
+    /// The error, followed by an excerpt of the line it is on if there is one.
+    pub fn describe(&self, sources: &SourceMap) -> String {
+        match self {
+            ImportModuleError::Parse(file, err) => match err.line() {
+                Some(line) => format!("{}\n{}", self, sources.excerpt(*file, line)),
+                None => self.to_string(),
+            },
+            _ => self.to_string(),
+        }
+    }
+
+    /// Whether there's no file for the module.
+    pub fn is_not_found(&self) -> bool {
+        matches!(
+            self,
+            ImportModuleError::Read(_, ReadError::Io(err)) if err.kind() == io::ErrorKind::NotFound
+        )
+    }
+}
+
+impl fmt::Display for ImportModuleError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ImportModuleError::Read(path, err) => {
+                write!(f, "Could not read {}: {}", path.display(), err)
+            }
+            ImportModuleError::Parse(_, err) => write!(f, "{}", err),
+        }
+    }
+}
+
+/// A module loaded into the source map and parsed.
+pub struct LoadedModule {
+    pub file: FileId,
+    pub ast: ModuleAst,
+    /// The line of the first byte that wasn't valid UTF-8, if the module had any and they were
+    /// replaced.
+    pub replaced: Option<usize>,
+}
+
+/// Loads a module into the source map and parses it. Bytes that aren't valid UTF-8 are
+/// replaced if `lossy`, and fail the import otherwise.
+pub fn get_module_ast(
+    module: &str,
+    sources: &mut SourceMap,
+    lossy: bool,
+) -> Result<LoadedModule, ImportModuleError> {
     let module_path = resolve_module_path(module);
-    let body = get_file_contents(module_path.to_str().unwrap()).unwrap();
-    let module_ast = GreenParser::parse(&body).unwrap();
-    Ok(module_ast)
+    let (body, replaced) = read_source_lossy(&module_path, lossy)
+        .map_err(|err| ImportModuleError::Read(module_path.clone(), err))?;
+
+    let file = sources.add(module_path.display().to_string(), body);
+    let ast = GreenParser::parse(sources.get(file).text())
+        .map_err(|err| ImportModuleError::Parse(file, err))?;
+    Ok(LoadedModule {
+        file,
+        ast,
+        replaced,
+    })
 }
 
-fn resolve_module_path(module: &String) -> Box<Path> {
-    let mut path = current_dir().unwrap();
+/// Held by tests that change the working directory, or that import modules from `lib/` in it,
+/// so they don't run at the same time.
+#[cfg(test)]
+pub(crate) static WORKING_DIR: std::sync::Mutex<()> = std::sync::Mutex::new(());
+
+fn resolve_module_path(module: &str) -> PathBuf {
+    let mut path = current_dir().unwrap_or_default();
     path.push(Path::new("lib"));
     for dir in module.split('.') {
         path.push(Path::new(dir))
@@ -23,9 +89,5 @@ fn resolve_module_path(module: &String) -> Box<Path> {
 
     path.set_extension(Path::new("green"));
 
-    path.into_boxed_path()
-}
-
-fn get_file_contents(path: &str) -> std::io::Result<String> {
-    std::fs::read_to_string(path)
+    path
 }
@@ -1,9 +1,18 @@
 use crate::compiler::chunk::Chunk;
 use crate::compiler::value::Value;
+use crate::vm::errors::RuntimeError;
+use crate::vm::obj::Gc;
+use crate::vm::vm::RunResult;
+use crate::vm::VM;
+use std::any::Any;
+use std::collections::hash_map::DefaultHasher;
 use std::collections::HashMap;
 use std::fmt;
 use std::fmt::{Display, Formatter};
-use crate::vm::obj::Gc;
+use std::hash::{Hash, Hasher};
+use std::mem;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicU64, Ordering};
 
 #[derive(Debug, Clone)]
 pub enum Object {
@@ -32,6 +41,8 @@ impl Display for Object {
 pub enum GreenFunctionType {
     Closure,
     Function,
+    Method,
+    Initializer,
     Script,
 }
 
@@ -46,7 +57,7 @@ impl GreenClosure {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
 pub struct GreenFunction {
     name: String,
     chunk: Chunk,
@@ -70,6 +81,10 @@ impl GreenFunction {
         &self.arity
     }
 
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
     pub fn name_mut(&mut self) -> &mut String {
         &mut self.name
     }
@@ -85,30 +100,393 @@ impl GreenFunction {
 
 impl fmt::Display for GreenFunction {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "#<fun {}/{}>", self.name, self.arity)
+        if self.name.is_empty() {
+            write!(f, "<script>")
+        } else {
+            write!(f, "<fn {}/{}>", self.name, self.arity)
+        }
+    }
+}
+
+/// Signature of a function implemented in Rust and callable from Green code.
+pub type NativeFn = fn(&mut VM, &[Value]) -> RunResult<Value>;
+/// A native that may close over state, like a method generated by `VM::build_foreign`.
+type NativeClosure = Rc<dyn Fn(&mut VM, &[Value]) -> RunResult<Value>>;
+
+#[derive(Clone)]
+pub struct NativeFunction {
+    name: String,
+    arity: u8,
+    fun: NativeClosure,
+    /// The value a method of a foreign type was looked up on, passed before the arguments.
+    receiver: Option<Value>,
+}
+
+impl NativeFunction {
+    pub fn new(name: String, arity: u8, fun: NativeFn) -> Self {
+        NativeFunction::closure(name, arity, fun)
+    }
+
+    /// A native implemented by a closure, for natives that need state of their own.
+    pub fn closure(
+        name: String,
+        arity: u8,
+        fun: impl Fn(&mut VM, &[Value]) -> RunResult<Value> + 'static,
+    ) -> Self {
+        NativeFunction {
+            name,
+            arity,
+            fun: Rc::new(fun),
+            receiver: None,
+        }
+    }
+
+    /// The function as a method of `receiver`, which it gets as its first argument.
+    pub fn bind(&self, receiver: Value) -> Self {
+        NativeFunction {
+            receiver: Some(receiver),
+            ..self.clone()
+        }
+    }
+
+    pub fn receiver(&self) -> Option<Value> {
+        self.receiver
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn arity(&self) -> &u8 {
+        &self.arity
+    }
+
+    pub fn call(&self, vm: &mut VM, args: &[Value]) -> RunResult<Value> {
+        (self.fun)(vm, args)
+    }
+}
+
+impl fmt::Display for NativeFunction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "<native {}/{}>", self.name, self.arity)
+    }
+}
+
+/// The numbers from `start` up to, but not including, `end`, counting by `step`, written
+/// `start to end step n`. A negative step counts down, as in `start downTo end`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Range {
+    pub start: f64,
+    pub end: f64,
+    pub step: f64,
+}
+
+impl Range {
+    pub fn new(start: f64, end: f64, step: f64) -> RunResult<Self> {
+        if step == 0.0 {
+            return Err(RuntimeError::ZeroRangeStep);
+        }
+        Ok(Range { start, end, step })
+    }
+
+    /// How many numbers the range counts.
+    pub fn len(&self) -> usize {
+        let count = ((self.end - self.start) / self.step).ceil();
+        if count > 0.0 {
+            count as usize
+        } else {
+            0
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The number at `index`, if the range gets that far.
+    pub fn get(&self, index: usize) -> Option<f64> {
+        (index < self.len()).then_some(self.start + index as f64 * self.step)
+    }
+
+    pub fn numbers(&self) -> impl Iterator<Item = f64> {
+        let Range { start, step, .. } = *self;
+        (0..self.len()).map(move |i| start + i as f64 * step)
+    }
+}
+
+impl Display for Range {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        if self.step < 0.0 {
+            write!(f, "{} downTo {}", self.start, self.end)?;
+        } else {
+            write!(f, "{} to {}", self.start, self.end)?;
+        }
+        if self.step.abs() != 1.0 {
+            write!(f, " step {}", self.step.abs())?;
+        }
+        Ok(())
+    }
+}
+
+/// A loop's place in a builtin collection, made by `iter`. Arrays, maps and strings are copied
+/// when the loop starts, so storing into them doesn't change what it goes on to see. Ranges are
+/// counted as the loop goes.
+pub struct Iter {
+    items: IterItems,
+    position: usize,
+}
+
+enum IterItems {
+    Values(Vec<Value>),
+    Range(Range),
+}
+
+impl Iter {
+    pub fn values(values: Vec<Value>) -> Self {
+        Iter {
+            items: IterItems::Values(values),
+            position: 0,
+        }
+    }
+
+    pub fn range(range: Range) -> Self {
+        Iter {
+            items: IterItems::Range(range),
+            position: 0,
+        }
+    }
+
+    pub fn has_next(&self) -> bool {
+        match &self.items {
+            IterItems::Values(values) => self.position < values.len(),
+            IterItems::Range(range) => self.position < range.len(),
+        }
+    }
+
+    /// The values still to come, which the garbage collector must keep alive.
+    pub fn pending(&self) -> &[Value] {
+        match &self.items {
+            IterItems::Values(values) => &values[self.position.min(values.len())..],
+            IterItems::Range(_) => &[],
+        }
+    }
+}
+
+impl Iterator for Iter {
+    type Item = Value;
+
+    fn next(&mut self) -> Option<Value> {
+        let value = match &self.items {
+            IterItems::Values(values) => values.get(self.position).copied(),
+            IterItems::Range(range) => range.get(self.position).map(Value::Number),
+        }?;
+        self.position += 1;
+        Some(value)
+    }
+}
+
+/// Reads a property from the data of a foreign value.
+pub type ForeignGetter = Box<dyn Fn(&dyn Any) -> RunResult<Value>>;
+/// Stores a property in the data of a foreign value.
+pub type ForeignSetter = Box<dyn Fn(&mut dyn Any, Value) -> RunResult<()>>;
+
+/// The name, properties and methods of a Rust type registered with `VM::register_foreign` or
+/// `VM::build_foreign`.
+pub struct ForeignType {
+    name: String,
+    methods: HashMap<String, NativeFunction>,
+    getters: HashMap<String, ForeignGetter>,
+    setters: HashMap<String, ForeignSetter>,
+}
+
+impl ForeignType {
+    pub fn new(name: String) -> Self {
+        ForeignType {
+            name,
+            methods: HashMap::new(),
+            getters: HashMap::new(),
+            setters: HashMap::new(),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn method(&self, name: &str) -> Option<&NativeFunction> {
+        self.methods.get(name)
+    }
+
+    pub fn getter(&self, name: &str) -> Option<&ForeignGetter> {
+        self.getters.get(name)
+    }
+
+    pub fn setter(&self, name: &str) -> Option<&ForeignSetter> {
+        self.setters.get(name)
+    }
+
+    pub fn add_method(&mut self, method: NativeFunction) {
+        self.methods.insert(method.name().to_string(), method);
+    }
+
+    pub fn add_getter(&mut self, name: &str, getter: ForeignGetter) {
+        self.getters.insert(name.to_string(), getter);
+    }
+
+    pub fn add_setter(&mut self, name: &str, setter: ForeignSetter) {
+        self.setters.insert(name.to_string(), setter);
+    }
+}
+
+/// A value owned by the application embedding Green, like a database handle. Scripts can't see
+/// inside it, only pass it around and call the methods its type registered.
+pub struct Foreign {
+    kind: Rc<ForeignType>,
+    data: Box<dyn Any>,
+}
+
+impl Foreign {
+    pub fn new(kind: Rc<ForeignType>, data: Box<dyn Any>) -> Self {
+        Foreign { kind, data }
+    }
+
+    pub fn kind(&self) -> &ForeignType {
+        &self.kind
+    }
+
+    /// The data, if it is a `T`.
+    pub fn get<T: Any>(&self) -> Option<&T> {
+        self.data.downcast_ref()
+    }
+
+    pub fn get_mut<T: Any>(&mut self) -> Option<&mut T> {
+        self.data.downcast_mut()
+    }
+
+    /// Reads a property through its type's getter, if it has one.
+    pub fn property(&self, name: &str) -> Option<RunResult<Value>> {
+        let get = self.kind.getter(name)?;
+        Some(get(&*self.data))
+    }
+
+    pub fn set_property(&mut self, name: &str, value: Value) -> RunResult<()> {
+        let set = self.kind.setter(name).ok_or_else(|| {
+            RuntimeError::UndefinedProperty(name.to_string(), self.kind.name().to_string())
+        })?;
+        set(&mut *self.data, value)
     }
 }
 
-impl Into<Object> for &str {
-    fn into(self) -> Object {
-        Object::String(self.to_string()) // TODO Object(String) should be Object(&str)
+impl From<&str> for Object {
+    fn from(val: &str) -> Self {
+        Object::String(val.to_string()) // TODO Object(String) should be Object(&str)
     }
 }
 
-impl Into<Object> for String {
-    fn into(self) -> Object {
-        Object::String(self)
+impl From<String> for Object {
+    fn from(val: String) -> Self {
+        Object::String(val)
     }
 }
 
+/// Source of class ids. 0 is never handed out, so property caches can use it for empty.
+static NEXT_CLASS_ID: AtomicU64 = AtomicU64::new(1);
+
 #[derive(Debug, Clone)]
 pub struct Class {
+    id: u64,
     name: String,
+    /// Fields declared in the class body. Empty if the class doesn't declare any, in which case
+    /// its instances can have any fields.
+    fields: Vec<String>,
+    /// The slot of each field any instance has ever been given. Slots are only ever added, so
+    /// a slot looked up once stays valid for every instance of the class.
+    slots: HashMap<String, usize>,
+    methods: HashMap<String, Gc<GreenClosure>>,
+    /// The `static var`s and `static def`s, looked up on the class itself.
+    statics: HashMap<String, Value>,
 }
 
 impl Class {
     pub fn new(name: String) -> Self {
-        Class { name }
+        Class {
+            id: NEXT_CLASS_ID.fetch_add(1, Ordering::Relaxed),
+            name,
+            fields: vec![],
+            slots: HashMap::new(),
+            methods: HashMap::new(),
+            statics: HashMap::new(),
+        }
+    }
+
+    /// Identifies the class, and so the layout of its instances, for property caches.
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn method(&self, name: &str) -> Option<Gc<GreenClosure>> {
+        self.methods.get(name).copied()
+    }
+
+    pub fn add_method(&mut self, name: String, method: Gc<GreenClosure>) {
+        self.methods.insert(name, method);
+    }
+
+    pub fn methods(&self) -> impl Iterator<Item = Gc<GreenClosure>> + '_ {
+        self.methods.values().copied()
+    }
+
+    pub fn static_member(&self, name: &str) -> Option<Value> {
+        self.statics.get(name).copied()
+    }
+
+    pub fn set_static(&mut self, name: String, value: Value) {
+        self.statics.insert(name, value);
+    }
+
+    pub fn statics(&self) -> impl Iterator<Item = Value> + '_ {
+        self.statics.values().copied()
+    }
+
+    pub fn fields(&self) -> &[String] {
+        &self.fields
+    }
+
+    pub fn has_fields(&self) -> bool {
+        !self.fields.is_empty()
+    }
+
+    pub fn has_field(&self, name: &str) -> bool {
+        self.fields.iter().any(|field| field == name)
+    }
+
+    pub fn declare_field(&mut self, name: String) {
+        self.add_slot(&name);
+        self.fields.push(name);
+    }
+
+    /// The slot instances keep the field `name` in, if any instance has one.
+    pub fn slot(&self, name: &str) -> Option<usize> {
+        self.slots.get(name).copied()
+    }
+
+    /// The slot for the field `name`, adding one if it doesn't have one yet.
+    pub fn add_slot(&mut self, name: &str) -> usize {
+        let next = self.slots.len();
+        *self.slots.entry(name.to_string()).or_insert(next)
+    }
+
+    /// The field in each slot, in slot order.
+    pub fn slot_names(&self) -> Vec<&str> {
+        let mut names = vec![""; self.slots.len()];
+        for (name, slot) in &self.slots {
+            names[*slot] = name;
+        }
+        names
     }
 }
 
@@ -118,29 +496,270 @@ impl fmt::Display for Class {
     }
 }
 
+/// A namespace of values, such as the native functions of a standard library module.
+#[derive(Debug, Clone)]
+pub struct Module {
+    name: String,
+    members: HashMap<String, Value>,
+}
+
+impl Module {
+    pub fn new(name: String) -> Self {
+        Module {
+            name,
+            members: HashMap::new(),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn get(&self, name: &str) -> Option<Value> {
+        self.members.get(name).cloned()
+    }
+
+    pub fn insert(&mut self, name: String, value: Value) {
+        self.members.insert(name, value);
+    }
+
+    pub fn members(&self) -> impl Iterator<Item = &Value> {
+        self.members.values()
+    }
+}
+
+impl fmt::Display for Module {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "<module {}>", self.name)
+    }
+}
+
+/// A plain data type declared with `struct Name(field, ...)`. Its fields live in fixed slots.
+#[derive(Debug, Clone)]
+pub struct Struct {
+    name: String,
+    fields: Vec<String>,
+}
+
+impl Struct {
+    pub fn new(name: String, fields: Vec<String>) -> Self {
+        Struct { name, fields }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn fields(&self) -> &Vec<String> {
+        &self.fields
+    }
+
+    pub fn field_slot(&self, name: &str) -> Option<usize> {
+        self.fields.iter().position(|field| field == name)
+    }
+}
+
+impl fmt::Display for Struct {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}({})", self.name, self.fields.join(", "))
+    }
+}
+
+/// A set of methods declared with `protocol Name ... end`. An instance is the protocol, for `is`,
+/// when its class has methods of those names taking as many arguments, whether or not the
+/// class declares that it `implements` the protocol.
+#[derive(Debug, Clone)]
+pub struct Protocol {
+    name: String,
+    methods: Vec<(String, u8)>,
+}
+
+impl Protocol {
+    pub fn new(name: String, methods: Vec<(String, u8)>) -> Self {
+        Protocol { name, methods }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The names of the methods and how many arguments each takes.
+    pub fn methods(&self) -> &[(String, u8)] {
+        &self.methods
+    }
+
+    /// The first of the protocol's methods that a class lacks, given the arity of the class's
+    /// method of each name, if it has one.
+    pub fn missing_method(&self, arity: impl Fn(&str) -> Option<u8>) -> Option<&str> {
+        self.methods
+            .iter()
+            .find(|(name, expected)| arity(name) != Some(*expected))
+            .map(|(name, _)| name.as_str())
+    }
+}
+
+impl fmt::Display for Protocol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let methods: Vec<&str> = self.methods.iter().map(|(name, _)| name.as_str()).collect();
+        write!(f, "{}({})", self.name, methods.join(", "))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct StructInstance {
+    pub def: Gc<Struct>,
+    pub fields: Vec<Value>,
+}
+
+impl StructInstance {
+    pub fn new(def: Gc<Struct>, fields: Vec<Value>) -> Self {
+        StructInstance { def, fields }
+    }
+}
+
+/// A method together with the instance it was accessed on.
+#[derive(Debug, Clone)]
+pub struct BoundMethod {
+    pub receiver: Value,
+    pub method: Gc<GreenClosure>,
+}
+
+impl BoundMethod {
+    pub fn new(receiver: Value, method: Gc<GreenClosure>) -> Self {
+        BoundMethod { receiver, method }
+    }
+}
+
+/// An instance of a class. Its fields live in the slots its class assigns them, `None` for a
+/// field some other instance has but this one doesn't.
 #[derive(Debug, Clone)]
 pub struct Instance {
     pub class: Gc<Class>,
-    pub fields: HashMap<String, Value>,
+    slots: Vec<Option<Value>>,
 }
 
 impl Instance {
     pub fn new(class: Gc<Class>) -> Self {
         Instance {
             class,
-            fields: HashMap::new(),
+            slots: Vec::with_capacity(class.fields().len()),
         }
     }
 
     pub fn get_property(&self, name: &str) -> Option<Value> {
-        self.fields.get(name).cloned()
+        self.get_slot(self.class.slot(name)?)
     }
 
     pub fn set_property(&mut self, property: &str, value: Value) {
-        if let Some(v) = self.fields.get_mut(property) {
-            *v = value;
-        } else {
-            self.fields.insert(property.to_string(), value);
+        let slot = self.class.add_slot(property);
+        self.set_slot(slot, value);
+    }
+
+    pub fn get_slot(&self, slot: usize) -> Option<Value> {
+        self.slots.get(slot).copied().flatten()
+    }
+
+    pub fn set_slot(&mut self, slot: usize, value: Value) {
+        if slot >= self.slots.len() {
+            self.slots.resize(slot + 1, None);
         }
+        self.slots[slot] = Some(value);
+    }
+
+    /// The fields the instance has, in the order its class first saw them.
+    pub fn fields(&self) -> impl Iterator<Item = (&str, Value)> + '_ {
+        self.class
+            .slot_names()
+            .into_iter()
+            .zip(&self.slots)
+            .filter_map(|(name, value)| Some((name, (*value)?)))
+    }
+
+    /// The values of the fields the instance has.
+    pub fn values(&self) -> impl Iterator<Item = Value> + '_ {
+        self.slots.iter().flatten().copied()
+    }
+}
+
+/// Key of a map entry. Only values compared by their contents can be keys.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum MapKey {
+    Number(u64),
+    Bool(bool),
+    Nil,
+    String(String),
+}
+
+impl MapKey {
+    fn from_value(value: &Value) -> RunResult<MapKey> {
+        match value {
+            // Normalize so that 0 and -0 are the same key.
+            Value::Number(n) if *n == 0.0 => Ok(MapKey::Number(0.0_f64.to_bits())),
+            Value::Number(n) => Ok(MapKey::Number(n.to_bits())),
+            Value::True => Ok(MapKey::Bool(true)),
+            Value::False => Ok(MapKey::Bool(false)),
+            Value::Nil => Ok(MapKey::Nil),
+            Value::String(s) => Ok(MapKey::String(s.to_string())),
+            value => Err(RuntimeError::UnhashableKey(value.type_name())),
+        }
+    }
+}
+
+/// A map from numbers, bools, nil or strings to values. Entries keep their insertion order.
+#[derive(Debug, Clone, Default)]
+pub struct Map {
+    indices: HashMap<MapKey, usize>,
+    entries: Vec<(Value, Value)>,
+}
+
+impl Map {
+    pub fn new() -> Self {
+        Map {
+            indices: HashMap::new(),
+            entries: vec![],
+        }
+    }
+
+    pub fn get(&self, key: &Value) -> RunResult<Option<Value>> {
+        let index = self.indices.get(&MapKey::from_value(key)?);
+        Ok(index.map(|index| self.entries[*index].1))
+    }
+
+    pub fn insert(&mut self, key: Value, value: Value) -> RunResult<()> {
+        match self.indices.get(&MapKey::from_value(&key)?) {
+            Some(index) => self.entries[*index].1 = value,
+            None => {
+                self.indices
+                    .insert(MapKey::from_value(&key)?, self.entries.len());
+                self.entries.push((key, value));
+            }
+        }
+        Ok(())
+    }
+
+    /// A hash of a key that stays the same from run to run. Keys that are equal as map keys hash
+    /// the same.
+    pub fn hash_key(key: &Value) -> RunResult<u64> {
+        let mut hasher = DefaultHasher::new();
+        MapKey::from_value(key)?.hash(&mut hasher);
+        Ok(hasher.finish())
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn entries(&self) -> &Vec<(Value, Value)> {
+        &self.entries
+    }
+
+    /// Bytes taken by the map's entries and index, which the VM counts toward its heap.
+    pub(crate) fn buffer_bytes(&self) -> usize {
+        self.entries.capacity() * mem::size_of::<(Value, Value)>()
+            + self.indices.capacity() * mem::size_of::<(MapKey, usize)>()
     }
 }
@@ -1,5 +1,6 @@
 use crate::syntax::expr::UnaryOperator;
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u8)]
 pub enum Opcode {
     Return,
@@ -35,6 +36,35 @@ pub enum Opcode {
     Class,
     GetProperty,
     SetProperty,
+
+    Is,
+    AssertFail,
+    Method,
+    NewArrayLong,
+    ArrayPush,
+    ArrayPushRange,
+    Modulo,
+    NewMap,
+
+    // Superinstructions, written over the first opcode of the sequence they replace by the
+    // peephole pass. The original instructions stay in place after them.
+    AddLocalConstant,
+    ConstantCall,
+    LessJumpIfFalse,
+    GreaterJumpIfFalse,
+    EqualJumpIfFalse,
+
+    JumpTable,
+
+    Field,
+    PopN,
+    NewTuple,
+    UnpackTuple,
+    UnpackArray,
+    UnpackMap,
+    NewRange,
+    Static,
+    ConstantLong,
 }
 
 impl From<u8> for Opcode {
@@ -70,11 +100,73 @@ impl From<u8> for Opcode {
             27 => Opcode::Class,          // TODO
             28 => Opcode::GetProperty,    // TODO
             29 => Opcode::SetProperty,    // TODO
+            30 => Opcode::Is,
+            31 => Opcode::AssertFail,
+            32 => Opcode::Method,
+            33 => Opcode::NewArrayLong,
+            34 => Opcode::ArrayPush,
+            35 => Opcode::ArrayPushRange,
+            36 => Opcode::Modulo,
+            37 => Opcode::NewMap,
+            38 => Opcode::AddLocalConstant,
+            39 => Opcode::ConstantCall,
+            40 => Opcode::LessJumpIfFalse,
+            41 => Opcode::GreaterJumpIfFalse,
+            42 => Opcode::EqualJumpIfFalse,
+            43 => Opcode::JumpTable,
+            44 => Opcode::Field,
+            45 => Opcode::PopN,
+            46 => Opcode::NewTuple,
+            47 => Opcode::UnpackTuple,
+            48 => Opcode::UnpackArray,
+            49 => Opcode::UnpackMap,
+            50 => Opcode::NewRange,
+            51 => Opcode::Static,
+            52 => Opcode::ConstantLong,
             _ => panic!("No opcode for byte: {}", byte),
         }
     }
 }
 
+impl Opcode {
+    /// Number of operand bytes following the opcode.
+    pub fn operand_len(&self) -> usize {
+        match self {
+            // A name, then a property cache.
+            Opcode::GetProperty | Opcode::SetProperty => 3,
+            Opcode::Jump
+            | Opcode::JumpIfFalse
+            | Opcode::Loop
+            | Opcode::NewArrayLong
+            | Opcode::DefineGlobal
+            | Opcode::GetGlobal
+            | Opcode::SetGlobal
+            | Opcode::Class
+            | Opcode::Method
+            | Opcode::Field
+            | Opcode::Static
+            | Opcode::ConstantLong
+            | Opcode::Closure
+            | Opcode::AssertFail => 2,
+            Opcode::Constant
+            | Opcode::GetLocal
+            | Opcode::SetLocal
+            | Opcode::Call
+            | Opcode::NewArray
+            | Opcode::NewMap
+            | Opcode::PopN
+            | Opcode::NewTuple
+            | Opcode::UnpackTuple
+            | Opcode::UnpackArray
+            | Opcode::UnpackMap
+            | Opcode::AddLocalConstant
+            | Opcode::ConstantCall
+            | Opcode::JumpTable => 1,
+            _ => 0,
+        }
+    }
+}
+
 impl From<UnaryOperator> for Opcode {
     fn from(op: UnaryOperator) -> Self {
         match op {
@@ -0,0 +1,341 @@
+use crate::compiler::chunk::Chunk;
+use crate::compiler::opcode::Opcode;
+use crate::syntax::ast::{Ast, ExprId};
+use crate::syntax::expr::{
+    BinaryOperator, ExprKind, GroupingExpr, LiteralExpr, UnaryExpr, UnaryOperator,
+};
+use crate::syntax::parser::ModuleAst;
+
+/// Folds constant expressions and removes redundant unary operators before compilation.
+pub fn optimize_module(module: &mut ModuleAst) {
+    for expr in module.exprs().clone() {
+        fold(module.ast_mut(), expr);
+    }
+}
+
+/// Folds `expr` in place, leaving whatever it folded away unused in the arena.
+fn fold(ast: &mut Ast, expr: ExprId) {
+    // Fold children first so constants propagate upwards.
+    let mut node = ast.take(expr);
+    node.for_each_child_mut(&mut |child| fold(ast, *child));
+
+    // Only the truthiness of a condition's value matters, so `!!x` is just `x` there.
+    if let Some(condition) = condition(&mut node) {
+        while let Some(inner) = double_not(ast, *condition) {
+            *condition = inner;
+        }
+    }
+    ast[expr] = node;
+
+    if let Some(folded) = simplify(ast, expr) {
+        ast[expr] = folded;
+    }
+}
+
+fn condition(node: &mut ExprKind) -> Option<&mut ExprId> {
+    match node {
+        ExprKind::Assert(a) => Some(&mut a.condition),
+        ExprKind::If(i) => Some(&mut i.condition),
+        ExprKind::IfElse(i) => Some(&mut i.condition),
+        ExprKind::While(w) => Some(&mut w.condition),
+        ExprKind::Comprehension(c) => c.condition.as_mut(),
+        _ => None,
+    }
+}
+
+fn simplify(ast: &mut Ast, expr: ExprId) -> Option<ExprKind> {
+    match &ast[expr] {
+        ExprKind::Grouping(GroupingExpr { expr }) => match &ast[*expr] {
+            ExprKind::Literal(_) => Some(ast.take(*expr)),
+            _ => None,
+        },
+        ExprKind::Binary(b) => match (&ast[b.lhs], &ast[b.rhs]) {
+            (
+                ExprKind::Literal(LiteralExpr::Number(a)),
+                ExprKind::Literal(LiteralExpr::Number(b_)),
+            ) => Some(ExprKind::Literal(fold_numbers(*a, *b_, &b.operator))),
+            _ => None,
+        },
+        ExprKind::Unary(UnaryExpr {
+            expr,
+            operator: UnaryOperator::Negate,
+        }) => match &ast[*expr] {
+            ExprKind::Literal(LiteralExpr::Number(n)) => {
+                Some(ExprKind::Literal(LiteralExpr::Number(-*n)))
+            }
+            ExprKind::Unary(UnaryExpr {
+                expr: inner,
+                operator: UnaryOperator::Negate,
+            }) => Some(ast.take(*inner)),
+            _ => None,
+        },
+        ExprKind::Unary(UnaryExpr {
+            expr,
+            operator: UnaryOperator::Not,
+        }) => match &ast[*expr] {
+            ExprKind::Literal(LiteralExpr::True) => Some(ExprKind::Literal(LiteralExpr::False)),
+            ExprKind::Literal(LiteralExpr::False) => Some(ExprKind::Literal(LiteralExpr::True)),
+            // `!!x` is only `x` when `x` is already a boolean.
+            ExprKind::Unary(UnaryExpr {
+                expr: inner,
+                operator: UnaryOperator::Not,
+            }) if is_boolean(ast, &ast[*inner]) => Some(ast.take(*inner)),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// The `x` in `!!x`.
+fn double_not(ast: &Ast, expr: ExprId) -> Option<ExprId> {
+    match &ast[expr] {
+        ExprKind::Unary(UnaryExpr {
+            expr,
+            operator: UnaryOperator::Not,
+        }) => match &ast[*expr] {
+            ExprKind::Unary(UnaryExpr {
+                expr: inner,
+                operator: UnaryOperator::Not,
+            }) => Some(*inner),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+fn is_boolean(ast: &Ast, node: &ExprKind) -> bool {
+    match node {
+        ExprKind::Literal(LiteralExpr::True | LiteralExpr::False) => true,
+        ExprKind::Unary(u) => u.operator == UnaryOperator::Not,
+        ExprKind::Binary(b) => matches!(
+            b.operator,
+            BinaryOperator::Equal
+                | BinaryOperator::BangEqual
+                | BinaryOperator::GreaterThan
+                | BinaryOperator::GreaterThanEqual
+                | BinaryOperator::LessThan
+                | BinaryOperator::LessThanEqual
+        ),
+        ExprKind::Is(_) => true,
+        ExprKind::Grouping(g) => is_boolean(ast, &ast[g.expr]),
+        _ => false,
+    }
+}
+
+fn fold_numbers(a: f64, b: f64, operator: &BinaryOperator) -> LiteralExpr {
+    let boolean = |value: bool| {
+        if value {
+            LiteralExpr::True
+        } else {
+            LiteralExpr::False
+        }
+    };
+
+    match operator {
+        BinaryOperator::Add => LiteralExpr::Number(a + b),
+        BinaryOperator::Subtract => LiteralExpr::Number(a - b),
+        BinaryOperator::Multiply => LiteralExpr::Number(a * b),
+        BinaryOperator::Divide => LiteralExpr::Number(a / b),
+        BinaryOperator::Modulo => LiteralExpr::Number(a % b),
+        BinaryOperator::Equal => boolean(a == b),
+        BinaryOperator::BangEqual => boolean(a != b),
+        BinaryOperator::GreaterThan => boolean(a > b),
+        BinaryOperator::GreaterThanEqual => boolean(a >= b),
+        BinaryOperator::LessThan => boolean(a < b),
+        BinaryOperator::LessThanEqual => boolean(a <= b),
+    }
+}
+
+/// Points jumps that land on another jump straight at the final destination.
+pub fn thread_jumps(chunk: &mut Chunk) {
+    let mut offset = 0;
+
+    while offset < chunk.code().len() {
+        let opcode = Opcode::from(chunk.code()[offset]);
+
+        if let Opcode::Jump | Opcode::JumpIfFalse = opcode {
+            let mut target = jump_target(chunk, offset);
+            while target < chunk.code().len()
+                && matches!(Opcode::from(chunk.code()[target]), Opcode::Jump)
+            {
+                target = jump_target(chunk, target);
+            }
+
+            let jump = target - offset - 3;
+            if jump <= u16::MAX as usize {
+                chunk.code_mut()[offset + 1] = ((jump >> 8) & 0xff) as u8;
+                chunk.code_mut()[offset + 2] = (jump & 0xff) as u8;
+            }
+        }
+
+        offset += 1 + opcode.operand_len();
+    }
+}
+
+/// Fuses common instruction sequences into superinstructions, saving dispatches in loops.
+/// Only the first opcode of a sequence is overwritten and the rest is left in place, so
+/// jumps into the middle of a sequence still land on valid instructions, and a fused
+/// instruction can fall back to running just the first one when its operands aren't numbers.
+pub fn fuse_instructions(chunk: &mut Chunk) {
+    let mut offset = 0;
+
+    while offset < chunk.code().len() {
+        let opcode = Opcode::from(chunk.code()[offset]);
+        let next = offset + 1 + opcode.operand_len();
+        let second = opcode_at(chunk, next);
+        let third = second
+            .as_ref()
+            .and_then(|second| opcode_at(chunk, next + 1 + second.operand_len()));
+
+        let fused = match (&opcode, second, third) {
+            (Opcode::GetLocal, Some(Opcode::Constant), Some(Opcode::Add)) => {
+                Some(Opcode::AddLocalConstant)
+            }
+            (Opcode::Constant, Some(Opcode::Call), _) => Some(Opcode::ConstantCall),
+            (Opcode::Less, Some(Opcode::JumpIfFalse), _) => Some(Opcode::LessJumpIfFalse),
+            (Opcode::Greater, Some(Opcode::JumpIfFalse), _) => Some(Opcode::GreaterJumpIfFalse),
+            (Opcode::Equal, Some(Opcode::JumpIfFalse), _) => Some(Opcode::EqualJumpIfFalse),
+            _ => None,
+        };
+        if let Some(fused) = fused {
+            chunk.code_mut()[offset] = fused as u8;
+        }
+
+        offset = next;
+    }
+}
+
+fn opcode_at(chunk: &Chunk, offset: usize) -> Option<Opcode> {
+    chunk.code().get(offset).map(|byte| Opcode::from(*byte))
+}
+
+fn jump_target(chunk: &Chunk, offset: usize) -> usize {
+    let hi = chunk.code()[offset + 1] as usize;
+    let lo = chunk.code()[offset + 2] as usize;
+    offset + 3 + ((hi << 8) | lo)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::compiler::Compiler;
+    use crate::compiler::options::{CompileOptions, OptLevel};
+    use crate::compiler::value::Value;
+    use crate::syntax::expr::{VarGetExpr, Variable};
+    use crate::syntax::parser::GreenParser;
+
+    fn not(expr: ExprId) -> ExprKind {
+        ExprKind::Unary(UnaryExpr::new(expr, UnaryOperator::Not))
+    }
+
+    fn optimized(source: &str) -> ModuleAst {
+        let mut module = GreenParser::parse(source).unwrap();
+        optimize_module(&mut module);
+        module
+    }
+
+    #[test]
+    fn folds_constant_expressions() {
+        let module = optimized("(1 + 2) * -3\n!(1 < 2)\n");
+        let (ast, exprs) = (module.ast(), module.exprs());
+
+        assert_eq!(ast[exprs[0]], ExprKind::Literal(LiteralExpr::Number(-9.0)));
+        assert_eq!(ast[exprs[1]], ExprKind::Literal(LiteralExpr::False));
+    }
+
+    #[test]
+    fn removes_double_negations() {
+        let module = optimized("--x\n!!(x < 1)\n!!x\nif !!x do 1 end\n");
+        let (ast, exprs) = (module.ast(), module.exprs());
+
+        let mut expected = Ast::new();
+        let x = expected.add(ExprKind::VarGet(VarGetExpr::new(Variable::new(
+            "x".to_string(),
+        ))));
+        let not_x = expected.add(not(x));
+        let not_not_x = expected.add(not(not_x));
+        assert!(ast.same(exprs[0], &expected, x));
+        assert!(matches!(ast[exprs[1]], ExprKind::Grouping(_)));
+        // `!!x` turns any value into a boolean, so it stays outside of conditions.
+        assert!(ast.same(exprs[2], &expected, not_not_x));
+        match &ast[exprs[3]] {
+            ExprKind::If(i) => assert!(ast.same(i.condition, &expected, x)),
+            _ => panic!("Expected if expression"),
+        }
+    }
+
+    #[test]
+    fn threads_jump_chains() {
+        let input = r#"
+        var x = if true do if false do 1 else 2 end else 3 end
+        "#;
+        let module = GreenParser::parse(input).unwrap();
+        let function = Compiler::compile(module).unwrap();
+        let chunk = function.chunk();
+
+        let mut offset = 0;
+        while offset < chunk.code().len() {
+            let opcode = Opcode::from(chunk.code()[offset]);
+            if let Opcode::Jump | Opcode::JumpIfFalse = opcode {
+                let target = jump_target(chunk, offset);
+                assert!(!matches!(Opcode::from(chunk.code()[target]), Opcode::Jump));
+            }
+            offset += 1 + opcode.operand_len();
+        }
+    }
+
+    fn function_opcodes(source: &str, opt_level: OptLevel) -> Vec<u8> {
+        let module = GreenParser::parse(source).unwrap();
+        let function = Compiler::compile_with(
+            module,
+            CompileOptions {
+                opt_level,
+                ..CompileOptions::default()
+            },
+        )
+        .unwrap();
+        let chunk = function
+            .chunk()
+            .constants()
+            .iter()
+            .find_map(|constant| match constant {
+                Value::Function(f) => Some(f.chunk().clone()),
+                _ => None,
+            })
+            .unwrap();
+
+        let mut opcodes = vec![];
+        let mut offset = 0;
+        while offset < chunk.code().len() {
+            opcodes.push(chunk.code()[offset]);
+            offset += 1 + Opcode::from(chunk.code()[offset]).operand_len();
+        }
+        opcodes
+    }
+
+    #[test]
+    fn fuses_common_sequences() {
+        let input = r#"
+        def f(n)
+            var i = n + 1
+            if i < 3 do i = f(2) end
+            return i
+        end
+        "#;
+
+        let opcodes = function_opcodes(input, OptLevel::O2);
+        for fused in [
+            Opcode::AddLocalConstant,
+            Opcode::ConstantCall,
+            Opcode::LessJumpIfFalse,
+        ] {
+            assert!(opcodes.contains(&(fused as u8)));
+        }
+
+        // The instructions after a superinstruction are left in place.
+        assert!(opcodes.contains(&(Opcode::Add as u8)));
+
+        let opcodes = function_opcodes(input, OptLevel::O1);
+        assert!(!opcodes.contains(&(Opcode::AddLocalConstant as u8)));
+    }
+}
@@ -0,0 +1,77 @@
+use std::str::FromStr;
+
+/// How a module is compiled, set by the embedder or on the command line and passed from the
+/// VM to the compiler.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CompileOptions {
+    pub opt_level: OptLevel,
+    /// Print warnings about the source to stderr.
+    pub warnings: bool,
+    /// Print each chunk as it is finished.
+    pub disassemble: bool,
+    /// Replace the bytes of imported modules that aren't valid UTF-8, with a warning, rather
+    /// than failing to import them.
+    pub lossy_utf8: bool,
+}
+
+impl Default for CompileOptions {
+    fn default() -> Self {
+        CompileOptions {
+            opt_level: OptLevel::O2,
+            warnings: true,
+            // Release builds skip the disassembly so benchmarks don't time terminal output.
+            disassemble: cfg!(debug_assertions),
+            lossy_utf8: false,
+        }
+    }
+}
+
+/// Which optimizations run, each level adding to the one below it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum OptLevel {
+    /// None: the bytecode maps one-to-one onto the source.
+    O0,
+    /// Constant folding on the AST and jump threading on the bytecode.
+    O1,
+    /// Superinstructions, jump tables for `match` and small imported functions inlined where
+    /// they're called too.
+    O2,
+}
+
+impl OptLevel {
+    pub const ALL: [OptLevel; 3] = [OptLevel::O0, OptLevel::O1, OptLevel::O2];
+
+    pub fn folds_constants(self) -> bool {
+        self >= OptLevel::O1
+    }
+
+    pub fn threads_jumps(self) -> bool {
+        self >= OptLevel::O1
+    }
+
+    pub fn fuses_instructions(self) -> bool {
+        self >= OptLevel::O2
+    }
+
+    pub fn uses_jump_tables(self) -> bool {
+        self >= OptLevel::O2
+    }
+
+    pub fn inlines_functions(self) -> bool {
+        self >= OptLevel::O2
+    }
+}
+
+/// Parses the number of a level, as in `-O1`.
+impl FromStr for OptLevel {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "0" => Ok(OptLevel::O0),
+            "1" => Ok(OptLevel::O1),
+            "2" => Ok(OptLevel::O2),
+            _ => Err(()),
+        }
+    }
+}
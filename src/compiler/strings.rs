@@ -0,0 +1,66 @@
+use std::collections::HashMap;
+
+/// How many names a module can use, as many as an instruction's two-byte operand can address.
+pub const MAX_STRINGS: usize = u16::MAX as usize + 1;
+
+/// Names used by a module, such as globals, properties and methods. Every chunk of the module
+/// shares one table, so each name is stored once and instructions refer to it by index.
+#[derive(Debug, Clone, Default)]
+pub struct StringTable {
+    strings: Vec<String>,
+    indices: HashMap<String, u16>,
+}
+
+impl StringTable {
+    pub fn new() -> Self {
+        StringTable {
+            strings: vec![],
+            indices: HashMap::new(),
+        }
+    }
+
+    /// Returns the index of `s`, adding it to the table if it's not there yet, or None if the
+    /// table is full.
+    pub fn intern(&mut self, s: &str) -> Option<u16> {
+        if let Some(index) = self.indices.get(s) {
+            return Some(*index);
+        }
+
+        if self.strings.len() >= MAX_STRINGS {
+            return None;
+        }
+
+        let index = self.strings.len() as u16;
+        self.strings.push(s.to_string());
+        self.indices.insert(s.to_string(), index);
+        Some(index)
+    }
+
+    pub fn get(&self, index: usize) -> Option<&String> {
+        self.strings.get(index)
+    }
+
+    pub fn len(&self) -> usize {
+        self.strings.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.strings.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn interning_reuses_indices() {
+        let mut table = StringTable::new();
+
+        assert_eq!(table.intern("x"), Some(0));
+        assert_eq!(table.intern("y"), Some(1));
+        assert_eq!(table.intern("x"), Some(0));
+        assert_eq!(table.len(), 2);
+        assert_eq!(table.get(1), Some(&"y".to_string()));
+    }
+}
@@ -1,28 +1,94 @@
-use crate::compiler::object::{GreenClosure, GreenFunction, Instance, Class};
-use std::cmp::Ordering;
-use std::fmt;
-use std::ops::{Add, Div, Mul, Neg, Sub};
+use crate::compiler::object::{
+    BoundMethod, Class, Foreign, GreenClosure, GreenFunction, Instance, Iter, Map, Module,
+    NativeFunction, Protocol, Range, Struct, StructInstance,
+};
+use crate::vm::errors::RuntimeError;
 use crate::vm::obj::Gc;
 use crate::vm::vm::RunResult;
-use crate::vm::errors::RuntimeError;
+use std::fmt;
+use std::ops::{Add, Div, Mul, Neg, Rem, Sub};
 
-#[derive(Clone)] // TODO Implement Copy
+/// A Green value. Heap data lives behind `Gc` pointers, so a value is two words and copying it
+/// never copies a string or array. Arrays are shared by reference: storing into one is visible
+/// through every copy.
+#[derive(Clone, Copy)]
 pub enum Value {
     Number(f64),
     True,
     False,
     Nil, // TODO Does Green lang use nils???
-    String(String),
-    Array(Vec<Value>), // TODO u32? Vec?
+    String(Gc<String>),
+    Array(Gc<Vec<Value>>),
+    /// A fixed group of values, like the several results of `return a, b`. Unlike arrays,
+    /// tuples can't be changed once made, so they compare equal by their elements.
+    Tuple(Gc<Vec<Value>>),
+    /// Numbers counted from a start to an end, like `1 to 10 step 2`, without storing them.
+    Range(Gc<Range>),
+    /// Where a loop is in a collection, see `iter`.
+    Iterator(Gc<Iter>),
+    Map(Gc<Map>),
     Closure(Gc<GreenClosure>),
     Function(Gc<GreenFunction>),
+    Native(Gc<NativeFunction>),
     Class(Gc<Class>),
     Instance(Gc<Instance>),
+    Struct(Gc<Struct>),
+    StructInstance(Gc<StructInstance>),
+    Protocol(Gc<Protocol>),
+    Module(Gc<Module>),
+    BoundMethod(Gc<BoundMethod>),
+    /// Data of a type the embedding application registered, see `VM::register_foreign`.
+    Foreign(Gc<Foreign>),
 }
 
+/// Type names that `is` checks against and `typeof` returns for builtin values.
+pub const BUILTIN_TYPES: [&str; 14] = [
+    "Number", "Bool", "Nil", "String", "Array", "Tuple", "Range", "Iterator", "Map", "Function",
+    "Class", "Struct", "Protocol", "Module",
+];
+
 impl Value {
     pub fn string(s: String) -> Value {
-        Value::String(s)
+        Value::String(Gc::new(s))
+    }
+
+    pub fn array(items: Vec<Value>) -> Value {
+        Value::Array(Gc::new(items))
+    }
+
+    pub fn tuple(items: Vec<Value>) -> Value {
+        Value::Tuple(Gc::new(items))
+    }
+
+    pub fn map(map: Map) -> Value {
+        Value::Map(Gc::new(map))
+    }
+
+    /// Name of the value's type: one of `BUILTIN_TYPES`, the class or struct name of an instance
+    /// or the name a foreign type was registered with.
+    pub fn type_name(&self) -> String {
+        match self {
+            Value::Number(_) => "Number",
+            Value::True | Value::False => "Bool",
+            Value::Nil => "Nil",
+            Value::String(_) => "String",
+            Value::Array(_) => "Array",
+            Value::Tuple(_) => "Tuple",
+            Value::Range(_) => "Range",
+            Value::Iterator(_) => "Iterator",
+            Value::Map(_) => "Map",
+            Value::Closure(_) | Value::Function(_) | Value::Native(_) | Value::BoundMethod(_) => {
+                "Function"
+            }
+            Value::Class(_) => "Class",
+            Value::Instance(i) => i.class.name(),
+            Value::Struct(_) => "Struct",
+            Value::Protocol(_) => "Protocol",
+            Value::StructInstance(s) => s.def.name(),
+            Value::Module(_) => "Module",
+            Value::Foreign(f) => f.kind().name(),
+        }
+        .to_string()
     }
 
     // TODO Find a better way to convert Value::Obj(Obj::String()) to String
@@ -42,9 +108,9 @@ impl Value {
     //     }
     // }
     //
-    pub fn as_instance(self) -> RunResult<Gc<Instance>> {
+    pub fn as_instance(&self) -> RunResult<Gc<Instance>> {
         match self {
-            Value::Instance(i) => Ok(i),
+            Value::Instance(i) => Ok(*i),
             _ => Err(RuntimeError::ArgumentTypes),
         }
     }
@@ -60,14 +126,14 @@ impl Value {
     //     }
     // }
 
-    pub fn as_number(self) -> f64 {
+    pub fn as_number(&self) -> f64 {
         match self {
-            Value::Number(n) => n,
+            Value::Number(n) => *n,
             _ => panic!("TODO"), // TODO
         }
     }
 
-    pub fn as_array(self) -> Vec<Value> {
+    pub fn into_array(self) -> Gc<Vec<Value>> {
         // FIXME
         match self {
             Value::Array(a) => a,
@@ -75,12 +141,17 @@ impl Value {
         }
     }
 
-    pub fn is_instance(&self) -> bool {
+    /// The foreign value an argument must be, for the methods of foreign types.
+    pub fn as_foreign(&self) -> RunResult<Gc<Foreign>> {
         match self {
-            Value::Instance(_) => true,
-            _ => false,
+            Value::Foreign(f) => Ok(*f),
+            _ => Err(RuntimeError::ArgumentTypes),
         }
     }
+
+    pub fn is_instance(&self) -> bool {
+        matches!(self, Value::Instance(_))
+    }
 }
 
 impl fmt::Debug for Value {
@@ -90,28 +161,115 @@ impl fmt::Debug for Value {
             Value::True => write!(f, "True"),
             Value::False => write!(f, "False"),
             Value::Nil => write!(f, "Nil"),
-            Value::String(s) => write!(f, "String({})", s),
-            Value::Array(_) => todo!(),
+            Value::String(s) => write!(f, "String({})", **s),
+            Value::Array(a) => write!(f, "Array({:?})", **a),
+            Value::Tuple(t) => write!(f, "Tuple({:?})", **t),
+            Value::Range(r) => write!(f, "Range({})", **r),
+            Value::Iterator(_) => write!(f, "Iterator"),
+            Value::Map(m) => write!(f, "Map({:?})", m.entries()),
             Value::Closure(clos) => write!(f, "Closure({:?})", clos),
             Value::Function(fun) => write!(f, "Function({})", **fun),
+            Value::Native(native) => write!(f, "Native({})", **native),
             Value::Class(c) => write!(f, "Class({})", **c),
             Value::Instance(i) => write!(f, "Instance({:?})", i),
+            Value::Struct(s) => write!(f, "Struct({})", **s),
+            Value::Protocol(p) => write!(f, "Protocol({})", **p),
+            Value::StructInstance(s) => write!(f, "{}({:?})", s.def.name(), s.fields),
+            Value::Module(m) => write!(f, "Module({})", m.name()),
+            Value::BoundMethod(b) => write!(f, "BoundMethod({})", b.method.function.name()),
+            Value::Foreign(foreign) => write!(f, "Foreign({})", foreign.kind().name()),
+        }
+    }
+}
+
+/// Writes a value nested in an array or map, quoting strings.
+fn fmt_nested(f: &mut fmt::Formatter<'_>, value: &Value) -> fmt::Result {
+    match value {
+        Value::String(s) => write!(f, "{:?}", **s),
+        value => fmt::Display::fmt(value, f),
+    }
+}
+
+/// How values are shown by `print` and `str`. Strings nested in arrays, tuples and maps are
+/// quoted. A precision, as in `{:.2}`, applies to every number in the value.
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Number(n) => match f.precision() {
+                Some(precision) => write!(f, "{:.*}", precision, n),
+                None => write!(f, "{}", n),
+            },
+            Value::True => write!(f, "true"),
+            Value::False => write!(f, "false"),
+            Value::Nil => write!(f, "nil"),
+            Value::String(s) => write!(f, "{}", **s),
+            Value::Array(a) => {
+                write!(f, "[")?;
+                for (i, item) in a.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    fmt_nested(f, item)?;
+                }
+                write!(f, "]")
+            }
+            Value::Tuple(t) => {
+                write!(f, "(")?;
+                for (i, item) in t.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    fmt_nested(f, item)?;
+                }
+                write!(f, ")")
+            }
+            Value::Range(r) => write!(f, "{}", **r),
+            Value::Iterator(_) => write!(f, "<Iterator>"),
+            Value::Map(m) => {
+                write!(f, "{{")?;
+                for (i, (key, value)) in m.entries().iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    fmt_nested(f, key)?;
+                    write!(f, ": ")?;
+                    fmt_nested(f, value)?;
+                }
+                write!(f, "}}")
+            }
+            Value::Closure(c) => write!(f, "{}", *c.function),
+            Value::Function(fun) => write!(f, "{}", **fun),
+            Value::Native(native) => write!(f, "{}", **native),
+            Value::Class(c) => write!(f, "{}", **c),
+            Value::Instance(i) => write!(f, "{} instance", i.class.name()),
+            Value::Struct(s) => write!(f, "{}", s.name()),
+            Value::Protocol(p) => write!(f, "{}", p.name()),
+            Value::StructInstance(s) => {
+                write!(f, "{}(", s.def.name())?;
+                for (i, field) in s.fields.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    fmt::Display::fmt(field, f)?;
+                }
+                write!(f, ")")
+            }
+            Value::Module(m) => write!(f, "{}", **m),
+            Value::BoundMethod(b) => write!(f, "{}", *b.method.function),
+            Value::Foreign(foreign) => write!(f, "<{}>", foreign.kind().name()),
         }
     }
 }
 
 impl From<&Value> for bool {
     fn from(value: &Value) -> Self {
-        match value {
-            Value::False | Value::Nil => false,
-            _ => true,
-        }
+        !matches!(value, Value::False | Value::Nil)
     }
 }
 
-impl Into<Value> for bool {
-    fn into(self) -> Value {
-        if self {
+impl From<bool> for Value {
+    fn from(val: bool) -> Self {
+        if val {
             Value::True
         } else {
             Value::False
@@ -120,104 +278,157 @@ impl Into<Value> for bool {
 }
 
 impl Add for Value {
-    type Output = Self;
+    type Output = RunResult<Value>;
 
     fn add(self, other: Self) -> Self::Output {
-        if let Value::Number(b) = self {
-            if let Value::Number(a) = other {
-                Value::Number(b + a)
-            } else {
-                panic!("Operand must be a number.");
-            }
-        } else {
-            panic!("Operand must be a number.");
-        }
+        let (a, b) = self.numbers(other, "+")?;
+        Ok(Value::Number(a + b))
     }
 }
 
 impl Sub for Value {
-    type Output = Self;
+    type Output = RunResult<Value>;
 
     fn sub(self, other: Self) -> Self::Output {
-        if let Value::Number(b) = self {
-            if let Value::Number(a) = other {
-                Value::Number(b - a)
-            } else {
-                panic!("Operand must be a number.");
-            }
-        } else {
-            panic!("Operand must be a number.");
-        }
+        let (a, b) = self.numbers(other, "-")?;
+        Ok(Value::Number(a - b))
     }
 }
 
 impl Mul for Value {
-    type Output = Self;
+    type Output = RunResult<Value>;
 
     fn mul(self, other: Self) -> Self::Output {
-        if let Value::Number(b) = self {
-            if let Value::Number(a) = other {
-                Value::Number(b * a)
-            } else {
-                panic!("Operand must be a number.");
-            }
-        } else {
-            panic!("Operand must be a number.");
-        }
+        let (a, b) = self.numbers(other, "*")?;
+        Ok(Value::Number(a * b))
     }
 }
 
 impl Div for Value {
-    type Output = Self;
+    type Output = RunResult<Value>;
 
     fn div(self, other: Self) -> Self::Output {
-        if let Value::Number(b) = self {
-            if let Value::Number(a) = other {
-                Value::Number(b / a)
-            } else {
-                panic!("Operand must be a number.");
-            }
-        } else {
-            panic!("Operand must be a number.");
-        }
+        let (a, b) = self.numbers(other, "/")?;
+        Ok(Value::Number(a / b))
+    }
+}
+
+impl Rem for Value {
+    type Output = RunResult<Value>;
+
+    fn rem(self, other: Self) -> Self::Output {
+        let (a, b) = self.numbers(other, "%")?;
+        Ok(Value::Number(a % b))
     }
 }
 
 impl Neg for Value {
-    type Output = Self;
+    type Output = RunResult<Value>;
 
     fn neg(self) -> Self::Output {
         match self {
-            Value::Number(a) => Value::Number(-a),
-            _ => todo!(),
+            Value::Number(a) => Ok(Value::Number(-a)),
+            _ => Err(RuntimeError::OperandType("-", self.type_name())),
         }
     }
 }
 
 impl PartialEq for Value {
+    /// Numbers, booleans, nil, strings, tuples and ranges are equal by value, anything else by
+    /// identity.
     fn eq(&self, other: &Self) -> bool {
-        if let Value::Number(b) = self {
-            if let Value::Number(a) = other {
-                b == a
-            } else {
-                panic!("Operand must be a number.");
-            }
-        } else {
-            panic!("Operand must be a number.");
+        match (self, other) {
+            (Value::Number(a), Value::Number(b)) => a == b,
+            (Value::True, Value::True)
+            | (Value::False, Value::False)
+            | (Value::Nil, Value::Nil) => true,
+            (Value::String(a), Value::String(b)) => **a == **b,
+            (Value::Array(a), Value::Array(b)) => Gc::ptr_eq(a, b),
+            (Value::Tuple(a), Value::Tuple(b)) => **a == **b,
+            (Value::Range(a), Value::Range(b)) => **a == **b,
+            (Value::Iterator(a), Value::Iterator(b)) => Gc::ptr_eq(a, b),
+            (Value::Map(a), Value::Map(b)) => Gc::ptr_eq(a, b),
+            (Value::Closure(a), Value::Closure(b)) => Gc::ptr_eq(a, b),
+            (Value::Function(a), Value::Function(b)) => Gc::ptr_eq(a, b),
+            (Value::Native(a), Value::Native(b)) => Gc::ptr_eq(a, b),
+            (Value::Class(a), Value::Class(b)) => Gc::ptr_eq(a, b),
+            (Value::Instance(a), Value::Instance(b)) => Gc::ptr_eq(a, b),
+            (Value::Struct(a), Value::Struct(b)) => Gc::ptr_eq(a, b),
+            (Value::Protocol(a), Value::Protocol(b)) => Gc::ptr_eq(a, b),
+            (Value::StructInstance(a), Value::StructInstance(b)) => Gc::ptr_eq(a, b),
+            (Value::Module(a), Value::Module(b)) => Gc::ptr_eq(a, b),
+            (Value::BoundMethod(a), Value::BoundMethod(b)) => Gc::ptr_eq(a, b),
+            (Value::Foreign(a), Value::Foreign(b)) => Gc::ptr_eq(a, b),
+            _ => false,
         }
     }
 }
 
-impl PartialOrd for Value {
-    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        if let Value::Number(b) = self {
-            if let Value::Number(a) = other {
-                b.partial_cmp(a)
-            } else {
-                panic!("Operand must be a number.");
+impl Value {
+    /// Equality by contents all the way down: arrays and tuples with equal elements, maps with
+    /// the same keys for equal values in any order, and struct instances of one struct with
+    /// equal fields. Anything else compares like `==`.
+    pub fn deep_eq(&self, other: &Value) -> bool {
+        match (self, other) {
+            (Value::Array(a), Value::Array(b)) | (Value::Tuple(a), Value::Tuple(b)) => {
+                a.len() == b.len() && a.iter().zip(b.iter()).all(|(a, b)| a.deep_eq(b))
             }
-        } else {
-            panic!("Operand must be a number.");
+            (Value::Map(a), Value::Map(b)) => {
+                a.len() == b.len()
+                    && a.entries().iter().all(|(key, value)| match b.get(key) {
+                        Ok(Some(other)) => value.deep_eq(&other),
+                        _ => false,
+                    })
+            }
+            (Value::StructInstance(a), Value::StructInstance(b)) => {
+                Gc::ptr_eq(&a.def, &b.def)
+                    && a.fields
+                        .iter()
+                        .zip(b.fields.iter())
+                        .all(|(a, b)| a.deep_eq(b))
+            }
+            _ => self == other,
+        }
+    }
+}
+
+impl Value {
+    /// `self < other`, for numbers only. Instances with an `lt` method are compared by the VM.
+    pub fn less(self, other: Value) -> RunResult<bool> {
+        let (a, b) = self.numbers(other, "<")?;
+        Ok(a < b)
+    }
+
+    /// `self > other`, for numbers only.
+    pub fn greater(self, other: Value) -> RunResult<bool> {
+        let (a, b) = self.numbers(other, ">")?;
+        Ok(a > b)
+    }
+
+    /// The operands of the binary operator `op`, which must both be numbers. Instances only
+    /// get here when their class has no method for `op`.
+    fn numbers(self, other: Value, op: &'static str) -> RunResult<(f64, f64)> {
+        match (self, other) {
+            (Value::Number(a), Value::Number(b)) => Ok((a, b)),
+            (Value::Instance(instance), _) => Err(RuntimeError::UnsupportedOperator(
+                instance.class.name().to_string(),
+                op,
+            )),
+            _ => Err(RuntimeError::OperandTypes(
+                op,
+                self.type_name(),
+                other.type_name(),
+            )),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn values_are_two_words() {
+        assert_eq!(std::mem::size_of::<Value>(), 16);
+    }
+}
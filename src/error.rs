@@ -1,4 +1,4 @@
-use crate::syntax::token::TokenType;
+use crate::syntax::token::{Position, TokenType};
 use std::fmt;
 use std::fmt::{Debug, Display, Formatter};
 
@@ -36,6 +36,40 @@ pub enum ParserError {
     UnexpectedToken(TokenType),
     Expect(TokenType, TokenType, usize),
     UnexpectedEOF,
+    BindingInAlternatives(usize),
+    TooDeep(usize),
+    /// More values in a `return a, b` or unpacking `var` than one instruction can handle.
+    TooManyValues(usize),
+    /// The source of an expression that isn't a variable, subscript or property, followed by
+    /// an '='.
+    InvalidAssignment(String, Position),
+    /// A macro called with the wrong number of arguments: its name, how many it takes, the line.
+    MacroArguments(String, usize, usize),
+    /// A macro that assigns to a parameter, called with something other than a variable for it.
+    MacroAssignment(String, usize),
+    Syntax(SyntaxError),
+}
+
+impl ParserError {
+    /// The line the error is on, if it is known.
+    pub fn line(&self) -> Option<usize> {
+        match self {
+            ParserError::Expect(_, _, line)
+            | ParserError::BindingInAlternatives(line)
+            | ParserError::TooDeep(line)
+            | ParserError::TooManyValues(line)
+            | ParserError::MacroArguments(_, _, line)
+            | ParserError::MacroAssignment(_, line) => Some(*line),
+            ParserError::InvalidAssignment(_, position) => Some(position.line),
+            _ => None,
+        }
+    }
+}
+
+impl From<SyntaxError> for ParserError {
+    fn from(error: SyntaxError) -> Self {
+        ParserError::Syntax(error)
+    }
 }
 
 impl Display for ParserError {
@@ -52,6 +86,36 @@ impl Display for ParserError {
                 )
             }
             ParserError::UnexpectedEOF => write!(f, "Unexpected EOF"),
+            ParserError::BindingInAlternatives(line) => write!(
+                f,
+                "A case with several patterns can't bind variables, on line: {}",
+                line
+            ),
+            ParserError::InvalidAssignment(target, position) => write!(
+                f,
+                "Invalid assignment target '{}', on line: {}",
+                target, position.line
+            ),
+            ParserError::TooDeep(line) => {
+                write!(f, "Expression nested too deeply, on line: {}", line)
+            }
+            ParserError::TooManyValues(line) => write!(
+                f,
+                "Can return or unpack at most {} values at once, on line: {}",
+                u8::MAX,
+                line
+            ),
+            ParserError::MacroArguments(name, arity, line) => write!(
+                f,
+                "Macro '{}' takes {} arguments, on line: {}",
+                name, arity, line
+            ),
+            ParserError::MacroAssignment(param, line) => write!(
+                f,
+                "Macro parameter '{}' is assigned to, so it must be passed a variable, on line: {}",
+                param, line
+            ),
+            ParserError::Syntax(error) => write!(f, "{:?}", error),
         }
     }
 }
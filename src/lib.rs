@@ -0,0 +1,13 @@
+#![allow(dead_code)]
+#![allow(clippy::module_inception)]
+
+pub mod bench;
+pub mod compiler;
+pub mod error;
+pub mod repl;
+pub mod source_map;
+pub mod stdlib;
+pub mod syntax;
+pub mod testing;
+pub mod type_system;
+pub mod vm;
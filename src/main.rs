@@ -1,38 +1,300 @@
-use crate::vm::VM;
-
-mod compiler;
-mod error;
-mod repl;
-mod syntax;
-mod type_system;
-mod vm;
+use green::bench;
+use green::compiler::cache::ModuleCache;
+use green::compiler::value::Value;
+use green::repl::Repl;
+use green::source_map::{read_source, read_source_lossy};
+use green::syntax::formatter;
+use green::testing;
+use green::vm::trace::OpcodeHistogram;
+use green::vm::{ExecPolicy, Timings, VmOptions, VM};
+use std::env;
+use std::path::PathBuf;
+use std::process::exit;
 
 fn main() {
-    // Repl::run();
-
     // type_system::repl::repl();
 
-    let source = get_file_contents("/Users/rickhuisman/Documents/rust/green/src/test.txt");
-    run(&source.unwrap());
+    let mut args = env::args().peekable();
+    args.next(); // Pop app path
+
+    let mut options = VmOptions::default();
+    let mut time = false;
+    let mut profile = false;
+    let mut cache = true;
+    while let Some(flag) = args.next_if(|arg| arg.starts_with('-')) {
+        if let Some(level) = flag.strip_prefix("-O") {
+            options.compile.opt_level = level.parse().unwrap_or_else(|_| usage());
+            continue;
+        }
+        if flag == "--quiet" {
+            options.quiet = true;
+            continue;
+        }
+        if flag == "--time" {
+            time = true;
+            continue;
+        }
+        if flag == "--profile" {
+            profile = true;
+            continue;
+        }
+        if flag == "--lossy-utf8" {
+            options.compile.lossy_utf8 = true;
+            continue;
+        }
+        if flag == "--no-cache" {
+            cache = false;
+            continue;
+        }
+        if flag == "--debug" {
+            options.debug = true;
+            continue;
+        }
+        if flag == "--trace" {
+            options.trace = true;
+            continue;
+        }
+        if flag == "--no-exec" {
+            options.exec = ExecPolicy::None;
+            continue;
+        }
+
+        let limit = match flag.as_str() {
+            "--max-frames" => &mut options.max_frames,
+            "--stack-size" => &mut options.stack_size,
+            _ => usage(),
+        };
+        *limit = match args.next().map(|value| value.parse()) {
+            Some(Ok(value)) => value,
+            _ => {
+                eprintln!("{} takes a number", flag);
+                exit(64);
+            }
+        };
+    }
+
+    let path = match args.next() {
+        Some(path) => path,
+        None => usage(),
+    };
+
+    if path == "bench" {
+        exit(run_benches(args.map(PathBuf::from).collect()));
+    }
+
+    if path == "repl" {
+        Repl::run();
+        exit(0);
+    }
+
+    if path == "test" {
+        exit(run_tests(args.map(PathBuf::from).collect()));
+    }
+
+    if path == "fmt" {
+        exit(run_fmt(args.collect()));
+    }
+
+    if path == "clean-cache" {
+        exit(clean_cache());
+    }
+
+    let source = match read_source_lossy(&path, options.compile.lossy_utf8) {
+        Ok((source, replaced)) => {
+            if let Some(line) = replaced {
+                eprintln!(
+                    "warning: {} is not valid UTF-8 from line {}, invalid bytes were replaced",
+                    path, line
+                );
+            }
+            source
+        }
+        Err(err) => {
+            eprintln!("Could not read {}: {}", path, err);
+            exit(74);
+        }
+    };
+
+    // Without a cache directory to use, scripts are compiled every time.
+    let cache = ModuleCache::default_dir()
+        .filter(|_| cache)
+        .map(ModuleCache::new);
+    let report = Report { time, profile };
+    exit(run(&path, &source, args.collect(), options, cache, report));
+}
+
+fn usage() -> ! {
+    eprintln!("Usage: green [options] <script> [args...]");
+    eprintln!("       green bench <script or directory>...");
+    eprintln!("       green test <script or directory>...");
+    eprintln!("       green fmt [--check] <script>...");
+    eprintln!("       green clean-cache");
+    eprintln!("       green repl");
+    eprintln!();
+    eprintln!("Options:");
+    eprintln!("    --quiet                 discard printed output and compiler messages");
+    eprintln!("    --time                  report how long parsing, compiling and running took");
+    eprintln!("    --profile               report the times and how often each instruction ran");
+    eprintln!(
+        "    --no-cache              compile the script even if it's unchanged since last run"
+    );
+    eprintln!(
+        "    --lossy-utf8            run scripts that aren't valid UTF-8, replacing bad bytes"
+    );
+    eprintln!("    --debug                 step through the script line by line");
+    eprintln!("    --trace                 log every instruction executed and the stack");
+    eprintln!("    --no-exec               stop the script from running programs with os.exec");
+    eprintln!("    -O0, -O1, -O2           optimize not at all, a little, or fully (the default)");
+    eprintln!("    --max-frames <calls>    limit how deeply calls can nest");
+    eprintln!("    --stack-size <values>   limit how many values the stack can hold");
+    exit(64);
+}
+
+/// What to report on stderr once a script has run.
+struct Report {
+    /// How long each phase took.
+    time: bool,
+    /// The times, and how many times each opcode was executed.
+    profile: bool,
+}
+
+/// Runs a script, then its `main(args)` function if it defines one, then any timers it left
+/// scheduled. A number returned from `main` becomes the exit code.
+fn run(
+    path: &str,
+    source: &str,
+    args: Vec<String>,
+    options: VmOptions,
+    cache: Option<ModuleCache>,
+    report: Report,
+) -> i32 {
+    let mut vm = VM::with_options(options);
+    if let Some(cache) = cache {
+        vm.set_cache(cache);
+    }
+    let histogram = OpcodeHistogram::new();
+    if report.profile {
+        vm.set_tracer(histogram.clone());
+    }
+    vm.set_args(args.clone());
+    vm.interpret_named(path, source);
+
+    let code = match vm.call_main(args) {
+        Ok(Some(Value::Number(code))) => code as i32,
+        Ok(_) => 0,
+        Err(err) => {
+            print!("{}", vm.error_report(&err));
+            exit(1);
+        }
+    };
+    vm.run_timers();
 
-    // if env::args().len() == 0 {
-    //     exit(1);
-    // }
-    //
-    // let mut args = env::args();
-    // args.next(); // Pop app path
-    //
-    // for arg in args {
-    //     let source = get_file_contents(&arg);
-    //     run(&source.unwrap());
-    // }
+    if report.time || report.profile {
+        print_timings(vm.timings());
+    }
+    if report.profile {
+        eprintln!();
+        eprint!("{}", histogram);
+    }
+    code
 }
 
-fn run(source: &str) {
-    let mut vm = VM::new();
-    vm.interpret(source);
+fn print_timings(timings: Timings) {
+    eprintln!("parse    {:>12.3?}", timings.parse);
+    eprintln!("compile  {:>12.3?}", timings.compile);
+    eprintln!("execute  {:>12.3?}", timings.execute);
+    eprintln!("total    {:>12.3?}", timings.total());
 }
 
-fn get_file_contents(path: &str) -> std::io::Result<String> {
-    std::fs::read_to_string(path)
+/// Deletes the compiled scripts kept between runs.
+fn clean_cache() -> i32 {
+    let cache = match ModuleCache::default_dir() {
+        Some(dir) => ModuleCache::new(dir),
+        None => return 0,
+    };
+    match cache.clear() {
+        Ok(_) => 0,
+        Err(err) => {
+            eprintln!("Could not clear {}: {}", cache.dir().display(), err);
+            74
+        }
+    }
+}
+
+/// Times the `main` function of each benchmark script, see `green::bench`.
+fn run_benches(paths: Vec<PathBuf>) -> i32 {
+    if paths.is_empty() {
+        eprintln!("Usage: green bench <script or directory>...");
+        return 64;
+    }
+
+    match bench::run(&paths) {
+        Ok(_) => 0,
+        Err(err) => {
+            eprintln!("Could not run benchmarks: {}", err);
+            74
+        }
+    }
+}
+
+/// Runs the test blocks of the scripts at `paths`, failing if any of them fail.
+fn run_tests(paths: Vec<PathBuf>) -> i32 {
+    if paths.is_empty() {
+        eprintln!("Usage: green test <script or directory>...");
+        return 64;
+    }
+
+    match testing::run(&paths) {
+        Ok(true) => 0,
+        Ok(false) => 1,
+        Err(err) => {
+            eprintln!("Could not run tests: {}", err);
+            74
+        }
+    }
+}
+
+/// Rewrites each script in canonical form, or with `--check` only lists the scripts that
+/// aren't and fails.
+fn run_fmt(mut args: Vec<String>) -> i32 {
+    let check = args.first().is_some_and(|arg| arg == "--check");
+    if check {
+        args.remove(0);
+    }
+    if args.is_empty() {
+        eprintln!("Usage: green fmt [--check] <script>...");
+        return 64;
+    }
+
+    let mut code = 0;
+    for path in &args {
+        let source = match read_source(path) {
+            Ok(source) => source,
+            Err(err) => {
+                eprintln!("Could not read {}: {}", path, err);
+                return 74;
+            }
+        };
+
+        let formatted = match formatter::format(&source) {
+            Ok(formatted) => formatted,
+            Err(err) => {
+                eprintln!("Could not format {}: {}", path, err);
+                code = 65;
+                continue;
+            }
+        };
+        if formatted == source {
+            continue;
+        }
+
+        if check {
+            println!("{}", path);
+            code = code.max(1);
+        } else if let Err(err) = std::fs::write(path, formatted) {
+            eprintln!("Could not write {}: {}", path, err);
+            return 74;
+        }
+    }
+    code
 }
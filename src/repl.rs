@@ -1,29 +1,154 @@
+use crate::compiler::object::{Class, Instance};
+use crate::compiler::value::Value;
+use crate::source_map::read_source;
 use crate::vm::VM;
+use std::collections::HashSet;
+use std::env;
 use std::io;
-use std::io::BufRead;
+use std::io::{BufRead, Write};
+use std::path::{Path, PathBuf};
+
+const DEFAULT_PROMPT: &str = "> ";
+
+const HELP: &str = "\
+:vars     list the globals defined in this session
+:globals  list every global, builtins included
+:stack    show the values on the stack
+:help     show this help";
 
 pub struct Repl {
     vm: VM,
+    /// Number of results kept so far, the last one being bound to `_{history}`.
+    history: usize,
+    /// The globals defined before the session started, which `:vars` leaves out.
+    builtins: HashSet<String>,
 }
 
 impl Repl {
     fn new() -> Self {
-        Repl { vm: VM::new() }
+        Repl::with_vm(VM::new())
     }
 
+    /// Wraps a VM, giving it the `repl` global whose `prompt` lines are read after.
+    fn with_vm(mut vm: VM) -> Self {
+        let class = vm.alloc(Class::new("Repl".to_string()));
+        let mut settings = vm.alloc(Instance::new(class));
+        settings.set_property("prompt", Value::string(DEFAULT_PROMPT.to_string()));
+        vm.bind_global("repl", Value::Instance(settings));
+
+        let builtins = vm.globals().map(|(name, _)| name.to_string()).collect();
+        Repl {
+            vm,
+            history: 0,
+            builtins,
+        }
+    }
+
+    /// Reads and evaluates lines until the input ends, after running `~/.greenrc` if there is
+    /// one.
     pub fn run() {
         let mut repl = Repl::new();
+        if let Some(rc) = rc_path().filter(|path| path.is_file()) {
+            repl.load(&rc);
+        }
 
         loop {
+            repl.show_prompt();
             match repl.read_line() {
+                Ok(line) if line.is_empty() => break,
                 Ok(line) => repl.eval(&line),
                 Err(e) => eprintln!("[error]: {}", e),
             }
         }
     }
 
+    /// Runs a file of Green code, e.g. helpers to have at hand in every session.
+    fn load(&mut self, path: &Path) {
+        match read_source(path) {
+            Ok(source) => {
+                if let Err(report) = self.vm.run_script(&path.display().to_string(), source) {
+                    print!("{}", report);
+                }
+            }
+            Err(err) => eprintln!("Could not read {}: {}", path.display(), err),
+        }
+    }
+
+    /// The prompt set in `repl.prompt`, shown as `print` would show it.
+    fn prompt(&mut self) -> String {
+        let prompt = match self.vm.global("repl") {
+            Some(Value::Instance(settings)) => settings.get_property("prompt"),
+            _ => None,
+        };
+        match prompt {
+            Some(prompt) => self.vm.stringify(prompt).unwrap_or_default(),
+            None => DEFAULT_PROMPT.to_string(),
+        }
+    }
+
+    fn show_prompt(&mut self) {
+        let prompt = self.prompt();
+        let mut stdout = io::stdout();
+        let _ = write!(stdout, "{}", prompt);
+        let _ = stdout.flush();
+    }
+
+    /// Evaluates a line and keeps its result, unless it's nil, as `_` and as the next of `_1`,
+    /// `_2`, ... so later lines can use it.
     fn eval(&mut self, source: &str) {
-        self.vm.interpret(source)
+        if let Some(command) = source.trim().strip_prefix(':') {
+            self.command(command);
+            return;
+        }
+
+        // A line that fails is reported, and the session goes on without it.
+        let value = match self.vm.run_script("<script>", source) {
+            Ok(Value::Nil) => return,
+            Ok(value) => value,
+            Err(report) => {
+                print!("{}", report);
+                return;
+            }
+        };
+
+        self.history += 1;
+        let name = format!("_{}", self.history);
+        let _ = writeln!(self.vm.output(), "{} = {}", name, value);
+
+        self.vm.bind_global(&name, value);
+        self.vm.bind_global("_", value);
+    }
+
+    /// Runs a `:command`, which looks around the session rather than evaluating code.
+    fn command(&mut self, command: &str) {
+        let mut lines = vec![];
+        match command {
+            "vars" | "globals" => {
+                let mut globals: Vec<(&str, &Value)> = self
+                    .vm
+                    .globals()
+                    .filter(|(name, _)| command == "globals" || !self.builtins.contains(*name))
+                    .collect();
+                globals.sort_by_key(|(name, _)| *name);
+                for (name, value) in globals {
+                    lines.push(format!("{}: {} = {}", name, value.type_name(), value));
+                }
+            }
+            "stack" => {
+                for (i, value) in self.vm.stack().iter().enumerate() {
+                    lines.push(format!("[{}] {}", i, value));
+                }
+                if lines.is_empty() {
+                    lines.push("(empty)".to_string());
+                }
+            }
+            "help" => lines.push(HELP.to_string()),
+            _ => lines.push(format!("Unknown command :{}, try :help", command)),
+        }
+
+        for line in lines {
+            let _ = writeln!(self.vm.output(), "{}", line);
+        }
     }
 
     fn read_line(&self) -> io::Result<String> {
@@ -33,3 +158,106 @@ impl Repl {
         Ok(line)
     }
 }
+
+/// Where the file run at the start of every session lives: `.greenrc` in the home directory.
+fn rc_path() -> Option<PathBuf> {
+    let home = env::var_os("HOME").or_else(|| env::var_os("USERPROFILE"))?;
+    Some(PathBuf::from(home).join(".greenrc"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::VmOptions;
+    use std::cell::RefCell;
+    use std::fs;
+    use std::rc::Rc;
+
+    #[test]
+    fn results_are_kept_in_history_variables() {
+        let vm = VM::with_options(VmOptions {
+            quiet: true,
+            ..VmOptions::default()
+        });
+        let mut repl = Repl::with_vm(vm);
+
+        repl.eval("1 + 2\n");
+        repl.eval("var x = 10\n");
+        repl.eval("_ * x\n");
+        repl.eval("_1 + _2\n");
+
+        assert_eq!(repl.vm.global("_1"), Some(&Value::Number(3.0)));
+        assert_eq!(repl.vm.global("_2"), Some(&Value::Number(30.0)));
+        assert_eq!(repl.vm.global("_3"), Some(&Value::Number(33.0)));
+        assert_eq!(repl.vm.global("_"), Some(&Value::Number(33.0)));
+        assert_eq!(repl.vm.global("_4"), None);
+    }
+
+    #[test]
+    fn commands_list_globals_and_the_stack() {
+        #[derive(Clone, Default)]
+        struct Shared(Rc<RefCell<Vec<u8>>>);
+
+        impl Write for Shared {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                self.0.borrow_mut().write(buf)
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let output = Shared::default();
+        let mut repl = Repl::with_vm(VM::with_output(VmOptions::default(), output.clone()));
+        let shown = |output: &Shared| {
+            let text = String::from_utf8(output.0.borrow().clone()).unwrap();
+            output.0.borrow_mut().clear();
+            text
+        };
+
+        repl.eval("var name = \"ada\"\n");
+        repl.eval("var ages = [36, 41]\n");
+        shown(&output);
+
+        repl.eval(":vars\n");
+        assert_eq!(
+            shown(&output),
+            "ages: Array = [36, 41]\nname: String = ada\n"
+        );
+
+        repl.eval(":globals\n");
+        let globals = shown(&output);
+        assert!(globals.contains("name: String = ada\n"));
+        assert!(globals.contains("io: Module = "));
+
+        repl.eval(":stack\n");
+        assert_eq!(shown(&output), "(empty)\n");
+
+        repl.eval(":nope\n");
+        assert_eq!(shown(&output), "Unknown command :nope, try :help\n");
+    }
+
+    #[test]
+    fn rc_files_define_helpers_and_set_the_prompt() {
+        let vm = VM::with_options(VmOptions {
+            quiet: true,
+            ..VmOptions::default()
+        });
+        let mut repl = Repl::with_vm(vm);
+        assert_eq!(repl.prompt(), "> ");
+
+        let rc = env::temp_dir().join(format!("green-rc-{}", std::process::id()));
+        fs::write(
+            &rc,
+            "def twice(x)\n    return x * 2\nend\nrepl.prompt = \"green> \"\n",
+        )
+        .unwrap();
+        repl.load(&rc);
+        fs::remove_file(&rc).unwrap();
+
+        assert_eq!(repl.prompt(), "green> ");
+        repl.eval("twice(21)\n");
+        assert_eq!(repl.vm.global("_"), Some(&Value::Number(42.0)));
+    }
+}
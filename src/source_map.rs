@@ -0,0 +1,183 @@
+use std::fmt;
+use std::fmt::Write;
+use std::io;
+use std::path::Path;
+
+/// Identifies a file loaded into a `SourceMap`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct FileId(usize);
+
+/// A loaded source file: where it came from and its text.
+#[derive(Debug)]
+pub struct SourceFile {
+    name: String,
+    text: String,
+    /// Byte offsets at which each line starts.
+    line_starts: Vec<usize>,
+}
+
+impl SourceFile {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    /// The text of a line, counting from 1, without its line break.
+    pub fn line(&self, line: usize) -> Option<&str> {
+        let start = *self.line_starts.get(line.checked_sub(1)?)?;
+        let end = self
+            .line_starts
+            .get(line)
+            .copied()
+            .unwrap_or(self.text.len());
+        Some(self.text[start..end].trim_end_matches(['\n', '\r']))
+    }
+}
+
+/// Owns the text of every file loaded while running a script, the script itself and the modules
+/// it imports, so diagnostics can show the lines they are about.
+#[derive(Debug, Default)]
+pub struct SourceMap {
+    files: Vec<SourceFile>,
+}
+
+impl SourceMap {
+    pub fn new() -> Self {
+        SourceMap::default()
+    }
+
+    /// Adds a file, named by its path or a description like `<script>`.
+    pub fn add(&mut self, name: impl Into<String>, text: impl Into<String>) -> FileId {
+        let text = text.into();
+        let line_starts = std::iter::once(0)
+            .chain(text.match_indices('\n').map(|(i, _)| i + 1))
+            .collect();
+
+        self.files.push(SourceFile {
+            name: name.into(),
+            text,
+            line_starts,
+        });
+        FileId(self.files.len() - 1)
+    }
+
+    pub fn get(&self, file: FileId) -> &SourceFile {
+        &self.files[file.0]
+    }
+
+    /// The files added from `first` on, like a script and the modules compiling it imported.
+    pub fn files_from(&self, first: FileId) -> impl Iterator<Item = FileId> {
+        (first.0..self.files.len()).map(FileId)
+    }
+
+    /// Where a line is and what it says, for showing under a diagnostic:
+    ///
+    /// ```text
+    ///  --> lib/io.green:3
+    ///   |
+    /// 3 | def double(x: Int) -> Int
+    /// ```
+    pub fn excerpt(&self, file: FileId, line: usize) -> String {
+        let file = self.get(file);
+        let mut excerpt = format!(" --> {}:{}\n", file.name, line);
+
+        if let Some(text) = file.line(line) {
+            let gutter = line.to_string().len();
+            let _ = writeln!(excerpt, "{:gutter$} |", "", gutter = gutter);
+            let _ = writeln!(excerpt, "{} | {}", line, text);
+        }
+        excerpt
+    }
+}
+
+/// Why a source file couldn't be read.
+#[derive(Debug)]
+pub enum ReadError {
+    Io(io::Error),
+    /// The file isn't UTF-8 text, like a Latin-1 script or a binary file. `text` is what it
+    /// says with the invalid bytes replaced by U+FFFD, for running it anyway.
+    NotUtf8 {
+        line: usize,
+        text: String,
+    },
+}
+
+impl fmt::Display for ReadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ReadError::Io(err) => write!(f, "{}", err),
+            ReadError::NotUtf8 { line, .. } => {
+                write!(f, "file is not valid UTF-8, from line {}", line)
+            }
+        }
+    }
+}
+
+/// Reads the text of a source file.
+pub fn read_source(path: impl AsRef<Path>) -> Result<String, ReadError> {
+    let bytes = std::fs::read(path).map_err(ReadError::Io)?;
+    String::from_utf8(bytes).map_err(|err| {
+        let bytes = err.as_bytes();
+        let valid = &bytes[..err.utf8_error().valid_up_to()];
+        ReadError::NotUtf8 {
+            line: valid.iter().filter(|b| **b == b'\n').count() + 1,
+            text: String::from_utf8_lossy(bytes).into_owned(),
+        }
+    })
+}
+
+/// Reads the text of a source file like `read_source`, replacing bytes that aren't valid UTF-8
+/// if `lossy`. The line of the first one replaced is returned with the text, to warn about.
+pub fn read_source_lossy(
+    path: impl AsRef<Path>,
+    lossy: bool,
+) -> Result<(String, Option<usize>), ReadError> {
+    match read_source(path) {
+        Ok(text) => Ok((text, None)),
+        Err(ReadError::NotUtf8 { line, text }) if lossy => Ok((text, Some(line))),
+        Err(err) => Err(err),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn excerpts_show_lines_from_any_file() {
+        let mut sources = SourceMap::new();
+        let script = sources.add("<script>", "import io\nprint 1\n");
+        let module = sources.add("lib/io.green", "def f()\r\n    return 1\nend");
+
+        assert_eq!(sources.get(module).name(), "lib/io.green");
+        assert_eq!(sources.get(module).line(1), Some("def f()"));
+        assert_eq!(sources.get(module).line(3), Some("end"));
+        assert_eq!(sources.get(module).line(4), None);
+        assert_eq!(sources.get(script).line(0), None);
+
+        assert_eq!(
+            sources.excerpt(module, 2),
+            " --> lib/io.green:2\n  |\n2 |     return 1\n"
+        );
+        assert_eq!(sources.excerpt(script, 9), " --> <script>:9\n");
+    }
+
+    #[test]
+    fn sources_that_are_not_utf8_are_reported() {
+        let path = std::env::temp_dir().join(format!("green-latin1-{}.green", std::process::id()));
+        std::fs::write(&path, b"print 1\nprint \"caf\xe9\"\n").unwrap();
+
+        let err = read_source(&path).unwrap_err();
+        assert_eq!(err.to_string(), "file is not valid UTF-8, from line 2");
+        assert!(read_source_lossy(&path, false).is_err());
+        let (text, replaced) = read_source_lossy(&path, true).unwrap();
+        assert_eq!(text, "print 1\nprint \"caf\u{FFFD}\"\n");
+        assert_eq!(replaced, Some(2));
+
+        std::fs::remove_file(&path).unwrap();
+        assert!(matches!(read_source(&path), Err(ReadError::Io(_))));
+    }
+}
@@ -0,0 +1,179 @@
+use crate::compiler::object::{NativeFn, NativeFunction};
+use crate::compiler::value::Value;
+use crate::vm::errors::RuntimeError;
+use crate::vm::vm::RunResult;
+use crate::vm::VM;
+use std::cmp::Ordering;
+
+/// The methods of arrays, like `xs.map(f)`, or `None` if arrays have no method `name`. Each
+/// gets the array as its first argument. The functions passed in see the elements as they were
+/// when the method was called, and the methods that make arrays make new ones.
+pub(crate) fn method(name: &str) -> Option<NativeFunction> {
+    let (arity, fun): (u8, NativeFn) = match name {
+        "map" => (1, map),
+        "filter" => (1, filter),
+        "reduce" => (2, reduce),
+        "sort" => (0, sort),
+        "sort_by" => (1, sort_by),
+        "contains" => (1, contains),
+        "index_of" => (1, index_of),
+        _ => return None,
+    };
+    Some(NativeFunction::new(name.to_string(), arity, fun))
+}
+
+fn items(value: &Value) -> RunResult<Vec<Value>> {
+    match value {
+        Value::Array(items) => Ok(items.to_vec()),
+        _ => Err(RuntimeError::ArgumentTypes),
+    }
+}
+
+/// xs.map(f): a new array of f(x) for each element x.
+fn map(vm: &mut VM, args: &[Value]) -> RunResult<Value> {
+    let mapped = items(&args[0])?
+        .into_iter()
+        .map(|item| vm.call_function(args[1], &[item]))
+        .collect::<RunResult<_>>()?;
+    Ok(vm.array(mapped))
+}
+
+/// xs.filter(f): a new array of the elements x for which f(x) is true.
+fn filter(vm: &mut VM, args: &[Value]) -> RunResult<Value> {
+    let mut kept = vec![];
+    for item in items(&args[0])? {
+        if bool::from(&vm.call_function(args[1], &[item])?) {
+            kept.push(item);
+        }
+    }
+    Ok(vm.array(kept))
+}
+
+/// xs.reduce(f, initial): combines the elements from the first to the last with
+/// `acc = f(acc, x)`, starting from `initial`, and returns the result.
+fn reduce(vm: &mut VM, args: &[Value]) -> RunResult<Value> {
+    items(&args[0])?
+        .into_iter()
+        .try_fold(args[2], |acc, item| vm.call_function(args[1], &[acc, item]))
+}
+
+/// xs.sort(): a new array of the elements in ascending order. They must be all numbers or all
+/// strings. NaN goes after every other number.
+fn sort(vm: &mut VM, args: &[Value]) -> RunResult<Value> {
+    let sorted = merge_sort(items(&args[0])?, &mut compare)?;
+    Ok(vm.array(sorted))
+}
+
+/// xs.sort_by(f): a new array of the elements ordered by `f(a, b)`, which returns a negative
+/// number if a goes before b, a positive one if it goes after and 0 if either will do. The
+/// order of elements f finds equal is kept.
+fn sort_by(vm: &mut VM, args: &[Value]) -> RunResult<Value> {
+    let mut compare = |a: &Value, b: &Value| match vm.call_function(args[1], &[*a, *b])? {
+        Value::Number(n) if n < 0.0 => Ok(Ordering::Less),
+        Value::Number(n) if n > 0.0 => Ok(Ordering::Greater),
+        // NaN too, like 0.
+        Value::Number(_) => Ok(Ordering::Equal),
+        _ => Err(RuntimeError::ArgumentTypes),
+    };
+    let sorted = merge_sort(items(&args[0])?, &mut compare)?;
+    Ok(vm.array(sorted))
+}
+
+fn compare(a: &Value, b: &Value) -> RunResult<Ordering> {
+    match (a, b) {
+        (Value::Number(a), Value::Number(b)) => Ok(nan_last(*a).total_cmp(&nan_last(*b))),
+        (Value::String(a), Value::String(b)) => Ok(a.cmp(b)),
+        _ => Err(RuntimeError::ArgumentTypes),
+    }
+}
+
+/// The number with any NaN made positive, which `f64::total_cmp` orders after every other
+/// number. Dividing 0 by 0 gives a negative one.
+fn nan_last(n: f64) -> f64 {
+    if n.is_nan() {
+        f64::NAN
+    } else {
+        n
+    }
+}
+
+/// Sorts the items stably, stopping at the first error `compare` returns. Unlike the sorts of
+/// std it can't panic when the comparisons contradict each other, as a script's function may.
+fn merge_sort(
+    mut items: Vec<Value>,
+    compare: &mut impl FnMut(&Value, &Value) -> RunResult<Ordering>,
+) -> RunResult<Vec<Value>> {
+    if items.len() <= 1 {
+        return Ok(items);
+    }
+    let right = items.split_off(items.len() / 2);
+    let left = merge_sort(items, compare)?;
+    let right = merge_sort(right, compare)?;
+
+    let mut merged = Vec::with_capacity(left.len() + right.len());
+    let (mut i, mut j) = (0, 0);
+    while i < left.len() && j < right.len() {
+        // Ties take from the left, which keeps equal elements in their order.
+        if compare(&right[j], &left[i])? == Ordering::Less {
+            merged.push(right[j]);
+            j += 1;
+        } else {
+            merged.push(left[i]);
+            i += 1;
+        }
+    }
+    merged.extend_from_slice(&left[i..]);
+    merged.extend_from_slice(&right[j..]);
+    Ok(merged)
+}
+
+/// xs.contains(value): whether an element is equal to `value`, as compared by `==`.
+fn contains(_vm: &mut VM, args: &[Value]) -> RunResult<Value> {
+    Ok(items(&args[0])?.contains(&args[1]).into())
+}
+
+/// xs.index_of(value): the index of the first element equal to `value`, or nil if there is
+/// none.
+fn index_of(_vm: &mut VM, args: &[Value]) -> RunResult<Value> {
+    let index = items(&args[0])?.iter().position(|item| *item == args[1]);
+    Ok(index.map_or(Value::Nil, |index| Value::Number(index as f64)))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::compiler::value::Value;
+    use crate::vm::VM;
+
+    #[test]
+    fn sorting_handles_nan_and_inconsistent_functions() {
+        let input = r#"
+        var nan = 0 / 0
+        var xs = [0 to 32]
+        xs[7] = nan
+        var sorted = xs.sort()
+
+        def by_nan(a, b)
+            return nan
+        end
+        def contrary(a, b)
+            return (a * 7 + b * 3) % 5 - 2
+        end
+        var unordered = xs.sort_by(by_nan)
+        var scrambled = xs.sort_by(contrary)
+        "#;
+
+        let mut vm = VM::new();
+        vm.interpret(input);
+
+        let numbers: Vec<String> = (0..32).filter(|n| *n != 7).map(|n| n.to_string()).collect();
+        let sorted = format!("[{}, NaN]", numbers.join(", "));
+        assert_eq!(vm.global("sorted").unwrap().to_string(), sorted);
+
+        let xs = vm.global("xs").unwrap().to_string();
+        assert_eq!(vm.global("unordered").unwrap().to_string(), xs);
+        match vm.global("scrambled") {
+            Some(Value::Array(items)) => assert_eq!(items.len(), 32),
+            other => panic!("Expected an array, got {:?}", other),
+        }
+    }
+}
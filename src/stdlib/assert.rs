@@ -0,0 +1,125 @@
+use crate::compiler::value::Value;
+use crate::vm::errors::RuntimeError;
+use crate::vm::obj::Gc;
+use crate::vm::vm::RunResult;
+use crate::vm::VM;
+
+/// assert_eq(actual, expected): fails the script unless the values are equal by contents, listing
+/// where they differ: by index in arrays, by key in maps, by field in structs and by line in
+/// strings.
+pub fn assert_eq(_vm: &mut VM, args: &[Value]) -> RunResult<Value> {
+    let (actual, expected) = (&args[0], &args[1]);
+    if actual.deep_eq(expected) {
+        return Ok(Value::Nil);
+    }
+
+    let mut differences = vec![];
+    diff(actual, expected, "value", &mut differences);
+    Err(RuntimeError::AssertionFailed(format!(
+        "assert_eq: values differ\n{}",
+        differences.join("\n")
+    )))
+}
+
+/// Adds a line to `out` for each place `actual` differs from `expected`, naming it by its path
+/// from the values compared, like `value[2]["name"]`.
+fn diff(actual: &Value, expected: &Value, path: &str, out: &mut Vec<String>) {
+    match (actual, expected) {
+        (Value::Array(a), Value::Array(e)) => {
+            for i in 0..a.len().max(e.len()) {
+                let path = format!("{}[{}]", path, i);
+                diff_entry(a.get(i), e.get(i), &path, out);
+            }
+        }
+        (Value::Map(a), Value::Map(e)) => {
+            for (key, value) in e.entries() {
+                let path = format!("{}[{}]", path, repr(key));
+                let actual = a.get(key).ok().flatten();
+                diff_entry(actual.as_ref(), Some(value), &path, out);
+            }
+            for (key, value) in a.entries() {
+                if !matches!(e.get(key), Ok(Some(_))) {
+                    let path = format!("{}[{}]", path, repr(key));
+                    diff_entry(Some(value), None, &path, out);
+                }
+            }
+        }
+        (Value::StructInstance(a), Value::StructInstance(e)) if Gc::ptr_eq(&a.def, &e.def) => {
+            let fields = a
+                .def
+                .fields()
+                .iter()
+                .zip(a.fields.iter().zip(e.fields.iter()));
+            for (name, (a, e)) in fields {
+                diff(a, e, &format!("{}.{}", path, name), out);
+            }
+        }
+        (Value::String(a), Value::String(e)) if a.contains('\n') || e.contains('\n') => {
+            if **a != **e {
+                diff_lines(a, e, path, out);
+            }
+        }
+        _ => {
+            if !actual.deep_eq(expected) {
+                out.push(format!(
+                    "  {}: {} != {}",
+                    path,
+                    repr(actual),
+                    repr(expected)
+                ));
+            }
+        }
+    }
+}
+
+fn diff_entry(actual: Option<&Value>, expected: Option<&Value>, path: &str, out: &mut Vec<String>) {
+    match (actual, expected) {
+        (Some(actual), Some(expected)) => diff(actual, expected, path, out),
+        (Some(actual), None) => out.push(format!("  {}: unexpected {}", path, repr(actual))),
+        (None, Some(expected)) => {
+            out.push(format!("  {}: missing, expected {}", path, repr(expected)))
+        }
+        (None, None) => {}
+    }
+}
+
+/// Shows the lines of two strings that differ, `-` for the expected line and `+` for the actual
+/// one, with a line of context around them.
+fn diff_lines(actual: &str, expected: &str, path: &str, out: &mut Vec<String>) {
+    let actual: Vec<&str> = actual.lines().collect();
+    let expected: Vec<&str> = expected.lines().collect();
+    let len = actual.len().max(expected.len());
+    let differs = |i: usize| actual.get(i) != expected.get(i);
+
+    out.push(format!("  {}: strings differ (- expected, + actual)", path));
+    let mut shown = None;
+    for i in 0..len {
+        let near_difference = (i.saturating_sub(1)..=i + 1).any(|j| j < len && differs(j));
+        if !near_difference {
+            continue;
+        }
+        if shown.is_some_and(|last| last + 1 < i) {
+            out.push("    ...".to_string());
+        }
+        shown = Some(i);
+
+        if differs(i) {
+            if let Some(line) = expected.get(i) {
+                out.push(format!("    - {}", line));
+            }
+            if let Some(line) = actual.get(i) {
+                out.push(format!("    + {}", line));
+            }
+        } else {
+            out.push(format!("      {}", actual[i]));
+        }
+    }
+}
+
+/// A value as it's written in Green, quoting strings.
+fn repr(value: &Value) -> String {
+    match value {
+        Value::String(s) => format!("{:?}", **s),
+        value => value.to_string(),
+    }
+}
@@ -0,0 +1,263 @@
+use crate::compiler::object::Map;
+use crate::compiler::value::Value;
+use crate::stdlib::string_builder::StringBuilder;
+use crate::vm::errors::RuntimeError;
+use crate::vm::vm::RunResult;
+use crate::vm::VM;
+use std::collections::hash_map::RandomState;
+use std::convert::TryFrom;
+use std::fmt::Write;
+use std::fs::File;
+use std::hash::{BuildHasher, Hasher};
+use std::io::Read;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// typeof(value): the name of the value's type, e.g. "Number" or the class name of an instance.
+pub fn type_of(vm: &mut VM, args: &[Value]) -> RunResult<Value> {
+    Ok(vm.string(args[0].type_name()))
+}
+
+/// str(value): the value converted to a string, the same way `print` shows it.
+pub fn str(vm: &mut VM, args: &[Value]) -> RunResult<Value> {
+    let string = vm.stringify(args[0])?;
+    Ok(vm.string(string))
+}
+
+/// The most digits `set_precision` and `format` show after the decimal point.
+const MAX_PRECISION: usize = 100;
+
+/// set_precision(digits): shows numbers printed from now on with that many digits after the
+/// decimal point, or as many as they need again for nil.
+pub fn set_precision(vm: &mut VM, args: &[Value]) -> RunResult<Value> {
+    let precision = precision_arg(args[0])?;
+    vm.set_precision(precision);
+    Ok(Value::Nil)
+}
+
+/// reset_precision(): shows printed numbers with as many digits as they need again.
+pub fn reset_precision(vm: &mut VM, _args: &[Value]) -> RunResult<Value> {
+    vm.set_precision(None);
+    Ok(Value::Nil)
+}
+
+/// format(value, digits): the value converted to a string like `str`, with that many digits
+/// after the decimal point in its numbers.
+pub fn format(vm: &mut VM, args: &[Value]) -> RunResult<Value> {
+    let precision = precision_arg(args[1])?;
+    let string = vm.format(args[0], precision)?;
+    Ok(vm.string(string))
+}
+
+fn precision_arg(value: Value) -> RunResult<Option<usize>> {
+    match value {
+        Value::Nil => Ok(None),
+        value => match usize::try_from(value)? {
+            digits if digits <= MAX_PRECISION => Ok(Some(digits)),
+            _ => Err(RuntimeError::ArgumentTypes),
+        },
+    }
+}
+
+/// hash(value): the hash of a number, bool, nil or string, as a whole number below 2^53. Values
+/// that are the same map key, like 0 and -0, hash the same.
+pub fn hash(_vm: &mut VM, args: &[Value]) -> RunResult<Value> {
+    let hash = Map::hash_key(&args[0])?;
+    Ok(Value::Number((hash & ((1 << 53) - 1)) as f64))
+}
+
+/// uuid(): a random version 4 UUID, like "3b241101-e2bb-4255-8caf-4136c566a962".
+pub fn uuid(vm: &mut VM, _args: &[Value]) -> RunResult<Value> {
+    let mut bytes = random_bytes();
+    bytes[6] = bytes[6] & 0x0f | 0x40;
+    bytes[8] = bytes[8] & 0x3f | 0x80;
+
+    let mut uuid = String::with_capacity(36);
+    for (i, b) in bytes.iter().enumerate() {
+        if matches!(i, 4 | 6 | 8 | 10) {
+            uuid.push('-');
+        }
+        let _ = write!(uuid, "{:02x}", b);
+    }
+    Ok(vm.string(uuid))
+}
+
+/// 16 bytes from the system's random source, or from the hasher std seeds randomly where there
+/// isn't one.
+fn random_bytes() -> [u8; 16] {
+    let mut bytes = [0; 16];
+    let read = File::open("/dev/urandom").and_then(|mut file| file.read_exact(&mut bytes));
+    if read.is_err() {
+        for half in bytes.chunks_mut(8) {
+            let mut hasher = RandomState::new().build_hasher();
+            hasher.write_u128(
+                SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(0, |d| d.as_nanos()),
+            );
+            half.copy_from_slice(&hasher.finish().to_le_bytes());
+        }
+    }
+    bytes
+}
+
+/// range(start, end): an array of the numbers from start up to, but not including, end.
+pub fn range(vm: &mut VM, args: &[Value]) -> RunResult<Value> {
+    match (&args[0], &args[1]) {
+        (Value::Number(start), Value::Number(end)) => {
+            let numbers = VM::range(*start, *end, 1.0)?;
+            Ok(vm.array(numbers))
+        }
+        _ => Err(RuntimeError::ArgumentTypes),
+    }
+}
+
+/// len(value): the number of elements in an array, tuple, range or map, or of characters in a
+/// string or `StringBuilder`.
+pub fn len(_vm: &mut VM, args: &[Value]) -> RunResult<Value> {
+    match &args[0] {
+        Value::Array(array) | Value::Tuple(array) => Ok(Value::Number(array.len() as f64)),
+        Value::Range(range) => Ok(Value::Number(range.len() as f64)),
+        Value::Map(map) => Ok(Value::Number(map.len() as f64)),
+        Value::String(string) => Ok(Value::Number(string.chars().count() as f64)),
+        Value::Foreign(foreign) => match foreign.get::<StringBuilder>() {
+            Some(builder) => Ok(Value::Number(builder.0.chars().count() as f64)),
+            None => Err(RuntimeError::ArgumentTypes),
+        },
+        _ => Err(RuntimeError::ArgumentTypes),
+    }
+}
+
+/// slice(array, start): a new array of the elements from index start on.
+pub fn slice(vm: &mut VM, args: &[Value]) -> RunResult<Value> {
+    match (&args[0], &args[1]) {
+        (Value::Array(array), Value::Number(start)) if *start >= 0.0 => {
+            let start = (*start as usize).min(array.len());
+            Ok(vm.array(array[start..].to_vec()))
+        }
+        _ => Err(RuntimeError::ArgumentTypes),
+    }
+}
+
+/// map(pairs): a map built from an array of `[key, value]` pairs, or a copy of a map.
+pub fn map(vm: &mut VM, args: &[Value]) -> RunResult<Value> {
+    let mut map = Map::new();
+    for pair in pair_values(vm, &args[0])? {
+        match pair {
+            Value::Array(pair) if pair.len() == 2 => map.insert(pair[0], pair[1])?,
+            _ => return Err(RuntimeError::ArgumentTypes),
+        }
+    }
+    Ok(vm.map(map))
+}
+
+/// pairs(value): the `[key, value]` pairs of a map, in insertion order, a copy of an array or the
+/// numbers of a range, so comprehensions can loop over any of them. Either way the result is a
+/// snapshot: a loop over it sees the elements as they were when it started, whatever the loop
+/// stores into the original.
+pub fn pairs(vm: &mut VM, args: &[Value]) -> RunResult<Value> {
+    let pairs = pair_values(vm, &args[0])?;
+    Ok(vm.array(pairs))
+}
+
+fn pair_values(vm: &mut VM, value: &Value) -> RunResult<Vec<Value>> {
+    match value {
+        Value::Array(array) => Ok(array.to_vec()),
+        Value::Range(range) => Ok(range.numbers().map(Value::Number).collect()),
+        Value::Map(map) => Ok(map
+            .entries()
+            .iter()
+            .map(|(key, value)| vm.array(vec![*key, *value]))
+            .collect()),
+        _ => Err(RuntimeError::ArgumentTypes),
+    }
+}
+
+/// iter(value): an iterator over an array, tuple, map, string or range, or whatever a class's
+/// `iter()` method returns. Iterators step with `has_next()` and `next()`; `for` loops use them.
+pub fn iter(vm: &mut VM, args: &[Value]) -> RunResult<Value> {
+    vm.iterate(args[0])
+}
+
+/// array(value): a new array of the elements of an array or tuple, or of the numbers of a range.
+pub fn array(vm: &mut VM, args: &[Value]) -> RunResult<Value> {
+    match &args[0] {
+        Value::Array(items) | Value::Tuple(items) => Ok(vm.array(items.to_vec())),
+        Value::Range(range) => Ok(vm.array(range.numbers().map(Value::Number).collect())),
+        _ => Err(RuntimeError::ArgumentTypes),
+    }
+}
+
+/// capture(f): calls `f` without arguments and returns what it printed as a string, instead of
+/// writing it out.
+pub fn capture(vm: &mut VM, args: &[Value]) -> RunResult<Value> {
+    let callee = args[0];
+    let (result, output) = vm.capture_output(|vm| vm.call_function(callee, &[]));
+    result?;
+    Ok(vm.string(output))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::compiler::value::Value;
+    use crate::vm::VM;
+
+    #[test]
+    fn hash_functions_hash_keys_and_digest_data() {
+        let input = r#"
+        var same = hash("key") == hash("key")
+        var zeros = hash(0) == hash(-0)
+        var different = hash("a") != hash("b")
+        var whole = hash(1.5) % 1 == 0
+        var sha = sha256("abc")
+        var sha_bytes = sha256(io.encode("abc", "utf-8"))
+        var md = md5("")
+        "#;
+
+        let mut vm = VM::new();
+        vm.interpret(input);
+
+        assert!(matches!(vm.global("same"), Some(Value::True)));
+        assert!(matches!(vm.global("zeros"), Some(Value::True)));
+        assert!(matches!(vm.global("different"), Some(Value::True)));
+        assert!(matches!(vm.global("whole"), Some(Value::True)));
+        let sha = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+        assert_eq!(vm.global("sha").unwrap().as_string(), sha);
+        assert_eq!(vm.global("sha_bytes").unwrap().as_string(), sha);
+        assert_eq!(
+            vm.global("md").unwrap().as_string(),
+            "d41d8cd98f00b204e9800998ecf8427e"
+        );
+    }
+
+    #[test]
+    fn hash_rejects_values_that_cant_be_keys() {
+        let mut vm = VM::new();
+        let report = vm.run_script("<script>", "hash([1])\n").unwrap_err();
+        assert!(
+            report.contains("Can't use a value of type Array as a map key."),
+            "{}",
+            report
+        );
+    }
+
+    #[test]
+    fn uuid_returns_distinct_version_4_uuids() {
+        let mut vm = VM::new();
+        vm.interpret("var a = uuid()\nvar b = uuid()\n");
+
+        let (a, b) = (
+            vm.global("a").unwrap().as_string(),
+            vm.global("b").unwrap().as_string(),
+        );
+        assert_ne!(a, b);
+        assert_eq!(a.len(), 36);
+        let groups: Vec<&str> = a.split('-').collect();
+        assert_eq!(
+            groups.iter().map(|group| group.len()).collect::<Vec<_>>(),
+            [8, 4, 4, 4, 12]
+        );
+        assert!(groups[2].starts_with('4'));
+        assert!(groups[3].starts_with(['8', '9', 'a', 'b']));
+        assert!(a.chars().all(|c| c == '-' || c.is_ascii_hexdigit()));
+    }
+}
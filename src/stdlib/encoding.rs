@@ -0,0 +1,141 @@
+use crate::compiler::value::Value;
+use crate::stdlib::io::{bytes_arg, bytes_value, string_arg};
+use crate::vm::vm::RunResult;
+use crate::vm::VM;
+
+const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+const HEX: &[u8; 16] = b"0123456789abcdef";
+
+/// base64.encode(data): a string or array of bytes as padded, standard alphabet base64.
+pub fn base64_encode(vm: &mut VM, args: &[Value]) -> RunResult<Value> {
+    let data = data_arg(&args[0])?;
+    let mut encoded = String::with_capacity(data.len().div_ceil(3) * 4);
+
+    for group in data.chunks(3) {
+        let bits = group
+            .iter()
+            .enumerate()
+            .fold(0u32, |bits, (i, &b)| bits | (b as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= group.len() {
+                encoded.push(BASE64[(bits >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    Ok(vm.string(encoded))
+}
+
+/// base64.decode(s): the bytes a base64 string encodes, padded or not. Returns an Error if the
+/// string isn't base64.
+pub fn base64_decode(vm: &mut VM, args: &[Value]) -> RunResult<Value> {
+    let s = string_arg(&args[0])?.trim_end_matches('=');
+    let mut bytes = Vec::with_capacity(s.len() * 3 / 4);
+    let (mut bits, mut count) = (0u32, 0);
+
+    for c in s.bytes() {
+        let digit = match BASE64.iter().position(|&d| d == c) {
+            Some(digit) => digit as u32,
+            None => return Ok(vm.error(format!("Invalid base64 character {:?}", c as char))),
+        };
+        bits = bits << 6 | digit;
+        count += 6;
+        if count >= 8 {
+            count -= 8;
+            bytes.push((bits >> count) as u8);
+        }
+    }
+    if count >= 6 {
+        return Ok(vm.error("Invalid base64 length".to_string()));
+    }
+    Ok(bytes_value(vm, bytes))
+}
+
+/// hex.encode(data): a string or array of bytes as lowercase hexadecimal, two digits a byte.
+pub fn hex_encode(vm: &mut VM, args: &[Value]) -> RunResult<Value> {
+    let data = data_arg(&args[0])?;
+    let encoded = data
+        .iter()
+        .flat_map(|&b| {
+            [
+                HEX[(b >> 4) as usize] as char,
+                HEX[(b & 0xf) as usize] as char,
+            ]
+        })
+        .collect();
+    Ok(vm.string(encoded))
+}
+
+/// hex.decode(s): the bytes a hexadecimal string encodes, in either case. Returns an Error if
+/// the string isn't an even number of hex digits.
+pub fn hex_decode(vm: &mut VM, args: &[Value]) -> RunResult<Value> {
+    let s = string_arg(&args[0])?;
+    if s.len() % 2 != 0 {
+        return Ok(vm.error("Hex string has an odd number of digits".to_string()));
+    }
+
+    let mut bytes = Vec::with_capacity(s.len() / 2);
+    for pair in s.as_bytes().chunks(2) {
+        match (hex_digit(pair[0]), hex_digit(pair[1])) {
+            (Some(high), Some(low)) => bytes.push(high << 4 | low),
+            _ => return Ok(vm.error(format!("Invalid hex digits in {:?}", s))),
+        }
+    }
+    Ok(bytes_value(vm, bytes))
+}
+
+fn hex_digit(c: u8) -> Option<u8> {
+    (c as char).to_digit(16).map(|digit| digit as u8)
+}
+
+/// Strings are encoded as their utf-8 bytes.
+pub(super) fn data_arg(value: &Value) -> RunResult<Vec<u8>> {
+    match value {
+        Value::String(s) => Ok(s.as_bytes().to_vec()),
+        _ => bytes_arg(value),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::compiler::value::Value;
+    use crate::vm::VM;
+
+    #[test]
+    fn base64_and_hex_round_trip_strings_and_bytes() {
+        let input = r#"
+        var empty = base64.encode("")
+        var one = base64.encode("f")
+        var two = base64.encode("fo")
+        var three = base64.encode("foo")
+        var binary = base64.encode([0, 255, 16])
+        var decoded = io.decode(base64.decode("Zm9vYmE="), "utf-8")
+        var unpadded = io.decode(base64.decode("Zm9vYmE"), "utf-8")
+        var bad_base64 = base64.decode("Zm9v!") is Error
+
+        var hexed = hex.encode([0, 255, 16])
+        var unhexed = io.decode(hex.decode("6869"), "utf-8")
+        var upper = hex.decode("FF")[0]
+        var odd = hex.decode("abc") is Error
+        var bad_hex = hex.decode("+f") is Error
+        "#;
+
+        let mut vm = VM::new();
+        vm.interpret(input);
+
+        assert_eq!(vm.global("empty").unwrap().as_string(), "");
+        assert_eq!(vm.global("one").unwrap().as_string(), "Zg==");
+        assert_eq!(vm.global("two").unwrap().as_string(), "Zm8=");
+        assert_eq!(vm.global("three").unwrap().as_string(), "Zm9v");
+        assert_eq!(vm.global("binary").unwrap().as_string(), "AP8Q");
+        assert_eq!(vm.global("decoded").unwrap().as_string(), "fooba");
+        assert_eq!(vm.global("unpadded").unwrap().as_string(), "fooba");
+        assert!(matches!(vm.global("bad_base64"), Some(Value::True)));
+        assert_eq!(vm.global("hexed").unwrap().as_string(), "00ff10");
+        assert_eq!(vm.global("unhexed").unwrap().as_string(), "hi");
+        assert_eq!(vm.global("upper").unwrap().as_number(), 255.0);
+        assert!(matches!(vm.global("odd"), Some(Value::True)));
+        assert!(matches!(vm.global("bad_hex"), Some(Value::True)));
+    }
+}
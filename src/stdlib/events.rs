@@ -0,0 +1,33 @@
+use crate::compiler::value::Value;
+use crate::stdlib::io::string_arg;
+use crate::vm::errors::RuntimeError;
+use crate::vm::vm::RunResult;
+use crate::vm::VM;
+
+/// events.on(name, fn): calls fn with the arguments of every emit of the event named `name`,
+/// from the script or the application running it. Returns an id to unsubscribe with.
+pub fn on(vm: &mut VM, args: &[Value]) -> RunResult<Value> {
+    let id = vm.on(string_arg(&args[0])?, args[1]);
+    Ok(Value::Number(id as f64))
+}
+
+/// events.off(id): unsubscribes a function subscribed with `on`. Returns whether it was still
+/// subscribed.
+pub fn off(vm: &mut VM, args: &[Value]) -> RunResult<Value> {
+    match &args[0] {
+        Value::Number(id) => Ok(vm.off(*id as u64).into()),
+        _ => Err(RuntimeError::ArgumentTypes),
+    }
+}
+
+/// events.emit(name, args): calls the functions subscribed to the event with the elements of
+/// the array `args` as arguments. Returns how many were called.
+pub fn emit(vm: &mut VM, args: &[Value]) -> RunResult<Value> {
+    let event = string_arg(&args[0])?.to_string();
+    let event_args = match &args[1] {
+        Value::Array(items) => items.to_vec(),
+        _ => return Err(RuntimeError::ArgumentTypes),
+    };
+    let called = vm.emit_event(&event, event_args)?;
+    Ok(Value::Number(called as f64))
+}
@@ -0,0 +1,153 @@
+use crate::compiler::object::Map;
+use crate::compiler::value::Value;
+use crate::stdlib::io::string_arg;
+use crate::stdlib::json;
+use crate::vm::vm::RunResult;
+use crate::vm::VM;
+use std::time::Duration;
+
+/// How long a request can take, connecting included, before it fails.
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// http.get(url): fetches the url. Returns a map of the response's "status", "headers" and
+/// "body", or an Error if no response came back.
+pub fn get(vm: &mut VM, args: &[Value]) -> RunResult<Value> {
+    let url = string_arg(&args[0])?;
+    let result = agent().get(url).call();
+    response(vm, result)
+}
+
+/// http.post(url, body): sends the body to the url, a string as text and anything else as JSON.
+/// Returns the response like `http.get`.
+pub fn post(vm: &mut VM, args: &[Value]) -> RunResult<Value> {
+    let url = string_arg(&args[0])?;
+    let request = agent().post(url);
+    let result = match &args[1] {
+        Value::String(body) => request
+            .set("Content-Type", "text/plain; charset=utf-8")
+            .send_string(body),
+        value => match json::encode(value, false) {
+            Ok(body) => request
+                .set("Content-Type", "application/json")
+                .send_string(&body),
+            Err(message) => return Ok(vm.error(message)),
+        },
+    };
+    response(vm, result)
+}
+
+fn agent() -> ureq::Agent {
+    ureq::AgentBuilder::new().timeout(TIMEOUT).build()
+}
+
+/// The response as a map. Responses with an error status are returned like any other, so only
+/// failing to get one at all is an Error.
+fn response(vm: &mut VM, result: Result<ureq::Response, ureq::Error>) -> RunResult<Value> {
+    let response = match result {
+        Ok(response) | Err(ureq::Error::Status(_, response)) => response,
+        Err(err) => return Ok(vm.error(format!("Request failed: {}", err))),
+    };
+
+    let mut headers = Map::new();
+    for name in response.headers_names() {
+        if let Some(value) = response.header(&name) {
+            let value = vm.string(value.to_string());
+            headers
+                .insert(vm.string(name), value)
+                .expect("strings to be hashable");
+        }
+    }
+    let status = response.status();
+    let body = match response.into_string() {
+        Ok(body) => body,
+        Err(err) => return Ok(vm.error(format!("Could not read the response: {}", err))),
+    };
+
+    let mut map = Map::new();
+    let fields = [
+        ("status", Value::Number(status as f64)),
+        ("headers", vm.map(headers)),
+        ("body", vm.string(body)),
+    ];
+    for (key, value) in fields {
+        map.insert(vm.string(key.to_string()), value)
+            .expect("strings to be hashable");
+    }
+    Ok(vm.map(map))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::compiler::value::Value;
+    use crate::vm::VM;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+
+    /// Answers one request with a 201 that echoes the request's method and body back.
+    fn serve_once() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut request_line = String::new();
+            reader.read_line(&mut request_line).unwrap();
+            let mut length = 0;
+            loop {
+                let mut header = String::new();
+                reader.read_line(&mut header).unwrap();
+                if header.trim().is_empty() {
+                    break;
+                }
+                if let Some(value) = header.to_lowercase().strip_prefix("content-length:") {
+                    length = value.trim().parse().unwrap();
+                }
+            }
+            let mut body = vec![0; length];
+            reader.read_exact(&mut body).unwrap();
+
+            let method = request_line.split(' ').next().unwrap();
+            let reply = format!("{} {}", method, String::from_utf8(body).unwrap());
+            let response = format!(
+                "HTTP/1.1 201 Created\r\nContent-Length: {}\r\nX-Test: yes\r\n\r\n{}",
+                reply.len(),
+                reply
+            );
+            reader.get_mut().write_all(response.as_bytes()).unwrap();
+        });
+        format!("http://{}/", address)
+    }
+
+    #[test]
+    fn requests_return_status_headers_and_body() {
+        let mut vm = VM::new();
+        vm.bind_global("first", serve_once().into());
+        vm.bind_global("second", serve_once().into());
+        let input = r#"
+        var got = http.get(first)
+        var posted = http.post(second, {"name": "green"})
+        var failed = http.get("http://127.0.0.1:1/")
+        "#;
+        vm.interpret(input);
+
+        let field = |vm: &VM, name: &str, key: &str| match vm.global(name) {
+            Some(Value::Map(map)) => map.get(&Value::from(key)).unwrap().unwrap(),
+            other => panic!("Expected a response, got {:?}", other),
+        };
+        assert_eq!(field(&vm, "got", "status"), Value::Number(201.0));
+        assert_eq!(field(&vm, "got", "body").as_string(), "GET ");
+        assert_eq!(
+            field(&vm, "got", "headers").to_string(),
+            r#"{"content-length": "4", "x-test": "yes"}"#
+        );
+        assert_eq!(
+            field(&vm, "posted", "body").as_string(),
+            r#"POST {"name":"green"}"#
+        );
+        assert!(matches!(
+            vm.global("failed"),
+            Some(Value::StructInstance(_))
+        ));
+    }
+}
@@ -0,0 +1,244 @@
+use crate::compiler::value::Value;
+use crate::vm::errors::RuntimeError;
+use crate::vm::vm::RunResult;
+use crate::vm::VM;
+use std::fs;
+use std::fs::OpenOptions;
+use std::io;
+use std::io::Write;
+use std::path::Path;
+
+/// io.read_file(path): the contents of a file as a string, or an Error if it can't be read.
+pub fn read_file(vm: &mut VM, args: &[Value]) -> RunResult<Value> {
+    let path = string_arg(&args[0])?;
+    Ok(match fs::read_to_string(path) {
+        Ok(contents) => vm.string(contents),
+        Err(err) => io_error(vm, "read", path, err),
+    })
+}
+
+/// io.write_file(path, s): replaces the contents of a file with a string, creating the file if
+/// needed. Returns nil, or an Error if the file can't be written.
+pub fn write_file(vm: &mut VM, args: &[Value]) -> RunResult<Value> {
+    let (path, contents) = (string_arg(&args[0])?, string_arg(&args[1])?);
+    Ok(match fs::write(path, contents) {
+        Ok(_) => Value::Nil,
+        Err(err) => io_error(vm, "write", path, err),
+    })
+}
+
+/// io.append(path, s): adds a string to the end of a file, creating the file if needed. Returns
+/// nil, or an Error if the file can't be written.
+pub fn append(vm: &mut VM, args: &[Value]) -> RunResult<Value> {
+    let (path, contents) = (string_arg(&args[0])?, string_arg(&args[1])?);
+    let appended = OpenOptions::new()
+        .append(true)
+        .create(true)
+        .open(path)
+        .and_then(|mut file| file.write_all(contents.as_bytes()));
+    Ok(match appended {
+        Ok(_) => Value::Nil,
+        Err(err) => io_error(vm, "append to", path, err),
+    })
+}
+
+/// io.read_bytes(path): the contents of a file as an array of bytes, or an Error if it can't be
+/// read.
+pub fn read_bytes(vm: &mut VM, args: &[Value]) -> RunResult<Value> {
+    let path = string_arg(&args[0])?;
+    Ok(match fs::read(path) {
+        Ok(contents) => bytes_value(vm, contents),
+        Err(err) => io_error(vm, "read", path, err),
+    })
+}
+
+/// io.write_bytes(path, bytes): replaces the contents of a file with an array of bytes. Returns
+/// nil, or an Error if the file can't be written.
+pub fn write_bytes(vm: &mut VM, args: &[Value]) -> RunResult<Value> {
+    let (path, contents) = (string_arg(&args[0])?, bytes_arg(&args[1])?);
+    Ok(match fs::write(path, contents) {
+        Ok(_) => Value::Nil,
+        Err(err) => io_error(vm, "write", path, err),
+    })
+}
+
+/// io.encode(s, encoding): the bytes of a string in "utf-8" or "latin-1". Returns an Error if
+/// the encoding is unknown or can't represent a character of the string.
+pub fn encode(vm: &mut VM, args: &[Value]) -> RunResult<Value> {
+    let (s, encoding) = (string_arg(&args[0])?, string_arg(&args[1])?);
+    let bytes = match Encoding::named(encoding) {
+        Some(Encoding::Utf8) => s.as_bytes().to_vec(),
+        Some(Encoding::Latin1) => match s.chars().find(|c| *c as u32 > 0xff) {
+            Some(c) => return Ok(vm.error(format!("Can't encode {:?} in latin-1", c))),
+            None => s.chars().map(|c| c as u8).collect(),
+        },
+        None => return Ok(unknown_encoding(vm, encoding)),
+    };
+    Ok(bytes_value(vm, bytes))
+}
+
+/// io.decode(bytes, encoding): the string an array of bytes encodes in "utf-8" or "latin-1".
+/// Returns an Error if the encoding is unknown or the bytes aren't valid in it.
+pub fn decode(vm: &mut VM, args: &[Value]) -> RunResult<Value> {
+    let (bytes, encoding) = (bytes_arg(&args[0])?, string_arg(&args[1])?);
+    Ok(match Encoding::named(encoding) {
+        Some(Encoding::Utf8) => match String::from_utf8(bytes) {
+            Ok(s) => vm.string(s),
+            Err(err) => vm.error(format!(
+                "Invalid utf-8 at byte {}",
+                err.utf8_error().valid_up_to()
+            )),
+        },
+        Some(Encoding::Latin1) => vm.string(latin1(&bytes)),
+        None => unknown_encoding(vm, encoding),
+    })
+}
+
+/// io.decode_lossy(bytes, encoding): like `decode`, but replaces bytes that aren't valid in the
+/// encoding with U+FFFD instead of failing.
+pub fn decode_lossy(vm: &mut VM, args: &[Value]) -> RunResult<Value> {
+    let (bytes, encoding) = (bytes_arg(&args[0])?, string_arg(&args[1])?);
+    Ok(match Encoding::named(encoding) {
+        Some(Encoding::Utf8) => vm.string(String::from_utf8_lossy(&bytes).into_owned()),
+        Some(Encoding::Latin1) => vm.string(latin1(&bytes)),
+        None => unknown_encoding(vm, encoding),
+    })
+}
+
+/// io.exists(path): whether a file or directory exists at the path.
+pub fn exists(_vm: &mut VM, args: &[Value]) -> RunResult<Value> {
+    Ok(Path::new(string_arg(&args[0])?).exists().into())
+}
+
+pub(super) fn string_arg(value: &Value) -> RunResult<&str> {
+    match value {
+        Value::String(s) => Ok(s),
+        _ => Err(RuntimeError::ArgumentTypes),
+    }
+}
+
+enum Encoding {
+    Utf8,
+    Latin1,
+}
+
+impl Encoding {
+    fn named(name: &str) -> Option<Encoding> {
+        match name.to_ascii_lowercase().as_str() {
+            "utf-8" | "utf8" => Some(Encoding::Utf8),
+            "latin-1" | "latin1" | "iso-8859-1" => Some(Encoding::Latin1),
+            _ => None,
+        }
+    }
+}
+
+/// Every byte is a character in latin-1, the first 256 code points.
+fn latin1(bytes: &[u8]) -> String {
+    bytes.iter().map(|&b| b as char).collect()
+}
+
+fn unknown_encoding(vm: &mut VM, name: &str) -> Value {
+    vm.error(format!("Unknown encoding {}", name))
+}
+
+/// Bytes are arrays of numbers from 0 to 255.
+pub(super) fn bytes_arg(value: &Value) -> RunResult<Vec<u8>> {
+    let items = match value {
+        Value::Array(items) => items,
+        _ => return Err(RuntimeError::ArgumentTypes),
+    };
+    items
+        .iter()
+        .map(|item| match item {
+            Value::Number(n) if n.fract() == 0.0 && (0.0..=255.0).contains(n) => Ok(*n as u8),
+            _ => Err(RuntimeError::ArgumentTypes),
+        })
+        .collect()
+}
+
+pub(super) fn bytes_value(vm: &mut VM, bytes: Vec<u8>) -> Value {
+    vm.array(bytes.into_iter().map(|b| Value::Number(b as f64)).collect())
+}
+
+fn io_error(vm: &mut VM, action: &str, path: &str, err: io::Error) -> Value {
+    vm.error(format!("Could not {} {}: {}", action, path, err))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::compiler::value::Value;
+    use crate::vm::VM;
+
+    #[test]
+    fn io_functions_read_and_write_files() {
+        let dir = std::env::temp_dir().join(format!("green-io-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("notes.txt");
+
+        let input = format!(
+            r#"
+        var path = "{}"
+        var before = io.exists(path)
+        io.write_file(path, "one")
+        io.append(path, ", two")
+        var contents = io.read_file(path)
+        var after = io.exists(path)
+
+        var missing = io.read_file("{}")
+        var failed = missing is Error
+        var message = missing.message
+        var checked = contents is Error
+        "#,
+            path.display(),
+            dir.join("missing.txt").display()
+        );
+
+        let mut vm = VM::new();
+        vm.interpret(input);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert!(matches!(vm.global("before"), Some(Value::False)));
+        assert_eq!(vm.global("contents").unwrap().as_string(), "one, two");
+        assert!(matches!(vm.global("after"), Some(Value::True)));
+        assert!(matches!(vm.global("failed"), Some(Value::True)));
+        assert!(matches!(vm.global("checked"), Some(Value::False)));
+        assert!(vm
+            .global("message")
+            .unwrap()
+            .as_string()
+            .starts_with("Could not read "));
+    }
+
+    #[test]
+    fn encode_and_decode_convert_between_strings_and_bytes() {
+        let input = r#"
+        var utf8 = io.encode("né", "utf-8")
+        var latin = io.encode("né", "latin-1")
+        var unencodable = io.encode("€", "latin-1") is Error
+        var unknown = io.encode("a", "ebcdic") is Error
+
+        var back = io.decode(utf8, "UTF-8")
+        var from_latin = io.decode(latin, "latin1")
+        var invalid = io.decode(latin, "utf-8") is Error
+        var replaced = io.decode_lossy(latin, "utf-8")
+        "#;
+
+        let mut vm = VM::new();
+        vm.interpret(input);
+
+        let bytes = |name: &str| -> Vec<f64> {
+            match vm.global(name).unwrap() {
+                Value::Array(items) => items.iter().map(|item| item.as_number()).collect(),
+                other => panic!("{} is not an array", other),
+            }
+        };
+        assert_eq!(bytes("utf8"), [110.0, 195.0, 169.0]);
+        assert_eq!(bytes("latin"), [110.0, 233.0]);
+        assert!(matches!(vm.global("unencodable"), Some(Value::True)));
+        assert!(matches!(vm.global("unknown"), Some(Value::True)));
+        assert_eq!(vm.global("back").unwrap().as_string(), "né");
+        assert_eq!(vm.global("from_latin").unwrap().as_string(), "né");
+        assert!(matches!(vm.global("invalid"), Some(Value::True)));
+        assert_eq!(vm.global("replaced").unwrap().as_string(), "n\u{fffd}");
+    }
+}
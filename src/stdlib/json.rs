@@ -0,0 +1,483 @@
+use crate::compiler::object::Map;
+use crate::compiler::value::Value;
+use crate::stdlib::io::string_arg;
+use crate::vm::vm::RunResult;
+use crate::vm::VM;
+use std::fmt::Write;
+
+/// How deeply arrays and objects can nest, so that a value containing itself or a deeply nested
+/// document can't overflow the stack.
+const MAX_DEPTH: usize = 512;
+
+/// json.parse(s): the value a JSON document describes. Objects become maps with string keys,
+/// arrays become arrays and null becomes nil. Returns an Error if the string isn't JSON.
+pub fn parse(vm: &mut VM, args: &[Value]) -> RunResult<Value> {
+    let text = string_arg(&args[0])?;
+    let mut parser = Parser {
+        vm,
+        text,
+        position: 0,
+    };
+
+    Ok(match parser.document() {
+        Ok(value) => value,
+        Err(message) => {
+            let (line, column) = parser.location();
+            vm.error(format!(
+                "Invalid JSON, {} at line {}, column {}",
+                message.to_lowercase(),
+                line,
+                column
+            ))
+        }
+    })
+}
+
+/// json.stringify(value, pretty): the value as JSON, indented by two spaces a level if pretty is
+/// true. Maps need string keys, and instances of classes and structs become objects of their
+/// fields. Returns an Error for values JSON can't describe, like functions or NaN.
+pub fn stringify(vm: &mut VM, args: &[Value]) -> RunResult<Value> {
+    Ok(match encode(&args[0], bool::from(&args[1])) {
+        Ok(json) => vm.string(json),
+        Err(message) => vm.error(message),
+    })
+}
+
+/// The value as JSON, or why it can't be written as JSON.
+pub(crate) fn encode(value: &Value, pretty: bool) -> Result<String, String> {
+    let mut writer = Writer {
+        out: String::new(),
+        pretty,
+    };
+    writer.value(value, 0)?;
+    Ok(writer.out)
+}
+
+struct Parser<'a> {
+    vm: &'a mut VM,
+    text: &'a str,
+    position: usize,
+}
+
+type ParseResult<T> = Result<T, &'static str>;
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<char> {
+        self.text[self.position..].chars().next()
+    }
+
+    fn next(&mut self) -> ParseResult<char> {
+        let c = self.peek().ok_or("Unexpected end of the document")?;
+        self.position += c.len_utf8();
+        Ok(c)
+    }
+
+    fn expect(&mut self, expected: char, message: &'static str) -> ParseResult<()> {
+        match self.peek() {
+            Some(c) if c == expected => {
+                self.position += 1;
+                Ok(())
+            }
+            _ => Err(message),
+        }
+    }
+
+    fn skip_whitespace(&mut self) {
+        while let Some(' ' | '\t' | '\n' | '\r') = self.peek() {
+            self.position += 1;
+        }
+    }
+
+    /// A value with nothing but whitespace around it.
+    fn document(&mut self) -> ParseResult<Value> {
+        self.skip_whitespace();
+        let value = self.value(0)?;
+        self.skip_whitespace();
+        match self.peek() {
+            None => Ok(value),
+            Some(_) => Err("Expected the end of the document"),
+        }
+    }
+
+    /// The line and column, counting from 1, of where parsing stopped.
+    fn location(&self) -> (usize, usize) {
+        let before = &self.text[..self.position];
+        let line = before.matches('\n').count() + 1;
+        let column = before.chars().rev().take_while(|&c| c != '\n').count() + 1;
+        (line, column)
+    }
+
+    fn value(&mut self, depth: usize) -> ParseResult<Value> {
+        if depth > MAX_DEPTH {
+            return Err("Nested too deeply");
+        }
+
+        match self.peek() {
+            Some('{') => self.object(depth),
+            Some('[') => self.array(depth),
+            Some('"') => {
+                let string = self.string()?;
+                Ok(self.vm.string(string))
+            }
+            Some('-' | '0'..='9') => self.number(),
+            Some(_) => {
+                for (word, value) in [("true", Value::True), ("false", Value::False)] {
+                    if self.text[self.position..].starts_with(word) {
+                        self.position += word.len();
+                        return Ok(value);
+                    }
+                }
+                if self.text[self.position..].starts_with("null") {
+                    self.position += "null".len();
+                    return Ok(Value::Nil);
+                }
+                Err("Expected a value")
+            }
+            None => Err("Expected a value"),
+        }
+    }
+
+    fn object(&mut self, depth: usize) -> ParseResult<Value> {
+        self.position += 1; // '{'
+        let mut map = Map::new();
+
+        self.skip_whitespace();
+        if self.peek() == Some('}') {
+            self.position += 1;
+            return Ok(self.vm.map(map));
+        }
+        loop {
+            self.skip_whitespace();
+            if self.peek() != Some('"') {
+                return Err("Expected a string key");
+            }
+            let key = self.string()?;
+            let key = self.vm.string(key);
+            self.skip_whitespace();
+            self.expect(':', "Expected ':' after a key")?;
+            self.skip_whitespace();
+            let value = self.value(depth + 1)?;
+            map.insert(key, value).expect("strings to be hashable");
+
+            self.skip_whitespace();
+            match self.next()? {
+                ',' => continue,
+                '}' => return Ok(self.vm.map(map)),
+                _ => return Err("Expected ',' or '}' in an object"),
+            }
+        }
+    }
+
+    fn array(&mut self, depth: usize) -> ParseResult<Value> {
+        self.position += 1; // '['
+        let mut items = vec![];
+
+        self.skip_whitespace();
+        if self.peek() == Some(']') {
+            self.position += 1;
+            return Ok(self.vm.array(items));
+        }
+        loop {
+            self.skip_whitespace();
+            items.push(self.value(depth + 1)?);
+
+            self.skip_whitespace();
+            match self.next()? {
+                ',' => continue,
+                ']' => return Ok(self.vm.array(items)),
+                _ => return Err("Expected ',' or ']' in an array"),
+            }
+        }
+    }
+
+    fn number(&mut self) -> ParseResult<Value> {
+        let start = self.position;
+        let digits = |parser: &mut Self| {
+            let from = parser.position;
+            while let Some('0'..='9') = parser.peek() {
+                parser.position += 1;
+            }
+            parser.position > from
+        };
+
+        if self.peek() == Some('-') {
+            self.position += 1;
+        }
+        if self.peek() == Some('0') {
+            self.position += 1;
+        } else if !digits(self) {
+            return Err("Expected a digit");
+        }
+        if self.peek() == Some('.') {
+            self.position += 1;
+            if !digits(self) {
+                return Err("Expected a digit after the decimal point");
+            }
+        }
+        if let Some('e' | 'E') = self.peek() {
+            self.position += 1;
+            if let Some('+' | '-') = self.peek() {
+                self.position += 1;
+            }
+            if !digits(self) {
+                return Err("Expected a digit in the exponent");
+            }
+        }
+
+        let number = self.text[start..self.position]
+            .parse()
+            .map_err(|_| "Invalid number")?;
+        Ok(Value::Number(number))
+    }
+
+    fn string(&mut self) -> ParseResult<String> {
+        self.position += 1; // '"'
+        let mut string = String::new();
+
+        loop {
+            match self.next()? {
+                '"' => return Ok(string),
+                '\\' => {
+                    let escaped = match self.next()? {
+                        '"' => '"',
+                        '\\' => '\\',
+                        '/' => '/',
+                        'b' => '\u{8}',
+                        'f' => '\u{c}',
+                        'n' => '\n',
+                        'r' => '\r',
+                        't' => '\t',
+                        'u' => self.unicode_escape()?,
+                        _ => return Err("Invalid escape in a string"),
+                    };
+                    string.push(escaped);
+                }
+                c if c < ' ' => return Err("Unescaped control character in a string"),
+                c => string.push(c),
+            }
+        }
+    }
+
+    /// The character of a `\uXXXX` escape, the 'u' having been read already. Characters outside
+    /// the Basic Multilingual Plane are written as two escapes, a surrogate pair.
+    fn unicode_escape(&mut self) -> ParseResult<char> {
+        let high = self.hex4()?;
+        let code = match high {
+            0xD800..=0xDBFF => {
+                if !self.text[self.position..].starts_with("\\u") {
+                    return Err("Expected the second half of a surrogate pair");
+                }
+                self.position += 2;
+                let low = self.hex4()?;
+                if !(0xDC00..=0xDFFF).contains(&low) {
+                    return Err("Invalid surrogate pair");
+                }
+                0x10000 + ((high - 0xD800) << 10) + (low - 0xDC00)
+            }
+            _ => high,
+        };
+        char::from_u32(code).ok_or("Invalid unicode escape")
+    }
+
+    fn hex4(&mut self) -> ParseResult<u32> {
+        let digits = self
+            .text
+            .get(self.position..self.position + 4)
+            .filter(|digits| digits.bytes().all(|b| b.is_ascii_hexdigit()))
+            .ok_or("Invalid unicode escape")?;
+        let code = u32::from_str_radix(digits, 16).map_err(|_| "Invalid unicode escape")?;
+        self.position += 4;
+        Ok(code)
+    }
+}
+
+struct Writer {
+    out: String,
+    pretty: bool,
+}
+
+impl Writer {
+    fn value(&mut self, value: &Value, depth: usize) -> Result<(), String> {
+        if depth > MAX_DEPTH {
+            return Err("Can't encode a value nested this deeply as JSON".to_string());
+        }
+
+        match value {
+            Value::Nil => self.out.push_str("null"),
+            Value::True => self.out.push_str("true"),
+            Value::False => self.out.push_str("false"),
+            Value::Number(n) if n.is_finite() => {
+                let _ = write!(self.out, "{}", n);
+            }
+            Value::String(s) => self.string(s),
+            Value::Array(items) | Value::Tuple(items) => {
+                self.sequence('[', ']', items.iter(), depth, |writer, item| {
+                    writer.value(item, depth + 1)
+                })?;
+            }
+            Value::Map(map) => {
+                let mut entries = vec![];
+                for (key, value) in map.entries() {
+                    match key {
+                        Value::String(key) => entries.push((key.to_string(), *value)),
+                        _ => {
+                            return Err(format!(
+                                "Can't encode a map with a {} key as JSON",
+                                key.type_name()
+                            ))
+                        }
+                    }
+                }
+                self.object(entries, depth)?;
+            }
+            Value::Instance(instance) => {
+                let fields = instance.fields().map(|(k, v)| (k.to_string(), v)).collect();
+                self.object(fields, depth)?;
+            }
+            Value::StructInstance(instance) => {
+                let fields = instance
+                    .def
+                    .fields()
+                    .iter()
+                    .cloned()
+                    .zip(instance.fields.iter().copied())
+                    .collect();
+                self.object(fields, depth)?;
+            }
+            _ => {
+                let type_name = match value {
+                    Value::Number(_) => value.to_string(),
+                    _ => format!("a {}", value.type_name()),
+                };
+                return Err(format!("Can't encode {} as JSON", type_name));
+            }
+        }
+        Ok(())
+    }
+
+    fn object(&mut self, entries: Vec<(String, Value)>, depth: usize) -> Result<(), String> {
+        self.sequence('{', '}', entries.iter(), depth, |writer, (key, value)| {
+            writer.string(key);
+            writer.out.push(':');
+            if writer.pretty {
+                writer.out.push(' ');
+            }
+            writer.value(value, depth + 1)
+        })
+    }
+
+    /// Writes items between brackets, separated by commas, each on its own line when pretty.
+    fn sequence<T>(
+        &mut self,
+        open: char,
+        close: char,
+        items: impl ExactSizeIterator<Item = T>,
+        depth: usize,
+        mut write: impl FnMut(&mut Self, T) -> Result<(), String>,
+    ) -> Result<(), String> {
+        self.out.push(open);
+        if items.len() == 0 {
+            self.out.push(close);
+            return Ok(());
+        }
+
+        let indent = depth * 2;
+        for (i, item) in items.enumerate() {
+            if i > 0 {
+                self.out.push(',');
+            }
+            if self.pretty {
+                self.newline(indent + 2);
+            }
+            write(self, item)?;
+        }
+        if self.pretty {
+            self.newline(indent);
+        }
+        self.out.push(close);
+        Ok(())
+    }
+
+    fn newline(&mut self, indent: usize) {
+        self.out.push('\n');
+        self.out.push_str(&" ".repeat(indent));
+    }
+
+    fn string(&mut self, s: &str) {
+        self.out.push('"');
+        for c in s.chars() {
+            match c {
+                '"' => self.out.push_str("\\\""),
+                '\\' => self.out.push_str("\\\\"),
+                '\n' => self.out.push_str("\\n"),
+                '\r' => self.out.push_str("\\r"),
+                '\t' => self.out.push_str("\\t"),
+                c if c < ' ' => {
+                    let _ = write!(self.out, "\\u{:04x}", c as u32);
+                }
+                c => self.out.push(c),
+            }
+        }
+        self.out.push('"');
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::compiler::value::Value;
+    use crate::vm::VM;
+
+    #[test]
+    fn json_round_trips_nested_values() {
+        let input = r#"
+        import json
+
+        var doc = json.parse(text)
+        var name = doc["name"]
+        var tags = doc["tags"]
+        var compact = json.stringify(doc, false)
+        var pretty = json.stringify({"a": [1, {"b": false}], "c": []}, true)
+        var emoji = json.parse(pair)
+
+        struct Point(x, y)
+        var point = json.stringify(Point(1, 2), false)
+        var bad = json.parse("[1, 2")
+        var unencodable = json.stringify({1: 2}, false)
+        "#;
+
+        let mut vm = VM::new();
+        let text = r#" {"name": "gréen\n", "tags": [1, 2.5, -3e2, true, null], "nested": {}} "#;
+        vm.bind_global("text", text.into());
+        vm.bind_global("pair", r#""😀""#.into());
+        vm.interpret(input);
+
+        assert_eq!(vm.global("name").unwrap().as_string(), "gr\u{e9}en\n");
+        assert_eq!(
+            vm.global("tags").unwrap().to_string(),
+            "[1, 2.5, -300, true, nil]"
+        );
+        assert_eq!(
+            vm.global("compact").unwrap().as_string(),
+            "{\"name\":\"gr\u{e9}en\\n\",\"tags\":[1,2.5,-300,true,null],\"nested\":{}}"
+        );
+        assert_eq!(
+            vm.global("pretty").unwrap().as_string(),
+            "{\n  \"a\": [\n    1,\n    {\n      \"b\": false\n    }\n  ],\n  \"c\": []\n}"
+        );
+        assert_eq!(vm.global("emoji").unwrap().as_string(), "\u{1f600}");
+        assert_eq!(vm.global("point").unwrap().as_string(), r#"{"x":1,"y":2}"#);
+
+        let error = |name| match vm.global(name) {
+            Some(Value::StructInstance(error)) => error.fields[0].to_string(),
+            other => panic!("Expected an Error, got {:?}", other),
+        };
+        assert_eq!(
+            error("bad"),
+            "Invalid JSON, unexpected end of the document at line 1, column 6"
+        );
+        assert_eq!(
+            error("unencodable"),
+            "Can't encode a map with a Number key as JSON"
+        );
+    }
+}
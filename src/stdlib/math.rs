@@ -0,0 +1,93 @@
+use crate::compiler::value::Value;
+use crate::vm::errors::RuntimeError;
+use crate::vm::vm::RunResult;
+use crate::vm::VM;
+use std::convert::TryFrom;
+
+fn number(value: Value) -> RunResult<f64> {
+    f64::try_from(value)
+}
+
+/// Applies a function of one number to the argument.
+fn unary(args: &[Value], f: fn(f64) -> f64) -> RunResult<Value> {
+    Ok(Value::Number(f(number(args[0])?)))
+}
+
+/// math.sin(x): the sine of an angle in radians.
+pub fn sin(_vm: &mut VM, args: &[Value]) -> RunResult<Value> {
+    unary(args, f64::sin)
+}
+
+/// math.cos(x): the cosine of an angle in radians.
+pub fn cos(_vm: &mut VM, args: &[Value]) -> RunResult<Value> {
+    unary(args, f64::cos)
+}
+
+/// math.tan(x): the tangent of an angle in radians.
+pub fn tan(_vm: &mut VM, args: &[Value]) -> RunResult<Value> {
+    unary(args, f64::tan)
+}
+
+/// math.sqrt(x): the square root of x, NaN for negative numbers.
+pub fn sqrt(_vm: &mut VM, args: &[Value]) -> RunResult<Value> {
+    unary(args, f64::sqrt)
+}
+
+/// math.log(x): the natural logarithm of x.
+pub fn log(_vm: &mut VM, args: &[Value]) -> RunResult<Value> {
+    unary(args, f64::ln)
+}
+
+/// math.pow(x, y): x raised to the power y.
+pub fn pow(_vm: &mut VM, args: &[Value]) -> RunResult<Value> {
+    Ok(Value::Number(number(args[0])?.powf(number(args[1])?)))
+}
+
+/// math.min(a, b): the smaller of two numbers.
+pub fn min(_vm: &mut VM, args: &[Value]) -> RunResult<Value> {
+    Ok(Value::Number(number(args[0])?.min(number(args[1])?)))
+}
+
+/// math.max(a, b): the larger of two numbers.
+pub fn max(_vm: &mut VM, args: &[Value]) -> RunResult<Value> {
+    Ok(Value::Number(number(args[0])?.max(number(args[1])?)))
+}
+
+/// math.clamp(x, low, high): x, or low or high when it's outside them. Fails when low is
+/// greater than high or either is NaN.
+pub fn clamp(_vm: &mut VM, args: &[Value]) -> RunResult<Value> {
+    let (x, low, high) = (number(args[0])?, number(args[1])?, number(args[2])?);
+    if low > high || low.is_nan() || high.is_nan() {
+        return Err(RuntimeError::ArgumentTypes);
+    }
+    Ok(Value::Number(x.clamp(low, high)))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::compiler::value::Value;
+    use crate::vm::VM;
+
+    #[test]
+    fn math_module_computes_with_numbers() {
+        let input = r#"
+        import math
+
+        var root = math.sqrt(16)
+        var power = math.pow(2, 10)
+        var angle = math.sin(math.pi / 2) + math.cos(0) + math.tan(0)
+        var natural = math.log(math.e)
+        var bounds = [math.min(3, -1), math.max(3, -1)]
+        var clamped = [math.clamp(5, 0, 3), math.clamp(-2, 0, 3), math.clamp(1, 0, 3)]
+        "#;
+
+        let mut vm = VM::new();
+        vm.interpret(input);
+        assert_eq!(vm.global("root"), Some(&Value::Number(4.0)));
+        assert_eq!(vm.global("power"), Some(&Value::Number(1024.0)));
+        assert_eq!(vm.global("angle"), Some(&Value::Number(2.0)));
+        assert_eq!(vm.global("natural"), Some(&Value::Number(1.0)));
+        assert_eq!(vm.global("bounds").unwrap().to_string(), "[-1, 3]");
+        assert_eq!(vm.global("clamped").unwrap().to_string(), "[3, 0, 1]");
+    }
+}
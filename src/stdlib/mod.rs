@@ -0,0 +1,174 @@
+use crate::compiler::value::Value;
+use crate::vm::VM;
+
+mod array;
+mod assert;
+mod core;
+mod digest;
+mod encoding;
+mod events;
+#[cfg(feature = "net")]
+mod http;
+mod io;
+mod json;
+mod math;
+mod os;
+mod runtime;
+mod string_builder;
+mod time;
+
+pub(crate) use array::method as array_method;
+pub(crate) use digest::sha256_hex;
+
+/// Registers the builtin modules available to every script.
+pub fn define_natives(vm: &mut VM) {
+    vm.define_module(
+        "core",
+        &[
+            ("typeof", 1, core::type_of),
+            ("hash", 1, core::hash),
+            ("sha256", 1, digest::sha256),
+            ("md5", 1, digest::md5),
+            ("uuid", 0, core::uuid),
+            ("assert_eq", 2, assert::assert_eq),
+            ("capture", 1, core::capture),
+            ("str", 1, core::str),
+            ("format", 2, core::format),
+            ("set_precision", 1, core::set_precision),
+            ("reset_precision", 0, core::reset_precision),
+            ("range", 2, core::range),
+            ("len", 1, core::len),
+            ("slice", 2, core::slice),
+            ("map", 1, core::map),
+            ("array", 1, core::array),
+            ("pairs", 1, core::pairs),
+            ("iter", 1, core::iter),
+            ("StringBuilder", 0, string_builder::new),
+        ],
+    );
+    string_builder::register(vm);
+    let error = Value::Struct(vm.error_struct());
+    vm.define_member("core", "Error", error);
+
+    vm.define_module(
+        "io",
+        &[
+            ("read_file", 1, io::read_file),
+            ("write_file", 2, io::write_file),
+            ("append", 2, io::append),
+            ("exists", 1, io::exists),
+            ("read_bytes", 1, io::read_bytes),
+            ("write_bytes", 2, io::write_bytes),
+            ("encode", 2, io::encode),
+            ("decode", 2, io::decode),
+            ("decode_lossy", 2, io::decode_lossy),
+        ],
+    );
+
+    vm.define_module(
+        "base64",
+        &[
+            ("encode", 1, encoding::base64_encode),
+            ("decode", 1, encoding::base64_decode),
+        ],
+    );
+    vm.define_module(
+        "hex",
+        &[
+            ("encode", 1, encoding::hex_encode),
+            ("decode", 1, encoding::hex_decode),
+        ],
+    );
+
+    vm.define_module(
+        "json",
+        &[("parse", 1, json::parse), ("stringify", 2, json::stringify)],
+    );
+
+    #[cfg(feature = "net")]
+    vm.define_module("http", &[("get", 1, http::get), ("post", 2, http::post)]);
+
+    vm.define_module(
+        "events",
+        &[
+            ("on", 2, events::on),
+            ("off", 1, events::off),
+            ("emit", 2, events::emit),
+        ],
+    );
+
+    vm.define_module(
+        "math",
+        &[
+            ("sin", 1, math::sin),
+            ("cos", 1, math::cos),
+            ("tan", 1, math::tan),
+            ("sqrt", 1, math::sqrt),
+            ("pow", 2, math::pow),
+            ("log", 1, math::log),
+            ("min", 2, math::min),
+            ("max", 2, math::max),
+            ("clamp", 3, math::clamp),
+        ],
+    );
+    vm.define_member("math", "pi", Value::Number(std::f64::consts::PI));
+    vm.define_member("math", "e", Value::Number(std::f64::consts::E));
+
+    vm.define_module(
+        "os",
+        &[
+            ("args", 0, os::args),
+            ("env", 1, os::env),
+            ("exec", 2, os::exec),
+        ],
+    );
+
+    vm.define_module(
+        "time",
+        &[
+            ("sleep", 1, time::sleep),
+            ("after", 2, time::after),
+            ("every", 2, time::every),
+            ("cancel", 1, time::cancel),
+        ],
+    );
+
+    vm.define_module(
+        "runtime",
+        &[
+            ("gc", 0, runtime::gc),
+            ("heap_bytes", 0, runtime::heap_bytes),
+            ("frame_depth", 0, runtime::frame_depth),
+        ],
+    );
+    let version = Value::string(env!("CARGO_PKG_VERSION").to_string());
+    vm.define_member("runtime", "version", version);
+
+    define_prelude(vm);
+}
+
+/// Re-exports commonly used builtins as globals, so they can be called without their module.
+fn define_prelude(vm: &mut VM) {
+    vm.reexport("core", "typeof");
+    vm.reexport("core", "str");
+    vm.reexport("core", "format");
+    vm.reexport("core", "set_precision");
+    vm.reexport("core", "reset_precision");
+    vm.reexport("core", "hash");
+    vm.reexport("core", "sha256");
+    vm.reexport("core", "md5");
+    vm.reexport("core", "uuid");
+    vm.reexport("core", "assert_eq");
+    vm.reexport("core", "capture");
+    vm.reexport("core", "range");
+    vm.reexport("core", "len");
+    vm.reexport("core", "map");
+    vm.reexport("core", "array");
+    vm.reexport("core", "pairs");
+    vm.reexport("core", "iter");
+    vm.reexport("core", "Error");
+    vm.reexport("core", "StringBuilder");
+    vm.reexport("time", "sleep");
+    vm.reexport("time", "after");
+    vm.reexport("time", "every");
+}
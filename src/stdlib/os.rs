@@ -0,0 +1,123 @@
+use crate::compiler::object::Map;
+use crate::compiler::value::Value;
+use crate::vm::errors::RuntimeError;
+use crate::vm::vm::RunResult;
+use crate::vm::VM;
+use std::env;
+use std::process::Command;
+
+/// os.args(): the command-line arguments given after the script path, as an array of strings.
+pub fn args(vm: &mut VM, _args: &[Value]) -> RunResult<Value> {
+    let args = vm.args().iter().cloned().map(Value::string).collect();
+    Ok(vm.array(args))
+}
+
+/// os.env(name): the value of an environment variable, or nil if it isn't set.
+pub fn env(_vm: &mut VM, args: &[Value]) -> RunResult<Value> {
+    match &args[0] {
+        Value::String(name) => Ok(env::var(name.as_str()).map_or(Value::Nil, Value::string)),
+        _ => Err(RuntimeError::ArgumentTypes),
+    }
+}
+
+/// os.exec(program, args): runs the program with an array of string arguments and waits for it
+/// to finish. Returns a map of its exit "code", nil if a signal ended it, and what it wrote to
+/// "stdout" and "stderr". Returns an Error if the VM's options don't allow running the program
+/// or it couldn't be started.
+pub fn exec(vm: &mut VM, args: &[Value]) -> RunResult<Value> {
+    let program = match &args[0] {
+        Value::String(program) => program.as_str(),
+        _ => return Err(RuntimeError::ArgumentTypes),
+    };
+    let arguments = match &args[1] {
+        Value::Array(array) => array
+            .iter()
+            .map(|arg| match arg {
+                Value::String(arg) => Ok(arg.as_str()),
+                _ => Err(RuntimeError::ArgumentTypes),
+            })
+            .collect::<RunResult<Vec<_>>>()?,
+        _ => return Err(RuntimeError::ArgumentTypes),
+    };
+
+    if !vm.exec_policy().allows(program) {
+        return Ok(vm.error(format!("Running '{}' isn't allowed", program)));
+    }
+    let output = match Command::new(program).args(arguments).output() {
+        Ok(output) => output,
+        Err(err) => return Ok(vm.error(format!("Could not run '{}': {}", program, err))),
+    };
+
+    let code = output
+        .status
+        .code()
+        .map_or(Value::Nil, |code| Value::Number(code as f64));
+    let fields = [
+        ("code", code),
+        (
+            "stdout",
+            vm.string(String::from_utf8_lossy(&output.stdout).into_owned()),
+        ),
+        (
+            "stderr",
+            vm.string(String::from_utf8_lossy(&output.stderr).into_owned()),
+        ),
+    ];
+    let mut map = Map::new();
+    for (key, value) in fields {
+        map.insert(vm.string(key.to_string()), value)?;
+    }
+    Ok(vm.map(map))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::compiler::value::Value;
+    use crate::vm::{ExecPolicy, VmOptions, VM};
+
+    #[test]
+    fn exec_runs_programs_the_options_allow() {
+        let input = r#"
+        var ran = os.exec("sh", ["-c", "echo out; echo err >&2; exit 3"])
+        var denied = os.exec("ls", [])
+        var missing = os.exec("green-no-such-program", [])
+        "#;
+
+        let mut vm = VM::with_options(VmOptions {
+            exec: ExecPolicy::Only(vec!["sh".to_string(), "green-no-such-program".to_string()]),
+            ..VmOptions::default()
+        });
+        vm.interpret(input);
+        assert_eq!(
+            vm.global("ran").unwrap().to_string(),
+            r#"{"code": 3, "stdout": "out\n", "stderr": "err\n"}"#
+        );
+        let message = |vm: &VM, name: &str| match vm.global(name) {
+            Some(Value::StructInstance(error)) => error.fields[0].as_string().to_string(),
+            other => panic!("Expected an error, got {:?}", other),
+        };
+        assert_eq!(message(&vm, "denied"), "Running 'ls' isn't allowed");
+        assert!(message(&vm, "missing").starts_with("Could not run 'green-no-such-program'"));
+    }
+    #[test]
+    fn os_functions_see_the_arguments_and_environment() {
+        let input = r#"
+        var args = str(os.args())
+        var path = os.env("PATH")
+        var unset = typeof(os.env("GREEN_TEST_UNSET_VARIABLE"))
+        "#;
+
+        let mut vm = VM::new();
+        vm.set_args(vec!["one".to_string(), "two words".to_string()]);
+        vm.interpret(input);
+
+        assert_eq!(
+            vm.global("args").unwrap().as_string(),
+            r#"["one", "two words"]"#
+        );
+        if let Ok(path) = std::env::var("PATH") {
+            assert_eq!(vm.global("path").unwrap().as_string(), &path);
+        }
+        assert_eq!(vm.global("unset").unwrap().as_string(), "Nil");
+    }
+}
@@ -0,0 +1,80 @@
+use crate::compiler::value::Value;
+use crate::vm::vm::RunResult;
+use crate::vm::VM;
+
+/// runtime.gc(): frees the objects nothing refers to any more and returns how many bytes that
+/// freed. Does nothing when called from a function a native called, as the native may hold
+/// values the collector can't see.
+pub fn gc(vm: &mut VM, _args: &[Value]) -> RunResult<Value> {
+    if vm.natives_running() > 1 {
+        return Ok(Value::Number(0.0));
+    }
+    Ok(Value::Number(vm.collect_garbage() as f64))
+}
+
+/// runtime.heap_bytes(): the size of the objects the VM allocated and hasn't freed.
+pub fn heap_bytes(vm: &mut VM, _args: &[Value]) -> RunResult<Value> {
+    Ok(Value::Number(vm.heap_bytes() as f64))
+}
+
+/// runtime.frame_depth(): how many calls are in progress, the script itself included.
+pub fn frame_depth(vm: &mut VM, _args: &[Value]) -> RunResult<Value> {
+    Ok(Value::Number(vm.frame_depth() as f64))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::compiler::object::Instance;
+    use crate::compiler::value::Value;
+    use crate::vm::VM;
+
+    #[test]
+    fn runtime_collects_garbage_and_reports_on_the_vm() {
+        let input = r#"
+        class Box
+            def init(v)
+                self.v = v
+            end
+        end
+
+        def depth()
+            return runtime.frame_depth()
+        end
+
+        var kept = Box(1)
+        var i = 0
+        while i < 10 do
+            Box(i)
+            i = i + 1
+        end
+
+        var before = runtime.heap_bytes()
+        var freed = runtime.gc()
+        var after = runtime.heap_bytes()
+        var again = runtime.gc()
+        var v = kept.v
+        var top = runtime.frame_depth()
+        var inner = depth()
+        var version = runtime.version
+        "#;
+
+        let mut vm = VM::new();
+        vm.interpret(input);
+
+        let number = |name: &str| match *vm.global(name).unwrap() {
+            Value::Number(n) => n,
+            _ => panic!("Expected {} to be a number", name),
+        };
+        let instance = std::mem::size_of::<Instance>() as f64;
+        assert_eq!(number("freed"), 10.0 * instance);
+        assert_eq!(number("after"), number("before") - number("freed"));
+        assert_eq!(number("again"), 0.0);
+        assert_eq!(number("v"), 1.0);
+        assert_eq!(number("top"), 1.0);
+        assert_eq!(number("inner"), 2.0);
+        assert_eq!(
+            vm.global("version").unwrap().as_string(),
+            env!("CARGO_PKG_VERSION")
+        );
+    }
+}
@@ -0,0 +1,79 @@
+use crate::compiler::value::Value;
+use crate::vm::errors::RuntimeError;
+use crate::vm::vm::RunResult;
+use crate::vm::VM;
+
+/// The text a `StringBuilder` has collected so far. Appending grows it in place, so building a
+/// string from many pieces takes time in proportion to its length, rather than copying it all
+/// for each piece as `s = s + piece` would.
+pub(crate) struct StringBuilder(pub(crate) String);
+
+/// Registers the type of the values `StringBuilder()` makes.
+pub(crate) fn register(vm: &mut VM) {
+    vm.build_foreign::<StringBuilder>("StringBuilder")
+        .native("append", 1, append)
+        .method("len", 0, |_, builder, _| {
+            Ok(Value::Number(builder.0.chars().count() as f64))
+        })
+        .method("clear", 0, |_, builder, _| {
+            builder.0.clear();
+            Ok(Value::Nil)
+        })
+        .method("to_string", 0, |vm, builder, _| {
+            Ok(vm.string(builder.0.clone()))
+        })
+        .register();
+}
+
+/// StringBuilder(): an empty builder to append the pieces of a string to, taken back out with
+/// `to_string()`.
+pub fn new(vm: &mut VM, _args: &[Value]) -> RunResult<Value> {
+    Ok(vm.foreign(StringBuilder(String::new())))
+}
+
+/// builder.append(value): adds the value to the end, converted like `str` does, and returns the
+/// builder so appends can be chained.
+fn append(vm: &mut VM, args: &[Value]) -> RunResult<Value> {
+    let stringified;
+    let piece: &str = match &args[1] {
+        Value::String(s) => s,
+        value => {
+            stringified = vm.stringify(*value)?;
+            &stringified
+        }
+    };
+
+    let mut foreign = args[0].as_foreign()?;
+    let builder = foreign
+        .get_mut::<StringBuilder>()
+        .ok_or(RuntimeError::ArgumentTypes)?;
+    builder.0.push_str(piece);
+    Ok(args[0])
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::compiler::value::Value;
+    use crate::vm::VM;
+
+    #[test]
+    fn builders_collect_appended_pieces() {
+        let input = r#"
+        var builder = StringBuilder()
+        for i in 0 to 3 do
+            builder.append("n").append(i).append(",")
+        end
+        var built = builder.to_string()
+        var length = len(builder)
+
+        builder.clear()
+        var empty = builder.append([1, true]).to_string()
+        "#;
+
+        let mut vm = VM::new();
+        vm.interpret(input);
+        assert_eq!(vm.global("built").unwrap().as_string(), "n0,n1,n2,");
+        assert_eq!(vm.global("length"), Some(&Value::Number(9.0)));
+        assert_eq!(vm.global("empty").unwrap().as_string(), "[1, true]");
+    }
+}
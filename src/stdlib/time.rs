@@ -0,0 +1,93 @@
+use crate::compiler::value::Value;
+use crate::vm::errors::RuntimeError;
+use crate::vm::vm::RunResult;
+use crate::vm::VM;
+use std::time::{Duration, Instant};
+
+/// sleep(ms): waits for a number of milliseconds, running any timers that come due meanwhile.
+pub fn sleep(vm: &mut VM, args: &[Value]) -> RunResult<Value> {
+    let deadline = Instant::now() + millis_arg(&args[0])?;
+    vm.sleep_until(deadline)?;
+    Ok(Value::Nil)
+}
+
+/// after(ms, fn): calls fn once, with no arguments, after a number of milliseconds. Returns an
+/// id to cancel the call with.
+pub fn after(vm: &mut VM, args: &[Value]) -> RunResult<Value> {
+    let delay = millis_arg(&args[0])?;
+    let id = vm.schedule(delay, None, args[1]);
+    Ok(Value::Number(id as f64))
+}
+
+/// every(ms, fn): calls fn, with no arguments, every number of milliseconds until cancelled.
+/// Returns an id to cancel the calls with.
+pub fn every(vm: &mut VM, args: &[Value]) -> RunResult<Value> {
+    let interval = millis_arg(&args[0])?;
+    if interval.is_zero() {
+        return Err(RuntimeError::ArgumentTypes);
+    }
+    let id = vm.schedule(interval, Some(interval), args[1]);
+    Ok(Value::Number(id as f64))
+}
+
+/// time.cancel(id): stops a timer started by `after` or `every`. Returns whether it was still
+/// pending.
+pub fn cancel(vm: &mut VM, args: &[Value]) -> RunResult<Value> {
+    match &args[0] {
+        Value::Number(id) => Ok(vm.cancel_timer(*id as u64).into()),
+        _ => Err(RuntimeError::ArgumentTypes),
+    }
+}
+
+fn millis_arg(value: &Value) -> RunResult<Duration> {
+    match value {
+        Value::Number(ms) if *ms >= 0.0 && ms.is_finite() => {
+            Ok(Duration::from_secs_f64(ms / 1000.0))
+        }
+        _ => Err(RuntimeError::ArgumentTypes),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::compiler::value::Value;
+    use crate::vm::VM;
+
+    #[test]
+    fn timers_run_while_sleeping_and_after_the_script() {
+        let input = r#"
+        var ticks = 0
+        var fired = false
+        var late = false
+        def tick()
+            ticks = ticks + 1
+        end
+        def fire()
+            fired = true
+        end
+        def finish()
+            late = true
+        end
+
+        var id = every(5, tick)
+        after(12, fire)
+        sleep(40)
+        var cancelled = time.cancel(id)
+        var again = time.cancel(id)
+        var ticked = ticks
+        after(1, finish)
+        "#;
+
+        let mut vm = VM::new();
+        vm.interpret(input);
+        assert!(matches!(vm.global("late"), Some(Value::False)));
+        vm.run_timers();
+
+        assert!(vm.global("ticked").unwrap().as_number() >= 2.0);
+        assert_eq!(vm.global("ticks"), vm.global("ticked"));
+        assert!(matches!(vm.global("fired"), Some(Value::True)));
+        assert!(matches!(vm.global("cancelled"), Some(Value::True)));
+        assert!(matches!(vm.global("again"), Some(Value::False)));
+        assert!(matches!(vm.global("late"), Some(Value::True)));
+    }
+}
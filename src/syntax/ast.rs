@@ -0,0 +1,127 @@
+use crate::syntax::expr::{ExprKind, LiteralExpr};
+use std::mem;
+use std::ops::{Index, IndexMut};
+
+/// An expression in an `Ast`, by its index there.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ExprId(u32);
+
+/// The expressions of a module, stored one after another rather than each in an allocation of
+/// its own. Expressions refer to the expressions inside them by their `ExprId`s, so passes can
+/// rewrite an expression in place, and expressions nothing refers to anymore, like the ones the
+/// optimizer folded away, stay in the arena until the module is dropped.
+#[derive(Debug, Clone, Default)]
+pub struct Ast {
+    nodes: Vec<ExprKind>,
+    /// Source line each expression starts on, or 0 when unknown.
+    lines: Vec<usize>,
+}
+
+impl Ast {
+    pub fn new() -> Self {
+        Ast::default()
+    }
+
+    /// Adds an expression on an unknown line, returning its id.
+    pub fn add(&mut self, node: ExprKind) -> ExprId {
+        let id = ExprId(self.nodes.len() as u32);
+        self.nodes.push(node);
+        self.lines.push(0);
+        id
+    }
+
+    pub fn line(&self, id: ExprId) -> usize {
+        self.lines[id.0 as usize]
+    }
+
+    pub fn set_line(&mut self, id: ExprId, line: usize) {
+        self.lines[id.0 as usize] = line;
+    }
+
+    /// Takes the expression out, leaving nil in its place.
+    pub fn take(&mut self, id: ExprId) -> ExprKind {
+        mem::replace(&mut self[id], ExprKind::Literal(LiteralExpr::Nil))
+    }
+
+    /// Adds a copy of the expression `id` of `from`, and of everything in it, keeping their
+    /// lines.
+    pub fn copy(&mut self, from: &Ast, id: ExprId) -> ExprId {
+        let mut node = from[id].clone();
+        node.for_each_child_mut(&mut |child| *child = self.copy(from, *child));
+        let copy = self.add(node);
+        self.set_line(copy, from.line(id));
+        copy
+    }
+
+    /// Adds a copy of the expression `id`, and of everything in it, keeping their lines.
+    pub fn duplicate(&mut self, id: ExprId) -> ExprId {
+        let mut node = self[id].clone();
+        node.for_each_child_mut(&mut |child| *child = self.duplicate(*child));
+        let copy = self.add(node);
+        self.set_line(copy, self.line(id));
+        copy
+    }
+
+    /// Whether the expression `id` has the same structure as the expression `other_id` of
+    /// `other`. Lines don't take part, so ASTs can be compared structurally.
+    pub fn same(&self, id: ExprId, other: &Ast, other_id: ExprId) -> bool {
+        let (mut node, mut other_node) = (self[id].clone(), other[other_id].clone());
+        let (mut children, mut other_children) = (vec![], vec![]);
+        node.for_each_child_mut(&mut |child| children.push(mem::replace(child, ExprId(0))));
+        other_node
+            .for_each_child_mut(&mut |child| other_children.push(mem::replace(child, ExprId(0))));
+
+        node == other_node
+            && children.len() == other_children.len()
+            && children
+                .into_iter()
+                .zip(other_children)
+                .all(|(child, other_child)| self.same(child, other, other_child))
+    }
+}
+
+impl Index<ExprId> for Ast {
+    type Output = ExprKind;
+
+    fn index(&self, id: ExprId) -> &ExprKind {
+        &self.nodes[id.0 as usize]
+    }
+}
+
+impl IndexMut<ExprId> for Ast {
+    fn index_mut(&mut self, id: ExprId) -> &mut ExprKind {
+        &mut self.nodes[id.0 as usize]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::syntax::expr::{BinaryExpr, BinaryOperator};
+
+    #[test]
+    fn copies_keep_structure_and_lines() {
+        let mut ast = Ast::new();
+        let one = ast.add(ExprKind::Literal(LiteralExpr::Number(1.0)));
+        let two = ast.add(ExprKind::Literal(LiteralExpr::Number(2.0)));
+        let sum = ast.add(ExprKind::Binary(BinaryExpr::new(
+            one,
+            two,
+            BinaryOperator::Add,
+        )));
+        ast.set_line(sum, 3);
+
+        let duplicate = ast.duplicate(sum);
+        let mut other = Ast::new();
+        let copy = other.copy(&ast, sum);
+        assert_ne!(duplicate, sum);
+        assert!(ast.same(duplicate, &ast, sum));
+        assert!(other.same(copy, &ast, sum));
+        assert_eq!((ast.line(duplicate), other.line(copy)), (3, 3));
+
+        // Changing the original leaves its copies as they were.
+        ast.take(two);
+        assert!(!ast.same(duplicate, &ast, sum));
+        assert!(ast.same(duplicate, &other, copy));
+    }
+}
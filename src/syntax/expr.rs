@@ -1,99 +1,21 @@
-use crate::compiler::compiler::Compiler;
-use crate::compiler::instance::CompilerInstance;
+use crate::compiler::compiler::{ClassShape, Compiler};
+use crate::compiler::jump_table::{Cases, JumpTable};
+use crate::compiler::local::Local;
 use crate::compiler::module_resolver::get_module_ast;
-use crate::compiler::object::GreenFunctionType;
+use crate::compiler::object::{GreenFunctionType, Protocol, Struct};
 use crate::compiler::opcode::Opcode;
-use crate::compiler::value::Value;
+use crate::compiler::value::{Value, BUILTIN_TYPES};
+use crate::syntax::ast::{Ast, ExprId};
 use crate::syntax::token::TokenType;
-use crate::compiler::opcode::Opcode::GetGlobal;
 use crate::vm::obj::Gc;
+use std::collections::BTreeMap;
+use std::rc::Rc;
 
 pub trait Compile {
     fn compile(&self, compiler: &mut Compiler);
 }
 
-#[derive(PartialEq, Debug)]
-pub struct Expr {
-    pub node: Box<ExprKind>,
-}
-
-impl Expr {
-    pub fn new(node: ExprKind) -> Expr {
-        Expr {
-            node: Box::new(node),
-        }
-    }
-
-    pub fn sequence(seq_expr: SequenceExpr) -> Expr {
-        Expr::new(ExprKind::Sequence(seq_expr))
-    }
-
-    pub fn import(import_expr: ImportExpr) -> Expr {
-        Expr::new(ExprKind::Import(import_expr))
-    }
-
-    pub fn literal(literal_expr: LiteralExpr) -> Expr {
-        Expr::new(ExprKind::Literal(literal_expr))
-    }
-
-    pub fn binary(binary: BinaryExpr) -> Expr {
-        Expr::new(ExprKind::Binary(binary))
-    }
-
-    pub fn block(block: BlockExpr) -> Expr {
-        Expr::new(ExprKind::Block(block))
-    }
-
-    pub fn var_assign(assign: VarAssignExpr) -> Expr {
-        Expr::new(ExprKind::VarAssign(assign))
-    }
-
-    pub fn var_set(set: VarSetExpr) -> Expr {
-        Expr::new(ExprKind::VarSet(set))
-    }
-
-    pub fn var_get(get: VarGetExpr) -> Expr {
-        Expr::new(ExprKind::VarGet(get))
-    }
-
-    pub fn print(print: PrintExpr) -> Expr {
-        Expr::new(ExprKind::Print(print))
-    }
-
-    pub fn grouping(group: GroupingExpr) -> Expr {
-        Expr::new(ExprKind::Grouping(group))
-    }
-
-    pub fn if_else(if_else: IfElseExpr) -> Expr {
-        Expr::new(ExprKind::IfElse(if_else))
-    }
-
-    pub fn while_(while_expr: WhileExpr) -> Expr {
-        Expr::new(ExprKind::While(while_expr))
-    }
-
-    pub fn return_(return_expr: ReturnExpr) -> Expr {
-        Expr::new(ExprKind::Return(return_expr))
-    }
-
-    pub fn get_property(get_property: GetExpr) -> Self {
-        Expr::new(ExprKind::GetProperty(get_property))
-    }
-
-    pub fn set_property(set_property: SetExpr) -> Self {
-        Expr::new(ExprKind::SetProperty(set_property))
-    }
-
-    pub fn nil() -> Expr {
-        Expr::new(ExprKind::Literal(LiteralExpr::Nil))
-    }
-
-    pub fn class(class_expr: ClassExpr) -> Expr {
-        Expr::new(ExprKind::Class(class_expr))
-    }
-}
-
-#[derive(PartialEq, Debug)]
+#[derive(PartialEq, Debug, Clone)]
 pub enum ExprKind {
     Sequence(SequenceExpr),
     Import(ImportExpr),
@@ -102,21 +24,35 @@ pub enum ExprKind {
     Unary(UnaryExpr),
     Block(BlockExpr),
     VarAssign(VarAssignExpr),
+    VarUnpack(VarUnpackExpr),
+    Destructure(DestructureExpr),
     VarSet(VarSetExpr),
     VarGet(VarGetExpr),
     Print(PrintExpr),
     Grouping(GroupingExpr),
     If(IfExpr),
     IfElse(IfElseExpr),
+    Match(MatchExpr),
     Function(FunctionExpr),
     Class(ClassExpr),
     Call(CallExpr),
     While(WhileExpr),
+    For(ForExpr),
+    Comprehension(ComprehensionExpr),
     Return(ReturnExpr),
     GetProperty(GetExpr),
     SetProperty(SetExpr),
     Array(ArrayExpr),
+    Tuple(TupleExpr),
+    Range(RangeExpr),
+    Append(AppendExpr),
+    Map(MapExpr),
     Subscript(SubscriptExpr),
+    Is(IsExpr),
+    Assert(AssertExpr),
+    Struct(StructExpr),
+    Protocol(ProtocolExpr),
+    Test(TestExpr),
 }
 
 impl Compile for ExprKind {
@@ -129,21 +65,35 @@ impl Compile for ExprKind {
             ExprKind::Unary(u) => u.compile(compiler),
             ExprKind::Block(b) => b.compile(compiler),
             ExprKind::VarAssign(v) => v.compile(compiler),
+            ExprKind::VarUnpack(v) => v.compile(compiler),
+            ExprKind::Destructure(d) => d.compile(compiler),
             ExprKind::VarSet(v) => v.compile(compiler),
             ExprKind::VarGet(v) => v.compile(compiler),
             ExprKind::Print(p) => p.compile(compiler),
             ExprKind::Grouping(g) => g.compile(compiler),
             ExprKind::If(i) => i.compile(compiler),
             ExprKind::IfElse(e) => e.compile(compiler),
+            ExprKind::Match(m) => m.compile(compiler),
             ExprKind::Function(f) => f.compile(compiler),
             ExprKind::Call(c) => c.compile(compiler),
             ExprKind::While(w) => w.compile(compiler),
+            ExprKind::For(f) => f.compile(compiler),
+            ExprKind::Comprehension(c) => c.compile(compiler),
             ExprKind::Return(r) => r.compile(compiler),
             ExprKind::Array(a) => a.compile(compiler),
+            ExprKind::Tuple(t) => t.compile(compiler),
+            ExprKind::Range(r) => r.compile(compiler),
+            ExprKind::Append(a) => a.compile(compiler),
+            ExprKind::Map(m) => m.compile(compiler),
             ExprKind::Subscript(s) => s.compile(compiler),
             ExprKind::Class(c) => c.compile(compiler),
             ExprKind::GetProperty(g) => g.compile(compiler),
             ExprKind::SetProperty(s) => s.compile(compiler),
+            ExprKind::Is(i) => i.compile(compiler),
+            ExprKind::Assert(a) => a.compile(compiler),
+            ExprKind::Struct(s) => s.compile(compiler),
+            ExprKind::Protocol(p) => p.compile(compiler),
+            ExprKind::Test(t) => t.compile(compiler),
         }
     }
 }
@@ -155,15 +105,288 @@ impl ExprKind {
             _ => None,
         }
     }
+
+    /// Whether every path through the expression ends in a `return`.
+    pub fn always_returns(&self, ast: &Ast) -> bool {
+        let returns = |block: &BlockExpr| block.exprs.iter().any(|e| ast[*e].always_returns(ast));
+        match self {
+            ExprKind::Return(_) => true,
+            ExprKind::Block(b) => returns(b),
+            ExprKind::IfElse(i) => returns(&i.then_clause) && returns(&i.else_clause),
+            ExprKind::Match(m) => {
+                m.arms.iter().all(|arm| returns(&arm.body))
+                    && m.else_clause.as_ref().is_some_and(returns)
+            }
+            _ => false,
+        }
+    }
+
+    /// Whether evaluating the expression does nothing but produce its value, so discarding the
+    /// value makes the expression pointless. Operators count as pure, even though operands
+    /// can overload them.
+    pub fn is_pure(&self, ast: &Ast) -> bool {
+        let pure = |expr: &ExprId| ast[*expr].is_pure(ast);
+        match self {
+            ExprKind::Literal(_) | ExprKind::VarGet(_) => true,
+            ExprKind::Binary(b) => pure(&b.lhs) && pure(&b.rhs),
+            ExprKind::Unary(u) => pure(&u.expr),
+            ExprKind::Grouping(g) => pure(&g.expr),
+            ExprKind::Is(i) => pure(&i.expr),
+            ExprKind::Array(a) => a.exprs.iter().flatten().all(pure),
+            ExprKind::Tuple(t) => t.exprs.iter().all(pure),
+            ExprKind::Range(r) => pure(&r.start) && pure(&r.end) && r.step.iter().all(pure),
+            ExprKind::Map(m) => m
+                .entries
+                .iter()
+                .all(|(key, value)| pure(key) && pure(value)),
+            _ => false,
+        }
+    }
+
+    /// Whether the compiled expression leaves a value on the stack.
+    pub fn leaves_value(&self) -> bool {
+        matches!(
+            self,
+            ExprKind::Literal(_)
+                | ExprKind::Binary(_)
+                | ExprKind::Unary(_)
+                | ExprKind::Block(_)
+                | ExprKind::For(_)
+                | ExprKind::Comprehension(_)
+                | ExprKind::VarSet(_)
+                | ExprKind::VarGet(_)
+                | ExprKind::Grouping(_)
+                | ExprKind::If(_)
+                | ExprKind::IfElse(_)
+                | ExprKind::Match(_)
+                | ExprKind::Call(_)
+                | ExprKind::GetProperty(_)
+                | ExprKind::SetProperty(_)
+                | ExprKind::Array(_)
+                | ExprKind::Tuple(_)
+                | ExprKind::Range(_)
+                | ExprKind::Append(_)
+                | ExprKind::Map(_)
+                | ExprKind::Subscript(_)
+                | ExprKind::Is(_)
+        )
+    }
+
+    /// Calls `visit` with each expression directly inside this one, including the values in
+    /// its patterns.
+    pub fn for_each_child(&self, visit: &mut dyn FnMut(ExprId)) {
+        let mut each = |exprs: &[ExprId]| exprs.iter().for_each(|expr| visit(*expr));
+        match self {
+            ExprKind::Sequence(s) => each(&s.exprs),
+            ExprKind::Block(b) => each(&b.exprs),
+            ExprKind::Binary(b) => each(&[b.lhs, b.rhs]),
+            ExprKind::Unary(u) => each(&[u.expr]),
+            ExprKind::Grouping(g) => each(&[g.expr]),
+            ExprKind::VarAssign(v) => each(&[v.initializer]),
+            ExprKind::VarUnpack(v) => each(&[v.initializer]),
+            ExprKind::Destructure(d) => each(&[d.initializer]),
+            ExprKind::VarSet(v) => each(&[v.initializer]),
+            ExprKind::Print(p) => each(&[p.expr]),
+            ExprKind::Assert(a) => {
+                each(&[a.condition]);
+                each(a.message.as_slice());
+            }
+            ExprKind::If(i) => each(&[i.condition, i.then_clause]),
+            ExprKind::IfElse(i) => {
+                each(&[i.condition]);
+                each(&i.then_clause.exprs);
+                each(&i.else_clause.exprs);
+            }
+            ExprKind::Match(m) => {
+                each(&[m.subject]);
+                for arm in &m.arms {
+                    for pattern in &arm.patterns {
+                        pattern.for_each_value(&mut |expr| each(&[expr]));
+                    }
+                    each(&arm.body.exprs);
+                }
+                if let Some(else_clause) = &m.else_clause {
+                    each(&else_clause.exprs);
+                }
+            }
+            ExprKind::While(w) => each(&[w.condition, w.body]),
+            ExprKind::For(f) => each(&[f.source, f.body]),
+            ExprKind::Comprehension(c) => {
+                match &c.collect {
+                    Comprehension::Array(element) => each(&[*element]),
+                    Comprehension::Map(key, value) => each(&[*key, *value]),
+                }
+                each(&[c.source]);
+                if let Some(range) = &c.range {
+                    each(&[range.end]);
+                    each(range.step.as_slice());
+                }
+                each(c.condition.as_slice());
+            }
+            ExprKind::Function(f) => each(&f.declaration.body.exprs),
+            ExprKind::Class(c) => {
+                for method in c.methods.iter().chain(&c.static_methods) {
+                    each(&method.declaration.body.exprs);
+                }
+                c.statics.iter().for_each(|var| each(&[var.value]));
+            }
+            ExprKind::Call(c) => {
+                each(&[c.callee]);
+                each(&c.args);
+            }
+            ExprKind::Return(r) => each(r.expr.as_slice()),
+            ExprKind::GetProperty(g) => each(&[g.expr]),
+            ExprKind::SetProperty(s) => each(&[s.lhs, s.rhs]),
+            ExprKind::Array(a) => each(a.exprs.as_deref().unwrap_or_default()),
+            ExprKind::Tuple(t) => each(&t.exprs),
+            ExprKind::Map(m) => m
+                .entries
+                .iter()
+                .for_each(|(key, value)| each(&[*key, *value])),
+            ExprKind::Append(a) => each(&[a.array, a.item]),
+            ExprKind::Range(r) => {
+                each(&[r.start, r.end]);
+                each(r.step.as_slice());
+            }
+            ExprKind::Subscript(s) => {
+                each(&[s.callee, s.index]);
+                each(s.expr.as_slice());
+            }
+            ExprKind::Is(i) => each(&[i.expr]),
+            ExprKind::Test(t) => each(&t.body.exprs),
+            ExprKind::Import(_)
+            | ExprKind::Literal(_)
+            | ExprKind::VarGet(_)
+            | ExprKind::Struct(_)
+            | ExprKind::Protocol(_) => {}
+        }
+    }
+
+    /// Calls `visit` with each expression directly inside this one, like `for_each_child`, so
+    /// it can be replaced.
+    pub fn for_each_child_mut(&mut self, visit: &mut dyn FnMut(&mut ExprId)) {
+        match self {
+            ExprKind::Sequence(s) => s.exprs.iter_mut().for_each(visit),
+            ExprKind::Block(b) => b.exprs.iter_mut().for_each(visit),
+            ExprKind::Binary(b) => {
+                visit(&mut b.lhs);
+                visit(&mut b.rhs);
+            }
+            ExprKind::Unary(u) => visit(&mut u.expr),
+            ExprKind::Grouping(g) => visit(&mut g.expr),
+            ExprKind::VarAssign(v) => visit(&mut v.initializer),
+            ExprKind::VarUnpack(v) => visit(&mut v.initializer),
+            ExprKind::Destructure(d) => visit(&mut d.initializer),
+            ExprKind::VarSet(v) => visit(&mut v.initializer),
+            ExprKind::Print(p) => visit(&mut p.expr),
+            ExprKind::Assert(a) => {
+                visit(&mut a.condition);
+                a.message.iter_mut().for_each(visit);
+            }
+            ExprKind::If(i) => {
+                visit(&mut i.condition);
+                visit(&mut i.then_clause);
+            }
+            ExprKind::IfElse(i) => {
+                visit(&mut i.condition);
+                i.then_clause.exprs.iter_mut().for_each(&mut *visit);
+                i.else_clause.exprs.iter_mut().for_each(visit);
+            }
+            ExprKind::Match(m) => {
+                visit(&mut m.subject);
+                for arm in &mut m.arms {
+                    for pattern in &mut arm.patterns {
+                        pattern.for_each_value_mut(visit);
+                    }
+                    arm.body.exprs.iter_mut().for_each(&mut *visit);
+                }
+                if let Some(else_clause) = &mut m.else_clause {
+                    else_clause.exprs.iter_mut().for_each(visit);
+                }
+            }
+            ExprKind::While(w) => {
+                visit(&mut w.condition);
+                visit(&mut w.body);
+            }
+            ExprKind::For(f) => {
+                visit(&mut f.source);
+                visit(&mut f.body);
+            }
+            ExprKind::Comprehension(c) => {
+                match &mut c.collect {
+                    Comprehension::Array(element) => visit(element),
+                    Comprehension::Map(key, value) => {
+                        visit(key);
+                        visit(value);
+                    }
+                }
+                visit(&mut c.source);
+                if let Some(range) = &mut c.range {
+                    visit(&mut range.end);
+                    range.step.iter_mut().for_each(&mut *visit);
+                }
+                c.condition.iter_mut().for_each(visit);
+            }
+            ExprKind::Function(f) => f.declaration.body.exprs.iter_mut().for_each(visit),
+            ExprKind::Class(c) => {
+                for method in c.methods.iter_mut().chain(&mut c.static_methods) {
+                    method
+                        .declaration
+                        .body
+                        .exprs
+                        .iter_mut()
+                        .for_each(&mut *visit);
+                }
+                c.statics.iter_mut().for_each(|var| visit(&mut var.value));
+            }
+            ExprKind::Call(c) => {
+                visit(&mut c.callee);
+                c.args.iter_mut().for_each(visit);
+            }
+            ExprKind::Return(r) => r.expr.iter_mut().for_each(visit),
+            ExprKind::GetProperty(g) => visit(&mut g.expr),
+            ExprKind::SetProperty(s) => {
+                visit(&mut s.lhs);
+                visit(&mut s.rhs);
+            }
+            ExprKind::Array(a) => a.exprs.iter_mut().flatten().for_each(visit),
+            ExprKind::Tuple(t) => t.exprs.iter_mut().for_each(visit),
+            ExprKind::Map(m) => m.entries.iter_mut().for_each(|(key, value)| {
+                visit(key);
+                visit(value);
+            }),
+            ExprKind::Append(a) => {
+                visit(&mut a.array);
+                visit(&mut a.item);
+            }
+            ExprKind::Range(r) => {
+                visit(&mut r.start);
+                visit(&mut r.end);
+                r.step.iter_mut().for_each(visit);
+            }
+            ExprKind::Subscript(s) => {
+                visit(&mut s.callee);
+                visit(&mut s.index);
+                s.expr.iter_mut().for_each(visit);
+            }
+            ExprKind::Is(i) => visit(&mut i.expr),
+            ExprKind::Test(t) => t.body.exprs.iter_mut().for_each(visit),
+            ExprKind::Import(_)
+            | ExprKind::Literal(_)
+            | ExprKind::VarGet(_)
+            | ExprKind::Struct(_)
+            | ExprKind::Protocol(_) => {}
+        }
+    }
 }
 
-#[derive(PartialEq, Debug)]
+#[derive(PartialEq, Debug, Clone)]
 pub struct SequenceExpr {
-    pub exprs: Vec<Expr>,
+    pub exprs: Vec<ExprId>,
 }
 
 impl SequenceExpr {
-    pub fn new(exprs: Vec<Expr>) -> Self {
+    pub fn new(exprs: Vec<ExprId>) -> Self {
         SequenceExpr { exprs }
     }
 }
@@ -171,12 +394,12 @@ impl SequenceExpr {
 impl Compile for SequenceExpr {
     fn compile(&self, compiler: &mut Compiler) {
         for expr in &self.exprs {
-            compiler.compile_expr(expr);
+            compiler.compile_statement(*expr);
         }
     }
 }
 
-#[derive(PartialEq, Debug)]
+#[derive(PartialEq, Debug, Clone)]
 pub struct ImportExpr {
     pub module: String,
 }
@@ -189,16 +412,49 @@ impl ImportExpr {
 
 impl Compile for ImportExpr {
     fn compile(&self, compiler: &mut Compiler) {
-        let module = get_module_ast(&self.module).unwrap();
+        let lossy = compiler.lossy_utf8();
+        let loaded = match get_module_ast(&self.module, &mut compiler.sources, lossy) {
+            Ok(loaded) => loaded,
+            // Native modules, like `math`, are globals already. Importing one without a file of
+            // the same name only checks that it's defined.
+            Err(err) if err.is_not_found() && !self.module.contains('.') => {
+                VarGetExpr::new(Variable::new(self.module.clone())).compile(compiler);
+                compiler.emit(Opcode::Pop);
+                return;
+            }
+            Err(err) => {
+                let message = format!(
+                    "Could not import {}: {}",
+                    self.module,
+                    err.describe(&compiler.sources)
+                );
+                compiler.error(message);
+                return;
+            }
+        };
 
-        // TODO Only compile top level expressions
-        for expr in module.exprs() {
-            compiler.compile_expr(expr);
+        if let Some(line) = loaded.replaced {
+            let message = format!(
+                "{} is not valid UTF-8 from line {}, invalid bytes were replaced",
+                self.module, line
+            );
+            compiler.warn(compiler.line(), &message);
         }
+
+        // TODO Only compile top level expressions
+        let (file, (ast, exprs)) = (loaded.file, loaded.ast.into_parts());
+        let importer = compiler.file.replace(file);
+        compiler.in_ast(Rc::new(ast), |compiler| {
+            for expr in &exprs {
+                compiler.compile_statement(*expr);
+            }
+            compiler.add_inline_functions(&exprs, Some(file));
+        });
+        compiler.file = importer;
     }
 }
 
-#[derive(PartialEq, Debug)]
+#[derive(PartialEq, Debug, Clone)]
 pub enum LiteralExpr {
     Number(f64),
     String(String),
@@ -211,37 +467,40 @@ impl Compile for LiteralExpr {
     fn compile(&self, compiler: &mut Compiler) {
         match self {
             LiteralExpr::Number(n) => compiler.emit_constant(Value::Number(*n)),
-            LiteralExpr::String(s) => compiler.emit_string(&s),
+            LiteralExpr::String(s) => compiler.emit_string(s),
             LiteralExpr::True => compiler.emit_constant(Value::True),
             LiteralExpr::False => compiler.emit_constant(Value::False),
-            _ => todo!(), // TODO NilLiteral
+            LiteralExpr::Nil => compiler.emit(Opcode::Nil),
         }
     }
 }
 
-#[derive(PartialEq, Debug)]
+#[derive(PartialEq, Debug, Clone)]
 pub struct BinaryExpr {
-    pub lhs: Expr,
-    pub rhs: Expr,
+    pub lhs: ExprId,
+    pub rhs: ExprId,
     pub operator: BinaryOperator,
 }
 
 impl BinaryExpr {
-    pub fn new(lhs: Expr, rhs: Expr, operator: BinaryOperator) -> BinaryExpr {
+    pub fn new(lhs: ExprId, rhs: ExprId, operator: BinaryOperator) -> BinaryExpr {
         BinaryExpr { lhs, rhs, operator }
     }
 }
 
 impl Compile for BinaryExpr {
     fn compile(&self, compiler: &mut Compiler) {
-        compiler.compile_expr(&self.lhs);
-        compiler.compile_expr(&self.rhs);
+        compiler.compile_expr(self.lhs);
+        compiler.push_temporary();
+        compiler.compile_expr(self.rhs);
+        compiler.pop_temporaries(1);
 
         match self.operator {
             BinaryOperator::Add => compiler.emit(Opcode::Add),
             BinaryOperator::Subtract => compiler.emit(Opcode::Subtract),
             BinaryOperator::Multiply => compiler.emit(Opcode::Multiply),
             BinaryOperator::Divide => compiler.emit(Opcode::Divide),
+            BinaryOperator::Modulo => compiler.emit(Opcode::Modulo),
             BinaryOperator::Equal => compiler.emit(Opcode::Equal),
             BinaryOperator::BangEqual => {
                 compiler.emit(Opcode::Equal);
@@ -273,6 +532,7 @@ pub enum BinaryOperator {
     Add,
     Divide,
     Multiply,
+    Modulo,
 }
 
 impl BinaryOperator {
@@ -282,6 +542,7 @@ impl BinaryOperator {
             TokenType::Plus => BinaryOperator::Add,
             TokenType::Star => BinaryOperator::Multiply,
             TokenType::Slash => BinaryOperator::Divide,
+            TokenType::Percent => BinaryOperator::Modulo,
             TokenType::BangEqual => BinaryOperator::BangEqual,
             TokenType::Equal => BinaryOperator::Equal,
             TokenType::EqualEqual => BinaryOperator::Equal,
@@ -296,21 +557,21 @@ impl BinaryOperator {
     }
 }
 
-#[derive(PartialEq, Debug)]
+#[derive(PartialEq, Debug, Clone)]
 pub struct UnaryExpr {
-    pub expr: Expr,
+    pub expr: ExprId,
     pub operator: UnaryOperator,
 }
 
 impl UnaryExpr {
-    pub fn new(expr: Expr, operator: UnaryOperator) -> Self {
+    pub fn new(expr: ExprId, operator: UnaryOperator) -> Self {
         UnaryExpr { expr, operator }
     }
 }
 
 impl Compile for UnaryExpr {
     fn compile(&self, compiler: &mut Compiler) {
-        compiler.compile_expr(&self.expr);
+        compiler.compile_expr(self.expr);
         compiler.emit(Opcode::from(self.operator.clone()));
     }
 }
@@ -321,52 +582,67 @@ pub enum UnaryOperator {
     Not,
 }
 
-#[derive(PartialEq, Debug)]
+#[derive(PartialEq, Debug, Clone)]
 pub struct BlockExpr {
-    pub exprs: Vec<Expr>,
+    pub exprs: Vec<ExprId>,
 }
 
 impl BlockExpr {
-    pub fn new(exprs: Vec<Expr>) -> Self {
+    pub fn new(exprs: Vec<ExprId>) -> Self {
         BlockExpr { exprs }
     }
 }
 
 impl Compile for BlockExpr {
+    /// Compiles the block to the value of its last expression, or nil if it has none.
     fn compile(&self, compiler: &mut Compiler) {
         compiler.begin_scope();
-        for expr in &self.exprs {
-            compiler.compile_expr(expr);
+
+        match compiler.reachable(&self.exprs).split_last() {
+            Some((last, rest)) => {
+                for expr in rest {
+                    compiler.compile_statement(*expr);
+                }
+
+                if compiler.ast[*last].leaves_value() {
+                    compiler.compile_expr(*last);
+                } else {
+                    compiler.compile_statement(*last);
+                    compiler.emit(Opcode::Nil);
+                }
+            }
+            None => compiler.emit(Opcode::Nil),
         }
-        compiler.end_scope();
+
+        compiler.end_scope_with_value();
     }
 }
 
-#[derive(PartialEq, Debug)]
+#[derive(PartialEq, Debug, Clone)]
 pub struct GroupingExpr {
-    pub expr: Expr,
+    pub expr: ExprId,
 }
 
 impl GroupingExpr {
-    pub fn new(expr: Expr) -> Self {
+    pub fn new(expr: ExprId) -> Self {
         GroupingExpr { expr }
     }
 }
 
 impl Compile for GroupingExpr {
     fn compile(&self, compiler: &mut Compiler) {
-        compiler.compile_expr(&self.expr);
+        compiler.compile_expr(self.expr);
     }
 }
 
-#[derive(PartialEq, Debug)]
+#[derive(PartialEq, Debug, Clone)]
 pub struct VarAssignExpr {
     pub variable: Variable,
-    pub initializer: Expr,
+    pub initializer: ExprId,
 }
 
 impl VarAssignExpr {
-    pub fn new(variable: Variable, initializer: Expr) -> Self {
+    pub fn new(variable: Variable, initializer: ExprId) -> Self {
         VarAssignExpr {
             variable,
             initializer,
@@ -377,9 +653,9 @@ impl VarAssignExpr {
 impl Compile for VarAssignExpr {
     fn compile(&self, compiler: &mut Compiler) {
         // TODO Check if initialized -> if not init with nil
-        compiler.compile_expr(&self.initializer);
+        compiler.compile_expr(self.initializer);
 
-        if *compiler.current.scope_depth() > 0 as isize {
+        if *compiler.current.scope_depth() > 0_isize {
             // Local
             compiler.compile_declare_var(&self.variable);
         } else {
@@ -389,14 +665,91 @@ impl Compile for VarAssignExpr {
     }
 }
 
-#[derive(PartialEq, Debug)]
+/// `var a, b = f()`: declares a variable for each value of the tuple the initializer returns.
+#[derive(PartialEq, Debug, Clone)]
+pub struct VarUnpackExpr {
+    pub variables: Vec<Variable>,
+    pub initializer: ExprId,
+}
+
+impl VarUnpackExpr {
+    pub fn new(variables: Vec<Variable>, initializer: ExprId) -> Self {
+        VarUnpackExpr {
+            variables,
+            initializer,
+        }
+    }
+}
+
+impl Compile for VarUnpackExpr {
+    fn compile(&self, compiler: &mut Compiler) {
+        compiler.compile_expr(self.initializer);
+        compiler.emit(Opcode::UnpackTuple);
+        compiler.emit_byte(self.variables.len() as u8);
+        compiler.compile_declare_vars(&self.variables);
+    }
+}
+
+/// The shape a destructuring `var` expects its initializer to have.
+#[derive(PartialEq, Debug, Clone)]
+pub enum Destructure {
+    /// `var [a, b] = list`: an array of exactly as many elements as there are variables.
+    Array(Vec<Variable>),
+    /// `var {x, y} = point`: a map with a key named after each variable, and maybe others.
+    Map(Vec<Variable>),
+}
+
+impl Destructure {
+    pub fn variables(&self) -> &[Variable] {
+        match self {
+            Destructure::Array(variables) | Destructure::Map(variables) => variables,
+        }
+    }
+}
+
+/// `var [a, b] = list` or `var {x, y} = point`: declares a variable for each element of an array
+/// or each named value of a map, failing at runtime if the value doesn't have that shape.
+#[derive(PartialEq, Debug, Clone)]
+pub struct DestructureExpr {
+    pub pattern: Destructure,
+    pub initializer: ExprId,
+}
+
+impl DestructureExpr {
+    pub fn new(pattern: Destructure, initializer: ExprId) -> Self {
+        DestructureExpr {
+            pattern,
+            initializer,
+        }
+    }
+}
+
+impl Compile for DestructureExpr {
+    fn compile(&self, compiler: &mut Compiler) {
+        compiler.compile_expr(self.initializer);
+        let variables = self.pattern.variables();
+        match &self.pattern {
+            Destructure::Array(_) => compiler.emit(Opcode::UnpackArray),
+            Destructure::Map(_) => {
+                for variable in variables {
+                    compiler.emit_string(&variable.name);
+                }
+                compiler.emit(Opcode::UnpackMap);
+            }
+        }
+        compiler.emit_byte(variables.len() as u8);
+        compiler.compile_declare_vars(variables);
+    }
+}
+
+#[derive(PartialEq, Debug, Clone)]
 pub struct VarSetExpr {
     pub variable: Variable,
-    pub initializer: Expr,
+    pub initializer: ExprId,
 }
 
 impl VarSetExpr {
-    pub fn new(variable: Variable, initializer: Expr) -> Self {
+    pub fn new(variable: Variable, initializer: ExprId) -> Self {
         VarSetExpr {
             variable,
             initializer,
@@ -406,7 +759,7 @@ impl VarSetExpr {
 
 impl Compile for VarSetExpr {
     fn compile(&self, compiler: &mut Compiler) {
-        compiler.compile_expr(&self.initializer);
+        compiler.compile_expr(self.initializer);
 
         let var_name = &self.variable.name;
         let arg = compiler.resolve_local(var_name);
@@ -416,15 +769,12 @@ impl Compile for VarSetExpr {
             compiler.emit_byte(arg as u8);
         } else {
             // Global
-            compiler.emit(Opcode::SetGlobal);
-            let str_obj = Value::string(var_name.clone());
-            let constant_id = compiler.current_chunk().add_constant(str_obj);
-            compiler.emit_byte(constant_id);
+            compiler.emit_name(Opcode::SetGlobal, var_name);
         }
     }
 }
 
-#[derive(PartialEq, Debug)]
+#[derive(PartialEq, Debug, Clone)]
 pub struct VarGetExpr {
     pub variable: Variable,
 }
@@ -445,33 +795,73 @@ impl Compile for VarGetExpr {
             compiler.emit_byte(arg as u8);
         } else {
             // Global
-            compiler.emit(Opcode::GetGlobal);
-            let str_obj = Value::string(var_name.clone());
-            let constant_id = compiler.current_chunk().add_constant(str_obj);
-            compiler.emit_byte(constant_id);
+            compiler.emit_name(Opcode::GetGlobal, var_name);
         }
     }
 }
 
-#[derive(PartialEq, Debug)]
+#[derive(PartialEq, Debug, Clone)]
 pub struct PrintExpr {
-    pub expr: Expr,
+    pub expr: ExprId,
 }
 
 impl PrintExpr {
-    pub fn new(expr: Expr) -> PrintExpr {
+    pub fn new(expr: ExprId) -> PrintExpr {
         PrintExpr { expr }
     }
 }
 
 impl Compile for PrintExpr {
     fn compile(&self, compiler: &mut Compiler) {
-        compiler.compile_expr(&self.expr);
+        compiler.compile_expr(self.expr);
         compiler.emit(Opcode::Print);
     }
 }
 
-#[derive(PartialEq, Debug)]
+#[derive(PartialEq, Debug, Clone)]
+pub struct AssertExpr {
+    pub condition: ExprId,
+    pub message: Option<ExprId>,
+    /// Source line and text of the condition, reported when the assertion fails.
+    pub description: String,
+}
+
+impl AssertExpr {
+    pub fn new(condition: ExprId, message: Option<ExprId>, description: String) -> AssertExpr {
+        AssertExpr {
+            condition,
+            message,
+            description,
+        }
+    }
+}
+
+impl Compile for AssertExpr {
+    fn compile(&self, compiler: &mut Compiler) {
+        compiler.compile_expr(self.condition);
+
+        let fail_jump = compiler.emit_jump(Opcode::JumpIfFalse);
+        compiler.emit(Opcode::Pop);
+        let end_jump = compiler.emit_jump(Opcode::Jump);
+
+        compiler.patch_jump(fail_jump);
+        compiler.emit(Opcode::Pop);
+
+        // The message is only evaluated when the assertion fails.
+        match &self.message {
+            Some(message) => compiler.compile_expr(*message),
+            None => compiler.emit(Opcode::Nil),
+        }
+
+        compiler.emit(Opcode::AssertFail);
+        let constant = compiler.add_constant(Value::string(self.description.clone()));
+        compiler.emit_short(constant);
+
+        compiler.patch_jump(end_jump);
+    }
+}
+
+#[derive(PartialEq, Debug, Clone)]
 pub struct Variable {
     pub name: String,
 }
@@ -482,14 +872,14 @@ impl Variable {
     }
 }
 
-#[derive(PartialEq, Debug)]
+#[derive(PartialEq, Debug, Clone)]
 pub struct IfExpr {
-    pub condition: Expr,
-    pub then_clause: Expr,
+    pub condition: ExprId,
+    pub then_clause: ExprId,
 }
 
 impl IfExpr {
-    pub fn new(condition: Expr, then_clause: Expr) -> Self {
+    pub fn new(condition: ExprId, then_clause: ExprId) -> Self {
         IfExpr {
             condition,
             then_clause,
@@ -498,33 +888,35 @@ impl IfExpr {
 }
 
 impl Compile for IfExpr {
+    /// Compiles to the value of the then clause, or nil when the condition is false.
     fn compile(&self, compiler: &mut Compiler) {
-        compiler.compile_expr(&self.condition);
+        compiler.compile_expr(self.condition);
 
         // Jump to else clause if false
         let then_jump = compiler.emit_jump(Opcode::JumpIfFalse);
         compiler.emit(Opcode::Pop);
 
-        compiler.compile_expr(&self.then_clause);
+        compiler.compile_expr(self.then_clause);
 
         let else_jump = compiler.emit_jump(Opcode::Jump);
 
         compiler.patch_jump(then_jump);
         compiler.emit(Opcode::Pop);
+        compiler.emit(Opcode::Nil);
 
         compiler.patch_jump(else_jump);
     }
 }
 
-#[derive(PartialEq, Debug)]
+#[derive(PartialEq, Debug, Clone)]
 pub struct IfElseExpr {
-    pub condition: Expr,
+    pub condition: ExprId,
     pub then_clause: BlockExpr,
     pub else_clause: BlockExpr,
 }
 
 impl IfElseExpr {
-    pub fn new(condition: Expr, then_clause: BlockExpr, else_clause: BlockExpr) -> Self {
+    pub fn new(condition: ExprId, then_clause: BlockExpr, else_clause: BlockExpr) -> Self {
         IfElseExpr {
             condition,
             then_clause,
@@ -535,30 +927,452 @@ impl IfElseExpr {
 
 impl Compile for IfElseExpr {
     fn compile(&self, compiler: &mut Compiler) {
-        compiler.compile_expr(&self.condition);
+        compiler.compile_expr(self.condition);
 
         // Jump to else clause if false
         let then_jump = compiler.emit_jump(Opcode::JumpIfFalse);
         compiler.emit(Opcode::Pop);
 
-        for expr in &self.then_clause.exprs {
-            compiler.compile_expr(expr);
-        }
+        self.then_clause.compile(compiler);
 
         let else_jump = compiler.emit_jump(Opcode::Jump);
 
         compiler.patch_jump(then_jump);
         compiler.emit(Opcode::Pop);
 
-        for expr in &self.else_clause.exprs {
-            compiler.compile_expr(expr);
-        }
+        self.else_clause.compile(compiler);
 
         compiler.patch_jump(else_jump);
     }
 }
 
-#[derive(PartialEq, Debug)]
+#[derive(PartialEq, Debug, Clone)]
+pub struct MatchExpr {
+    pub subject: ExprId,
+    pub arms: Vec<MatchArm>,
+    pub else_clause: Option<BlockExpr>,
+    pub line: usize,
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub struct MatchArm {
+    pub patterns: Vec<Pattern>,
+    pub body: BlockExpr,
+    pub line: usize,
+}
+
+impl MatchArm {
+    pub fn new(patterns: Vec<Pattern>, body: BlockExpr, line: usize) -> Self {
+        MatchArm {
+            patterns,
+            body,
+            line,
+        }
+    }
+}
+
+/// What a `match` arm compares its subject against.
+#[derive(PartialEq, Debug, Clone)]
+pub enum Pattern {
+    /// Matches values equal to a literal.
+    Value(ExprId),
+    /// Matches anything, binding it to the variable unless that's `_`.
+    Binding(Variable),
+    /// Matches arrays with an element for each item pattern, or at least that many when the
+    /// other elements are bound to a rest variable: `[first, *rest]`.
+    Array {
+        items: Vec<Pattern>,
+        rest: Option<Variable>,
+    },
+    /// Matches instances of a class or struct whose fields match the field patterns:
+    /// `Point(x, y: 0)`.
+    Class {
+        class: Variable,
+        fields: Vec<(String, Pattern)>,
+    },
+}
+
+/// A step from the subject of a match to a value inside it.
+#[derive(Clone)]
+enum Access {
+    Index(usize),
+    /// The elements of an array from the index on.
+    Rest(usize),
+    Property(String),
+}
+
+/// A test of the value at a path a pattern must pass.
+enum Check<'a> {
+    Equal(ExprId),
+    Is(&'a str),
+    /// An array length, exactly or at least.
+    Len(usize, bool),
+}
+
+impl Pattern {
+    /// Whether the pattern binds any variables.
+    pub fn binds(&self) -> bool {
+        match self {
+            Pattern::Value(_) => false,
+            Pattern::Binding(var) => var.name != "_",
+            Pattern::Array { items, rest } => {
+                items.iter().any(Pattern::binds) || rest.iter().any(|rest| rest.name != "_")
+            }
+            Pattern::Class { fields, .. } => fields.iter().any(|(_, pattern)| pattern.binds()),
+        }
+    }
+
+    /// Calls `visit` with each value the pattern compares against.
+    pub fn for_each_value(&self, visit: &mut dyn FnMut(ExprId)) {
+        match self {
+            Pattern::Value(expr) => visit(*expr),
+            Pattern::Binding(_) => {}
+            Pattern::Array { items, .. } => {
+                items.iter().for_each(|item| item.for_each_value(visit))
+            }
+            Pattern::Class { fields, .. } => fields
+                .iter()
+                .for_each(|(_, pattern)| pattern.for_each_value(visit)),
+        }
+    }
+
+    /// Calls `visit` with each value the pattern compares against, so it can be replaced.
+    pub fn for_each_value_mut(&mut self, visit: &mut dyn FnMut(&mut ExprId)) {
+        match self {
+            Pattern::Value(expr) => visit(expr),
+            Pattern::Binding(_) => {}
+            Pattern::Array { items, .. } => items
+                .iter_mut()
+                .for_each(|item| item.for_each_value_mut(visit)),
+            Pattern::Class { fields, .. } => fields
+                .iter_mut()
+                .for_each(|(_, pattern)| pattern.for_each_value_mut(visit)),
+        }
+    }
+
+    /// Flattens the pattern into the checks the value at `path` must pass, in the order they're
+    /// safe to run in, and the variables bound to values inside it.
+    fn lower<'a>(
+        &'a self,
+        path: &mut Vec<Access>,
+        checks: &mut Vec<(Vec<Access>, Check<'a>)>,
+        bindings: &mut Vec<(&'a Variable, Vec<Access>)>,
+    ) {
+        match self {
+            Pattern::Value(expr) => checks.push((path.clone(), Check::Equal(*expr))),
+            Pattern::Binding(var) => {
+                if var.name != "_" {
+                    bindings.push((var, path.clone()));
+                }
+            }
+            Pattern::Array { items, rest } => {
+                checks.push((path.clone(), Check::Is("Array")));
+                checks.push((path.clone(), Check::Len(items.len(), rest.is_none())));
+
+                for (i, item) in items.iter().enumerate() {
+                    path.push(Access::Index(i));
+                    item.lower(path, checks, bindings);
+                    path.pop();
+                }
+                if let Some(rest) = rest.as_ref().filter(|rest| rest.name != "_") {
+                    path.push(Access::Rest(items.len()));
+                    bindings.push((rest, path.clone()));
+                    path.pop();
+                }
+            }
+            Pattern::Class { class, fields } => {
+                checks.push((path.clone(), Check::Is(&class.name)));
+
+                for (field, pattern) in fields {
+                    path.push(Access::Property(field.clone()));
+                    pattern.lower(path, checks, bindings);
+                    path.pop();
+                }
+            }
+        }
+    }
+}
+
+/// Pushes the value at `path` inside the match subject in `slot`.
+fn load_path(compiler: &mut Compiler, slot: u8, path: &[Access]) {
+    let call_core = |compiler: &mut Compiler, name: &str| {
+        VarGetExpr::new(Variable::new("core".to_string())).compile(compiler);
+        compiler.emit_property(Opcode::GetProperty, name);
+    };
+
+    match path.split_last() {
+        None => {
+            compiler.emit(Opcode::GetLocal);
+            compiler.emit_byte(slot);
+        }
+        Some((Access::Index(i), parent)) => {
+            load_path(compiler, slot, parent);
+            compiler.emit_constant(Value::Number(*i as f64));
+            compiler.emit(Opcode::IndexSubscript);
+        }
+        Some((Access::Rest(start), parent)) => {
+            call_core(compiler, "slice");
+            load_path(compiler, slot, parent);
+            compiler.emit_constant(Value::Number(*start as f64));
+            compiler.emit(Opcode::Call);
+            compiler.emit_byte(2);
+        }
+        Some((Access::Property(name), parent)) => {
+            load_path(compiler, slot, parent);
+            compiler.emit_property(Opcode::GetProperty, name);
+        }
+    }
+}
+
+/// Emits a check of the value at `path`, leaving whether it passed on the stack.
+fn compile_check(compiler: &mut Compiler, slot: u8, path: &[Access], check: &Check) {
+    match check {
+        Check::Equal(expr) => {
+            load_path(compiler, slot, path);
+            compiler.push_temporary();
+            compiler.compile_expr(*expr);
+            compiler.pop_temporaries(1);
+            compiler.emit(Opcode::Equal);
+        }
+        Check::Is(target) => {
+            load_path(compiler, slot, path);
+            compile_type(compiler, target);
+            compiler.emit(Opcode::Is);
+        }
+        Check::Len(len, exact) => {
+            VarGetExpr::new(Variable::new("core".to_string())).compile(compiler);
+            compiler.emit_property(Opcode::GetProperty, "len");
+            load_path(compiler, slot, path);
+            compiler.emit(Opcode::Call);
+            compiler.emit_byte(1);
+
+            compiler.emit_constant(Value::Number(*len as f64));
+            if *exact {
+                compiler.emit(Opcode::Equal);
+            } else {
+                compiler.emit(Opcode::Less);
+                compiler.emit(Opcode::Not);
+            }
+        }
+    }
+}
+
+/// Below this many cases, comparing against each one is about as fast as a jump table.
+const MIN_JUMP_TABLE_CASES: usize = 4;
+
+impl MatchExpr {
+    pub fn new(
+        subject: ExprId,
+        arms: Vec<MatchArm>,
+        else_clause: Option<BlockExpr>,
+        line: usize,
+    ) -> Self {
+        MatchExpr {
+            subject,
+            arms,
+            else_clause,
+            line,
+        }
+    }
+
+    /// The cases as a jump table to arm indices, when they are all integers close together or
+    /// all strings. Gaps between the integers, and duplicate cases after the first, are left
+    /// out, which the index one past the last arm stands for.
+    fn jump_table_cases(&self, ast: &Ast) -> Option<Cases> {
+        let cases = self
+            .arms
+            .iter()
+            .enumerate()
+            .flat_map(|(arm, a)| a.patterns.iter().map(move |pattern| (pattern, arm)))
+            .map(|(pattern, arm)| match pattern {
+                Pattern::Value(expr) => match &ast[*expr] {
+                    ExprKind::Literal(literal) => Some((literal, arm)),
+                    _ => None,
+                },
+                _ => None,
+            })
+            .collect::<Option<Vec<_>>>()?;
+        if cases.len() < MIN_JUMP_TABLE_CASES {
+            return None;
+        }
+
+        let integers = cases
+            .iter()
+            .map(|(literal, arm)| match literal {
+                LiteralExpr::Number(n) if n.fract() == 0.0 && n.abs() <= u32::MAX as f64 => {
+                    Some((*n as i64, *arm))
+                }
+                _ => None,
+            })
+            .collect::<Option<Vec<_>>>();
+        if let Some(integers) = integers {
+            let min = integers.iter().map(|(n, _)| *n).min()?;
+            let max = integers.iter().map(|(n, _)| *n).max()?;
+            let len = (max - min + 1) as usize;
+            if len > integers.len() * 2 {
+                return None;
+            }
+
+            let mut targets = vec![self.arms.len(); len];
+            for (n, arm) in integers.into_iter().rev() {
+                targets[(n - min) as usize] = arm;
+            }
+            return Some(Cases::Dense { min, targets });
+        }
+
+        let mut strings = BTreeMap::new();
+        for (literal, arm) in cases {
+            match literal {
+                LiteralExpr::String(s) => {
+                    strings.entry(s.clone()).or_insert(arm);
+                }
+                _ => return None,
+            }
+        }
+        Some(Cases::Strings(strings))
+    }
+
+    /// Warns about arms that can never run, after one matching anything, and about matches
+    /// destructuring their subject that may match no arm and quietly evaluate to nil.
+    fn warn_exhaustiveness(&self, compiler: &mut Compiler) {
+        let catch_all = self.arms.iter().position(|arm| {
+            arm.patterns
+                .iter()
+                .any(|pattern| matches!(pattern, Pattern::Binding(_)))
+        });
+
+        match catch_all {
+            Some(catch_all) => {
+                for arm in &self.arms[catch_all + 1..] {
+                    compiler.warn(arm.line, "Unreachable case");
+                }
+            }
+            None if self.else_clause.is_none() => {
+                let destructures = self
+                    .arms
+                    .iter()
+                    .flat_map(|arm| &arm.patterns)
+                    .any(|pattern| {
+                        matches!(pattern, Pattern::Array { .. } | Pattern::Class { .. })
+                    });
+                if destructures {
+                    compiler.warn(
+                        self.line,
+                        "Match may not handle every value, add an else or a catch-all case",
+                    );
+                }
+            }
+            None => {}
+        }
+    }
+}
+
+impl Compile for MatchExpr {
+    /// Compiles to the value of the first arm with a pattern matching the subject, or else to
+    /// the value of the else clause or nil. The subject is kept in a hidden local that each
+    /// pattern is checked against, after jumping straight to the right arm through a jump
+    /// table when optimizing a match on many constant cases.
+    fn compile(&self, compiler: &mut Compiler) {
+        self.warn_exhaustiveness(compiler);
+        compiler.begin_scope();
+
+        // Names starting with '$' can't be written in Green, so the subject can't clash.
+        let subject = Variable::new("$subject".to_string());
+        compiler.compile_expr(self.subject);
+        compiler.compile_declare_var(&subject);
+        let slot = compiler.resolve_local(&subject.name) as u8;
+
+        let cases = if compiler.opt_level().uses_jump_tables() {
+            self.jump_table_cases(&compiler.ast)
+        } else {
+            None
+        };
+        // The table's index is taken now, before any `match` in the arms takes one, and its
+        // targets are filled in once the arms are compiled. A chunk whose tables have run out
+        // compares against each case instead.
+        let table = cases.and_then(|cases| {
+            let placeholder = JumpTable::new(Cases::Strings(Default::default()), 0, 0);
+            let index = compiler.current_chunk().add_jump_table(placeholder)?;
+            compiler.emit(Opcode::GetLocal);
+            compiler.emit_byte(slot);
+            compiler.emit(Opcode::JumpTable);
+            compiler.emit_byte(index);
+            Some((index, cases))
+        });
+        let fallback = compiler.current_chunk().code().len();
+
+        let mut body_starts = vec![];
+        let mut exits = vec![];
+        for arm in &self.arms {
+            let mut hits = vec![];
+            let mut misses = vec![];
+            let mut bindings = vec![];
+
+            for (i, pattern) in arm.patterns.iter().enumerate() {
+                // Every failed check of the previous pattern lands here, leaving its result.
+                if !misses.is_empty() {
+                    for miss in misses.drain(..) {
+                        compiler.patch_jump(miss);
+                    }
+                    compiler.emit(Opcode::Pop);
+                }
+
+                let mut checks = vec![];
+                pattern.lower(&mut vec![], &mut checks, &mut bindings);
+                for (path, check) in &checks {
+                    compile_check(compiler, slot, path, check);
+                    misses.push(compiler.emit_jump(Opcode::JumpIfFalse));
+                    compiler.emit(Opcode::Pop);
+                }
+
+                if i + 1 < arm.patterns.len() {
+                    hits.push(compiler.emit_jump(Opcode::Jump));
+                }
+            }
+
+            for hit in hits {
+                compiler.patch_jump(hit);
+            }
+            body_starts.push(compiler.current_chunk().code().len());
+
+            compiler.begin_scope();
+            for (var, path) in bindings {
+                load_path(compiler, slot, &path);
+                compiler.compile_declare_var(var);
+            }
+            arm.body.compile(compiler);
+            compiler.end_scope_with_value();
+            exits.push(compiler.emit_jump(Opcode::Jump));
+
+            if !misses.is_empty() {
+                for miss in misses {
+                    compiler.patch_jump(miss);
+                }
+                compiler.emit(Opcode::Pop);
+            }
+        }
+
+        let else_start = compiler.current_chunk().code().len();
+        match &self.else_clause {
+            Some(else_clause) => else_clause.compile(compiler),
+            None => compiler.emit(Opcode::Nil),
+        }
+
+        for exit in exits {
+            compiler.patch_jump(exit);
+        }
+        compiler.end_scope_with_value();
+
+        if let Some((index, cases)) = table {
+            let cases =
+                cases.map_targets(|arm| body_starts.get(arm).copied().unwrap_or(else_start));
+            *compiler.current_chunk().jump_table_mut(index.into()) =
+                JumpTable::new(cases, else_start, fallback);
+        }
+    }
+}
+
+#[derive(PartialEq, Debug, Clone)]
 pub struct FunctionDeclaration {
     pub parameters: Vec<Variable>,
     pub body: BlockExpr,
@@ -570,7 +1384,7 @@ impl FunctionDeclaration {
     }
 }
 
-#[derive(PartialEq, Debug)]
+#[derive(PartialEq, Debug, Clone)]
 pub struct FunctionExpr {
     pub variable: Variable,
     pub declaration: FunctionDeclaration,
@@ -585,16 +1399,29 @@ impl FunctionExpr {
     }
 }
 
-impl Compile for FunctionExpr {
-    fn compile(&self, compiler: &mut Compiler) {
-        let current_copy = compiler.current.clone();
-        compiler.current = CompilerInstance::new(GreenFunctionType::Function);
-        *compiler.current.enclosing_mut() = Box::new(Some(current_copy));
+impl FunctionExpr {
+    /// Compiles the function into a closure left on top of the stack.
+    pub(crate) fn compile_closure(
+        &self,
+        compiler: &mut Compiler,
+        function_type: GreenFunctionType,
+    ) {
+        compiler.begin_compiler(function_type.clone());
 
         // Set function name.
         *compiler.current.function_mut().name_mut() = self.variable.name.clone();
         *compiler.current.function_mut().chunk_mut().name_mut() = Some(self.variable.name.clone());
 
+        // Slot 0 holds the receiver of a method, or else the closure being called. Binding the
+        // function's name to it lets the body call itself no matter what the name refers to
+        // outside.
+        let slot_zero = match function_type {
+            GreenFunctionType::Method | GreenFunctionType::Initializer => "self".to_string(),
+            _ => self.variable.name.clone(),
+        };
+        compiler.current_chunk().begin_local(&slot_zero, 0);
+        compiler.current.locals_mut()[0] = Local::new(slot_zero, 0, 0);
+
         compiler.begin_scope();
 
         // Compile parameters.
@@ -609,51 +1436,304 @@ impl Compile for FunctionExpr {
         // Create the function object.
         let fun = compiler.end_compiler();
 
+        let constant = compiler.add_constant(Value::Function(fun));
         compiler.emit(Opcode::Closure);
+        compiler.emit_short(constant);
+    }
+}
 
-        let constant_id = compiler
-            .current_chunk()
-            .add_constant(Value::Function(Gc::new(fun)));
-            // .add_constant(Value::Function((compiler.alloc)(fun)));
-
-        compiler.emit_byte(constant_id);
+impl Compile for FunctionExpr {
+    fn compile(&self, compiler: &mut Compiler) {
+        self.compile_closure(compiler, GreenFunctionType::Function);
 
-        compiler.compile_define_var(&self.variable); // TODO fun is always global?
+        if *compiler.current.scope_depth() > 0 {
+            compiler.compile_declare_var(&self.variable);
+        } else {
+            compiler.compile_define_var(&self.variable);
+        }
     }
 }
 
-#[derive(PartialEq, Debug)]
+#[derive(PartialEq, Debug, Clone)]
 pub struct ClassExpr {
     pub name: Variable,
+    pub fields: Vec<FieldDecl>,
+    pub methods: Vec<FunctionExpr>,
+    /// `static var`s, kept on the class itself rather than its instances.
+    pub statics: Vec<StaticVarDecl>,
+    /// `static def`s, called on the class as `Name.method()` and without a `self`.
+    pub static_methods: Vec<FunctionExpr>,
+    /// The protocols the class says it `implements`, which it's checked against when they're
+    /// known while compiling.
+    pub protocols: Vec<Variable>,
 }
 
 impl ClassExpr {
-    pub fn new(name: Variable) -> Self {
-        ClassExpr { name }
+    pub fn new(name: Variable, fields: Vec<FieldDecl>, methods: Vec<FunctionExpr>) -> Self {
+        ClassExpr {
+            name,
+            fields,
+            methods,
+            statics: vec![],
+            static_methods: vec![],
+            protocols: vec![],
+        }
+    }
+
+    pub fn with_statics(
+        mut self,
+        statics: Vec<StaticVarDecl>,
+        static_methods: Vec<FunctionExpr>,
+    ) -> Self {
+        self.statics = statics;
+        self.static_methods = static_methods;
+        self
+    }
+
+    pub fn implementing(mut self, protocols: Vec<Variable>) -> Self {
+        self.protocols = protocols;
+        self
+    }
+
+    /// Warns about each protocol the class says it implements but has a method missing from,
+    /// or taking a different number of arguments.
+    fn check_protocols(&self, compiler: &mut Compiler) {
+        for protocol in &self.protocols {
+            let missing = compiler.protocols.get(&protocol.name).and_then(|p| {
+                p.missing_method(|name| {
+                    let method = self.methods.iter().find(|m| m.variable.name == name)?;
+                    Some(method.declaration.parameters.len() as u8)
+                })
+                .map(|method| format!("{}.{}", p.name(), method))
+            });
+            if let Some(method) = missing {
+                let message = format!(
+                    "Class {} implements {} but doesn't define {}",
+                    self.name.name, protocol.name, method
+                );
+                compiler.warn(compiler.line(), &message);
+            }
+        }
+    }
+}
+
+/// A field declared in a class body, `var x: Number`.
+#[derive(Debug, Clone)]
+pub struct FieldDecl {
+    pub name: Variable,
+    pub type_name: Variable,
+    /// Source line of the declaration, or 0 when unknown.
+    pub line: usize,
+}
+
+impl FieldDecl {
+    pub fn new(name: Variable, type_name: Variable) -> Self {
+        FieldDecl {
+            name,
+            type_name,
+            line: 0,
+        }
+    }
+
+    pub fn at_line(mut self, line: usize) -> Self {
+        self.line = line;
+        self
+    }
+}
+
+/// Like the lines of expressions in an `Ast`, lines don't take part in equality.
+impl PartialEq for FieldDecl {
+    fn eq(&self, other: &Self) -> bool {
+        self.name == other.name && self.type_name == other.type_name
+    }
+}
+
+/// `static var name = value` in a class body. The value is computed once, when the class is
+/// defined.
+#[derive(Debug, Clone)]
+pub struct StaticVarDecl {
+    pub name: Variable,
+    pub value: ExprId,
+    /// Source line of the declaration, or 0 when unknown.
+    pub line: usize,
+}
+
+impl StaticVarDecl {
+    pub fn new(name: Variable, value: ExprId) -> Self {
+        StaticVarDecl {
+            name,
+            value,
+            line: 0,
+        }
+    }
+
+    pub fn at_line(mut self, line: usize) -> Self {
+        self.line = line;
+        self
+    }
+}
+
+/// Like the lines of expressions in an `Ast`, lines don't take part in equality.
+impl PartialEq for StaticVarDecl {
+    fn eq(&self, other: &Self) -> bool {
+        self.name == other.name && self.value == other.value
     }
 }
 
 impl Compile for ClassExpr {
     fn compile(&self, compiler: &mut Compiler) {
-        let name_constant = compiler
-            .current_chunk()
-            .add_constant(Value::string(self.name.name.to_string()));
+        self.check_protocols(compiler);
+
         compiler.compile_declare_var(&self.name);
 
-        compiler.emit(Opcode::Class);
-        compiler.emit_byte(name_constant);
+        compiler.emit_name(Opcode::Class, &self.name.name);
         compiler.compile_define_var(&self.name);
+
+        if self.fields.is_empty()
+            && self.methods.is_empty()
+            && self.statics.is_empty()
+            && self.static_methods.is_empty()
+        {
+            return;
+        }
+
+        // Load the class so fields and methods can be attached to it.
+        VarGetExpr::new(self.name.clone()).compile(compiler);
+
+        for field in &self.fields {
+            compiler.emit_name(Opcode::Field, &field.name.name);
+        }
+
+        compiler.classes.push(ClassShape::new(self));
+
+        for method in &self.methods {
+            let function_type = if method.variable.name == "init" {
+                GreenFunctionType::Initializer
+            } else {
+                GreenFunctionType::Method
+            };
+            method.compile_closure(compiler, function_type);
+
+            compiler.emit_name(Opcode::Method, &method.variable.name);
+        }
+        compiler.classes.pop();
+
+        for method in &self.static_methods {
+            method.compile_closure(compiler, GreenFunctionType::Function);
+
+            compiler.emit_name(Opcode::Static, &method.variable.name);
+        }
+        for var in &self.statics {
+            // The class is below the value on the stack.
+            compiler.push_temporary();
+            compiler.compile_expr(var.value);
+            compiler.pop_temporaries(1);
+
+            compiler.emit_name(Opcode::Static, &var.name.name);
+        }
+
+        compiler.emit(Opcode::Pop);
+    }
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub struct StructExpr {
+    pub name: Variable,
+    pub fields: Vec<Variable>,
+}
+
+impl StructExpr {
+    pub fn new(name: Variable, fields: Vec<Variable>) -> Self {
+        StructExpr { name, fields }
+    }
+}
+
+impl Compile for StructExpr {
+    fn compile(&self, compiler: &mut Compiler) {
+        let fields = self.fields.iter().map(|f| f.name.clone()).collect();
+        let def = Struct::new(self.name.name.clone(), fields);
+        compiler.emit_constant(Value::Struct(Gc::new(def)));
+
+        if *compiler.current.scope_depth() > 0 {
+            compiler.compile_declare_var(&self.name);
+        } else {
+            compiler.compile_define_var(&self.name);
+        }
+    }
+}
+
+/// `protocol Name` followed by the methods it requires, one `def name(params)` per line, then
+/// `end`.
+#[derive(PartialEq, Debug, Clone)]
+pub struct ProtocolExpr {
+    pub name: Variable,
+    pub methods: Vec<MethodSignature>,
+}
+
+impl ProtocolExpr {
+    pub fn new(name: Variable, methods: Vec<MethodSignature>) -> Self {
+        ProtocolExpr { name, methods }
+    }
+}
+
+/// A method a protocol requires: its name and parameters, but no body.
+#[derive(PartialEq, Debug, Clone)]
+pub struct MethodSignature {
+    pub name: Variable,
+    pub parameters: Vec<Variable>,
+}
+
+impl MethodSignature {
+    pub fn new(name: Variable, parameters: Vec<Variable>) -> Self {
+        MethodSignature { name, parameters }
+    }
+}
+
+impl Compile for ProtocolExpr {
+    fn compile(&self, compiler: &mut Compiler) {
+        let methods = self
+            .methods
+            .iter()
+            .map(|m| (m.name.name.clone(), m.parameters.len() as u8))
+            .collect();
+        let protocol = Gc::new(Protocol::new(self.name.name.clone(), methods));
+        compiler.protocols.insert(self.name.name.clone(), protocol);
+        compiler.emit_constant(Value::Protocol(protocol));
+
+        if *compiler.current.scope_depth() > 0 {
+            compiler.compile_declare_var(&self.name);
+        } else {
+            compiler.compile_define_var(&self.name);
+        }
+    }
+}
+
+/// `test "name" do ... end` at the top level of a script. Running the script skips it: `green
+/// test` runs each test on its own, after the rest of the script.
+#[derive(PartialEq, Debug, Clone)]
+pub struct TestExpr {
+    pub name: String,
+    pub body: BlockExpr,
+}
+
+impl TestExpr {
+    pub fn new(name: String, body: BlockExpr) -> Self {
+        TestExpr { name, body }
     }
 }
 
-#[derive(PartialEq, Debug)]
+impl Compile for TestExpr {
+    fn compile(&self, _compiler: &mut Compiler) {}
+}
+
+#[derive(PartialEq, Debug, Clone)]
 pub struct WhileExpr {
-    pub condition: Expr,
-    pub body: Expr,
+    pub condition: ExprId,
+    pub body: ExprId,
 }
 
 impl WhileExpr {
-    pub fn new(condition: Expr, body: Expr) -> Self {
+    pub fn new(condition: ExprId, body: ExprId) -> Self {
         WhileExpr { condition, body }
     }
 }
@@ -661,11 +1741,11 @@ impl WhileExpr {
 impl Compile for WhileExpr {
     fn compile(&self, compiler: &mut Compiler) {
         let loop_start = compiler.current_chunk().code().len();
-        compiler.compile_expr(&self.condition);
+        compiler.compile_expr(self.condition);
 
         let exit_jump = compiler.emit_jump(Opcode::JumpIfFalse);
         compiler.emit(Opcode::Pop);
-        compiler.compile_expr(&self.body);
+        compiler.compile_statement(self.body);
 
         compiler.emit_loop(loop_start);
         compiler.patch_jump(exit_jump);
@@ -673,54 +1753,310 @@ impl Compile for WhileExpr {
     }
 }
 
-#[derive(PartialEq, Debug)]
+/// `for x in source do ... end`, looping over anything `iter` accepts: arrays, tuples, maps,
+/// strings, ranges and classes with an `iter()` method. Naming two variables, `for k, v in m`,
+/// unpacks each `[key, value]` pair of a map.
+#[derive(PartialEq, Debug, Clone)]
+pub struct ForExpr {
+    pub variable: Variable,
+    pub second_variable: Option<Variable>,
+    pub source: ExprId,
+    pub body: ExprId,
+}
+
+impl ForExpr {
+    pub fn new(
+        variable: Variable,
+        second_variable: Option<Variable>,
+        source: ExprId,
+        body: ExprId,
+    ) -> Self {
+        ForExpr {
+            variable,
+            second_variable,
+            source,
+            body,
+        }
+    }
+
+    /// The `while` loop the for loop stands for, in a block that scopes the loop variable to
+    /// the loop. It's added to `ast`, along with copies of the loop's expressions in `from`.
+    /// Ranges written in the loop are counted without making an iterator.
+    fn desugar(&self, from: &Ast, ast: &mut Ast) -> ExprId {
+        let body = ast.copy(from, self.body);
+        let exprs = match &from[self.source] {
+            ExprKind::Range(range) if self.second_variable.is_none() => {
+                let range = RangeExpr::new(
+                    ast.copy(from, range.start),
+                    ast.copy(from, range.end),
+                    range.step.map(|step| ast.copy(from, step)),
+                    range.descending,
+                );
+                counting_loop(ast, self.variable.clone(), range, body)
+            }
+            _ => {
+                let source = ast.copy(from, self.source);
+                let second_variable = self.second_variable.clone();
+                iterating_loop(ast, self.variable.clone(), second_variable, source, body)
+            }
+        };
+        ast.add(ExprKind::Block(BlockExpr::new(exprs)))
+    }
+}
+
+/// Compiles the expression `desugar` adds to a fresh AST, given the AST being compiled to copy
+/// the expressions it needs from.
+fn compile_desugared(compiler: &mut Compiler, desugar: impl FnOnce(&Ast, &mut Ast) -> ExprId) {
+    let mut ast = Ast::new();
+    let expr = desugar(&compiler.ast, &mut ast);
+    compiler.in_ast(Rc::new(ast), |compiler| compiler.compile_expr(expr));
+}
+
+/// Names starting with '$' can't be written in Green, so desugared loops can keep their state
+/// in them without clashing with the loop's own variables.
+fn hidden(name: &str) -> Variable {
+    Variable::new(format!("${}", name))
+}
+
+/// Declares `variable` at the range's start, then runs `body` and steps it until it reaches
+/// the end. The end and step are evaluated once, before the loop, unless they are literals.
+fn counting_loop(ast: &mut Ast, variable: Variable, range: RangeExpr, body: ExprId) -> Vec<ExprId> {
+    let get =
+        |ast: &mut Ast, var: &Variable| ast.add(ExprKind::VarGet(VarGetExpr::new(var.clone())));
+    let (compare, advance) = if range.descending {
+        (BinaryOperator::GreaterThan, BinaryOperator::Subtract)
+    } else {
+        (BinaryOperator::LessThan, BinaryOperator::Add)
+    };
+    let step = range
+        .step
+        .unwrap_or_else(|| ast.add(ExprKind::Literal(LiteralExpr::Number(1.0))));
+
+    let mut exprs = vec![ast.add(ExprKind::VarAssign(VarAssignExpr::new(
+        variable.clone(),
+        range.start,
+    )))];
+    let mut evaluate_once = |ast: &mut Ast, name: &str, expr: ExprId| {
+        if matches!(ast[expr], ExprKind::Literal(_)) {
+            return expr;
+        }
+        let var = hidden(name);
+        exprs.push(ast.add(ExprKind::VarAssign(VarAssignExpr::new(var.clone(), expr))));
+        get(ast, &var)
+    };
+    let end = evaluate_once(ast, "end", range.end);
+    let step = evaluate_once(ast, "step", step);
+
+    let current = get(ast, &variable);
+    let condition = ast.add(ExprKind::Binary(BinaryExpr::new(current, end, compare)));
+    let current = get(ast, &variable);
+    let next = ast.add(ExprKind::Binary(BinaryExpr::new(current, step, advance)));
+    let advance = ast.add(ExprKind::VarSet(VarSetExpr::new(variable, next)));
+    let body = ast.add(ExprKind::Sequence(SequenceExpr::new(vec![body, advance])));
+    exprs.push(ast.add(ExprKind::While(WhileExpr::new(condition, body))));
+    exprs
+}
+
+/// Gets an iterator for `source` with `iter`, then runs `body` for each value its `next()`
+/// gives while `has_next()` is true, declaring `variable` in a fresh scope each time.
+fn iterating_loop(
+    ast: &mut Ast,
+    variable: Variable,
+    second_variable: Option<Variable>,
+    source: ExprId,
+    body: ExprId,
+) -> Vec<ExprId> {
+    let iterator = hidden("iter");
+    let call_method = |ast: &mut Ast, name: &str| {
+        let receiver = ast.add(ExprKind::VarGet(VarGetExpr::new(iterator.clone())));
+        let method = ast.add(ExprKind::GetProperty(GetExpr::new(
+            receiver,
+            name.to_string(),
+        )));
+        ast.add(ExprKind::Call(CallExpr::new(method, vec![])))
+    };
+    let core = ast.add(ExprKind::VarGet(VarGetExpr::new(Variable::new(
+        "core".to_string(),
+    ))));
+    let iter = ast.add(ExprKind::GetProperty(GetExpr::new(
+        core,
+        "iter".to_string(),
+    )));
+
+    let next = call_method(ast, "next");
+    let declare = match second_variable {
+        Some(second_variable) => ast.add(ExprKind::Destructure(DestructureExpr::new(
+            Destructure::Array(vec![variable, second_variable]),
+            next,
+        ))),
+        None => ast.add(ExprKind::VarAssign(VarAssignExpr::new(variable, next))),
+    };
+
+    let iterate = ast.add(ExprKind::Call(CallExpr::new(iter, vec![source])));
+    let has_next = call_method(ast, "has_next");
+    let body = ast.add(ExprKind::Block(BlockExpr::new(vec![declare, body])));
+    vec![
+        ast.add(ExprKind::VarAssign(VarAssignExpr::new(
+            iterator.clone(),
+            iterate,
+        ))),
+        ast.add(ExprKind::While(WhileExpr::new(has_next, body))),
+    ]
+}
+
+impl Compile for ForExpr {
+    fn compile(&self, compiler: &mut Compiler) {
+        compile_desugared(compiler, |from, ast| self.desugar(from, ast));
+    }
+}
+
+/// What a comprehension collects: array elements, or the keys and values of a map.
+#[derive(PartialEq, Debug, Clone)]
+pub enum Comprehension {
+    Array(ExprId),
+    Map(ExprId, ExprId),
+}
+
+/// The rest of a range a comprehension loops over, `to end step n`, its start being the
+/// comprehension's source.
+#[derive(PartialEq, Debug, Clone)]
+pub struct ComprehensionRange {
+    pub end: ExprId,
+    pub step: Option<ExprId>,
+    pub descending: bool,
+}
+
+/// `[element for x in source if condition]` or `{key: value for k, v in source if condition}`.
+/// The source is a range or anything a `for` loop can loop over, a map's `[key, value]` pairs
+/// being looped over. Naming two variables unpacks each pair. Arrays and maps are looped over
+/// as they were when the loop started: storing into them from the loop doesn't change what it
+/// sees.
+#[derive(PartialEq, Debug, Clone)]
+pub struct ComprehensionExpr {
+    pub collect: Comprehension,
+    pub variable: Variable,
+    pub second_variable: Option<Variable>,
+    pub source: ExprId,
+    pub range: Option<ComprehensionRange>,
+    pub condition: Option<ExprId>,
+}
+
+impl ComprehensionExpr {
+    /// A block that loops over the source, adding each element to a fresh array or map, and
+    /// evaluates to that collection. Like a desugared `for` loop, it's added to `ast` along
+    /// with copies of the comprehension's expressions in `from`.
+    fn desugar(&self, from: &Ast, ast: &mut Ast) -> ExprId {
+        let items = hidden("items");
+        let get_items = |ast: &mut Ast| ast.add(ExprKind::VarGet(VarGetExpr::new(items.clone())));
+
+        let (collection, add) = match self.collect {
+            Comprehension::Array(element) => {
+                let (array, element) = (get_items(ast), ast.copy(from, element));
+                (
+                    ast.add(ExprKind::Array(ArrayExpr::new(Some(vec![])))),
+                    ast.add(ExprKind::Append(AppendExpr::new(array, element))),
+                )
+            }
+            Comprehension::Map(key, value) => {
+                let map = get_items(ast);
+                let (key, value) = (ast.copy(from, key), ast.copy(from, value));
+                (
+                    ast.add(ExprKind::Map(MapExpr::new(vec![]))),
+                    ast.add(ExprKind::Subscript(SubscriptExpr::new(
+                        map,
+                        key,
+                        Some(value),
+                    ))),
+                )
+            }
+        };
+        let body = match self.condition {
+            Some(condition) => {
+                let condition = ast.copy(from, condition);
+                ast.add(ExprKind::If(IfExpr::new(condition, add)))
+            }
+            None => add,
+        };
+
+        let mut exprs = vec![ast.add(ExprKind::VarAssign(VarAssignExpr::new(
+            items.clone(),
+            collection,
+        )))];
+        let (variable, source) = (self.variable.clone(), ast.copy(from, self.source));
+        exprs.extend(match &self.range {
+            Some(range) => {
+                let end = ast.copy(from, range.end);
+                let step = range.step.map(|step| ast.copy(from, step));
+                let range = RangeExpr::new(source, end, step, range.descending);
+                counting_loop(ast, variable, range, body)
+            }
+            None => {
+                let second_variable = self.second_variable.clone();
+                iterating_loop(ast, variable, second_variable, source, body)
+            }
+        });
+        exprs.push(get_items(ast));
+        ast.add(ExprKind::Block(BlockExpr::new(exprs)))
+    }
+}
+
+impl Compile for ComprehensionExpr {
+    fn compile(&self, compiler: &mut Compiler) {
+        compile_desugared(compiler, |from, ast| self.desugar(from, ast));
+    }
+}
+
+#[derive(PartialEq, Debug, Clone)]
 pub struct CallExpr {
-    pub callee: Expr,
-    pub args: Vec<Expr>,
+    pub callee: ExprId,
+    pub args: Vec<ExprId>,
 }
 
 impl CallExpr {
-    pub fn new(callee: Expr, args: Vec<Expr>) -> Self {
+    pub fn new(callee: ExprId, args: Vec<ExprId>) -> Self {
         CallExpr { callee, args }
     }
 }
 
 impl Compile for CallExpr {
     fn compile(&self, compiler: &mut Compiler) {
+        if compiler.compile_inlined(self) {
+            return;
+        }
+
         let arity = self.args.len();
         if arity > 8 {
             panic!() // TODO
         }
 
-        compiler.compile_expr(&self.callee);
+        compiler.compile_expr(self.callee);
+        compiler.push_temporary();
 
         for arg in &self.args {
-            compiler.compile_expr(arg);
+            compiler.compile_expr(*arg);
+            compiler.push_temporary();
         }
+        compiler.pop_temporaries(arity + 1);
 
         compiler.emit(Opcode::Call);
         compiler.emit_byte(arity as u8);
     }
 }
 
-#[derive(PartialEq, Debug)]
+#[derive(PartialEq, Debug, Clone)]
 pub struct ReturnExpr {
-    pub expr: Option<Expr>,
+    pub expr: Option<ExprId>,
 }
 
 impl ReturnExpr {
-    pub fn new(expr: Option<Expr>) -> Self {
+    pub fn new(expr: Option<ExprId>) -> Self {
         ReturnExpr { expr }
     }
 }
 
 impl Compile for ReturnExpr {
     fn compile(&self, compiler: &mut Compiler) {
-        if *compiler.current.function_type() == GreenFunctionType::Script {
-            panic!("Can't return from top level code."); // TODO Turn into error
-        }
-
-        if let Some(expr) = &self.expr {
+        if let Some(expr) = self.expr {
             compiler.compile_expr(expr);
             compiler.emit(Opcode::Return);
         } else {
@@ -729,40 +2065,212 @@ impl Compile for ReturnExpr {
     }
 }
 
-#[derive(PartialEq, Debug)]
+#[derive(PartialEq, Debug, Clone)]
 pub struct ArrayExpr {
-    pub exprs: Option<Vec<Expr>>,
+    pub exprs: Option<Vec<ExprId>>,
 }
 
 impl ArrayExpr {
-    pub fn new(exprs: Option<Vec<Expr>>) -> Self {
+    pub fn new(exprs: Option<Vec<ExprId>>) -> Self {
         ArrayExpr { exprs }
     }
 }
 
 impl Compile for ArrayExpr {
     fn compile(&self, compiler: &mut Compiler) {
-        if let Some(exprs) = &self.exprs {
+        let ast = Rc::clone(&compiler.ast);
+        let exprs = self.exprs.as_deref().unwrap_or_default();
+        if exprs
+            .iter()
+            .any(|expr| matches!(ast[*expr], ExprKind::Range(_)))
+        {
+            // Ranges expand into a number of elements only known at runtime, so the array is
+            // built up one element or range at a time.
+            compiler.emit(Opcode::NewArray);
+            compiler.emit_byte(0);
+            compiler.push_temporary();
             for expr in exprs {
-                expr.node.compile(compiler);
+                match &ast[*expr] {
+                    ExprKind::Range(range) => range.compile_push(compiler),
+                    node => {
+                        node.compile(compiler);
+                        compiler.emit(Opcode::ArrayPush);
+                    }
+                }
             }
+            compiler.pop_temporaries(1);
+            return;
+        }
+
+        for expr in exprs {
+            ast[*expr].compile(compiler);
+            compiler.push_temporary();
         }
+        compiler.pop_temporaries(exprs.len());
 
-        compiler.emit(Opcode::NewArray);
         let exprs_len = self.exprs.as_ref().map_or_else(|| 0, |a| a.len());
-        compiler.emit_byte(exprs_len as u8);
+        if exprs_len <= u8::MAX as usize {
+            compiler.emit(Opcode::NewArray);
+            compiler.emit_byte(exprs_len as u8);
+        } else if exprs_len <= u16::MAX as usize {
+            compiler.emit(Opcode::NewArrayLong);
+            compiler.emit_short(exprs_len as u16);
+        } else {
+            compiler.error(format!(
+                "Array literal has {} elements, but can have at most {}",
+                exprs_len,
+                u16::MAX
+            ));
+        }
+    }
+}
+
+/// `a, b`: the values of a `return` that gives back more than one.
+#[derive(PartialEq, Debug, Clone)]
+pub struct TupleExpr {
+    pub exprs: Vec<ExprId>,
+}
+
+impl TupleExpr {
+    pub fn new(exprs: Vec<ExprId>) -> Self {
+        TupleExpr { exprs }
+    }
+}
+
+impl Compile for TupleExpr {
+    fn compile(&self, compiler: &mut Compiler) {
+        let ast = Rc::clone(&compiler.ast);
+        for expr in &self.exprs {
+            ast[*expr].compile(compiler);
+            compiler.push_temporary();
+        }
+        compiler.pop_temporaries(self.exprs.len());
+
+        compiler.emit(Opcode::NewTuple);
+        compiler.emit_byte(self.exprs.len() as u8);
+    }
+}
+
+/// The numbers from `start` up to, but not including, `end`, written `start to end` or
+/// `start downTo end` with an optional `step`. As an element of an array literal it expands
+/// into its numbers, anywhere else it makes a range value.
+#[derive(PartialEq, Debug, Clone)]
+pub struct RangeExpr {
+    pub start: ExprId,
+    pub end: ExprId,
+    pub step: Option<ExprId>,
+    pub descending: bool,
+}
+
+impl RangeExpr {
+    pub fn new(start: ExprId, end: ExprId, step: Option<ExprId>, descending: bool) -> Self {
+        RangeExpr {
+            start,
+            end,
+            step,
+            descending,
+        }
+    }
+
+    /// Appends the range's numbers to the array on top of the stack.
+    fn compile_push(&self, compiler: &mut Compiler) {
+        self.compile_bounds(compiler);
+        compiler.emit(Opcode::ArrayPushRange);
+    }
+
+    /// Pushes the range's start, end and step, the step negated if the range counts down.
+    fn compile_bounds(&self, compiler: &mut Compiler) {
+        compiler.compile_expr(self.start);
+        compiler.push_temporary();
+        compiler.compile_expr(self.end);
+        compiler.push_temporary();
+        match &self.step {
+            Some(step) => compiler.compile_expr(*step),
+            None => compiler.emit_constant(Value::Number(1.0)),
+        }
+        compiler.pop_temporaries(2);
+        if self.descending {
+            compiler.emit(Opcode::Negate);
+        }
     }
 }
 
-#[derive(PartialEq, Debug)]
+/// Outside array literals, a range is a value of its own.
+impl Compile for RangeExpr {
+    fn compile(&self, compiler: &mut Compiler) {
+        self.compile_bounds(compiler);
+        compiler.emit(Opcode::NewRange);
+    }
+}
+
+/// A map literal, `{key: value, ...}`.
+#[derive(PartialEq, Debug, Clone)]
+pub struct MapExpr {
+    pub entries: Vec<(ExprId, ExprId)>,
+}
+
+impl MapExpr {
+    pub fn new(entries: Vec<(ExprId, ExprId)>) -> Self {
+        MapExpr { entries }
+    }
+}
+
+impl Compile for MapExpr {
+    fn compile(&self, compiler: &mut Compiler) {
+        if self.entries.len() > u8::MAX as usize {
+            panic!(
+                "Map literal has {} entries, but can have at most {}.",
+                self.entries.len(),
+                u8::MAX
+            );
+        }
+
+        for (key, value) in &self.entries {
+            compiler.compile_expr(*key);
+            compiler.push_temporary();
+            compiler.compile_expr(*value);
+            compiler.push_temporary();
+        }
+        compiler.pop_temporaries(self.entries.len() * 2);
+
+        compiler.emit(Opcode::NewMap);
+        compiler.emit_byte(self.entries.len() as u8);
+    }
+}
+
+/// Appends an item to an array, leaving the array on the stack. There is no syntax for it; the
+/// parser produces it when desugaring comprehensions.
+#[derive(PartialEq, Debug, Clone)]
+pub struct AppendExpr {
+    pub array: ExprId,
+    pub item: ExprId,
+}
+
+impl AppendExpr {
+    pub fn new(array: ExprId, item: ExprId) -> Self {
+        AppendExpr { array, item }
+    }
+}
+
+impl Compile for AppendExpr {
+    fn compile(&self, compiler: &mut Compiler) {
+        compiler.compile_expr(self.array);
+        compiler.push_temporary();
+        compiler.compile_expr(self.item);
+        compiler.pop_temporaries(1);
+        compiler.emit(Opcode::ArrayPush);
+    }
+}
+
+#[derive(PartialEq, Debug, Clone)]
 pub struct SubscriptExpr {
-    callee: Expr, // TODO Naming???
-    index: Expr,
-    expr: Option<Expr>, // TODO Comment
+    pub callee: ExprId, // TODO Naming???
+    pub index: ExprId,
+    pub expr: Option<ExprId>, // TODO Comment
 }
 
 impl SubscriptExpr {
-    pub fn new(callee: Expr, index: Expr, expr: Option<Expr>) -> SubscriptExpr {
+    pub fn new(callee: ExprId, index: ExprId, expr: Option<ExprId>) -> SubscriptExpr {
         SubscriptExpr {
             callee,
             index,
@@ -773,66 +2281,98 @@ impl SubscriptExpr {
 
 impl Compile for SubscriptExpr {
     fn compile(&self, compiler: &mut Compiler) {
-        self.callee.node.compile(compiler);
-        self.index.node.compile(compiler);
-
-        if let Some(expr) = &self.expr {
-            expr.node.compile(compiler);
+        let ast = Rc::clone(&compiler.ast);
+        ast[self.callee].compile(compiler);
+        compiler.push_temporary();
+        ast[self.index].compile(compiler);
+
+        if let Some(expr) = self.expr {
+            compiler.push_temporary();
+            ast[expr].compile(compiler);
+            compiler.pop_temporaries(2);
             compiler.emit(Opcode::StoreSubscript);
         } else {
+            compiler.pop_temporaries(1);
             compiler.emit(Opcode::IndexSubscript);
         }
     }
 }
 
-#[derive(PartialEq, Debug)]
+#[derive(PartialEq, Debug, Clone)]
 pub struct GetExpr {
-    expr: Expr, // TODO Rename
-    property: String,
+    pub expr: ExprId, // TODO Rename
+    pub property: String,
 }
 
 impl GetExpr {
-    pub fn new(expr: Expr, property: String) -> Self {
+    pub fn new(expr: ExprId, property: String) -> Self {
         GetExpr { expr, property }
     }
 }
 
 impl Compile for GetExpr {
+    /// Stack contract: `GetProperty` pops the instance and pushes the property's value.
     fn compile(&self, compiler: &mut Compiler) {
-        compiler.compile_expr(&self.expr);
-
-        compiler.emit(Opcode::GetProperty);
+        compiler.check_property(self.expr, &self.property, None);
+        compiler.compile_expr(self.expr);
 
-        let property_constant = compiler
-            .current_chunk()
-            .add_constant(Value::string(self.property.to_string()));
-        compiler.emit_byte(property_constant);
+        compiler.emit_property(Opcode::GetProperty, &self.property);
     }
 }
 
-#[derive(PartialEq, Debug)]
+#[derive(PartialEq, Debug, Clone)]
 pub struct SetExpr {
-    lhs: Expr,
-    rhs: Expr,
-    property: String,
+    pub lhs: ExprId,
+    pub rhs: ExprId,
+    pub property: String,
 }
 
 impl SetExpr {
-    pub fn new(lhs: Expr, rhs: Expr, property: String) -> Self {
+    pub fn new(lhs: ExprId, rhs: ExprId, property: String) -> Self {
         SetExpr { lhs, rhs, property }
     }
 }
 
 impl Compile for SetExpr {
+    /// Stack contract: the instance is evaluated first, then the value. `SetProperty` pops both
+    /// and pushes the value back, so `a.b.c = d` loads `a.b` with `GetProperty` before setting
+    /// `c` on it.
     fn compile(&self, compiler: &mut Compiler) {
-        compiler.compile_expr(&self.lhs);
-        compiler.compile_expr(&self.rhs);
+        compiler.check_property(self.lhs, &self.property, Some(self.rhs));
+        compiler.compile_expr(self.lhs);
+        compiler.push_temporary();
+        compiler.compile_expr(self.rhs);
+        compiler.pop_temporaries(1);
+
+        compiler.emit_property(Opcode::SetProperty, &self.property);
+    }
+}
 
-        compiler.emit(Opcode::SetProperty);
+#[derive(PartialEq, Debug, Clone)]
+pub struct IsExpr {
+    pub expr: ExprId,
+    pub target: Variable,
+}
+
+impl IsExpr {
+    pub fn new(expr: ExprId, target: Variable) -> Self {
+        IsExpr { expr, target }
+    }
+}
+
+impl Compile for IsExpr {
+    fn compile(&self, compiler: &mut Compiler) {
+        compiler.compile_expr(self.expr);
+        compile_type(compiler, &self.target.name);
+        compiler.emit(Opcode::Is);
+    }
+}
 
-        let property_constant = compiler
-            .current_chunk()
-            .add_constant(Value::string(self.property.to_string()));
-        compiler.emit_byte(property_constant);
+/// Pushes the type `is` checks against: builtin types by name, classes and structs by identity.
+fn compile_type(compiler: &mut Compiler, name: &str) {
+    if BUILTIN_TYPES.contains(&name) && compiler.resolve_local(&name.to_string()) == -1 {
+        compiler.emit_string(name);
+    } else {
+        VarGetExpr::new(Variable::new(name.to_string())).compile(compiler);
     }
 }
@@ -0,0 +1,724 @@
+use crate::error::ParserError;
+use crate::syntax::ast::{Ast, ExprId};
+use crate::syntax::expr::{
+    BinaryOperator, Comprehension, Destructure, ExprKind, FieldDecl, FunctionExpr, LiteralExpr,
+    Pattern, StaticVarDecl, UnaryOperator, Variable,
+};
+use crate::syntax::lexer::Lexer;
+use crate::syntax::parser::GreenParser;
+use crate::syntax::token::{Keyword, TokenType};
+use std::collections::VecDeque;
+
+const INDENT: &str = "    ";
+
+/// Formats Green source in its canonical layout: one statement per line, blocks indented by
+/// four spaces, single spaces around operators and after commas, and at most one blank line
+/// in a row. Comments and blank lines between statements are kept. Blocks written on a single
+/// line, like `if n < 2 do return n end`, stay on one line. Modules that define macros are left
+/// as they are.
+pub fn format(source: &str) -> Result<String, ParserError> {
+    let module = GreenParser::parse(source)?;
+    // Only the expansions of macro calls are parsed, so there'd be no calls left to print.
+    if module.defines_macros() {
+        return Ok(source.to_string());
+    }
+
+    let mut formatter = Formatter::new(source, module.ast())?;
+    let mut out = formatter.statements(module.exprs());
+    for comment in formatter.comments.drain(..) {
+        out.push_str(comment.text);
+        out.push('\n');
+    }
+    Ok(out)
+}
+
+/// Something declared in a class body.
+enum Member<'a> {
+    Field(&'a FieldDecl),
+    StaticVar(&'a StaticVarDecl),
+    /// A method, and whether it's static.
+    Method(&'a FunctionExpr, bool),
+}
+
+struct Comment<'a> {
+    line: usize,
+    text: &'a str,
+    /// Whether the comment follows code on its line.
+    trailing: bool,
+}
+
+struct Formatter<'a> {
+    ast: &'a Ast,
+    lines: Vec<&'a str>,
+    /// The comments not formatted yet, in source order.
+    comments: VecDeque<Comment<'a>>,
+    indent: usize,
+    /// The line of the statement being formatted.
+    line: usize,
+    /// The line of the statement after it, which comments in its blocks come before.
+    next_line: usize,
+    /// The line and name of every function definition, in source order.
+    defs: Vec<(usize, &'a str)>,
+}
+
+impl<'a> Formatter<'a> {
+    fn new(source: &'a str, ast: &'a Ast) -> Result<Self, ParserError> {
+        let tokens = Lexer::parse(source)?;
+
+        let mut comments = VecDeque::new();
+        let mut defs = vec![];
+        let mut previous = None;
+        for token in tokens {
+            if previous == Some(TokenType::Keyword(Keyword::Def)) {
+                defs.push((token.position.line, token.source));
+            }
+            if token.token_type == TokenType::LineComment {
+                comments.push_back(Comment {
+                    line: token.position.line,
+                    text: token.source,
+                    trailing: !matches!(previous, None | Some(TokenType::Line)),
+                });
+            }
+            previous = Some(token.token_type);
+        }
+
+        Ok(Formatter {
+            ast,
+            lines: source.lines().collect(),
+            comments,
+            indent: 0,
+            line: 0,
+            next_line: usize::MAX,
+            defs,
+        })
+    }
+
+    /// The line of the first definition of a function with the given name after a line.
+    fn def_line(&self, name: &str, after: usize) -> usize {
+        self.defs
+            .iter()
+            .find(|(line, def)| *line > after && *def == name)
+            .map_or(after, |(line, _)| *line)
+    }
+
+    /// Formats statements at the current indentation, each on its own line.
+    fn statements(&mut self, exprs: &[ExprId]) -> String {
+        let mut out = String::new();
+        let next_line = self.next_line;
+        for (i, expr) in exprs.iter().enumerate() {
+            let expr_line = self.ast.line(*expr);
+            self.next_line = exprs
+                .get(i + 1)
+                .map_or(next_line, |next| self.ast.line(*next));
+            if expr_line != 0 {
+                self.comments_before(&mut out, expr_line);
+                self.blank_line_before(&mut out, expr_line);
+            }
+
+            let trailing = match self.comments.front() {
+                Some(comment) if comment.trailing && comment.line == expr_line => {
+                    self.comments.pop_front()
+                }
+                _ => None,
+            };
+
+            let line = std::mem::replace(&mut self.line, expr_line);
+            let text = self.expr(*expr);
+            self.line = line;
+
+            out.push_str(&self.pad());
+            match trailing {
+                // The comment goes at the end of the statement's first line.
+                Some(comment) => {
+                    let (first, rest) = text.split_at(text.find('\n').unwrap_or(text.len()));
+                    out.push_str(&format!("{} {}{}", first, comment.text, rest));
+                }
+                None => out.push_str(&text),
+            }
+            out.push('\n');
+        }
+        self.next_line = next_line;
+        out
+    }
+
+    /// Formats the statements of a block one level deeper. Comments after the last statement
+    /// stay in the block when they're indented deeper than the statement opening it.
+    fn block(&mut self, exprs: &[ExprId]) -> String {
+        self.indent += 1;
+        let mut out = self.statements(exprs);
+
+        let column = self.column(self.line);
+        while let Some(comment) = self.comments.front() {
+            if comment.trailing
+                || comment.line >= self.next_line
+                || self.column(comment.line) <= column
+            {
+                break;
+            }
+
+            let line = comment.line;
+            self.blank_line_before(&mut out, line);
+            out.push_str(&format!("{}{}\n", self.pad(), comment.text));
+            self.comments.pop_front();
+        }
+
+        self.indent -= 1;
+        out
+    }
+
+    fn comments_before(&mut self, out: &mut String, line: usize) {
+        while let Some(comment) = self.comments.front() {
+            if comment.line >= line {
+                break;
+            }
+
+            let comment_line = comment.line;
+            self.blank_line_before(out, comment_line);
+            let comment = self.comments.pop_front().unwrap();
+            out.push_str(&format!("{}{}\n", self.pad(), comment.text));
+        }
+    }
+
+    /// Keeps a blank line before the given line, unless it starts a block.
+    fn blank_line_before(&self, out: &mut String, line: usize) {
+        let blank = line >= 2
+            && self
+                .lines
+                .get(line - 2)
+                .is_some_and(|l| l.trim().is_empty());
+        if blank && !out.is_empty() && !out.ends_with("\n\n") {
+            out.push('\n');
+        }
+    }
+
+    fn column(&self, line: usize) -> usize {
+        match line.checked_sub(1).and_then(|index| self.lines.get(index)) {
+            Some(line) => line.len() - line.trim_start().len(),
+            None => 0,
+        }
+    }
+
+    fn pad(&self) -> String {
+        INDENT.repeat(self.indent)
+    }
+
+    /// Formats a construct made of blocks, like `if c do a else b end`. It stays on one line
+    /// when all of its blocks were written on the line it starts on, and it fits there.
+    fn blocks(&mut self, line: usize, opening: String, clauses: &[(&str, &[ExprId])]) -> String {
+        self.clauses(line, opening, clauses, "end")
+    }
+
+    /// Formats blocks like `blocks`, closed by `closing` unless that's empty.
+    fn clauses(
+        &mut self,
+        line: usize,
+        opening: String,
+        clauses: &[(&str, &[ExprId])],
+        closing: &str,
+    ) -> String {
+        let one_line = clauses
+            .iter()
+            .all(|(_, exprs)| exprs.len() <= 1 && exprs.iter().all(|e| self.ast.line(*e) == line));
+        if one_line {
+            let mut out = opening.clone();
+            for (keyword, exprs) in clauses {
+                if !keyword.is_empty() {
+                    out.push(' ');
+                    out.push_str(keyword);
+                }
+                for expr in exprs.iter() {
+                    out.push(' ');
+                    out.push_str(&self.expr(*expr));
+                }
+            }
+            if !closing.is_empty() {
+                out.push(' ');
+                out.push_str(closing);
+            }
+
+            if !out.contains('\n') {
+                return out;
+            }
+        }
+
+        let mut out = opening;
+        for (keyword, exprs) in clauses {
+            if !keyword.is_empty() {
+                out.push_str(&self.pad());
+                out.push_str(keyword);
+            }
+            out.push('\n');
+            out.push_str(&self.block(exprs));
+        }
+        if closing.is_empty() {
+            // The caller ends the line.
+            out.pop();
+        } else {
+            out.push_str(&self.pad());
+            out.push_str(closing);
+        }
+        out
+    }
+
+    fn expr(&mut self, expr: ExprId) -> String {
+        let ast = self.ast;
+        match &ast[expr] {
+            ExprKind::Sequence(s) => {
+                let exprs: Vec<String> = s.exprs.iter().map(|e| self.expr(*e)).collect();
+                exprs.join("; ")
+            }
+            ExprKind::Import(i) => format!("import {}", i.module),
+            ExprKind::Literal(l) => literal(l),
+            ExprKind::Binary(b) => format!(
+                "{} {} {}",
+                self.expr(b.lhs),
+                binary_operator(&b.operator),
+                self.expr(b.rhs)
+            ),
+            ExprKind::Unary(u) => {
+                let operator = match u.operator {
+                    UnaryOperator::Negate => "-",
+                    UnaryOperator::Not => "!",
+                };
+                format!("{}{}", operator, self.expr(u.expr))
+            }
+            ExprKind::Block(b) => self.blocks(self.line, "do".to_string(), &[("", &b.exprs)]),
+            ExprKind::VarAssign(v) => match &ast[v.initializer] {
+                ExprKind::Literal(LiteralExpr::Nil) => format!("var {}", v.variable.name),
+                _ => format!("var {} = {}", v.variable.name, self.expr(v.initializer)),
+            },
+            ExprKind::VarUnpack(v) => {
+                format!("var {} = {}", names(&v.variables), self.expr(v.initializer))
+            }
+            ExprKind::Destructure(d) => {
+                let pattern = match &d.pattern {
+                    Destructure::Array(variables) => format!("[{}]", names(variables)),
+                    Destructure::Map(variables) => format!("{{{}}}", names(variables)),
+                };
+                format!("var {} = {}", pattern, self.expr(d.initializer))
+            }
+            ExprKind::VarSet(v) => format!("{} = {}", v.variable.name, self.expr(v.initializer)),
+            ExprKind::VarGet(v) => v.variable.name.clone(),
+            ExprKind::Print(p) => match &ast[p.expr] {
+                ExprKind::Grouping(_) => format!("print{}", self.expr(p.expr)),
+                _ => format!("print {}", self.expr(p.expr)),
+            },
+            ExprKind::Grouping(g) => format!("({})", self.expr(g.expr)),
+            ExprKind::If(i) => {
+                let opening = format!("if {} do", self.expr(i.condition));
+                match &ast[i.then_clause] {
+                    ExprKind::Block(then) => self.blocks(self.line, opening, &[("", &then.exprs)]),
+                    _ => self.blocks(
+                        self.line,
+                        opening,
+                        &[("", std::slice::from_ref(&i.then_clause))],
+                    ),
+                }
+            }
+            ExprKind::IfElse(i) => {
+                let opening = format!("if {} do", self.expr(i.condition));
+                self.blocks(
+                    self.line,
+                    opening,
+                    &[("", &i.then_clause.exprs), ("else", &i.else_clause.exprs)],
+                )
+            }
+            ExprKind::Match(m) => {
+                let mut out = format!("match {}\n", self.expr(m.subject));
+                for arm in &m.arms {
+                    let patterns: Vec<String> =
+                        arm.patterns.iter().map(|p| self.pattern(p)).collect();
+                    let opening = format!("case {} do", patterns.join(", "));
+                    out.push_str(&self.pad());
+                    let line = std::mem::replace(&mut self.line, arm.line);
+                    out.push_str(&self.clauses(arm.line, opening, &[("", &arm.body.exprs)], ""));
+                    self.line = line;
+                    out.push('\n');
+                }
+                if let Some(else_clause) = &m.else_clause {
+                    out.push_str(&self.pad());
+                    out.push_str("else\n");
+                    out.push_str(&self.block(&else_clause.exprs));
+                }
+                out.push_str(&self.pad());
+                out.push_str("end");
+                out
+            }
+            ExprKind::Function(f) => self.function(f),
+            ExprKind::Class(c) => {
+                let mut out = format!("class {}", c.name.name);
+                if !c.protocols.is_empty() {
+                    out.push_str(&format!(" implements {}", names(&c.protocols)));
+                }
+                out.push('\n');
+                let (line, next_line) = (self.line, self.next_line);
+
+                // Methods aren't statements, so their lines are looked up by name. Members are
+                // written back in the order they were declared in.
+                let mut members = vec![];
+                members.extend(c.fields.iter().map(|f| (f.line, Member::Field(f))));
+                members.extend(c.statics.iter().map(|v| (v.line, Member::StaticVar(v))));
+                for (methods, is_static) in [(&c.methods, false), (&c.static_methods, true)] {
+                    let mut after = line;
+                    for method in methods {
+                        after = self.def_line(&method.variable.name, after);
+                        members.push((after, Member::Method(method, is_static)));
+                    }
+                }
+                members.sort_by_key(|(line, _)| *line);
+
+                self.indent += 1;
+                for (i, (member_line, member)) in members.iter().enumerate() {
+                    let method = match member {
+                        Member::Field(field) => {
+                            self.comments_before(&mut out, *member_line);
+                            out.push_str(&format!(
+                                "{}var {}: {}\n",
+                                self.pad(),
+                                field.name.name,
+                                field.type_name.name
+                            ));
+                            continue;
+                        }
+                        Member::StaticVar(var) => {
+                            self.comments_before(&mut out, *member_line);
+                            let value = match &ast[var.value] {
+                                ExprKind::Literal(LiteralExpr::Nil) => String::new(),
+                                _ => format!(" = {}", self.expr(var.value)),
+                            };
+                            out.push_str(&format!(
+                                "{}static var {}{}\n",
+                                self.pad(),
+                                var.name.name,
+                                value
+                            ));
+                            continue;
+                        }
+                        Member::Method(method, is_static) => {
+                            if i > 0 {
+                                out.push('\n');
+                            }
+                            self.comments_before(&mut out, *member_line);
+                            out.push_str(&self.pad());
+                            if *is_static {
+                                out.push_str("static ");
+                            }
+                            method
+                        }
+                    };
+
+                    self.line = *member_line;
+                    self.next_line = members.get(i + 1).map_or(next_line, |(line, _)| *line);
+                    out.push_str(&format!("{}\n", self.function(method)));
+                }
+                self.indent -= 1;
+                self.line = line;
+                self.next_line = next_line;
+
+                out.push_str(&self.pad());
+                out.push_str("end");
+                out
+            }
+            ExprKind::Call(c) => {
+                let args: Vec<String> = c.args.iter().map(|arg| self.expr(*arg)).collect();
+                format!("{}({})", self.expr(c.callee), args.join(", "))
+            }
+            ExprKind::While(w) => {
+                let opening = format!("while {} do", self.expr(w.condition));
+                self.loop_body(opening, w.body)
+            }
+            ExprKind::For(f) => {
+                let mut opening = format!("for {}", f.variable.name);
+                if let Some(second) = &f.second_variable {
+                    opening.push_str(&format!(", {}", second.name));
+                }
+                opening.push_str(&format!(" in {} do", self.expr(f.source)));
+                self.loop_body(opening, f.body)
+            }
+            ExprKind::Comprehension(c) => {
+                let (open, element, close) = match &c.collect {
+                    Comprehension::Array(element) => ("[", self.expr(*element), "]"),
+                    Comprehension::Map(key, value) => (
+                        "{",
+                        format!("{}: {}", self.expr(*key), self.expr(*value)),
+                        "}",
+                    ),
+                };
+                let mut out = format!("{}{} for {}", open, element, c.variable.name);
+                if let Some(second) = &c.second_variable {
+                    out.push_str(&format!(", {}", second.name));
+                }
+                out.push_str(&format!(" in {}", self.expr(c.source)));
+                if let Some(range) = &c.range {
+                    let to = if range.descending { "downTo" } else { "to" };
+                    out.push_str(&format!(" {} {}", to, self.expr(range.end)));
+                    if let Some(step) = &range.step {
+                        out.push_str(&format!(" step {}", self.expr(*step)));
+                    }
+                }
+                if let Some(condition) = &c.condition {
+                    out.push_str(&format!(" if {}", self.expr(*condition)));
+                }
+                out.push_str(close);
+                out
+            }
+            ExprKind::Return(r) => match &r.expr {
+                Some(expr) => format!("return {}", self.expr(*expr)),
+                None => "return".to_string(),
+            },
+            ExprKind::GetProperty(g) => format!("{}.{}", self.expr(g.expr), g.property),
+            ExprKind::SetProperty(s) => {
+                format!("{}.{} = {}", self.expr(s.lhs), s.property, self.expr(s.rhs))
+            }
+            ExprKind::Tuple(t) => {
+                let items: Vec<String> = t.exprs.iter().map(|e| self.expr(*e)).collect();
+                items.join(", ")
+            }
+            ExprKind::Array(a) => {
+                let items: Vec<String> = a.exprs.iter().flatten().map(|e| self.expr(*e)).collect();
+                format!("[{}]", items.join(", "))
+            }
+            ExprKind::Range(r) => {
+                let to = if r.descending { "downTo" } else { "to" };
+                let mut out = format!("{} {} {}", self.expr(r.start), to, self.expr(r.end));
+                if let Some(step) = &r.step {
+                    out.push_str(&format!(" step {}", self.expr(*step)));
+                }
+                out
+            }
+            ExprKind::Append(_) => unreachable!("Appends are only built by desugaring"),
+            ExprKind::Map(m) => {
+                let entries: Vec<String> = m
+                    .entries
+                    .iter()
+                    .map(|(key, value)| format!("{}: {}", self.expr(*key), self.expr(*value)))
+                    .collect();
+                format!("{{{}}}", entries.join(", "))
+            }
+            ExprKind::Subscript(s) => {
+                let mut out = format!("{}[{}]", self.expr(s.callee), self.expr(s.index));
+                if let Some(value) = &s.expr {
+                    out.push_str(&format!(" = {}", self.expr(*value)));
+                }
+                out
+            }
+            ExprKind::Is(i) => format!("{} is {}", self.expr(i.expr), i.target.name),
+            ExprKind::Assert(a) => match &a.message {
+                Some(message) => {
+                    format!("assert {}, {}", self.expr(a.condition), self.expr(*message))
+                }
+                None => format!("assert {}", self.expr(a.condition)),
+            },
+            ExprKind::Struct(s) => format!("struct {}({})", s.name.name, names(&s.fields)),
+            ExprKind::Test(t) => {
+                let opening = format!("test \"{}\" do", t.name);
+                self.blocks(self.line, opening, &[("", &t.body.exprs)])
+            }
+            ExprKind::Protocol(p) => {
+                let mut out = format!("protocol {}\n", p.name.name);
+                self.indent += 1;
+                for method in &p.methods {
+                    out.push_str(&format!(
+                        "{}def {}({})\n",
+                        self.pad(),
+                        method.name.name,
+                        names(&method.parameters)
+                    ));
+                }
+                self.indent -= 1;
+                out.push_str(&self.pad());
+                out.push_str("end");
+                out
+            }
+        }
+    }
+
+    fn loop_body(&mut self, opening: String, body: ExprId) -> String {
+        match &self.ast[body] {
+            ExprKind::Block(block) => self.blocks(self.line, opening, &[("", &block.exprs)]),
+            _ => self.blocks(self.line, opening, &[("", std::slice::from_ref(&body))]),
+        }
+    }
+
+    fn function(&mut self, function: &FunctionExpr) -> String {
+        let declaration = &function.declaration;
+        let mut out = format!(
+            "def {}({})\n",
+            function.variable.name,
+            names(&declaration.parameters)
+        );
+        out.push_str(&self.block(&declaration.body.exprs));
+        out.push_str(&self.pad());
+        out.push_str("end");
+        out
+    }
+
+    fn pattern(&mut self, pattern: &Pattern) -> String {
+        match pattern {
+            Pattern::Value(expr) => self.expr(*expr),
+            Pattern::Binding(variable) => variable.name.clone(),
+            Pattern::Array { items, rest } => {
+                let mut items: Vec<String> = items.iter().map(|p| self.pattern(p)).collect();
+                if let Some(rest) = rest {
+                    items.push(format!("*{}", rest.name));
+                }
+                format!("[{}]", items.join(", "))
+            }
+            Pattern::Class { class, fields } => {
+                let fields: Vec<String> = fields
+                    .iter()
+                    .map(|(field, pattern)| match pattern {
+                        Pattern::Binding(variable) if variable.name == *field => field.clone(),
+                        _ => format!("{}: {}", field, self.pattern(pattern)),
+                    })
+                    .collect();
+                format!("{}({})", class.name, fields.join(", "))
+            }
+        }
+    }
+}
+
+fn literal(literal: &LiteralExpr) -> String {
+    match literal {
+        LiteralExpr::Number(n) => n.to_string(),
+        LiteralExpr::String(s) => format!("\"{}\"", s),
+        LiteralExpr::True => "true".to_string(),
+        LiteralExpr::False => "false".to_string(),
+        LiteralExpr::Nil => "nil".to_string(),
+    }
+}
+
+fn binary_operator(operator: &BinaryOperator) -> &'static str {
+    match operator {
+        BinaryOperator::Equal => "==",
+        BinaryOperator::BangEqual => "!=",
+        BinaryOperator::GreaterThan => ">",
+        BinaryOperator::GreaterThanEqual => ">=",
+        BinaryOperator::LessThan => "<",
+        BinaryOperator::LessThanEqual => "<=",
+        BinaryOperator::Subtract => "-",
+        BinaryOperator::Add => "+",
+        BinaryOperator::Divide => "/",
+        BinaryOperator::Multiply => "*",
+        BinaryOperator::Modulo => "%",
+    }
+}
+
+fn names(variables: &[Variable]) -> String {
+    let names: Vec<&str> = variables.iter().map(|v| v.name.as_str()).collect();
+    names.join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_blocks_operators_and_comments() {
+        let input = r#"
+# Squares.
+def square( x )
+  return x*x   # Multiplies.
+end
+
+
+protocol Summable
+  def sum( )
+end
+
+class Point   implements Summable
+var x:Number
+  # Vertical.
+var y :Number
+static   var count=0
+def init(x,y)
+self.x=x
+self.y = y
+end
+def sum()
+    # Both coordinates.
+    return self.x+self.y
+end
+static def origin()
+return Point(0,0)
+end
+end
+var p=Point(1,2)
+if p.sum() > 2 do print(1) else print(2) end
+for i in 1 to 3 do
+        print(i)
+    # Done.
+end
+var evens=[i*2 for i in 1 to 5 if i%2==0]
+var x = match p
+case Point(x, y: 2) do x
+else
+0
+end
+test   "squares" do
+assert_eq(square(3),9)
+end
+"#;
+        let expected = r#"# Squares.
+def square(x)
+    return x * x # Multiplies.
+end
+
+protocol Summable
+    def sum()
+end
+
+class Point implements Summable
+    var x: Number
+    # Vertical.
+    var y: Number
+    static var count = 0
+
+    def init(x, y)
+        self.x = x
+        self.y = y
+    end
+
+    def sum()
+        # Both coordinates.
+        return self.x + self.y
+    end
+
+    static def origin()
+        return Point(0, 0)
+    end
+end
+var p = Point(1, 2)
+if p.sum() > 2 do print(1) else print(2) end
+for i in 1 to 3 do
+    print(i)
+    # Done.
+end
+var evens = [i * 2 for i in 1 to 5 if i % 2 == 0]
+var x = match p
+case Point(x, y: 2) do x
+else
+    0
+end
+test "squares" do
+    assert_eq(square(3), 9)
+end
+"#;
+
+        let formatted = format(input).unwrap();
+        assert_eq!(formatted, expected);
+        assert_eq!(format(&formatted).unwrap(), formatted);
+    }
+
+    #[test]
+    fn formatting_keeps_the_meaning_of_the_source() {
+        let sample = include_str!("../../benches/data/sample.green");
+        let formatted = format(sample).unwrap();
+
+        assert_eq!(
+            GreenParser::parse(sample).unwrap(),
+            GreenParser::parse(&formatted).unwrap()
+        );
+        assert_eq!(format(&formatted).unwrap(), formatted);
+    }
+}
@@ -5,35 +5,43 @@ use std::str::{CharIndices, FromStr};
 
 type Result<T> = std::result::Result<T, SyntaxError>;
 
+/// Reads tokens from source as they are asked for, ending with an EOF token. After an error or
+/// the EOF token it returns no more tokens.
 pub struct Lexer<'a> {
     source: &'a str,
     chars: PeekWithNext<CharIndices<'a>>,
     line: usize,
+    finished: bool,
+}
+
+impl<'a> Iterator for Lexer<'a> {
+    type Item = Result<Token<'a>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.finished {
+            return None;
+        }
+
+        let token = self.read_token();
+        self.finished = !matches!(&token, Ok(token) if token.token_type != TokenType::EOF);
+        Some(token)
+    }
 }
 
 impl<'a> Lexer<'a> {
-    fn new(source: &'a str) -> Self {
+    pub fn new(source: &'a str) -> Self {
         let chars = PeekWithNext::new(source.char_indices());
         Lexer {
             source,
             chars,
             line: 1,
+            finished: false,
         }
     }
 
-    pub fn parse(source: &str) -> Result<Vec<Token>> {
-        let mut lexer = Lexer::new(source);
-
-        let mut tokens = vec![];
-        while !lexer.is_at_end() {
-            tokens.push(lexer.read_token()?);
-        }
-
-        if !(tokens.last().unwrap().token_type == TokenType::EOF) {
-            tokens.push(lexer.eof());
-        }
-
-        Ok(tokens)
+    /// Reads all tokens at once.
+    pub fn parse(source: &str) -> Result<Vec<Token<'_>>> {
+        Lexer::new(source).collect()
     }
 
     fn read_token(&mut self) -> Result<Token<'a>> {
@@ -42,13 +50,14 @@ impl<'a> Lexer<'a> {
             return Ok(self.eof());
         }
 
+        let line = self.line;
         let (start, char) = self.advance().ok_or(SyntaxError::UnexpectedEOF)?;
 
-        if char.is_alphabetic() {
+        if char.is_alphabetic() || char == '_' {
             return self.identifier(start);
         }
 
-        if char.is_digit(10) {
+        if char.is_ascii_digit() {
             return self.number(start);
         }
 
@@ -74,7 +83,14 @@ impl<'a> Lexer<'a> {
             '/' => TokenType::Slash,
             '*' => TokenType::Star,
             ':' => TokenType::Colon,
-            ';' | '\n' | '\r' => TokenType::Line,
+            '\r' => {
+                // A "\r\n" line break is a single line.
+                if self.match_next('\n') {
+                    self.advance();
+                }
+                TokenType::Line
+            }
+            ';' | '\n' => TokenType::Line,
             '!' => {
                 if self.match_next('=') {
                     self.advance();
@@ -120,20 +136,23 @@ impl<'a> Lexer<'a> {
                 };
             }
             '#' => {
-                // '#' indicates a comment.
+                // '#' indicates a comment, up to but not including the line break ending it.
                 self.advance_while(|&c| c != '\n');
-                self.advance();
                 TokenType::LineComment
             }
             _ => {
                 return Err(SyntaxError::UnexpectedChar(char));
             }
         };
-        Ok(self.make_token(start, token_type))
+
+        let mut token = self.make_token(start, token_type);
+        // Lines belong to the line they end, not the one after them.
+        token.position.line = line;
+        Ok(token)
     }
 
     fn identifier(&mut self, start: usize) -> Result<Token<'a>> {
-        self.advance_while(|&c| c.is_alphanumeric());
+        self.advance_while(|&c| c.is_alphanumeric() || c == '_');
 
         let word = self.token_contents(start);
 
@@ -145,17 +164,17 @@ impl<'a> Lexer<'a> {
     }
 
     fn number(&mut self, start: usize) -> Result<Token<'a>> {
-        self.advance_while(|c| c.is_digit(10));
+        self.advance_while(|c| c.is_ascii_digit());
 
         // Look for a fractional part
         if let Some(peek) = self.peek() {
             if peek == '.' {
                 if let Some(next) = self.peek_next() {
-                    if next.is_digit(10) {
+                    if next.is_ascii_digit() {
                         // Consume the '.'
                         self.advance();
 
-                        self.advance_while(|c| c.is_digit(10));
+                        self.advance_while(|c| c.is_ascii_digit());
                     }
                 }
             }
@@ -196,7 +215,7 @@ impl<'a> Lexer<'a> {
             .peek()
             .map(|&(i, _)| i)
             .unwrap_or(self.source.len());
-        &self.source[start..end].trim_end()
+        self.source[start..end].trim_end()
     }
 
     fn advance_while<F>(&mut self, f: F) -> usize
@@ -225,8 +244,7 @@ impl<'a> Lexer<'a> {
     }
 
     fn match_next(&mut self, c: char) -> bool {
-        // TODO Option???
-        self.peek().unwrap() == c
+        self.peek() == Some(c)
     }
 
     fn peek_next(&mut self) -> Option<char> {
@@ -245,6 +263,7 @@ impl<'a> Lexer<'a> {
 #[cfg(test)]
 mod tests {
     use super::Lexer;
+    use crate::error::SyntaxError;
     use crate::syntax::token::{Keyword, Position, Token, TokenType};
 
     // TODO: Test Token position
@@ -343,4 +362,45 @@ mod tests {
 
         assert_eq!(expect, actual);
     }
+
+    #[test]
+    fn parse_lines() {
+        let input = "x;y\r\nz\n";
+        let tokens: Vec<_> = Lexer::parse(input)
+            .unwrap()
+            .iter()
+            .map(|token| (token.token_type, token.position.line))
+            .collect();
+
+        assert_eq!(
+            tokens,
+            vec![
+                (TokenType::Identifier, 1),
+                (TokenType::Line, 1),
+                (TokenType::Identifier, 1),
+                (TokenType::Line, 1),
+                (TokenType::Identifier, 2),
+                (TokenType::Line, 2),
+                (TokenType::EOF, 3),
+            ]
+        );
+    }
+
+    #[test]
+    fn tokens_are_read_as_they_are_asked_for() {
+        let mut lexer = Lexer::new("x $");
+
+        let first = lexer.next().unwrap().unwrap();
+        assert_eq!(first.token_type, TokenType::Identifier);
+        assert!(matches!(
+            lexer.next(),
+            Some(Err(SyntaxError::UnexpectedChar('$')))
+        ));
+        assert!(lexer.next().is_none());
+
+        let types: Vec<_> = Lexer::new(" ")
+            .map(|token| token.unwrap().token_type)
+            .collect();
+        assert_eq!(types, vec![TokenType::EOF]);
+    }
 }
@@ -0,0 +1,203 @@
+use crate::error::ParserError;
+use crate::syntax::ast::{Ast, ExprId};
+use crate::syntax::expr::{Destructure, ExprKind, Pattern, Variable};
+use std::collections::HashSet;
+
+/// A macro defined with `macro name(params) -> template`. Each call to it is replaced while
+/// parsing by the template, with the arguments in place of the parameters, so using one costs
+/// nothing at runtime. An argument is copied to each place its parameter is used and evaluated
+/// whenever that place is reached, so `unless`'s body runs only when the condition is false.
+///
+/// Expansions are hygienic: the variables, functions and parameters the template declares are
+/// renamed in each expansion to names scripts can't write, so they can't clash with the
+/// caller's or capture the variables its arguments use.
+#[derive(Debug, Clone)]
+pub struct Macro {
+    params: Vec<String>,
+    template: ExprId,
+}
+
+impl Macro {
+    pub fn new(params: Vec<Variable>, template: ExprId) -> Self {
+        Macro {
+            params: params.into_iter().map(|param| param.name).collect(),
+            template,
+        }
+    }
+
+    pub fn arity(&self) -> usize {
+        self.params.len()
+    }
+
+    /// Adds a copy of the template to `ast` with `args` in place of the parameters, as the
+    /// `expansion`th expansion in the module, from a call on `line`.
+    pub fn expand(
+        &self,
+        ast: &mut Ast,
+        args: &[ExprId],
+        expansion: usize,
+        line: usize,
+    ) -> Result<ExprId, ParserError> {
+        let expr = ast.duplicate(self.template);
+        let mut exprs = vec![];
+        walk(ast, expr, &mut exprs);
+
+        let mut declared = HashSet::new();
+        for id in &exprs {
+            declarations(&mut ast[*id], &mut |var| {
+                declared.insert(var.name.clone());
+            });
+        }
+        for param in &self.params {
+            declared.remove(param);
+        }
+
+        for id in exprs {
+            ast.set_line(id, line);
+            let node = &mut ast[id];
+            if let ExprKind::Match(m) = node {
+                m.line = line;
+                m.arms.iter_mut().for_each(|arm| arm.line = line);
+            }
+
+            let mut rename = |var: &mut Variable| {
+                if declared.contains(&var.name) {
+                    var.name = format!("{}@{}", var.name, expansion);
+                }
+            };
+            declarations(node, &mut rename);
+            references(node, &mut rename);
+        }
+
+        self.substitute(ast, expr, args, line)
+    }
+
+    /// Replaces the uses of parameters in `expr` by copies of their arguments, returning what
+    /// `expr` becomes. Arguments aren't substituted in themselves, so a caller's variable named
+    /// like a parameter stays its own.
+    fn substitute(
+        &self,
+        ast: &mut Ast,
+        expr: ExprId,
+        args: &[ExprId],
+        line: usize,
+    ) -> Result<ExprId, ParserError> {
+        let mut node = ast.take(expr);
+        match &mut node {
+            ExprKind::VarGet(get) => {
+                if let Some(index) = self.param(&get.variable.name) {
+                    return Ok(ast.duplicate(args[index]));
+                }
+            }
+            // Assigning to a parameter assigns to the variable passed for it.
+            ExprKind::VarSet(set) => {
+                if let Some(index) = self.param(&set.variable.name) {
+                    match &ast[args[index]] {
+                        ExprKind::VarGet(get) => set.variable = get.variable.clone(),
+                        _ => {
+                            return Err(ParserError::MacroAssignment(
+                                set.variable.name.clone(),
+                                line,
+                            ))
+                        }
+                    }
+                }
+            }
+            _ => {}
+        }
+
+        let mut result = Ok(());
+        node.for_each_child_mut(&mut |child| {
+            if result.is_ok() {
+                result = self
+                    .substitute(ast, *child, args, line)
+                    .map(|substituted| *child = substituted);
+            }
+        });
+        ast[expr] = node;
+        result.map(|()| expr)
+    }
+
+    fn param(&self, name: &str) -> Option<usize> {
+        self.params.iter().position(|param| param == name)
+    }
+}
+
+/// Collects `expr` and then everything in it.
+fn walk(ast: &Ast, expr: ExprId, exprs: &mut Vec<ExprId>) {
+    exprs.push(expr);
+    ast[expr].for_each_child(&mut |child| walk(ast, child, exprs));
+}
+
+/// The variables `node` itself declares, not counting those its children do. A destructured
+/// map's variables are left out, as their names are also the keys they're read from, and so are
+/// the names of methods.
+fn declarations(node: &mut ExprKind, visit: &mut dyn FnMut(&mut Variable)) {
+    match node {
+        ExprKind::VarAssign(v) => visit(&mut v.variable),
+        ExprKind::VarUnpack(v) => v.variables.iter_mut().for_each(visit),
+        ExprKind::Destructure(d) => {
+            if let Destructure::Array(variables) = &mut d.pattern {
+                variables.iter_mut().for_each(visit);
+            }
+        }
+        ExprKind::For(f) => {
+            visit(&mut f.variable);
+            f.second_variable.iter_mut().for_each(visit);
+        }
+        ExprKind::Comprehension(c) => {
+            visit(&mut c.variable);
+            c.second_variable.iter_mut().for_each(visit);
+        }
+        ExprKind::Function(f) => {
+            visit(&mut f.variable);
+            f.declaration.parameters.iter_mut().for_each(visit);
+        }
+        ExprKind::Class(c) => {
+            for method in c.methods.iter_mut().chain(&mut c.static_methods) {
+                method
+                    .declaration
+                    .parameters
+                    .iter_mut()
+                    .for_each(&mut *visit);
+            }
+        }
+        ExprKind::Match(m) => {
+            for arm in &mut m.arms {
+                arm.patterns
+                    .iter_mut()
+                    .for_each(|pattern| pattern_bindings(pattern, visit));
+            }
+        }
+        _ => {}
+    }
+}
+
+/// The variables `node` itself reads or assigns.
+fn references(node: &mut ExprKind, visit: &mut dyn FnMut(&mut Variable)) {
+    match node {
+        ExprKind::VarGet(get) => visit(&mut get.variable),
+        ExprKind::VarSet(set) => visit(&mut set.variable),
+        _ => {}
+    }
+}
+
+fn pattern_bindings(pattern: &mut Pattern, visit: &mut dyn FnMut(&mut Variable)) {
+    match pattern {
+        Pattern::Value(_) => {}
+        Pattern::Binding(var) => {
+            if var.name != "_" {
+                visit(var);
+            }
+        }
+        Pattern::Array { items, rest } => {
+            items
+                .iter_mut()
+                .for_each(|pattern| pattern_bindings(pattern, visit));
+            rest.iter_mut().for_each(visit);
+        }
+        Pattern::Class { fields, .. } => fields
+            .iter_mut()
+            .for_each(|(_, pattern)| pattern_bindings(pattern, visit)),
+    }
+}
@@ -1,5 +1,8 @@
+pub mod ast;
 pub mod expr;
+pub mod formatter;
 pub mod lexer;
+pub mod macros;
 mod morpher;
 pub mod parser;
 mod peek;
@@ -1,58 +1,114 @@
+use crate::error::SyntaxError;
 use crate::syntax::token::{Token, TokenType};
 
-/// Cleans a sequence of tokens into a token sequence of meaningful tokens.
-/// Tokens that are removed from the sequence:
+/// Cleans a stream of tokens into a stream of meaningful tokens, keeping their order.
+/// Tokens that are removed from the stream:
 /// - Comments
-/// - Unessential lines
-pub fn morph(mut tokens: Vec<Token>) -> Vec<Token> {
-    let mut morphed = vec![];
-
-    while !tokens.is_empty() {
-        let token = tokens.pop().unwrap();
-        match token.token_type {
-            TokenType::LineComment => {
+/// - Unessential lines: leading lines and every line directly following another
+///
+/// A line is inserted before the end of the file if the last expression isn't followed by one.
+pub fn morph<'a, I>(tokens: I) -> Morpher<I::IntoIter>
+where
+    I: IntoIterator<Item = Result<Token<'a>, SyntaxError>>,
+{
+    Morpher {
+        tokens: tokens.into_iter(),
+        last: None,
+        end: None,
+    }
+}
+
+/// The stream of tokens returned by `morph`, cleaned as they are pulled.
+pub struct Morpher<I> {
+    tokens: I,
+    last: Option<TokenType>,
+    /// The end of the file, held back while the line inserted before it is returned.
+    end: Option<Token<'static>>,
+}
+
+impl<'a, I> Iterator for Morpher<I>
+where
+    I: Iterator<Item = Result<Token<'a>, SyntaxError>>,
+{
+    type Item = Result<Token<'a>, SyntaxError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(end) = self.end.take() {
+            self.last = Some(TokenType::EOF);
+            return Some(Ok(end));
+        }
+
+        loop {
+            let token = match self.tokens.next()? {
+                Ok(token) => token,
+                Err(err) => return Some(Err(err)),
+            };
+
+            let after_line = matches!(self.last, None | Some(TokenType::Line));
+            match token.token_type {
                 // Ignore comments.
-            }
-            TokenType::Line => {
-                if morphed.is_empty() {
-                    morphed.push(token);
-                } else {
-                    let last_token_type = morphed.last().unwrap().token_type;
-                    if last_token_type != TokenType::Line {
-                        morphed.push(token);
-                    }
+                TokenType::LineComment => continue,
+                TokenType::Line if after_line => continue,
+                TokenType::EOF if !after_line => {
+                    self.end = Some(Token::new(TokenType::EOF, "", token.position));
+                    self.last = Some(TokenType::Line);
+                    return Some(Ok(Token::new(TokenType::Line, "", token.position)));
                 }
+                _ => {}
             }
-            _ => morphed.push(token),
+
+            self.last = Some(token.token_type);
+            return Some(Ok(token));
         }
     }
-
-    morphed.reverse();
-
-    morphed
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::syntax::lexer::Lexer;
+    use crate::syntax::token::Keyword;
+
+    fn morphed_types(input: &str) -> Vec<TokenType> {
+        morph(Lexer::new(input))
+            .map(|token| token.unwrap().token_type)
+            .collect()
+    }
 
     #[test]
     fn morph_comments() {
         let input = r#"
         # This is a test!
-        print(10)
+        print(10) # Prints ten
+
         "#;
-        let tokens = Lexer::parse(input).unwrap();
-        for token in &tokens {
-            println!("{:?}", token);
-        }
 
-        println!("Morph");
+        assert_eq!(
+            morphed_types(input),
+            vec![
+                TokenType::Keyword(Keyword::Print),
+                TokenType::LeftParen,
+                TokenType::Number,
+                TokenType::RightParen,
+                TokenType::Line,
+                TokenType::EOF,
+            ]
+        );
+    }
 
-        let tokens2 = morph(tokens);
-        for token in tokens2 {
-            println!("{:?}", token);
-        }
+    #[test]
+    fn morph_inserts_a_line_before_the_end() {
+        assert_eq!(
+            morphed_types("x;;y"),
+            vec![
+                TokenType::Identifier,
+                TokenType::Line,
+                TokenType::Identifier,
+                TokenType::Line,
+                TokenType::EOF,
+            ]
+        );
+        assert_eq!(morphed_types(""), vec![TokenType::EOF]);
+        assert_eq!(morphed_types("\n# Nothing\n"), vec![TokenType::EOF]);
     }
 }
@@ -1,105 +1,281 @@
 use crate::error::ParserError;
-use crate::syntax::expr::ExprKind::{Binary, Literal};
+use crate::syntax::ast::{Ast, ExprId};
 use crate::syntax::expr::{
-    BinaryExpr, BinaryOperator, BlockExpr, ClassExpr, Expr, ExprKind, FunctionDeclaration,
-    FunctionExpr, IfElseExpr, IfExpr, ImportExpr, LiteralExpr, PrintExpr, ReturnExpr, SequenceExpr,
-    VarAssignExpr, VarGetExpr, VarSetExpr, Variable, WhileExpr,
+    AssertExpr, BlockExpr, ClassExpr, Comprehension, ComprehensionExpr, ComprehensionRange,
+    Destructure, DestructureExpr, ExprKind, FieldDecl, ForExpr, FunctionDeclaration, FunctionExpr,
+    IfElseExpr, IfExpr, ImportExpr, LiteralExpr, MatchArm, MatchExpr, MethodSignature, Pattern,
+    PrintExpr, ProtocolExpr, ReturnExpr, StaticVarDecl, StructExpr, TestExpr, TupleExpr,
+    VarAssignExpr, VarUnpackExpr, Variable, WhileExpr,
 };
 use crate::syntax::lexer::Lexer;
-use crate::syntax::morpher::morph;
+use crate::syntax::macros::Macro;
+use crate::syntax::morpher::{morph, Morpher};
 use crate::syntax::rule::{get_infix_rule, get_precedence, get_prefix_rule, Precedence};
-use crate::syntax::token::{Keyword, Token, TokenType};
-use std::fmt;
-use std::fmt::Display;
+use crate::syntax::token::{Keyword, Position, Token, TokenType};
+use std::collections::HashMap;
+use std::mem;
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone)]
 pub struct ModuleAst {
-    exprs: Vec<Expr>,
+    ast: Ast,
+    /// The module's top-level expressions, in `ast`.
+    exprs: Vec<ExprId>,
+    defines_macros: bool,
+}
+
+/// Modules are equal when their expressions have the same structure, wherever they are in their
+/// ASTs.
+impl PartialEq for ModuleAst {
+    fn eq(&self, other: &Self) -> bool {
+        self.defines_macros == other.defines_macros
+            && self.exprs.len() == other.exprs.len()
+            && self
+                .exprs
+                .iter()
+                .zip(&other.exprs)
+                .all(|(expr, other_expr)| self.ast.same(*expr, &other.ast, *other_expr))
+    }
 }
 
 impl ModuleAst {
-    pub fn new(exprs: Vec<Expr>) -> Self {
-        ModuleAst { exprs }
+    pub fn new(ast: Ast, exprs: Vec<ExprId>) -> Self {
+        ModuleAst {
+            ast,
+            exprs,
+            defines_macros: false,
+        }
+    }
+
+    /// Whether the module defines macros, whose calls its expressions hold only the expansions
+    /// of.
+    pub fn defines_macros(&self) -> bool {
+        self.defines_macros
     }
 
-    pub fn exprs(&self) -> &Vec<Expr> {
+    pub fn ast(&self) -> &Ast {
+        &self.ast
+    }
+
+    pub fn exprs(&self) -> &Vec<ExprId> {
         &self.exprs
     }
+
+    /// The names of the module's test blocks, in source order.
+    pub fn tests(&self) -> Vec<&str> {
+        self.exprs
+            .iter()
+            .filter_map(|expr| match &self.ast[*expr] {
+                ExprKind::Test(test) => Some(test.name.as_str()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Turns the `index`th test block into a plain block run after the rest of the module, and
+    /// leaves out the other tests.
+    pub fn select_test(&mut self, index: usize) {
+        let ast = &mut self.ast;
+        let (tests, rest): (Vec<ExprId>, Vec<ExprId>) = mem::take(&mut self.exprs)
+            .into_iter()
+            .partition(|expr| matches!(ast[*expr], ExprKind::Test(_)));
+        self.exprs = rest;
+
+        if let Some(test) = tests.into_iter().nth(index) {
+            if let ExprKind::Test(t) = ast.take(test) {
+                ast[test] = ExprKind::Block(t.body);
+                self.exprs.push(test);
+            }
+        }
+    }
+
+    pub fn exprs_mut(&mut self) -> &mut Vec<ExprId> {
+        &mut self.exprs
+    }
+
+    pub fn ast_mut(&mut self) -> &mut Ast {
+        &mut self.ast
+    }
+
+    pub fn into_parts(self) -> (Ast, Vec<ExprId>) {
+        (self.ast, self.exprs)
+    }
 }
 
 type Result<T> = std::result::Result<T, ParserError>;
 
+/// How deeply expressions and patterns can nest, so that parsing them can't overflow the stack.
+const MAX_DEPTH: usize = 128;
+
 pub struct GreenParser<'a> {
-    tokens: Vec<Token<'a>>,
+    source: &'a str,
+    /// The tokens after the current one, lexed only when the parser gets to them.
+    tokens: Morpher<Lexer<'a>>,
+    /// The next token to parse, or None after the end of the file.
+    current: Option<Token<'a>>,
+    previous_end: usize,
+    depth: usize,
+    /// Whether the expression being parsed may be an assignment target. Only expressions parsed
+    /// at assignment precedence can be, so `a + b = c` doesn't parse as `a + (b = c)`.
+    can_assign: bool,
+    /// The macros defined so far, which calls are expanded with from then on.
+    macros: HashMap<String, Macro>,
+    expansions: usize,
+    /// The expressions parsed so far.
+    pub(crate) ast: Ast,
 }
 
 impl<'a> GreenParser<'a> {
-    fn new(source: &'a str) -> Self {
-        let mut tokens = Lexer::parse(source).unwrap();
-        tokens = morph(tokens);
-        tokens.reverse();
-
-        GreenParser { tokens }
+    fn new(source: &'a str) -> Result<Self> {
+        let mut tokens = morph(Lexer::new(source));
+        let current = tokens.next().transpose()?;
+
+        Ok(GreenParser {
+            source,
+            tokens,
+            current,
+            previous_end: 0,
+            depth: 0,
+            can_assign: true,
+            macros: HashMap::new(),
+            expansions: 0,
+            ast: Ast::new(),
+        })
     }
 
+    /// Parses a module. Any input, however malformed, gives a module or an error.
     pub fn parse(source: &str) -> Result<ModuleAst> {
-        let mut parser = GreenParser::new(source);
+        let mut parser = GreenParser::new(source)?;
 
         let mut exprs = vec![];
         while !parser.match_(TokenType::EOF)? {
-            parser.skip_lines();
+            parser.skip_lines()?;
 
+            if parser.check(TokenType::Keyword(Keyword::Macro))? {
+                parser.define_macro()?;
+                continue;
+            }
+            if parser.check(TokenType::Keyword(Keyword::Test))? {
+                exprs.push(parser.parse_test()?);
+                continue;
+            }
             exprs.push(parser.parse_top_level_expression()?);
         }
 
-        Ok(ModuleAst::new(exprs))
+        Ok(ModuleAst {
+            ast: parser.ast,
+            exprs,
+            defines_macros: !parser.macros.is_empty(),
+        })
     }
 
-    fn parse_top_level_expression(&mut self) -> Result<Expr> {
-        match self.peek_type()? {
+    fn parse_top_level_expression(&mut self) -> Result<ExprId> {
+        let line = self.peek()?.position.line;
+
+        let expr = match self.peek_type()? {
             TokenType::Keyword(Keyword::Import) => self.parse_import(),
             TokenType::Keyword(Keyword::Print) => self.parse_print(),
+            TokenType::Keyword(Keyword::Assert) => self.parse_assert(),
+            TokenType::Keyword(Keyword::Struct) => self.parse_struct(),
+            TokenType::Keyword(Keyword::Protocol) => self.parse_protocol(),
             TokenType::Keyword(Keyword::Def) => self.declare_def(),
             TokenType::Keyword(Keyword::Var) => self.declare_var(),
-            TokenType::Keyword(Keyword::If) => self.parse_if(),
             TokenType::Keyword(Keyword::While) => self.parse_while(),
             TokenType::Keyword(Keyword::For) => self.parse_for(),
             TokenType::Keyword(Keyword::Return) => self.parse_return(),
-            TokenType::Keyword(Keyword::Do) => self.parse_block(),
             TokenType::Keyword(Keyword::Class) => self.parse_class(),
             _ => Ok(self.parse_expression_statement()?),
-        }
+        }?;
+        self.ast.set_line(expr, line);
+        Ok(expr)
     }
 
-    pub fn parse_expression_statement(&mut self) -> Result<Expr> {
+    pub fn parse_expression_statement(&mut self) -> Result<ExprId> {
         let expr = self.parse_expression()?;
-        self.expect(TokenType::Line)?;
+        self.expect_statement_end()?;
         Ok(expr)
     }
 
-    pub fn parse_expression(&mut self) -> Result<Expr> {
+    /// Every statement ends with a line break or ';'. The last one in a block can also be
+    /// closed by the 'end', 'else' or 'case' on the same line.
+    fn expect_statement_end(&mut self) -> Result<()> {
+        if !self.at_block_end()? {
+            self.expect(TokenType::Line)?;
+        }
+        Ok(())
+    }
+
+    fn at_block_end(&mut self) -> Result<bool> {
+        Ok(matches!(
+            self.peek_type()?,
+            TokenType::Keyword(Keyword::End)
+                | TokenType::Keyword(Keyword::Else)
+                | TokenType::Keyword(Keyword::Case)
+        ))
+    }
+
+    /// A block is opened by 'do' or by a line break, e.g. after a function's parameters.
+    fn expect_block_start(&mut self) -> Result<()> {
+        if !self.match_(TokenType::Keyword(Keyword::Do))? {
+            self.expect(TokenType::Line)?;
+        }
+        Ok(())
+    }
+
+    pub fn parse_expression(&mut self) -> Result<ExprId> {
         self.parse_precedence(Precedence::Assignment)
     }
 
-    pub fn parse_precedence(&mut self, precedence: Precedence) -> Result<Expr> {
+    pub fn parse_precedence(&mut self, precedence: Precedence) -> Result<ExprId> {
+        self.nested(|parser| parser.parse_precedence_unchecked(precedence))
+    }
+
+    /// Runs `parse` one level of nesting deeper, failing once nesting gets too deep.
+    fn nested<T>(&mut self, parse: impl FnOnce(&mut Self) -> Result<T>) -> Result<T> {
+        if self.depth >= MAX_DEPTH {
+            return Err(ParserError::TooDeep(self.line()));
+        }
+
+        self.depth += 1;
+        let result = parse(self);
+        self.depth -= 1;
+        result
+    }
+
+    fn parse_precedence_unchecked(&mut self, precedence: Precedence) -> Result<ExprId> {
+        let can_assign = precedence as u8 <= Precedence::Assignment as u8;
+
         // Prefix
         let token = self.consume()?;
+        let (start, line) = (token.position.start(), token.position.line);
 
-        if let Some(prefix) = get_prefix_rule(&token.token_type) {
-            let left = prefix.parse(self, token)?;
+        let prefix = get_prefix_rule(&token.token_type)
+            .ok_or(ParserError::UnexpectedToken(token.token_type))?;
+        self.can_assign = can_assign;
+        let left = prefix.parse(self, token)?;
 
-            // Infix
-            if !self.is_empty() {
-                Ok(self.parse_infix(left, precedence as u8)?)
-            } else {
-                Ok(left)
-            }
-        } else {
-            Err(ParserError::UnexpectedToken(token.token_type))
+        // Infix
+        let expr = self.parse_infix(left, precedence as u8, can_assign)?;
+
+        // An '=' that no target took means the expression before it can't be assigned to.
+        // Only variables, subscripts and properties take one.
+        if can_assign && self.check(TokenType::Equal)? {
+            let position = Position::new(start, self.previous_end, line);
+            let target = self
+                .source
+                .get(start..self.previous_end)
+                .unwrap_or_default();
+            return Err(ParserError::InvalidAssignment(target.to_string(), position));
         }
+
+        Ok(expr)
     }
 
-    fn parse_infix(&mut self, left: Expr, precedence: u8) -> Result<Expr> {
+    /// Whether the expression being parsed may take an '=' and become an assignment.
+    pub(crate) fn can_assign(&self) -> bool {
+        self.can_assign
+    }
+
+    fn parse_infix(&mut self, left: ExprId, precedence: u8, can_assign: bool) -> Result<ExprId> {
         let mut infix = left;
 
         loop {
@@ -114,6 +290,7 @@ impl<'a> GreenParser<'a> {
 
             let token = self.consume()?;
             if let Some(rule) = get_infix_rule(&token.token_type) {
+                self.can_assign = can_assign;
                 infix = rule.parse(self, infix, token)?;
             }
         }
@@ -121,34 +298,83 @@ impl<'a> GreenParser<'a> {
         Ok(infix)
     }
 
-    fn parse_import(&mut self) -> Result<Expr> {
+    fn parse_import(&mut self) -> Result<ExprId> {
         self.expect(TokenType::Keyword(Keyword::Import))?;
 
         // Consume tokens till end of line; this is the path of the module.
         let mut module_path = String::new();
-        while !self.match_(TokenType::Line)? {
+        while !self.check(TokenType::Line)? && !self.at_block_end()? {
             module_path.push_str(self.consume()?.source);
         }
+        self.expect_statement_end()?;
 
         let import_expr = ImportExpr::new(module_path.to_string());
-        Ok(Expr::import(import_expr))
+        Ok(self.ast.add(ExprKind::Import(import_expr)))
     }
 
-    fn parse_print(&mut self) -> Result<Expr> {
+    fn parse_print(&mut self) -> Result<ExprId> {
         self.expect(TokenType::Keyword(Keyword::Print))?;
         let expr = self.parse_expression_statement()?;
-        Ok(Expr::print(PrintExpr::new(expr)))
+        Ok(self.ast.add(ExprKind::Print(PrintExpr::new(expr))))
+    }
+
+    fn parse_assert(&mut self) -> Result<ExprId> {
+        let assert = self.expect(TokenType::Keyword(Keyword::Assert))?;
+
+        let start = self.peek()?.position.start();
+        let condition = self.parse_expression()?;
+        let description = format!(
+            "on line {}: {}",
+            assert.position.line,
+            self.source
+                .get(start..self.previous_end)
+                .unwrap_or_default()
+        );
+
+        let message = if self.match_(TokenType::Comma)? {
+            Some(self.parse_expression()?)
+        } else {
+            None
+        };
+        self.expect_statement_end()?;
+
+        Ok(self.ast.add(ExprKind::Assert(AssertExpr::new(
+            condition,
+            message,
+            description,
+        ))))
+    }
+
+    fn declare_def(&mut self) -> Result<ExprId> {
+        let function = self.parse_def()?;
+        Ok(self.ast.add(ExprKind::Function(function)))
     }
 
-    fn declare_def(&mut self) -> Result<Expr> {
+    fn parse_def(&mut self) -> Result<FunctionExpr> {
         self.consume()?;
 
         let identifier = self.expect(TokenType::Identifier)?;
+        let parameters = self.parse_parameters()?;
+
+        self.expect_block_start()?;
+        let body = BlockExpr::new(self.parse_block_body()?);
+        self.expect(TokenType::Keyword(Keyword::End))?;
+        self.expect_statement_end()?;
 
+        let fun_decl = FunctionDeclaration::new(parameters, body);
+
+        Ok(FunctionExpr::new(
+            Variable::new(identifier.source.to_string()),
+            fun_decl,
+        ))
+    }
+
+    /// A parenthesized list of parameter names.
+    fn parse_parameters(&mut self) -> Result<Vec<Variable>> {
         self.expect(TokenType::LeftParen)?;
 
         let mut parameters = vec![];
-        while !self.match_(TokenType::RightParen)? && !self.match_(TokenType::EOF)? {
+        while !self.check(TokenType::RightParen)? && !self.check(TokenType::EOF)? {
             let param = self.expect(TokenType::Identifier)?;
 
             parameters.push(Variable::new(param.source.to_string()));
@@ -161,201 +387,565 @@ impl<'a> GreenParser<'a> {
         }
 
         self.expect(TokenType::RightParen)?;
+        Ok(parameters)
+    }
 
-        let body = self.parse_block()?.node.block().unwrap(); // TODO Unwrap
+    /// `macro name(params) -> template`, at the top level of a module. The macro is expanded at
+    /// each call to it later in the module, and nothing is compiled for the definition itself.
+    fn define_macro(&mut self) -> Result<()> {
+        self.expect(TokenType::Keyword(Keyword::Macro))?;
+        let name = self.expect(TokenType::Identifier)?.source.to_string();
+        let parameters = self.parse_parameters()?;
+        self.expect(TokenType::Arrow)?;
+        let template = self.parse_expression()?;
+        self.expect_statement_end()?;
+
+        self.macros.insert(name, Macro::new(parameters, template));
+        Ok(())
+    }
 
-        let fun_decl = FunctionDeclaration::new(parameters, body);
+    /// `test "name" do`, then the test's body up to its `end`.
+    fn parse_test(&mut self) -> Result<ExprId> {
+        let line = self
+            .expect(TokenType::Keyword(Keyword::Test))?
+            .position
+            .line;
+        let name = self.expect(TokenType::String)?.source.to_string();
+        self.expect_block_start()?;
+        let body = self.parse_block_body()?;
+        self.expect(TokenType::Keyword(Keyword::End))?;
+        self.expect_statement_end()?;
 
-        Ok(Expr::new(ExprKind::Function(FunctionExpr::new(
-            Variable::new(identifier.source.to_string()),
-            fun_decl,
-        ))))
+        let test = self
+            .ast
+            .add(ExprKind::Test(TestExpr::new(name, BlockExpr::new(body))));
+        self.ast.set_line(test, line);
+        Ok(test)
+    }
+
+    pub(crate) fn is_macro(&self, name: &str) -> bool {
+        self.macros.contains_key(name)
     }
 
-    fn declare_var(&mut self) -> Result<Expr> {
+    /// The expansion of a call on `line` to the macro called `name`.
+    pub(crate) fn expand_macro(
+        &mut self,
+        name: &str,
+        args: &[ExprId],
+        line: usize,
+    ) -> Result<ExprId> {
+        let macro_ = &self.macros[name];
+        if args.len() != macro_.arity() {
+            return Err(ParserError::MacroArguments(
+                name.to_string(),
+                macro_.arity(),
+                line,
+            ));
+        }
+
+        self.expansions += 1;
+        macro_.expand(&mut self.ast, args, self.expansions, line)
+    }
+
+    fn declare_var(&mut self) -> Result<ExprId> {
         self.consume()?; // Consume "var"
 
+        if self.match_(TokenType::LeftBracket)? {
+            let variables = self.destructured_names(TokenType::RightBracket)?;
+            return self.destructure(Destructure::Array(variables));
+        }
+        if self.match_(TokenType::LeftBrace)? {
+            let variables = self.destructured_names(TokenType::RightBrace)?;
+            return self.destructure(Destructure::Map(variables));
+        }
+
         let identifier = self.expect(TokenType::Identifier)?;
         let var = Variable::new(identifier.source.to_string());
 
+        if self.check(TokenType::Comma)? {
+            // `var a, b = f()` unpacks a tuple.
+            let mut variables = vec![var];
+            while self.match_(TokenType::Comma)? {
+                let identifier = self.expect(TokenType::Identifier)?;
+                variables.push(Variable::new(identifier.source.to_string()));
+            }
+            if variables.len() > u8::MAX as usize {
+                return Err(ParserError::TooManyValues(self.line()));
+            }
+            self.expect(TokenType::Equal)?;
+            let initializer = self.parse_expression_statement()?;
+            return Ok(self.ast.add(ExprKind::VarUnpack(VarUnpackExpr::new(
+                variables,
+                initializer,
+            ))));
+        }
+
         let initializer = if self.match_(TokenType::Equal)? {
             self.parse_expression_statement()?
         } else {
-            self.expect(TokenType::Line)?;
-            Expr::nil()
+            self.expect_statement_end()?;
+            self.ast.add(ExprKind::Literal(LiteralExpr::Nil))
         };
 
-        Ok(Expr::var_assign(VarAssignExpr::new(var, initializer)))
+        Ok(self
+            .ast
+            .add(ExprKind::VarAssign(VarAssignExpr::new(var, initializer))))
+    }
+
+    /// Parses the names in `[a, b]` or `{x, y}` up to the closing bracket, the opening one having
+    /// been consumed already.
+    fn destructured_names(&mut self, close: TokenType) -> Result<Vec<Variable>> {
+        let mut variables = vec![];
+        while !self.match_(close)? {
+            let identifier = self.expect(TokenType::Identifier)?;
+            variables.push(Variable::new(identifier.source.to_string()));
+            if !self.match_(TokenType::Comma)? {
+                self.expect(close)?;
+                break;
+            }
+        }
+        if variables.len() > u8::MAX as usize {
+            return Err(ParserError::TooManyValues(self.line()));
+        }
+        Ok(variables)
     }
 
-    fn parse_if(&mut self) -> Result<Expr> {
-        self.expect(TokenType::Keyword(Keyword::If));
+    fn destructure(&mut self, pattern: Destructure) -> Result<ExprId> {
+        self.expect(TokenType::Equal)?;
+        let initializer = self.parse_expression_statement()?;
+        Ok(self.ast.add(ExprKind::Destructure(DestructureExpr::new(
+            pattern,
+            initializer,
+        ))))
+    }
 
+    /// Parses an if expression, the 'if' keyword having been consumed already.
+    pub fn parse_if(&mut self) -> Result<ExprId> {
         let cond = self.parse_expression()?;
-        let then = self.parse_block()?.node.block().unwrap();
+
+        self.expect_block_start()?;
+        let then = BlockExpr::new(self.parse_block_body()?);
 
         let expr_kind = if self.match_(TokenType::Keyword(Keyword::Else))? {
-            let else_clause = self.parse_block()?.node.block().unwrap(); // TODO Unwrap
+            let else_clause = BlockExpr::new(self.parse_block_body()?);
 
             ExprKind::IfElse(IfElseExpr::new(cond, then, else_clause))
         } else {
-            ExprKind::If(IfExpr::new(
-                cond,
-                Expr::sequence(SequenceExpr::new(then.exprs)),
-            ))
+            ExprKind::If(IfExpr::new(cond, self.ast.add(ExprKind::Block(then))))
         };
 
-        Ok(Expr::new(expr_kind))
+        self.expect(TokenType::Keyword(Keyword::End))?;
+
+        Ok(self.ast.add(expr_kind))
     }
 
-    fn parse_while(&mut self) -> Result<Expr> {
-        self.expect(TokenType::Keyword(Keyword::While))?;
-        let cond = self.parse_expression()?;
+    /// Parses a match expression, the 'match' keyword having been consumed already. Each arm
+    /// lists the cases it handles, `case 1, 2 do ...`, and is closed by the next arm, the
+    /// else clause or the 'end'.
+    pub fn parse_match(&mut self) -> Result<ExprId> {
+        let line = self.peek()?.position.line;
+        let subject = self.parse_expression()?;
+        self.expect(TokenType::Line)?;
+        self.skip_lines()?;
+
+        let mut arms = vec![];
+        while self.check(TokenType::Keyword(Keyword::Case))? {
+            let arm_line = self.consume()?.position.line;
+            let mut patterns = vec![self.parse_pattern()?];
+            while self.match_(TokenType::Comma)? {
+                patterns.push(self.parse_pattern()?);
+            }
+            // Variables bound by some patterns would be undefined when another one matches.
+            if patterns.len() > 1 && patterns.iter().any(Pattern::binds) {
+                return Err(ParserError::BindingInAlternatives(arm_line));
+            }
+            self.expect(TokenType::Keyword(Keyword::Do))?;
 
-        let body = self.parse_block()?;
+            arms.push(MatchArm::new(
+                patterns,
+                BlockExpr::new(self.parse_block_body()?),
+                arm_line,
+            ));
+        }
+
+        let else_clause = if self.match_(TokenType::Keyword(Keyword::Else))? {
+            Some(BlockExpr::new(self.parse_block_body()?))
+        } else {
+            None
+        };
+        self.expect(TokenType::Keyword(Keyword::End))?;
 
-        Ok(Expr::while_(WhileExpr::new(cond, body)))
+        Ok(self.ast.add(ExprKind::Match(MatchExpr::new(
+            subject,
+            arms,
+            else_clause,
+            line,
+        ))))
     }
 
-    fn parse_for(&mut self) -> Result<Expr> {
-        self.expect(TokenType::Keyword(Keyword::For))?;
+    /// Parses the pattern of a match arm: a literal, a variable to bind the value to, `_`, an
+    /// array pattern like `[first, *rest]`, or a class pattern like `Point(x, y: 0)`, which
+    /// binds or matches the named fields.
+    fn parse_pattern(&mut self) -> Result<Pattern> {
+        self.nested(GreenParser::parse_pattern_unchecked)
+    }
 
-        // Initializer
-        let var_ident = self.expect(TokenType::Identifier)?;
+    fn parse_pattern_unchecked(&mut self) -> Result<Pattern> {
+        match self.peek_type()? {
+            TokenType::Identifier => {
+                let name = self.consume()?.source.to_string();
+                if !self.match_(TokenType::LeftParen)? {
+                    return Ok(Pattern::Binding(Variable::new(name)));
+                }
+
+                let mut fields = vec![];
+                while !self.check(TokenType::RightParen)? {
+                    let field = self.expect(TokenType::Identifier)?.source.to_string();
+                    let pattern = if self.match_(TokenType::Colon)? {
+                        self.parse_pattern()?
+                    } else {
+                        Pattern::Binding(Variable::new(field.clone()))
+                    };
+                    fields.push((field, pattern));
+
+                    if !self.match_(TokenType::Comma)? {
+                        break;
+                    }
+                }
+                self.expect(TokenType::RightParen)?;
+
+                Ok(Pattern::Class {
+                    class: Variable::new(name),
+                    fields,
+                })
+            }
+            TokenType::LeftBracket => {
+                self.consume()?;
 
-        let mut sequence: Vec<Expr> = vec![];
+                let mut items = vec![];
+                let mut rest = None;
+                while !self.check(TokenType::RightBracket)? {
+                    // The rest of the elements can only be bound at the end.
+                    if self.match_(TokenType::Star)? {
+                        let name = self.expect(TokenType::Identifier)?.source;
+                        rest = Some(Variable::new(name.to_string()));
+                        break;
+                    }
+
+                    items.push(self.parse_pattern()?);
+                    if !self.match_(TokenType::Comma)? {
+                        break;
+                    }
+                }
+                self.expect(TokenType::RightBracket)?;
+
+                Ok(Pattern::Array { items, rest })
+            }
+            _ => Ok(Pattern::Value(self.parse_precedence(Precedence::Unary)?)),
+        }
+    }
 
-        // Condition
-        self.expect(TokenType::Keyword(Keyword::In))?;
-        let x_init = self.expect(TokenType::Number)?;
+    fn parse_while(&mut self) -> Result<ExprId> {
+        self.expect(TokenType::Keyword(Keyword::While))?;
+        let cond = self.parse_expression()?;
 
-        let op = match self.peek_type()? {
-            TokenType::Keyword(Keyword::To) => BinaryOperator::LessThan,
-            TokenType::Keyword(Keyword::DownTo) => BinaryOperator::GreaterThan,
-            _ => todo!(),
-        };
-        self.consume()?;
+        let body = self.parse_block()?;
+        self.expect_statement_end()?;
 
-        let max_val = self.expect(TokenType::Number)?;
+        Ok(self.ast.add(ExprKind::While(WhileExpr::new(cond, body))))
+    }
 
-        let step_incr = if self.match_(TokenType::Keyword(Keyword::Step))? {
-            self.consume()?;
+    fn parse_for(&mut self) -> Result<ExprId> {
+        self.expect(TokenType::Keyword(Keyword::For))?;
 
-            self.expect(TokenType::Number)?
-                .source
-                .parse::<f64>()
-                .unwrap()
+        let variable = Variable::new(self.expect(TokenType::Identifier)?.source.to_string());
+        let second_variable = if self.match_(TokenType::Comma)? {
+            let name = self.expect(TokenType::Identifier)?.source.to_string();
+            Some(Variable::new(name))
         } else {
-            1.0
+            None
         };
+        self.expect(TokenType::Keyword(Keyword::In))?;
+        let source = self.parse_expression()?;
 
-        let var_decl = Expr::new(ExprKind::VarAssign(VarAssignExpr::new(
-            Variable::new(var_ident.source.to_string()),
-            Expr::new(ExprKind::Literal(LiteralExpr::Number(
-                x_init.source.parse::<f64>().unwrap(),
-            ))),
-        )));
-        sequence.push(var_decl);
-
-        let condition = Expr::new(ExprKind::Binary(BinaryExpr::new(
-            Expr::new(ExprKind::VarGet(VarGetExpr::new(Variable::new(
-                var_ident.source.to_string(),
-            )))),
-            Expr::new(ExprKind::Literal(LiteralExpr::Number(
-                max_val.source.parse::<f64>().unwrap(),
-            ))),
-            op,
-        )));
-
-        // Parse body
-        let incr_op = match op {
-            BinaryOperator::LessThan => BinaryOperator::Add,
-            BinaryOperator::GreaterThan => BinaryOperator::Subtract,
-            _ => todo!(),
-        };
+        let body = self.parse_block()?;
+        self.expect_statement_end()?;
 
-        let incr_expr = VarSetExpr::new(
-            Variable::new(var_ident.source.to_string()),
-            Expr::new(ExprKind::Binary(BinaryExpr::new(
-                Expr::new(ExprKind::VarGet(VarGetExpr::new(Variable::new(
-                    var_ident.source.to_string(),
-                )))),
-                Expr::new(ExprKind::Literal(LiteralExpr::Number(step_incr))),
-                incr_op,
-            ))),
-        );
+        Ok(self.ast.add(ExprKind::For(ForExpr::new(
+            variable,
+            second_variable,
+            source,
+            body,
+        ))))
+    }
 
-        let test_body = self.parse_block()?;
-        let mut foo = vec![];
-        foo.push(test_body);
-        foo.push(Expr::new(ExprKind::VarSet(incr_expr)));
+    /// Parses the rest of a comprehension, `[element for x in source if condition]` or
+    /// `{key: value for k, v in source if condition}`, the element having been parsed already.
+    /// The source is a range, written `start to end` like in a `for` loop, an array or a map,
+    /// whose `[key, value]` pairs are looped over. Naming two variables unpacks each pair.
+    pub(crate) fn parse_comprehension(&mut self, collect: Comprehension) -> Result<ExprId> {
+        self.expect(TokenType::Keyword(Keyword::For))?;
+        let variable = Variable::new(self.expect(TokenType::Identifier)?.source.to_string());
+        let second_variable = if self.match_(TokenType::Comma)? {
+            let name = self.expect(TokenType::Identifier)?.source.to_string();
+            Some(Variable::new(name))
+        } else {
+            None
+        };
+        self.expect(TokenType::Keyword(Keyword::In))?;
+        let source = self.parse_precedence(Precedence::Or)?;
 
-        let body = Expr::new(ExprKind::Sequence(SequenceExpr::new(foo)));
+        let descending = match self.peek_type()? {
+            TokenType::Keyword(Keyword::To) => Some(false),
+            TokenType::Keyword(Keyword::DownTo) => Some(true),
+            _ => None,
+        };
+        let range = match descending {
+            // Numbers in a range can't be unpacked.
+            Some(_) if second_variable.is_some() => {
+                return Err(ParserError::UnexpectedToken(self.peek_type()?));
+            }
+            Some(descending) => {
+                self.consume()?;
+                let end = self.parse_precedence(Precedence::Or)?;
+                let step = if self.match_(TokenType::Keyword(Keyword::Step))? {
+                    Some(self.parse_precedence(Precedence::Or)?)
+                } else {
+                    None
+                };
+                Some(ComprehensionRange {
+                    end,
+                    step,
+                    descending,
+                })
+            }
+            None => None,
+        };
 
-        let while_expr = Expr::while_(WhileExpr::new(condition, body));
-        sequence.push(while_expr);
+        let condition = if self.match_(TokenType::Keyword(Keyword::If))? {
+            Some(self.parse_precedence(Precedence::Or)?)
+        } else {
+            None
+        };
 
-        Ok(Expr::new(ExprKind::Sequence(SequenceExpr::new(sequence))))
+        Ok(self.ast.add(ExprKind::Comprehension(ComprehensionExpr {
+            collect,
+            variable,
+            second_variable,
+            source,
+            range,
+            condition,
+        })))
     }
 
-    fn parse_return(&mut self) -> Result<Expr> {
+    fn parse_return(&mut self) -> Result<ExprId> {
         self.expect(TokenType::Keyword(Keyword::Return))?;
 
-        let return_expr = if self.match_(TokenType::Colon)? {
-            // TODO
+        let return_expr = if self.check(TokenType::Line)? || self.at_block_end()? {
             None
         } else {
-            Some(self.parse_top_level_expression()?)
+            let expr = self.parse_expression()?;
+            if self.check(TokenType::Comma)? {
+                // `return a, b` gives back a tuple.
+                let mut exprs = vec![expr];
+                while self.match_(TokenType::Comma)? {
+                    exprs.push(self.parse_expression()?);
+                }
+                if exprs.len() > u8::MAX as usize {
+                    return Err(ParserError::TooManyValues(self.line()));
+                }
+                Some(self.ast.add(ExprKind::Tuple(TupleExpr::new(exprs))))
+            } else {
+                Some(expr)
+            }
         };
+        self.expect_statement_end()?;
 
-        Ok(Expr::return_(ReturnExpr::new(return_expr)))
+        Ok(self.ast.add(ExprKind::Return(ReturnExpr::new(return_expr))))
     }
 
-    fn parse_block(&mut self) -> Result<Expr> {
-        self.consume()?; // Consume 'do'
+    fn parse_block(&mut self) -> Result<ExprId> {
+        self.expect_block_start()?;
 
-        self.match_(TokenType::Line)?;
+        self.parse_do_block()
+    }
 
-        let mut exprs = vec![];
+    /// Parses a block up to and including its 'end', the 'do' having been consumed already.
+    pub fn parse_do_block(&mut self) -> Result<ExprId> {
+        let exprs = self.parse_block_body()?;
 
-        loop {
-            if let TokenType::Keyword(Keyword::End) = self.peek_type()? {
-                break;
-            }
+        self.expect(TokenType::Keyword(Keyword::End))?;
+
+        Ok(self.ast.add(ExprKind::Block(BlockExpr::new(exprs))))
+    }
 
-            // if self.check(TokenType::Keyword(Keyword::End))? ||
-            //     self.check(TokenType::EOF)? {
-            //     break;
-            // }
+    /// Parses expressions up to (but not including) the 'end', 'else' or 'case' closing the
+    /// block.
+    fn parse_block_body(&mut self) -> Result<Vec<ExprId>> {
+        self.match_(TokenType::Line)?;
 
+        let mut exprs = vec![];
+
+        while !self.at_block_end()? {
             exprs.push(self.parse_top_level_expression()?);
         }
 
-        self.expect(TokenType::Keyword(Keyword::End))?;
-        self.expect(TokenType::Line)?;
-
-        Ok(Expr::block(BlockExpr::new(exprs)))
+        Ok(exprs)
     }
 
-    fn parse_class(&mut self) -> Result<Expr> {
+    fn parse_class(&mut self) -> Result<ExprId> {
         self.consume()?; // Consume 'class'
 
         let class_name = self.expect(TokenType::Identifier)?.source;
+        let mut protocols = vec![];
+        if self.match_(TokenType::Keyword(Keyword::Implements))? {
+            loop {
+                let protocol = self.expect(TokenType::Identifier)?.source;
+                protocols.push(Variable::new(protocol.to_string()));
+                if !self.match_(TokenType::Comma)? {
+                    break;
+                }
+            }
+        }
         self.expect(TokenType::Line)?;
 
+        let mut fields = vec![];
+        let mut methods = vec![];
+        let mut statics = vec![];
+        let mut static_methods = vec![];
+        while !self.check(TokenType::Keyword(Keyword::End))? {
+            if self.match_(TokenType::Keyword(Keyword::Static))? {
+                if self.check(TokenType::Keyword(Keyword::Var))? {
+                    statics.push(self.declare_static_var()?);
+                } else {
+                    static_methods.push(self.declare_method()?);
+                }
+                self.skip_lines()?;
+                continue;
+            }
+
+            if self.check(TokenType::Keyword(Keyword::Var))? {
+                fields.push(self.declare_field()?);
+                self.skip_lines()?;
+                continue;
+            }
+
+            methods.push(self.declare_method()?);
+            self.skip_lines()?;
+        }
+
         self.expect(TokenType::Keyword(Keyword::End))?;
+        self.expect_statement_end()?;
+
+        Ok(self.ast.add(ExprKind::Class(
+            ClassExpr::new(Variable::new(class_name.to_string()), fields, methods)
+                .with_statics(statics, static_methods)
+                .implementing(protocols),
+        )))
+    }
+
+    fn declare_method(&mut self) -> Result<FunctionExpr> {
+        if !self.check(TokenType::Keyword(Keyword::Def))? {
+            let token = self.peek()?;
+            return Err(ParserError::Expect(
+                TokenType::Keyword(Keyword::Def),
+                token.token_type,
+                token.position.line,
+            ));
+        }
+
+        self.parse_def()
+    }
+
+    // static var count = 0
+    fn declare_static_var(&mut self) -> Result<StaticVarDecl> {
+        let line = self.consume()?.position.line; // Consume "var"
+
+        let name = self.expect(TokenType::Identifier)?.source;
+        let value = if self.match_(TokenType::Equal)? {
+            self.parse_expression_statement()?
+        } else {
+            self.expect_statement_end()?;
+            self.ast.add(ExprKind::Literal(LiteralExpr::Nil))
+        };
+
+        Ok(StaticVarDecl::new(Variable::new(name.to_string()), value).at_line(line))
+    }
+
+    // var x: Number
+    fn declare_field(&mut self) -> Result<FieldDecl> {
+        let line = self.consume()?.position.line; // Consume "var"
+
+        let name = self.expect(TokenType::Identifier)?.source;
+        self.expect(TokenType::Colon)?;
+        let type_name = self.expect(TokenType::Identifier)?.source;
+        self.expect_statement_end()?;
+
+        Ok(FieldDecl::new(
+            Variable::new(name.to_string()),
+            Variable::new(type_name.to_string()),
+        )
+        .at_line(line))
+    }
+
+    fn parse_struct(&mut self) -> Result<ExprId> {
+        self.expect(TokenType::Keyword(Keyword::Struct))?;
+
+        let name = self.expect(TokenType::Identifier)?.source;
+
+        self.expect(TokenType::LeftParen)?;
+        let mut fields = vec![];
+        while !self.check(TokenType::RightParen)? {
+            let field = self.expect(TokenType::Identifier)?.source;
+            fields.push(Variable::new(field.to_string()));
+
+            if !self.match_(TokenType::Comma)? {
+                break;
+            }
+        }
+        self.expect(TokenType::RightParen)?;
+        self.expect_statement_end()?;
+
+        Ok(self.ast.add(ExprKind::Struct(StructExpr::new(
+            Variable::new(name.to_string()),
+            fields,
+        ))))
+    }
+
+    fn parse_protocol(&mut self) -> Result<ExprId> {
+        self.expect(TokenType::Keyword(Keyword::Protocol))?;
+
+        let name = self.expect(TokenType::Identifier)?.source;
         self.expect(TokenType::Line)?;
+        self.skip_lines()?;
+
+        let mut methods = vec![];
+        while !self.check(TokenType::Keyword(Keyword::End))? {
+            self.expect(TokenType::Keyword(Keyword::Def))?;
+            let method = self.expect(TokenType::Identifier)?.source;
+            let parameters = self.parse_parameters()?;
+            self.expect_statement_end()?;
+            self.skip_lines()?;
+
+            methods.push(MethodSignature::new(
+                Variable::new(method.to_string()),
+                parameters,
+            ));
+        }
+        self.expect(TokenType::Keyword(Keyword::End))?;
+        self.expect_statement_end()?;
 
-        Ok(Expr::class(ClassExpr::new(Variable::new(
-            class_name.to_string(),
+        Ok(self.ast.add(ExprKind::Protocol(ProtocolExpr::new(
+            Variable::new(name.to_string()),
+            methods,
         ))))
     }
 
-    fn skip_lines(&mut self) {
-        while self.check(TokenType::Line).unwrap() {
-            // TODO Unwrap
-            self.consume();
+    fn skip_lines(&mut self) -> Result<()> {
+        while self.check(TokenType::Line)? {
+            self.consume()?;
         }
+        Ok(())
     }
 
     pub fn match_(&mut self, token_type: TokenType) -> Result<bool> {
@@ -371,54 +961,74 @@ impl<'a> GreenParser<'a> {
         Ok(self.peek_type()? == token_type)
     }
 
-    fn peek_type(&self) -> Result<TokenType> {
-        if self.tokens.is_empty() {
-            return Ok(TokenType::EOF);
-        }
-        Ok(self.peek()?.token_type)
+    pub(crate) fn peek_type(&self) -> Result<TokenType> {
+        Ok(self
+            .current
+            .map_or(TokenType::EOF, |token| token.token_type))
     }
 
     fn peek(&self) -> Result<&Token<'a>> {
-        self.tokens.last().ok_or(ParserError::UnexpectedEOF)
+        self.current.as_ref().ok_or(ParserError::UnexpectedEOF)
     }
 
     pub fn expect(&mut self, expect: TokenType) -> Result<Token<'a>> {
         if self.check(expect)? {
             Ok(self.consume()?)
         } else {
-            Err(ParserError::Expect(
-                expect,
-                self.peek_type()?,
-                self.peek().unwrap().position.line,
-            ))
+            Err(ParserError::Expect(expect, self.peek_type()?, self.line()))
         }
     }
 
+    fn expect_number(&mut self) -> Result<f64> {
+        let token = self.expect(TokenType::Number)?;
+        parse_number(&token)
+    }
+
+    /// The line of the next token, or of the last one once all are consumed.
+    fn line(&self) -> usize {
+        self.current.map_or(0, |token| token.position.line)
+    }
+
     pub fn consume(&mut self) -> Result<Token<'a>> {
-        self.tokens.pop().ok_or(ParserError::UnexpectedEOF)
+        let token = self.current.take().ok_or(ParserError::UnexpectedEOF)?;
+        self.current = self.tokens.next().transpose()?;
+        self.previous_end = token.position.end();
+        Ok(token)
     }
 
     fn is_empty(&self) -> bool {
-        self.tokens.is_empty()
+        self.current.is_none()
     }
 }
 
+/// The value of a number token.
+pub(crate) fn parse_number(token: &Token) -> Result<f64> {
+    token
+        .source
+        .parse()
+        .map_err(|_| ParserError::UnexpectedToken(token.token_type))
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
-    use crate::syntax::expr::{ClassExpr, GroupingExpr};
+    use crate::syntax::expr::{
+        ArrayExpr, BinaryExpr, BinaryOperator, CallExpr, ClassExpr, GetExpr, GroupingExpr, IsExpr,
+        LiteralExpr, RangeExpr, SetExpr, SubscriptExpr, UnaryExpr, UnaryOperator, VarGetExpr,
+        VarSetExpr,
+    };
 
     #[test]
     fn parse_block() {
-        let expected_exprs = Expr::block(BlockExpr::new(vec![
-            Expr::print(PrintExpr::new(Expr::grouping(GroupingExpr::new(
-                Expr::literal(LiteralExpr::Number(1.0)),
-            )))),
-            Expr::print(PrintExpr::new(Expr::grouping(GroupingExpr::new(
-                Expr::literal(LiteralExpr::Number(5.0)),
-            )))),
-        ]));
-        let expect = ModuleAst::new(vec![expected_exprs]);
+        let mut ast = Ast::new();
+        let one = ast.add(ExprKind::Literal(LiteralExpr::Number(1.0)));
+        let one = ast.add(ExprKind::Grouping(GroupingExpr::new(one)));
+        let print_one = ast.add(ExprKind::Print(PrintExpr::new(one)));
+        let five = ast.add(ExprKind::Literal(LiteralExpr::Number(5.0)));
+        let five = ast.add(ExprKind::Grouping(GroupingExpr::new(five)));
+        let print_five = ast.add(ExprKind::Print(PrintExpr::new(five)));
+        let block = ast.add(ExprKind::Block(BlockExpr::new(vec![print_one, print_five])));
+        let expect = ModuleAst::new(ast, vec![block]);
 
         let input = r#"
         do
@@ -433,11 +1043,13 @@ mod test {
 
     #[test]
     fn parse_declare_var() {
-        let expected_exprs = vec![Expr::var_assign(VarAssignExpr::new(
+        let mut ast = Ast::new();
+        let five = ast.add(ExprKind::Literal(LiteralExpr::Number(5.0)));
+        let expected_exprs = vec![ast.add(ExprKind::VarAssign(VarAssignExpr::new(
             Variable::new("x".to_string()),
-            Expr::literal(LiteralExpr::Number(5.0)),
-        ))];
-        let expect = ModuleAst::new(expected_exprs);
+            five,
+        )))];
+        let expect = ModuleAst::new(ast, expected_exprs);
 
         let input = r#"
         var x = 5
@@ -449,11 +1061,13 @@ mod test {
 
     #[test]
     fn parse_set_var() {
-        let expected_exprs = vec![Expr::var_set(VarSetExpr::new(
+        let mut ast = Ast::new();
+        let five = ast.add(ExprKind::Literal(LiteralExpr::Number(5.0)));
+        let expected_exprs = vec![ast.add(ExprKind::VarSet(VarSetExpr::new(
             Variable::new("x".to_string()),
-            Expr::literal(LiteralExpr::Number(5.0)),
-        ))];
-        let expect = ModuleAst::new(expected_exprs);
+            five,
+        )))];
+        let expect = ModuleAst::new(ast, expected_exprs);
 
         let input = r#"
         x = 5
@@ -465,17 +1079,22 @@ mod test {
 
     #[test]
     fn parse_get_var() {
+        let mut ast = Ast::new();
+        let five = ast.add(ExprKind::Literal(LiteralExpr::Number(5.0)));
+        let x = ast.add(ExprKind::VarGet(VarGetExpr::new(Variable::new(
+            "x".to_string(),
+        ))));
         let expected_exprs = vec![
-            Expr::var_assign(VarAssignExpr::new(
+            ast.add(ExprKind::VarAssign(VarAssignExpr::new(
                 Variable::new("x".to_string()),
-                Expr::literal(LiteralExpr::Number(5.0)),
-            )),
-            Expr::var_assign(VarAssignExpr::new(
+                five,
+            ))),
+            ast.add(ExprKind::VarAssign(VarAssignExpr::new(
                 Variable::new("y".to_string()),
-                Expr::var_get(VarGetExpr::new(Variable::new("x".to_string()))),
-            )),
+                x,
+            ))),
         ];
-        let expect = ModuleAst::new(expected_exprs);
+        let expect = ModuleAst::new(ast, expected_exprs);
 
         let input = r#"
         var x = 5
@@ -488,18 +1107,20 @@ mod test {
 
     #[test]
     fn parse_if_else() {
-        let empty_vec: Vec<Expr> = vec![]; // FIXME
-        let empty_vec2: Vec<Expr> = vec![];
-        let expected_exprs = vec![Expr::if_else(IfElseExpr::new(
-            Expr::binary(BinaryExpr::new(
-                Expr::literal(LiteralExpr::Number(10.0)),
-                Expr::literal(LiteralExpr::Number(5.0)),
-                BinaryOperator::GreaterThan,
-            )),
-            BlockExpr::new(empty_vec),
-            BlockExpr::new(empty_vec2),
-        ))];
-        let expect = ModuleAst::new(expected_exprs);
+        let mut ast = Ast::new();
+        let ten = ast.add(ExprKind::Literal(LiteralExpr::Number(10.0)));
+        let five = ast.add(ExprKind::Literal(LiteralExpr::Number(5.0)));
+        let condition = ast.add(ExprKind::Binary(BinaryExpr::new(
+            ten,
+            five,
+            BinaryOperator::GreaterThan,
+        )));
+        let expected_exprs = vec![ast.add(ExprKind::IfElse(IfElseExpr::new(
+            condition,
+            BlockExpr::new(vec![]),
+            BlockExpr::new(vec![]),
+        )))];
+        let expect = ModuleAst::new(ast, expected_exprs);
 
         let input = r#"
         if 10 > 5 do
@@ -513,12 +1134,13 @@ mod test {
 
     #[test]
     fn parse_import() {
+        let mut ast = Ast::new();
         let expected_exprs = vec![
-            Expr::import(ImportExpr::new("foo.bar".to_string())),
-            Expr::import(ImportExpr::new("util".to_string())),
-            Expr::import(ImportExpr::new("..bar.foo".to_string())),
+            ast.add(ExprKind::Import(ImportExpr::new("foo.bar".to_string()))),
+            ast.add(ExprKind::Import(ImportExpr::new("util".to_string()))),
+            ast.add(ExprKind::Import(ImportExpr::new("..bar.foo".to_string()))),
         ];
-        let expect = ModuleAst::new(expected_exprs);
+        let expect = ModuleAst::new(ast, expected_exprs);
 
         let input = r#"
         import foo.bar
@@ -532,20 +1154,25 @@ mod test {
 
     #[test]
     fn parse_def() {
-        let expected_exprs = vec![Expr::new(ExprKind::Function(FunctionExpr::new(
+        let mut ast = Ast::new();
+        let x = ast.add(ExprKind::VarGet(VarGetExpr::new(Variable::new(
+            "x".to_string(),
+        ))));
+        let two = ast.add(ExprKind::Literal(LiteralExpr::Number(2.0)));
+        let double = ast.add(ExprKind::Binary(BinaryExpr::new(
+            x,
+            two,
+            BinaryOperator::Multiply,
+        )));
+        let return_ = ast.add(ExprKind::Return(ReturnExpr::new(Some(double))));
+        let expected_exprs = vec![ast.add(ExprKind::Function(FunctionExpr::new(
             Variable::new("double".to_string()),
             FunctionDeclaration::new(
                 vec![Variable::new("x".to_string())],
-                BlockExpr::new(vec![Expr::return_(ReturnExpr::new(Some(Expr::binary(
-                    BinaryExpr::new(
-                        Expr::var_get(VarGetExpr::new(Variable::new("x".to_string()))),
-                        Expr::literal(LiteralExpr::Number(2.0)),
-                        BinaryOperator::Multiply,
-                    ),
-                ))))]),
+                BlockExpr::new(vec![return_]),
             ),
         )))];
-        let expect = ModuleAst::new(expected_exprs);
+        let expect = ModuleAst::new(ast, expected_exprs);
 
         let input = r#"
         def double(x)
@@ -559,28 +1186,38 @@ mod test {
 
     #[test]
     fn parse_while() {
-        let expected_exprs = vec![
-            Expr::var_assign(VarAssignExpr::new(
-                Variable::new("x".to_string()),
-                Expr::literal(LiteralExpr::Number(0.0)),
-            )),
-            Expr::while_(WhileExpr::new(
-                Expr::binary(BinaryExpr::new(
-                    Expr::var_get(VarGetExpr::new(Variable::new("x".to_string()))),
-                    Expr::literal(LiteralExpr::Number(10.0)),
-                    BinaryOperator::LessThan,
-                )),
-                Expr::block(BlockExpr::new(vec![Expr::var_set(VarSetExpr::new(
-                    Variable::new("x".to_string()),
-                    Expr::binary(BinaryExpr::new(
-                        Expr::var_get(VarGetExpr::new(Variable::new("x".to_string()))),
-                        Expr::literal(LiteralExpr::Number(1.0)),
-                        BinaryOperator::Add,
-                    )),
-                ))])),
-            )),
-        ];
-        let expect = ModuleAst::new(expected_exprs);
+        let mut ast = Ast::new();
+        let x = || ExprKind::VarGet(VarGetExpr::new(Variable::new("x".to_string())));
+        let zero = ast.add(ExprKind::Literal(LiteralExpr::Number(0.0)));
+        let declare = ast.add(ExprKind::VarAssign(VarAssignExpr::new(
+            Variable::new("x".to_string()),
+            zero,
+        )));
+        let (lhs, ten) = (
+            ast.add(x()),
+            ast.add(ExprKind::Literal(LiteralExpr::Number(10.0))),
+        );
+        let condition = ast.add(ExprKind::Binary(BinaryExpr::new(
+            lhs,
+            ten,
+            BinaryOperator::LessThan,
+        )));
+        let (lhs, one) = (
+            ast.add(x()),
+            ast.add(ExprKind::Literal(LiteralExpr::Number(1.0))),
+        );
+        let add = ast.add(ExprKind::Binary(BinaryExpr::new(
+            lhs,
+            one,
+            BinaryOperator::Add,
+        )));
+        let set = ast.add(ExprKind::VarSet(VarSetExpr::new(
+            Variable::new("x".to_string()),
+            add,
+        )));
+        let body = ast.add(ExprKind::Block(BlockExpr::new(vec![set])));
+        let while_ = ast.add(ExprKind::While(WhileExpr::new(condition, body)));
+        let expect = ModuleAst::new(ast, vec![declare, while_]);
 
         let input = r#"
         var x = 0;
@@ -595,10 +1232,13 @@ mod test {
 
     #[test]
     fn parse_class() {
-        let expected_exprs = vec![Expr::class(ClassExpr::new(Variable::new(
-            "Point".to_string(),
+        let mut ast = Ast::new();
+        let expected_exprs = vec![ast.add(ExprKind::Class(ClassExpr::new(
+            Variable::new("Point".to_string()),
+            vec![],
+            vec![],
         )))];
-        let expect = ModuleAst::new(expected_exprs);
+        let expect = ModuleAst::new(ast, expected_exprs);
 
         let input = r#"
         class Point
@@ -608,4 +1248,356 @@ mod test {
 
         assert_eq!(expect, actual);
     }
+
+    #[test]
+    fn parse_class_fields() {
+        let mut ast = Ast::new();
+        let expected_exprs = vec![ast.add(ExprKind::Class(ClassExpr::new(
+            Variable::new("Point".to_string()),
+            vec![
+                FieldDecl::new(
+                    Variable::new("x".to_string()),
+                    Variable::new("Number".to_string()),
+                ),
+                FieldDecl::new(
+                    Variable::new("label".to_string()),
+                    Variable::new("String".to_string()),
+                ),
+            ],
+            vec![],
+        )))];
+        let expect = ModuleAst::new(ast, expected_exprs);
+
+        let input = r#"
+        class Point
+            var x: Number
+            var label: String
+        end
+        "#;
+        let actual = GreenParser::parse(input).unwrap();
+
+        assert_eq!(expect, actual);
+        assert!(GreenParser::parse("class Point\n    var x\nend").is_err());
+    }
+
+    #[test]
+    fn parse_if_else_expression() {
+        let mut ast = Ast::new();
+        let condition = ast.add(ExprKind::Literal(LiteralExpr::True));
+        let one = ast.add(ExprKind::Literal(LiteralExpr::Number(1.0)));
+        let two = ast.add(ExprKind::Literal(LiteralExpr::Number(2.0)));
+        let if_else = ast.add(ExprKind::IfElse(IfElseExpr::new(
+            condition,
+            BlockExpr::new(vec![one]),
+            BlockExpr::new(vec![two]),
+        )));
+        let expected_exprs = vec![ast.add(ExprKind::VarAssign(VarAssignExpr::new(
+            Variable::new("x".to_string()),
+            if_else,
+        )))];
+        let expect = ModuleAst::new(ast, expected_exprs);
+
+        let input = r#"
+        var x = if true do 1 else 2 end
+        "#;
+        let actual = GreenParser::parse(input).unwrap();
+
+        assert_eq!(expect, actual);
+    }
+
+    #[test]
+    fn parse_is() {
+        let mut ast = Ast::new();
+        let x = ast.add(ExprKind::VarGet(VarGetExpr::new(Variable::new(
+            "x".to_string(),
+        ))));
+        let expected_exprs = vec![ast.add(ExprKind::Is(IsExpr::new(
+            x,
+            Variable::new("Number".to_string()),
+        )))];
+        let expect = ModuleAst::new(ast, expected_exprs);
+
+        let input = r#"
+        x is Number
+        "#;
+        let actual = GreenParser::parse(input).unwrap();
+
+        assert_eq!(expect, actual);
+    }
+
+    #[test]
+    fn parse_assert() {
+        let mut ast = Ast::new();
+        let x = ast.add(ExprKind::VarGet(VarGetExpr::new(Variable::new(
+            "x".to_string(),
+        ))));
+        let two = ast.add(ExprKind::Literal(LiteralExpr::Number(2.0)));
+        let condition = ast.add(ExprKind::Binary(BinaryExpr::new(
+            x,
+            two,
+            BinaryOperator::LessThan,
+        )));
+        let message = ast.add(ExprKind::Literal(LiteralExpr::String(
+            "too big".to_string(),
+        )));
+        let expected_exprs = vec![ast.add(ExprKind::Assert(AssertExpr::new(
+            condition,
+            Some(message),
+            "on line 2: x < 2".to_string(),
+        )))];
+        let expect = ModuleAst::new(ast, expected_exprs);
+
+        let input = r#"
+        assert x < 2, "too big"
+        "#;
+        let actual = GreenParser::parse(input).unwrap();
+
+        assert_eq!(expect, actual);
+    }
+
+    #[test]
+    fn parse_struct() {
+        let mut ast = Ast::new();
+        let expected_exprs = vec![ast.add(ExprKind::Struct(StructExpr::new(
+            Variable::new("Point".to_string()),
+            vec![
+                Variable::new("x".to_string()),
+                Variable::new("y".to_string()),
+            ],
+        )))];
+        let expect = ModuleAst::new(ast, expected_exprs);
+
+        let input = r#"
+        struct Point(x, y)
+        "#;
+        let actual = GreenParser::parse(input).unwrap();
+
+        assert_eq!(expect, actual);
+    }
+
+    #[test]
+    fn parse_chained_property_call_and_subscript() {
+        let mut ast = Ast::new();
+        let foo = ast.add(ExprKind::VarGet(VarGetExpr::new(Variable::new(
+            "foo".to_string(),
+        ))));
+        let bar = ast.add(ExprKind::GetProperty(GetExpr::new(foo, "bar".to_string())));
+        let call = ast.add(ExprKind::Call(CallExpr::new(bar, vec![])));
+        let baz = ast.add(ExprKind::GetProperty(GetExpr::new(call, "baz".to_string())));
+        let zero = ast.add(ExprKind::Literal(LiteralExpr::Number(0.0)));
+        let index = ast.add(ExprKind::Subscript(SubscriptExpr::new(baz, zero, None)));
+        let one = ast.add(ExprKind::Literal(LiteralExpr::Number(1.0)));
+        let expected_exprs = vec![ast.add(ExprKind::SetProperty(SetExpr::new(
+            index,
+            one,
+            "qux".to_string(),
+        )))];
+        let expect = ModuleAst::new(ast, expected_exprs);
+
+        let input = r#"
+        foo.bar().baz[0].qux = 1
+        "#;
+        let actual = GreenParser::parse(input).unwrap();
+
+        assert_eq!(expect, actual);
+    }
+
+    #[test]
+    fn parse_ranges_in_array_literal() {
+        let mut ast = Ast::new();
+        let mut range = |start, end| {
+            let start = ast.add(ExprKind::Literal(LiteralExpr::Number(start)));
+            let end = ast.add(ExprKind::Literal(LiteralExpr::Number(end)));
+            ast.add(ExprKind::Range(RangeExpr::new(start, end, None, false)))
+        };
+        let ranges = [range(1.0, 3.0), range(0.0, 2.0)];
+        // A call to `range` stays a call, as scripts can define a `range` of their own.
+        let callee = ast.add(ExprKind::VarGet(VarGetExpr::new(Variable::new(
+            "range".to_string(),
+        ))));
+        let args = vec![
+            ast.add(ExprKind::Literal(LiteralExpr::Number(0.0))),
+            ast.add(ExprKind::Literal(LiteralExpr::Number(2.0))),
+        ];
+        let range_call = ast.add(ExprKind::Call(CallExpr::new(callee, args)));
+        let expected_exprs = vec![ast.add(ExprKind::Array(ArrayExpr::new(Some(vec![
+            ranges[0], ranges[1], range_call,
+        ]))))];
+        let expect = ModuleAst::new(ast, expected_exprs);
+
+        let input = r#"
+        [1 to 3, 0 to 2, range(0, 2)]
+        "#;
+        let actual = GreenParser::parse(input).unwrap();
+
+        assert_eq!(expect, actual);
+    }
+
+    #[test]
+    fn parse_match_patterns() {
+        let module = GreenParser::parse("match x\ncase [a, *rest], Point(y: 1) do a\nend\n");
+        assert!(matches!(module, Err(ParserError::BindingInAlternatives(2))));
+
+        let module = GreenParser::parse("match x\ncase [_, *_], Point(y: 1), -1 do 0\nend\n");
+        let exprs = module.unwrap().exprs().len();
+        assert_eq!(exprs, 1);
+    }
+
+    #[test]
+    fn parse_malformed_input_without_panicking() {
+        let sample = include_str!("../../benches/data/sample.green");
+        for (end, _) in sample.char_indices() {
+            let _ = GreenParser::parse(&sample[..end]);
+        }
+
+        let deep = "(".repeat(10_000);
+        let inputs = [
+            "",
+            "-",
+            "=",
+            "#",
+            "\"",
+            "1 +",
+            "for i = 1 step",
+            "for i = 1 by 2 do end",
+            "def",
+            "def f(",
+            "match",
+            "match x\ncase [",
+            "x.",
+            "[1, *",
+            "{1:",
+            "if 1 2 end",
+            &deep,
+        ];
+        for input in inputs {
+            let _ = GreenParser::parse(input);
+        }
+        assert!(matches!(
+            GreenParser::parse(&deep),
+            Err(ParserError::TooDeep(_))
+        ));
+    }
+
+    #[test]
+    fn parse_assignment_targets() {
+        let var = |name: &str| Variable::new(name.to_string());
+        let mut ast = Ast::new();
+        let one = ast.add(ExprKind::Literal(LiteralExpr::Number(1.0)));
+        let set_y = ast.add(ExprKind::VarSet(VarSetExpr::new(var("y"), one)));
+        let set_x = ast.add(ExprKind::VarSet(VarSetExpr::new(var("x"), set_y)));
+        let one = ast.add(ExprKind::Literal(LiteralExpr::Number(1.0)));
+        let negated = ast.add(ExprKind::Unary(UnaryExpr::new(one, UnaryOperator::Negate)));
+        let two = ast.add(ExprKind::Literal(LiteralExpr::Number(2.0)));
+        let add = ast.add(ExprKind::Binary(BinaryExpr::new(
+            negated,
+            two,
+            BinaryOperator::Add,
+        )));
+        let expect = ModuleAst::new(ast, vec![set_x, add]);
+        assert_eq!(GreenParser::parse("x = y = 1\n-1 + 2\n").unwrap(), expect);
+        assert!(GreenParser::parse("a.b[0] = 1\nif x = 2 do end\n").is_ok());
+
+        let invalid = [
+            ("1 + x = 5", "1 + x", 1),
+            ("-x = 1", "-x", 1),
+            ("f() = 1", "f()", 1),
+            ("x\n(a + b) = 3", "(a + b)", 2),
+            ("a == b = 1", "a == b", 1),
+            ("a.b() = 1", "a.b()", 1),
+            ("var x = [1][0] + 1 = 2", "[1][0] + 1", 1),
+        ];
+        for (input, target, line) in invalid {
+            match GreenParser::parse(input) {
+                Err(ParserError::InvalidAssignment(actual, position)) => {
+                    assert_eq!((actual.as_str(), position.line), (target, line));
+                }
+                _ => panic!("{} should be an invalid assignment", input),
+            }
+        }
+    }
+
+    #[test]
+    fn parse_statement_ends() {
+        let input = r#"
+        def f(x) do if x do return end; return x end
+        while true do while false do end end; var y
+        struct Point(x, y); class A
+        def b() do return 1 end
+        end
+        import util
+        "#;
+        let module = GreenParser::parse(input).unwrap();
+        assert_eq!(module.exprs().len(), 6);
+
+        // Statements need a line break between them.
+        assert!(GreenParser::parse("var x = 1 var y = 2").is_err());
+        assert!(GreenParser::parse("def f() 1 end f()").is_err());
+    }
+
+    #[test]
+    fn parse_tuple_return_and_unpacking() {
+        let input = r#"
+        def f() do return 1, 2 end
+        var a, b = f()
+        "#;
+        let module = GreenParser::parse(input).unwrap();
+        let ast = module.ast();
+
+        let ExprKind::Function(f) = &ast[module.exprs()[0]] else {
+            panic!("Expected a function");
+        };
+        let mut expected = Ast::new();
+        let one = expected.add(ExprKind::Literal(LiteralExpr::Number(1.0)));
+        let two = expected.add(ExprKind::Literal(LiteralExpr::Number(2.0)));
+        let tuple = expected.add(ExprKind::Tuple(TupleExpr::new(vec![one, two])));
+        let return_ = expected.add(ExprKind::Return(ReturnExpr::new(Some(tuple))));
+        assert!(matches!(
+            f.declaration.body.exprs.as_slice(),
+            [expr] if ast.same(*expr, &expected, return_)
+        ));
+        let ExprKind::VarUnpack(unpack) = &ast[module.exprs()[1]] else {
+            panic!("Expected an unpacking var");
+        };
+        assert_eq!(
+            unpack.variables,
+            vec![
+                Variable::new("a".to_string()),
+                Variable::new("b".to_string())
+            ]
+        );
+
+        // Unpacking always needs an initializer.
+        assert!(GreenParser::parse("var a, b\n").is_err());
+    }
+
+    #[test]
+    fn parse_destructuring_var() {
+        let names = |names: &[&str]| -> Vec<Variable> {
+            names.iter().map(|n| Variable::new(n.to_string())).collect()
+        };
+        let mut ast = Ast::new();
+        let mut destructure = |pattern| {
+            let list = ast.add(ExprKind::VarGet(VarGetExpr::new(Variable::new(
+                "list".to_string(),
+            ))));
+            ast.add(ExprKind::Destructure(DestructureExpr::new(pattern, list)))
+        };
+        let exprs = vec![
+            destructure(Destructure::Array(names(&["a", "b"]))),
+            destructure(Destructure::Map(names(&["x", "y"]))),
+            destructure(Destructure::Array(vec![])),
+        ];
+        let expect = ModuleAst::new(ast, exprs);
+
+        let input = r#"
+        var [a, b] = list
+        var {x, y,} = list
+        var [] = list
+        "#;
+        assert_eq!(expect, GreenParser::parse(input).unwrap());
+
+        assert!(GreenParser::parse("var [a, b]\n").is_err());
+        assert!(GreenParser::parse("var [a b] = list\n").is_err());
+    }
 }
@@ -1,133 +1,69 @@
 use crate::error::ParserError;
+use crate::syntax::ast::ExprId;
 use crate::syntax::expr::{
-    ArrayExpr, BinaryExpr, BinaryOperator, CallExpr, Expr, ExprKind, GetExpr, GroupingExpr,
-    LiteralExpr, SetExpr, SubscriptExpr, UnaryExpr, UnaryOperator, VarGetExpr, VarSetExpr,
-    Variable,
+    ArrayExpr, BinaryExpr, BinaryOperator, CallExpr, Comprehension, ExprKind, GetExpr,
+    GroupingExpr, IsExpr, LiteralExpr, MapExpr, RangeExpr, SetExpr, SubscriptExpr, UnaryExpr,
+    UnaryOperator, VarGetExpr, VarSetExpr, Variable,
 };
-use crate::syntax::parser::GreenParser;
+use crate::syntax::parser::{parse_number, GreenParser};
 use crate::syntax::token::{Keyword, Token, TokenType};
-use std::collections::HashMap;
 
 type Result<T> = std::result::Result<T, ParserError>;
 
 pub trait PrefixParser {
-    fn parse<'a>(&self, parser: &mut GreenParser, token: Token<'a>) -> Result<Expr>;
+    fn parse<'a>(&self, parser: &mut GreenParser, token: Token<'a>) -> Result<ExprId>;
 }
 
 pub trait InfixParser {
-    fn parse<'a>(&self, parser: &mut GreenParser, left: Expr, token: Token<'a>) -> Result<Expr>;
+    fn parse<'a>(&self, parser: &mut GreenParser, left: ExprId, token: Token<'a>)
+        -> Result<ExprId>;
     fn get_precedence(&self) -> Precedence;
 }
 
-pub fn get_prefix_rule(token_type: &TokenType) -> Option<Box<dyn PrefixParser>> {
-    // TODO Fix this mess.
-    let mut map = HashMap::new();
-    map.insert(TokenType::Number, LiteralParser {});
-    map.insert(TokenType::String, LiteralParser {});
-    map.insert(TokenType::Keyword(Keyword::True), LiteralParser {});
-    map.insert(TokenType::Keyword(Keyword::False), LiteralParser {});
-
-    let mut map2 = HashMap::new();
-    map2.insert(TokenType::LeftParen, GroupingParser {});
-
-    let mut map3 = HashMap::new();
-    map3.insert(TokenType::Identifier, IdentifierParser {});
-
-    let mut map4 = HashMap::new();
-    map4.insert(TokenType::Bang, UnaryParser {});
-    map4.insert(TokenType::Minus, UnaryParser {});
-
-    let mut map5 = HashMap::new();
-    map5.insert(TokenType::LeftBracket, ArrayParser {});
-
-    if let Some(token_type) = map.get(token_type) {
-        Some(Box::new(*token_type))
-    } else {
-        if let Some(token_type) = map2.get(token_type) {
-            Some(Box::new(*token_type))
-        } else {
-            if let Some(token_type) = map3.get(token_type) {
-                Some(Box::new(*token_type))
-            } else {
-                if let Some(token_type) = map4.get(token_type) {
-                    Some(Box::new(*token_type))
-                } else {
-                    if let Some(token_type) = map5.get(token_type) {
-                        Some(Box::new(*token_type))
-                    } else {
-                        None
-                    }
-                }
-            }
-        }
-    }
+pub fn get_prefix_rule(token_type: &TokenType) -> Option<&'static dyn PrefixParser> {
+    let rule: &'static dyn PrefixParser = match token_type {
+        TokenType::Number
+        | TokenType::String
+        | TokenType::Keyword(Keyword::True)
+        | TokenType::Keyword(Keyword::False) => &LiteralParser,
+        TokenType::LeftParen => &GroupingParser,
+        TokenType::Identifier => &IdentifierParser,
+        TokenType::Bang | TokenType::Minus => &UnaryParser,
+        TokenType::LeftBracket => &ArrayParser,
+        TokenType::LeftBrace => &MapParser,
+        TokenType::Keyword(Keyword::If) => &IfParser,
+        TokenType::Keyword(Keyword::Match) => &MatchParser,
+        TokenType::Keyword(Keyword::Do) => &BlockParser,
+        _ => return None,
+    };
+    Some(rule)
 }
 
-pub fn get_infix_rule(token_type: &TokenType) -> Option<Box<dyn InfixParser>> {
-    let mut map = HashMap::new();
-    map.insert(TokenType::Plus, InfixOperatorParser::new(Precedence::Term));
-    map.insert(TokenType::Minus, InfixOperatorParser::new(Precedence::Term));
-
-    map.insert(
-        TokenType::Star,
-        InfixOperatorParser::new(Precedence::Factor),
-    );
-    map.insert(
-        TokenType::Slash,
-        InfixOperatorParser::new(Precedence::Factor),
-    );
-
-    map.insert(
-        TokenType::EqualEqual,
-        InfixOperatorParser::new(Precedence::Equality),
-    );
-    map.insert(
-        TokenType::BangEqual,
-        InfixOperatorParser::new(Precedence::Equality),
-    );
-    map.insert(
-        TokenType::GreaterThan,
-        InfixOperatorParser::new(Precedence::Comparison),
-    );
-    map.insert(
-        TokenType::GreaterThanEqual,
-        InfixOperatorParser::new(Precedence::Comparison),
-    );
-    map.insert(
-        TokenType::LessThan,
-        InfixOperatorParser::new(Precedence::Comparison),
-    );
-    map.insert(
-        TokenType::LessThanEqual,
-        InfixOperatorParser::new(Precedence::Comparison),
-    );
-
-    let mut map2 = HashMap::new();
-    map2.insert(TokenType::LeftParen, CallParser::new());
-
-    let mut map3 = HashMap::new();
-    map3.insert(TokenType::LeftBracket, SubscriptParser::new());
-
-    let mut map4 = HashMap::new();
-    map4.insert(TokenType::Dot, DotParser::new());
-
-    if let Some(token_type) = map.get(&token_type) {
-        Some(Box::new(*token_type))
-    } else {
-        if let Some(token_type) = map2.get(&token_type) {
-            Some(Box::new(*token_type))
-        } else {
-            if let Some(token_type) = map3.get(&token_type) {
-                Some(Box::new(*token_type))
-            } else {
-                if let Some(token_type) = map4.get(&token_type) {
-                    Some(Box::new(*token_type))
-                } else {
-                    None
-                }
-            }
-        }
-    }
+pub fn get_infix_rule(token_type: &TokenType) -> Option<&'static dyn InfixParser> {
+    let rule: &'static dyn InfixParser = match token_type {
+        TokenType::Plus | TokenType::Minus => &InfixOperatorParser {
+            precedence: Precedence::Term,
+        },
+        TokenType::Star | TokenType::Slash | TokenType::Percent => &InfixOperatorParser {
+            precedence: Precedence::Factor,
+        },
+        TokenType::EqualEqual | TokenType::BangEqual => &InfixOperatorParser {
+            precedence: Precedence::Equality,
+        },
+        TokenType::GreaterThan
+        | TokenType::GreaterThanEqual
+        | TokenType::LessThan
+        | TokenType::LessThanEqual => &InfixOperatorParser {
+            precedence: Precedence::Comparison,
+        },
+        TokenType::LeftParen => &CallParser,
+        TokenType::LeftBracket => &SubscriptParser,
+        TokenType::Dot => &DotParser,
+        TokenType::Keyword(Keyword::Is) => &IsParser,
+        TokenType::Keyword(Keyword::To) | TokenType::Keyword(Keyword::DownTo) => &RangeParser,
+        _ => return None,
+    };
+    Some(rule)
 }
 
 pub fn get_precedence(token: &Token) -> Precedence {
@@ -145,37 +81,38 @@ pub fn get_precedence(token: &Token) -> Precedence {
 pub enum Precedence {
     None = 0,
     Assignment = 1,
+    // to downTo
+    Range = 2,
     // or
-    Or = 2,
+    Or = 3,
     // and
-    And = 3,
+    And = 4,
     // =
-    Equality = 4,
+    Equality = 5,
     // == !=
-    Comparison = 5,
+    Comparison = 6,
     // < > <= >=
-    Term = 6,
+    Term = 7,
     // + -
-    Factor = 7,
+    Factor = 8,
     // * /
-    Unary = 8,      // ! -
-    Call = 9,       // x()
-    Subscript = 10, // [] .
+    Unary = 9, // ! -
+    Call = 10, // x() x[] x.y
 }
 
 #[derive(Copy, Clone)]
 struct LiteralParser;
 
 impl PrefixParser for LiteralParser {
-    fn parse<'a>(&self, parser: &mut GreenParser, token: Token<'a>) -> Result<Expr> {
+    fn parse<'a>(&self, parser: &mut GreenParser, token: Token<'a>) -> Result<ExprId> {
         let op = match token.token_type {
-            TokenType::Number => LiteralExpr::Number(token.source.parse::<f64>().unwrap()),
+            TokenType::Number => LiteralExpr::Number(parse_number(&token)?),
             TokenType::String => LiteralExpr::String(token.source.to_string()), // TODO
             TokenType::Keyword(Keyword::True) => LiteralExpr::True,
             TokenType::Keyword(Keyword::False) => LiteralExpr::False,
-            _ => panic!("No rule for token: {:?}", token),
+            unexpected => return Err(ParserError::UnexpectedToken(unexpected)),
         };
-        Ok(Expr::new(ExprKind::Literal(op)))
+        Ok(parser.ast.add(ExprKind::Literal(op)))
     }
 }
 
@@ -183,10 +120,10 @@ impl PrefixParser for LiteralParser {
 struct GroupingParser;
 
 impl PrefixParser for GroupingParser {
-    fn parse<'a>(&self, parser: &mut GreenParser, token: Token<'a>) -> Result<Expr> {
+    fn parse<'a>(&self, parser: &mut GreenParser, _token: Token<'a>) -> Result<ExprId> {
         let expr = parser.parse_expression()?;
         parser.expect(TokenType::RightParen)?;
-        Ok(Expr::new(ExprKind::Grouping(GroupingExpr::new(expr))))
+        Ok(parser.ast.add(ExprKind::Grouping(GroupingExpr::new(expr))))
     }
 }
 
@@ -194,15 +131,17 @@ impl PrefixParser for GroupingParser {
 struct IdentifierParser;
 
 impl PrefixParser for IdentifierParser {
-    fn parse<'a>(&self, parser: &mut GreenParser, token: Token<'a>) -> Result<Expr> {
+    fn parse<'a>(&self, parser: &mut GreenParser, token: Token<'a>) -> Result<ExprId> {
         let var = Variable::new(token.source.to_string());
 
-        Ok(if parser.match_(TokenType::Equal)? {
+        Ok(if parser.can_assign() && parser.match_(TokenType::Equal)? {
             let initializer = parser.parse_expression()?;
 
-            Expr::var_set(VarSetExpr::new(var, initializer))
+            parser
+                .ast
+                .add(ExprKind::VarSet(VarSetExpr::new(var, initializer)))
         } else {
-            Expr::var_get(VarGetExpr::new(var))
+            parser.ast.add(ExprKind::VarGet(VarGetExpr::new(var)))
         })
     }
 }
@@ -212,24 +151,21 @@ struct InfixOperatorParser {
     precedence: Precedence,
 }
 
-impl InfixOperatorParser {
-    pub fn new(precedence: Precedence) -> Self {
-        InfixOperatorParser { precedence }
-    }
-}
-
 impl InfixParser for InfixOperatorParser {
-    fn parse<'a>(&self, parser: &mut GreenParser, left: Expr, token: Token<'a>) -> Result<Expr> {
+    fn parse<'a>(
+        &self,
+        parser: &mut GreenParser,
+        left: ExprId,
+        token: Token<'a>,
+    ) -> Result<ExprId> {
         // Assume left associativity.
         let right = parser.parse_precedence(self.precedence)?;
 
-        let binary = BinaryExpr::new(
-            left,
-            right,
-            BinaryOperator::from_token(token.token_type).unwrap(),
-        );
+        let operator = BinaryOperator::from_token(token.token_type)
+            .ok_or(ParserError::UnexpectedToken(token.token_type))?;
+        let binary = BinaryExpr::new(left, right, operator);
 
-        Ok(Expr::new(ExprKind::Binary(binary)))
+        Ok(parser.ast.add(ExprKind::Binary(binary)))
     }
 
     fn get_precedence(&self) -> Precedence {
@@ -240,25 +176,30 @@ impl InfixParser for InfixOperatorParser {
 #[derive(Copy, Clone)]
 struct CallParser;
 
-impl CallParser {
-    pub fn new() -> Self {
-        CallParser {}
-    }
-}
-
 impl InfixParser for CallParser {
-    fn parse<'a>(&self, parser: &mut GreenParser, left: Expr, token: Token<'a>) -> Result<Expr> {
+    fn parse<'a>(
+        &self,
+        parser: &mut GreenParser,
+        left: ExprId,
+        token: Token<'a>,
+    ) -> Result<ExprId> {
         let mut args = vec![];
         if !parser.check(TokenType::RightParen)? {
             args.push(parser.parse_expression()?);
             while parser.match_(TokenType::Comma)? {
-                parser.consume()?;
                 args.push(parser.parse_expression()?);
             }
         }
         parser.expect(TokenType::RightParen)?;
 
-        Ok(Expr::new(ExprKind::Call(CallExpr::new(left, args))))
+        if let ExprKind::VarGet(get) = &parser.ast[left] {
+            if parser.is_macro(&get.variable.name) {
+                let name = get.variable.name.clone();
+                return parser.expand_macro(&name, &args, token.position.line);
+            }
+        }
+
+        Ok(parser.ast.add(ExprKind::Call(CallExpr::new(left, args))))
     }
 
     fn get_precedence(&self) -> Precedence {
@@ -269,122 +210,189 @@ impl InfixParser for CallParser {
 #[derive(Copy, Clone)]
 struct SubscriptParser;
 
-impl SubscriptParser {
-    pub fn new() -> Self {
-        SubscriptParser {}
-    }
-}
-
 impl InfixParser for SubscriptParser {
-    fn parse<'a>(&self, parser: &mut GreenParser, left: Expr, token: Token<'a>) -> Result<Expr> {
+    fn parse<'a>(
+        &self,
+        parser: &mut GreenParser,
+        left: ExprId,
+        _token: Token<'a>,
+    ) -> Result<ExprId> {
+        let can_assign = parser.can_assign();
         let index = parser.parse_precedence(Precedence::Or)?;
         parser.expect(TokenType::RightBracket)?;
 
-        let expr = if parser.match_(TokenType::Equal)? {
-            parser.consume()?;
+        let expr = if can_assign && parser.match_(TokenType::Equal)? {
             Some(parser.parse_expression()?)
         } else {
             None
         };
 
-        Ok(Expr::new(ExprKind::Subscript(SubscriptExpr::new(
-            left, index, expr,
-        ))))
+        Ok(parser
+            .ast
+            .add(ExprKind::Subscript(SubscriptExpr::new(left, index, expr))))
     }
 
     fn get_precedence(&self) -> Precedence {
-        Precedence::Subscript
+        Precedence::Call
     }
 }
 
 #[derive(Copy, Clone)]
 struct UnaryParser;
 
-impl UnaryParser {
-    pub fn new() -> Self {
-        UnaryParser {}
-    }
-}
-
 impl PrefixParser for UnaryParser {
-    fn parse<'a>(&self, parser: &mut GreenParser, token: Token<'a>) -> Result<Expr> {
+    fn parse<'a>(&self, parser: &mut GreenParser, token: Token<'a>) -> Result<ExprId> {
         let operator_type = token.token_type;
 
-        let expr = parser.parse_expression()?;
+        let expr = parser.parse_precedence(Precedence::Unary)?;
 
         let op = match operator_type {
             TokenType::Minus => UnaryOperator::Negate,
             TokenType::Bang => UnaryOperator::Not,
-            _ => panic!("TODO"), // TODO
+            unexpected => return Err(ParserError::UnexpectedToken(unexpected)),
         };
 
-        Ok(Expr::new(ExprKind::Unary(UnaryExpr::new(expr, op))))
+        Ok(parser.ast.add(ExprKind::Unary(UnaryExpr::new(expr, op))))
     }
 }
 
 #[derive(Copy, Clone)]
 struct ArrayParser;
 
+impl PrefixParser for ArrayParser {
+    fn parse<'a>(&self, parser: &mut GreenParser, _token: Token<'a>) -> Result<ExprId> {
+        let mut exprs = vec![];
+
+        while !parser.check(TokenType::RightBracket)? {
+            let item = ArrayParser::parse_item(parser)?;
+
+            if exprs.is_empty() && parser.check(TokenType::Keyword(Keyword::For))? {
+                let comprehension = parser.parse_comprehension(Comprehension::Array(item))?;
+                parser.expect(TokenType::RightBracket)?;
+                return Ok(comprehension);
+            }
+            exprs.push(item);
+
+            // A trailing comma is allowed.
+            if !parser.match_(TokenType::Comma)? {
+                break;
+            }
+        }
+
+        parser.expect(TokenType::RightBracket)?;
+
+        Ok(parser.ast.add(ExprKind::Array(ArrayExpr::new(Some(exprs)))))
+    }
+}
+
 impl ArrayParser {
-    pub fn new() -> Self {
-        ArrayParser {}
+    /// Parses an element of an array literal. Ranges, written `start to end`, expand into their
+    /// numbers.
+    fn parse_item(parser: &mut GreenParser) -> Result<ExprId> {
+        let start = parser.parse_precedence(Precedence::Or)?;
+
+        let descending = match parser.peek_type()? {
+            TokenType::Keyword(Keyword::To) => false,
+            TokenType::Keyword(Keyword::DownTo) => true,
+            _ => return Ok(start),
+        };
+        parser.consume()?;
+
+        let end = parser.parse_precedence(Precedence::Or)?;
+        let step = if parser.match_(TokenType::Keyword(Keyword::Step))? {
+            Some(parser.parse_precedence(Precedence::Or)?)
+        } else {
+            None
+        };
+
+        let range = RangeExpr::new(start, end, step, descending);
+        Ok(parser.ast.add(ExprKind::Range(range)))
     }
 }
 
-impl PrefixParser for ArrayParser {
-    fn parse<'a>(&self, parser: &mut GreenParser, token: Token<'a>) -> Result<Expr> {
-        let mut exprs = vec![];
+#[derive(Copy, Clone)]
+struct MapParser;
 
-        if !parser.match_(TokenType::RightBracket)? {
-            loop {
-                if parser.match_(TokenType::RightBracket)? {
-                    // trailing comma case
-                    break;
-                }
+impl PrefixParser for MapParser {
+    fn parse<'a>(&self, parser: &mut GreenParser, _token: Token<'a>) -> Result<ExprId> {
+        let mut entries = vec![];
 
-                let expr = parser.parse_precedence(Precedence::Or)?;
-                exprs.push(expr);
+        while !parser.check(TokenType::RightBrace)? {
+            let key = parser.parse_precedence(Precedence::Or)?;
+            parser.expect(TokenType::Colon)?;
+            let value = parser.parse_precedence(Precedence::Or)?;
 
-                // TODO Max items in array
-                // if (itemCount == UINT8_COUNT) {
-                //     error("Cannot have more than 256 items in a array literal.");
-                // }
+            if entries.is_empty() && parser.check(TokenType::Keyword(Keyword::For))? {
+                let comprehension = parser.parse_comprehension(Comprehension::Map(key, value))?;
+                parser.expect(TokenType::RightBrace)?;
+                return Ok(comprehension);
+            }
+            entries.push((key, value));
 
-                if parser.match_(TokenType::Comma)? {
-                    parser.consume()?;
-                }
+            // A trailing comma is allowed.
+            if !parser.match_(TokenType::Comma)? {
+                break;
             }
         }
 
-        parser.expect(TokenType::RightBracket)?;
+        parser.expect(TokenType::RightBrace)?;
 
-        Ok(Expr::new(ExprKind::Array(ArrayExpr::new(Some(exprs)))))
+        Ok(parser.ast.add(ExprKind::Map(MapExpr::new(entries))))
     }
 }
 
 #[derive(Copy, Clone)]
-struct DotParser;
+struct IfParser;
 
-impl DotParser {
-    pub fn new() -> Self {
-        DotParser {}
+impl PrefixParser for IfParser {
+    fn parse<'a>(&self, parser: &mut GreenParser, _token: Token<'a>) -> Result<ExprId> {
+        parser.parse_if()
     }
 }
 
+#[derive(Copy, Clone)]
+struct MatchParser;
+
+impl PrefixParser for MatchParser {
+    fn parse<'a>(&self, parser: &mut GreenParser, _token: Token<'a>) -> Result<ExprId> {
+        parser.parse_match()
+    }
+}
+
+#[derive(Copy, Clone)]
+struct BlockParser;
+
+impl PrefixParser for BlockParser {
+    fn parse<'a>(&self, parser: &mut GreenParser, _token: Token<'a>) -> Result<ExprId> {
+        parser.parse_do_block()
+    }
+}
+
+#[derive(Copy, Clone)]
+struct DotParser;
+
 impl InfixParser for DotParser {
-    fn parse<'a>(&self, parser: &mut GreenParser, left: Expr, token: Token<'a>) -> Result<Expr> {
+    fn parse<'a>(
+        &self,
+        parser: &mut GreenParser,
+        left: ExprId,
+        _token: Token<'a>,
+    ) -> Result<ExprId> {
         let property_token = parser.expect(TokenType::Identifier)?;
         let property = property_token.source;
 
-        if parser.match_(TokenType::Equal)? {
+        if parser.can_assign() && parser.match_(TokenType::Equal)? {
             let value = parser.parse_expression()?;
-            Ok(Expr::set_property(SetExpr::new(
+            Ok(parser.ast.add(ExprKind::SetProperty(SetExpr::new(
                 left,
                 value,
                 property.to_string(),
-            )))
+            ))))
         } else {
-            Ok(Expr::get_property(GetExpr::new(left, property.to_string())))
+            Ok(parser.ast.add(ExprKind::GetProperty(GetExpr::new(
+                left,
+                property.to_string(),
+            ))))
         }
 
         // uint8_t name = identifierConstant(&parser.previous);
@@ -401,3 +409,56 @@ impl InfixParser for DotParser {
         Precedence::Call
     }
 }
+
+/// `start to end step n` or `start downTo end`, making a range value. Binds looser than any
+/// other operator, so `0 to len(xs) - 1` needs no parentheses. Array literals, comprehensions
+/// and `for` loops parse their own ranges.
+#[derive(Copy, Clone)]
+struct RangeParser;
+
+impl InfixParser for RangeParser {
+    fn parse<'a>(
+        &self,
+        parser: &mut GreenParser,
+        left: ExprId,
+        token: Token<'a>,
+    ) -> Result<ExprId> {
+        let descending = token.token_type == TokenType::Keyword(Keyword::DownTo);
+        let end = parser.parse_precedence(Precedence::Or)?;
+        let step = if parser.match_(TokenType::Keyword(Keyword::Step))? {
+            Some(parser.parse_precedence(Precedence::Or)?)
+        } else {
+            None
+        };
+
+        let range = RangeExpr::new(left, end, step, descending);
+        Ok(parser.ast.add(ExprKind::Range(range)))
+    }
+
+    fn get_precedence(&self) -> Precedence {
+        Precedence::Range
+    }
+}
+
+#[derive(Copy, Clone)]
+struct IsParser;
+
+impl InfixParser for IsParser {
+    fn parse<'a>(
+        &self,
+        parser: &mut GreenParser,
+        left: ExprId,
+        _token: Token<'a>,
+    ) -> Result<ExprId> {
+        let target = parser.expect(TokenType::Identifier)?;
+
+        Ok(parser.ast.add(ExprKind::Is(IsExpr::new(
+            left,
+            Variable::new(target.source.to_string()),
+        ))))
+    }
+
+    fn get_precedence(&self) -> Precedence {
+        Precedence::Comparison
+    }
+}
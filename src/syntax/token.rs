@@ -35,8 +35,17 @@ impl Position {
     pub fn new(start: usize, end: usize, line: usize) -> Self {
         Position { start, end, line }
     }
+
+    pub fn start(&self) -> usize {
+        self.start
+    }
+
+    pub fn end(&self) -> usize {
+        self.end
+    }
 }
 
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Debug, PartialEq, Eq, Hash, Copy, Clone)]
 pub enum TokenType {
     // Single-character tokens
@@ -104,6 +113,16 @@ pub enum Keyword {
     False,
     Return,
     Class,
+    Is,
+    Assert,
+    Struct,
+    Match,
+    Case,
+    Static,
+    Macro,
+    Protocol,
+    Implements,
+    Test,
 }
 
 impl FromStr for Keyword {
@@ -130,6 +149,16 @@ impl FromStr for Keyword {
             "false" => Ok(Keyword::False),
             "return" => Ok(Keyword::Return),
             "class" => Ok(Keyword::Class),
+            "is" => Ok(Keyword::Is),
+            "assert" => Ok(Keyword::Assert),
+            "struct" => Ok(Keyword::Struct),
+            "match" => Ok(Keyword::Match),
+            "case" => Ok(Keyword::Case),
+            "static" => Ok(Keyword::Static),
+            "macro" => Ok(Keyword::Macro),
+            "protocol" => Ok(Keyword::Protocol),
+            "implements" => Ok(Keyword::Implements),
+            "test" => Ok(Keyword::Test),
             _ => Err(()),
         }
     }
@@ -0,0 +1,142 @@
+use crate::bench::find_scripts;
+use crate::compiler::options::CompileOptions;
+use crate::error::ParserError;
+use crate::syntax::parser::GreenParser;
+use crate::vm::{VmOptions, VM};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+/// How a `test "name" do ... end` block went.
+#[derive(Debug)]
+pub struct TestResult {
+    pub name: String,
+    /// The report of the error that failed the test, like a failed assertion and where it is.
+    pub failure: Option<String>,
+}
+
+impl TestResult {
+    pub fn passed(&self) -> bool {
+        self.failure.is_none()
+    }
+}
+
+impl fmt::Display for TestResult {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.failure {
+            None => write!(f, "  ok      {}", self.name),
+            Some(report) => {
+                write!(f, "  FAILED  {}", self.name)?;
+                for line in report.lines() {
+                    write!(f, "\n      {}", line)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Runs each test block of a script in a VM of its own, after the rest of the script, so tests
+/// can use what it defines but not what other tests did. Fails if the script doesn't parse.
+pub fn test_script(name: &str, source: &str) -> Result<Vec<TestResult>, ParserError> {
+    let module = GreenParser::parse(source)?;
+
+    let mut results = vec![];
+    for (index, test) in module.tests().into_iter().enumerate() {
+        let mut vm = VM::with_options(VmOptions {
+            compile: CompileOptions {
+                warnings: false,
+                disassemble: false,
+                ..CompileOptions::default()
+            },
+            ..VmOptions::default()
+        });
+        let mut selected = module.clone();
+        selected.select_test(index);
+
+        let failure = vm.run_module(name, source, selected).err();
+        results.push(TestResult {
+            name: test.to_string(),
+            failure,
+        });
+    }
+    Ok(results)
+}
+
+/// Runs the tests of every script found at `paths`, printing each script's results as it
+/// finishes and then how many passed. Returns whether they all did.
+pub fn run(paths: &[PathBuf]) -> io::Result<bool> {
+    let (mut passed, mut failed) = (0, 0);
+
+    for path in paths {
+        for script in find_scripts(path)? {
+            let source = fs::read_to_string(&script)?;
+            let name = script.display().to_string();
+            println!("{}", name);
+
+            match test_script(&name, &source) {
+                Ok(results) => {
+                    for result in results {
+                        println!("{}", result);
+                        if result.passed() {
+                            passed += 1;
+                        } else {
+                            failed += 1;
+                        }
+                    }
+                }
+                Err(err) => {
+                    println!("  Could not parse {}: {}", name, err);
+                    failed += 1;
+                }
+            }
+        }
+    }
+
+    println!();
+    println!("{} passed, {} failed", passed, failed);
+    Ok(failed == 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tests_run_on_their_own_after_the_script() {
+        let source = r#"
+var counter = 0
+def double(n)
+    return n * 2
+end
+
+test "sees the script's definitions" do
+    counter = counter + 1
+    assert_eq(double(counter), 4)
+end
+
+test "starts from a fresh script" do
+    assert_eq(counter, 1)
+    assert_eq([double(1), double(2)], [2, 5])
+end
+
+counter = 1
+"#;
+
+        let results = test_script("doubles.green", source).unwrap();
+        let names: Vec<&str> = results.iter().map(|result| result.name.as_str()).collect();
+        assert_eq!(
+            names,
+            [
+                "sees the script's definitions",
+                "starts from a fresh script"
+            ]
+        );
+        assert!(results[0].passed());
+
+        let failure = results[1].failure.as_deref().unwrap();
+        assert!(failure.contains("value[1]: 4 != 5"), "{}", failure);
+        assert!(failure.contains("doubles.green:14"), "{}", failure);
+    }
+}
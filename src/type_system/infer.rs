@@ -30,20 +30,16 @@ impl fmt::Display for Syntax {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         use Syntax::*;
         match self {
-            &Lambda { ref v, ref body } => {
+            Lambda { v, body } => {
                 write!(f, "(fn {v} => {body})", v = v, body = body)
             }
-            &Identifier { ref name } => {
+            Identifier { name } => {
                 write!(f, "{}", name)
             }
-            &Apply { ref func, ref arg } => {
+            Apply { func, arg } => {
                 write!(f, "({func} {arg})", func = func, arg = arg)
             }
-            &Let {
-                ref v,
-                ref defn,
-                ref body,
-            } => {
+            Let { v, defn, body } => {
                 write!(
                     f,
                     "(let {v} = {defn} in {body})",
@@ -52,11 +48,7 @@ impl fmt::Display for Syntax {
                     body = body
                 )
             }
-            &Letrec {
-                ref v,
-                ref defn,
-                ref body,
-            } => {
+            Letrec { v, defn, body } => {
                 write!(
                     f,
                     "(letrec {v} = {defn} in {body})",
@@ -104,7 +96,7 @@ impl Namer {
     }
 
     fn name(&mut self, t: ArenaType) -> String {
-        let k = { self.set.get(&t).map(|x| x.clone()) };
+        let k = { self.set.get(&t).cloned() };
         if let Some(val) = k {
             val.clone()
         } else {
@@ -119,7 +111,6 @@ impl Namer {
 ///
 /// All type variables have a unique id, but names are
 /// only assigned lazily, when required.
-
 impl Type {
     fn new_variable(idx: ArenaType) -> Type {
         Type::Variable {
@@ -137,9 +128,9 @@ impl Type {
     }
 
     fn id(&self) -> usize {
-        match self {
-            &Type::Variable { id, .. } => id,
-            &Type::Operator { id, .. } => id,
+        match *self {
+            Type::Variable { id, .. } => id,
+            Type::Operator { id, .. } => id,
         }
     }
 
@@ -164,11 +155,7 @@ impl Type {
                 ..
             } => a[inst].as_string(a, namer),
             &Type::Variable { .. } => namer.name(self.id()),
-            &Type::Operator {
-                ref types,
-                ref name,
-                ..
-            } => match types.len() {
+            Type::Operator { types, name, .. } => match types.len() {
                 0 => name.clone(),
                 2 => {
                     let l = a[types[0]].as_string(a, namer);
@@ -237,18 +224,18 @@ pub struct Env(HashMap<String, ArenaType>);
 /// importantly, the type-checking program when extending the language.
 ///
 /// Args:
-///     node: The root of the abstract syntax tree.
-///     env: The type environment is a mapping of expression identifier names
-///         to type assignments.
-///     non_generic: A set of non-generic variables, or None
+/// node: The root of the abstract syntax tree.
+/// env: The type environment is a mapping of expression identifier names
+///   to type assignments.
+/// non_generic: A set of non-generic variables, or None
 ///
 /// Returns:
-///     The computed type of the expression.
+/// The computed type of the expression.
 ///
 /// Raises:
-///     InferenceError: The type of the expression could not be inferred, for example
-///         if it is not possible to unify two types such as Integer and Bool
-///     ParseError: The abstract syntax tree rooted at node could not be parsed
+/// InferenceError: The type of the expression could not be inferred, for example
+///   if it is not possible to unify two types such as Integer and Bool
+/// ParseError: The abstract syntax tree rooted at node could not be parsed
 pub fn analyse(
     a: &mut Vec<Type>,
     node: &Syntax,
@@ -257,44 +244,36 @@ pub fn analyse(
 ) -> ArenaType {
     use Syntax::*;
     match node {
-        &Identifier { ref name } => get_type(a, name, env, non_generic),
-        &Apply { ref func, ref arg } => {
+        Identifier { name } => get_type(a, name, env, non_generic),
+        Apply { func, arg } => {
             let fun_type = analyse(a, func, env, non_generic);
             let arg_type = analyse(a, arg, env, non_generic);
             let result_type = new_variable(a);
-            let first = new_function(a, arg_type, result_type.clone());
+            let first = new_function(a, arg_type, result_type);
             unify(a, first, fun_type);
             result_type
         }
-        &Lambda { ref v, ref body } => {
+        Lambda { v, body } => {
             let arg_type = new_variable(a);
             let mut new_env = env.clone();
             new_env.0.insert(v.clone(), arg_type);
             let mut new_non_generic = non_generic.clone();
-            new_non_generic.insert(arg_type.clone());
+            new_non_generic.insert(arg_type);
             let result_type = analyse(a, body, &new_env, &new_non_generic);
             new_function(a, arg_type, result_type)
         }
-        &Let {
-            ref defn,
-            ref v,
-            ref body,
-        } => {
+        Let { defn, v, body } => {
             let defn_type = analyse(a, defn, env, non_generic);
             let mut new_env = env.clone();
             new_env.0.insert(v.clone(), defn_type);
             analyse(a, body, &new_env, non_generic)
         }
-        &Letrec {
-            ref defn,
-            ref v,
-            ref body,
-        } => {
+        Letrec { defn, v, body } => {
             let new_type = new_variable(a);
             let mut new_env = env.clone();
-            new_env.0.insert(v.clone(), new_type.clone());
+            new_env.0.insert(v.clone(), new_type);
             let mut new_non_generic = non_generic.clone();
-            new_non_generic.insert(new_type.clone());
+            new_non_generic.insert(new_type);
             let defn_type = analyse(a, defn, &new_env, &new_non_generic);
             unify(a, new_type, defn_type);
             analyse(a, body, &new_env, non_generic)
@@ -304,14 +283,14 @@ pub fn analyse(
 
 /// Get the type of identifier name from the type environment env.
 ///
-///     Args:
-///         name: The identifier name
-///         env: The type environment mapping from identifier names to types
-///         non_generic: A set of non-generic TypeVariables
+/// Args:
+///   name: The identifier name
+///   env: The type environment mapping from identifier names to types
+///   non_generic: A set of non-generic TypeVariables
 ///
-///     Raises:
-///         ParseError: Raised if name is an undefined symbol in the type
-///             environment.
+/// Raises:
+///   ParseError: Raised if name is an undefined symbol in the type
+///    environment.
 fn get_type(
     a: &mut Vec<Type>,
     name: &str,
@@ -331,12 +310,12 @@ fn get_type(
 
 /// Makes a copy of a type expression.
 ///
-///     The type t is copied. The the generic variables are duplicated and the
-///     non_generic variables are shared.
+/// The type t is copied. The the generic variables are duplicated and the
+/// non_generic variables are shared.
 ///
-///     Args:
-///         t: A type to be copied.
-///         non_generic: A set of non-generic TypeVariables
+/// Args:
+///   t: A type to be copied.
+///   non_generic: A set of non-generic TypeVariables
 fn fresh(a: &mut Vec<Type>, t: ArenaType, non_generic: &[ArenaType]) -> ArenaType {
     // A mapping of TypeVariables to TypeVariables
     let mut mappings = HashMap::new();
@@ -351,7 +330,7 @@ fn fresh(a: &mut Vec<Type>, t: ArenaType, non_generic: &[ArenaType]) -> ArenaTyp
         match a.get(p).unwrap().clone() {
             Type::Variable { .. } => {
                 if is_generic(a, p, non_generic) {
-                    mappings.entry(p).or_insert(new_variable(a)).clone()
+                    *mappings.entry(p).or_insert(new_variable(a))
                 } else {
                     p
                 }
@@ -375,17 +354,17 @@ fn fresh(a: &mut Vec<Type>, t: ArenaType, non_generic: &[ArenaType]) -> ArenaTyp
 
 /// Unify the two types t1 and t2.
 ///
-///     Makes the types t1 and t2 the same.
+/// Makes the types t1 and t2 the same.
 ///
-///     Args:
-///         t1: The first type to be made equivalent
-///         t2: The second type to be be equivalent
+/// Args:
+///   t1: The first type to be made equivalent
+///   t2: The second type to be be equivalent
 ///
-///     Returns:
-///         None
+/// Returns:
+///   None
 ///
-///     Raises:
-///         InferenceError: Raised if the types cannot be unified.
+/// Raises:
+///   InferenceError: Raised if the types cannot be unified.
 fn unify(alloc: &mut Vec<Type>, t1: ArenaType, t2: ArenaType) {
     let a = prune(alloc, t1);
     let b = prune(alloc, t2);
@@ -425,28 +404,25 @@ fn unify(alloc: &mut Vec<Type>, t1: ArenaType, t2: ArenaType) {
 
 /// Returns the currently defining instance of t.
 ///
-///     As a side effect, collapses the list of type instances. The function Prune
-///     is used whenever a type expression has to be inspected: it will always
-///     return a type expression which is either an uninstantiated type variable or
-///     a type operator; i.e. it will skip instantiated variables, and will
-///     actually prune them from expressions to remove long chains of instantiated
-///     variables.
+/// As a side effect, collapses the list of type instances. The function Prune
+/// is used whenever a type expression has to be inspected: it will always
+/// return a type expression which is either an uninstantiated type variable or
+/// a type operator; i.e. it will skip instantiated variables, and will
+/// actually prune them from expressions to remove long chains of instantiated
+/// variables.
 ///
-///     Args:
-///         t: The type to be pruned
+/// Args:
+///   t: The type to be pruned
 ///
-///     Returns:
-///         An uninstantiated TypeVariable or a TypeOperator
+/// Returns:
+///   An uninstantiated TypeVariable or a TypeOperator
 fn prune(a: &mut Vec<Type>, t: ArenaType) -> ArenaType {
     let v2 = match a.get(t).unwrap() {
         //TODO screwed up
-        &Type::Variable { instance, .. } => {
-            if let Some(value) = instance {
-                value
-            } else {
-                return t;
-            }
-        }
+        &Type::Variable {
+            instance: Some(value),
+            ..
+        } => value,
         _ => {
             return t;
         }
@@ -469,32 +445,32 @@ fn prune(a: &mut Vec<Type>, t: ArenaType) -> ArenaType {
 
 /// Checks whether a given variable occurs in a list of non-generic variables
 ///
-///     Note that a variables in such a list may be instantiated to a type term,
-///     in which case the variables contained in the type term are considered
-///     non-generic.
+/// Note that a variables in such a list may be instantiated to a type term,
+/// in which case the variables contained in the type term are considered
+/// non-generic.
 ///
-///     Note: Must be called with v pre-pruned
+/// Note: Must be called with v pre-pruned
 ///
-///     Args:
-///         v: The TypeVariable to be tested for genericity
-///         non_generic: A set of non-generic TypeVariables
+/// Args:
+///   v: The TypeVariable to be tested for genericity
+///   non_generic: A set of non-generic TypeVariables
 ///
-///     Returns:
-///         True if v is a generic variable, otherwise False
+/// Returns:
+///   True if v is a generic variable, otherwise False
 fn is_generic(a: &mut Vec<Type>, v: ArenaType, non_generic: &[ArenaType]) -> bool {
     !occurs_in(a, v, non_generic)
 }
 
 /// Checks whether a type variable occurs in a type expression.
 ///
-///     Note: Must be called with v pre-pruned
+/// Note: Must be called with v pre-pruned
 ///
-///     Args:
-///         v:  The TypeVariable to be tested for
-///         type2: The type in which to search
+/// Args:
+///   v:  The TypeVariable to be tested for
+///   type2: The type in which to search
 ///
-///     Returns:
-///         True if v occurs in type2, otherwise False
+/// Returns:
+///   True if v occurs in type2, otherwise False
 fn occurs_in_type(a: &mut Vec<Type>, v: ArenaType, type2: ArenaType) -> bool {
     let pruned_type2 = prune(a, type2);
     if pruned_type2 == v {
@@ -509,11 +485,11 @@ fn occurs_in_type(a: &mut Vec<Type>, v: ArenaType, type2: ArenaType) -> bool {
 /// Checks whether a types variable occurs in any other types.
 ///
 /// Args:
-///     t:  The TypeVariable to be tested for
-///     types: The sequence of types in which to search
+/// t:  The TypeVariable to be tested for
+/// types: The sequence of types in which to search
 ///
 /// Returns:
-///     True if t occurs in any of types, otherwise False
+/// True if t occurs in any of types, otherwise False
 ///
 fn occurs_in(a: &mut Vec<Type>, t: ArenaType, types: &[ArenaType]) -> bool {
     for t2 in types.iter() {
@@ -521,16 +497,16 @@ fn occurs_in(a: &mut Vec<Type>, t: ArenaType, types: &[ArenaType]) -> bool {
             return true;
         }
     }
-    return false;
+    false
 }
 
 /// Checks whether name is an integer literal string.
 ///
 /// Args:
-///     name: The identifier to check
+/// name: The identifier to check
 ///
 /// Returns:
-///     True if name is an integer literal, otherwise False
+/// True if name is an integer literal, otherwise False
 fn is_integer_literal(name: &str) -> bool {
     name.parse::<isize>().is_ok()
 }
@@ -589,14 +565,14 @@ fn test_env() -> (Vec<Type>, Env) {
         let right = new_function(&mut a, var2, pair_type);
         new_function(&mut a, var1, right)
     });
-    my_env.insert("true".to_string(), { 1 });
+    my_env.insert("true".to_string(), 1);
     my_env.insert("cond".to_string(), {
         let right = new_function(&mut a, var3, var3);
         let right = new_function(&mut a, var3, right);
         new_function(&mut a, 1, right)
     });
-    my_env.insert("zero".to_string(), { new_function(&mut a, 0, 1) });
-    my_env.insert("pred".to_string(), { new_function(&mut a, 0, 0) });
+    my_env.insert("zero".to_string(), new_function(&mut a, 0, 1));
+    my_env.insert("pred".to_string(), new_function(&mut a, 0, 0));
     my_env.insert("times".to_string(), {
         let right = new_function(&mut a, 0, 0);
         new_function(&mut a, 0, right)
@@ -605,10 +581,10 @@ fn test_env() -> (Vec<Type>, Env) {
     (a, Env(my_env))
 }
 
-/// Sets up some predefined types using the type constructors TypeVariable,
-/// TypeOperator and Function.  Creates a list of example expressions to be
-/// evaluated. Evaluates the expressions, printing the type or errors arising
-/// from each.
+// Sets up some predefined types using the type constructors TypeVariable,
+// TypeOperator and Function.  Creates a list of example expressions to be
+// evaluated. Evaluates the expressions, printing the type or errors arising
+// from each.
 
 #[cfg(test)]
 mod tests {
@@ -11,7 +11,7 @@ pub fn repl() {
 fn read_line() -> String {
     let mut input = String::new();
     match io::stdin().read_line(&mut input) {
-        Ok(n) => {
+        Ok(_n) => {
             trim_newline(input.borrow_mut());
             input
         }
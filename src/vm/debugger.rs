@@ -0,0 +1,251 @@
+use crate::vm::hooks::LineTracker;
+use crate::vm::VM;
+use std::collections::HashSet;
+use std::io;
+use std::io::{BufRead, Write};
+use std::path::Path;
+
+const HELP: &str = "\
+step, s       run to the next line
+continue, c   run to the next breakpoint
+break N, b N  stop at line N of this file
+stack         show the values on the stack
+backtrace, bt show the calls in progress
+locals, l     show the local variables
+instr, i      show the instruction about to run
+help, h       show this help";
+
+/// Pauses a script at breakpoints, or at every line while stepping, and reads commands to look
+/// around with until told to go on.
+pub(crate) struct Debugger {
+    /// Lines to stop at, by file name.
+    breakpoints: HashSet<(String, usize)>,
+    stepping: bool,
+    /// So a line pauses when it's reached rather than at each of its instructions.
+    lines: LineTracker,
+    input: Box<dyn BufRead>,
+    output: Box<dyn Write>,
+}
+
+impl Debugger {
+    pub(crate) fn new(stepping: bool) -> Self {
+        Debugger {
+            breakpoints: HashSet::new(),
+            stepping,
+            lines: LineTracker::default(),
+            input: Box::new(io::BufReader::new(io::stdin())),
+            output: Box::new(io::stderr()),
+        }
+    }
+
+    fn has_breakpoint(&self, file: &str, line: usize) -> bool {
+        self.breakpoints
+            .iter()
+            .any(|(breakpoint_file, breakpoint_line)| {
+                *breakpoint_line == line && Path::new(file).ends_with(breakpoint_file)
+            })
+    }
+
+    /// Prompts for a command, or `None` once the input is closed.
+    fn read_command(&mut self) -> Option<String> {
+        let _ = write!(self.output, "(debug) ");
+        let _ = self.output.flush();
+
+        let mut line = String::new();
+        match self.input.read_line(&mut line) {
+            Ok(0) | Err(_) => None,
+            Ok(_) => Some(line.trim().to_string()),
+        }
+    }
+}
+
+impl VM {
+    /// Stops the script at a line of a file. The file matches scripts and modules whose path
+    /// ends with it.
+    pub fn set_breakpoint(&mut self, file: &str, line: usize) {
+        self.debugger
+            .get_or_insert_with(|| Debugger::new(false))
+            .breakpoints
+            .insert((file.to_string(), line));
+    }
+
+    /// Reads debugger commands from `input` and writes what they show to `output`, instead of
+    /// the terminal.
+    pub fn set_debugger_io(&mut self, input: impl BufRead + 'static, output: impl Write + 'static) {
+        let debugger = self.debugger.get_or_insert_with(|| Debugger::new(false));
+        debugger.input = Box::new(input);
+        debugger.output = Box::new(output);
+    }
+
+    /// Called before each instruction while debugging, pausing if it starts a line to stop at.
+    pub(crate) fn debug_instruction(&mut self) {
+        let mut debugger = match self.debugger.take() {
+            Some(debugger) => debugger,
+            None => return,
+        };
+
+        let offset = *self.frame().ip();
+        let chunk = self.current_chunk();
+        let line = chunk.line_at(offset);
+        if debugger.lines.entered_line(self.frames.len(), line) {
+            let file = chunk.file();
+            let stop = debugger.stepping
+                || file.is_some_and(|file| {
+                    debugger.has_breakpoint(self.sources.get(file).name(), line)
+                });
+            if stop {
+                self.pause(&mut debugger, offset, line);
+            }
+        }
+
+        self.debugger = Some(debugger);
+    }
+
+    fn pause(&mut self, debugger: &mut Debugger, offset: usize, line: usize) {
+        let file = self.current_chunk().file();
+        let location = match file {
+            Some(file) => self.sources.excerpt(file, line),
+            None => format!(" --> line {}\n", line),
+        };
+        let _ = write!(debugger.output, "{}", location);
+
+        loop {
+            let command = match debugger.read_command() {
+                Some(command) => command,
+                None => {
+                    // Nobody is left to type commands, so let the script finish.
+                    debugger.stepping = false;
+                    debugger.breakpoints.clear();
+                    return;
+                }
+            };
+
+            let mut words = command.split_whitespace();
+            match (words.next(), words.next()) {
+                (Some("step" | "s"), _) => {
+                    debugger.stepping = true;
+                    return;
+                }
+                (Some("continue" | "c"), _) => {
+                    debugger.stepping = false;
+                    return;
+                }
+                (Some("break" | "b"), Some(line)) => match (file, line.parse()) {
+                    (Some(file), Ok(line)) => {
+                        let name = self.sources.get(file).name().to_string();
+                        debugger.breakpoints.insert((name, line));
+                    }
+                    _ => {
+                        let _ = writeln!(debugger.output, "Can't stop at line {}", line);
+                    }
+                },
+                (Some("stack"), _) => {
+                    let start = *self.frame().stack_start();
+                    for (i, value) in self.stack[start..].iter().enumerate() {
+                        let _ = writeln!(debugger.output, "[{}] {}", i, value);
+                    }
+                }
+                (Some("backtrace" | "bt"), _) => {
+                    for (i, frame) in self.frames.iter().rev().enumerate() {
+                        let chunk = frame.closure().function.chunk();
+                        // The instruction pointer of a caller is just past its call.
+                        let ip = if i == 0 { *frame.ip() } else { frame.ip() - 1 };
+                        let line = chunk.line_at(ip);
+                        let _ = writeln!(
+                            debugger.output,
+                            "{} line {}",
+                            *frame.closure().function,
+                            line
+                        );
+                    }
+                }
+                (Some("locals" | "l"), _) => {
+                    let start = *self.frame().stack_start();
+                    for local in self.current_chunk().locals_at(offset) {
+                        // Locals the compiler made up for itself are named `$...`.
+                        if local.name.is_empty() || local.name.starts_with('$') {
+                            continue;
+                        }
+                        if let Some(value) = self.stack.get(start + local.slot) {
+                            let _ = writeln!(debugger.output, "{} = {}", local.name, value);
+                        }
+                    }
+                }
+                (Some("instr" | "i"), _) => {
+                    let instruction = self.current_chunk().instruction(offset);
+                    let _ = write!(debugger.output, "{}", instruction);
+                }
+                (Some("help" | "h"), _) => {
+                    let _ = writeln!(debugger.output, "{}", HELP);
+                }
+                (None, _) => {}
+                (Some(command), _) => {
+                    let _ = writeln!(debugger.output, "Unknown command {}, try help", command);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::value::Value;
+    use crate::vm::VmOptions;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[derive(Clone, Default)]
+    struct Shared(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn debug(input: &str, setup: impl FnOnce(&mut VM)) -> (VM, String) {
+        let source = "def add(a, b)\n    var sum = a + b\n    return sum\nend\n\nvar x = add(1, 2)\nprint x\n";
+        let output = Shared::default();
+
+        let mut vm = VM::with_options(VmOptions {
+            quiet: true,
+            ..VmOptions::default()
+        });
+        vm.set_debugger_io(io::Cursor::new(input.to_string()), output.clone());
+        setup(&mut vm);
+        vm.interpret(source);
+
+        let shown = String::from_utf8(output.0.borrow().clone()).unwrap();
+        (vm, shown)
+    }
+
+    #[test]
+    fn breakpoints_pause_to_show_locals_and_calls() {
+        let (vm, shown) = debug("locals\nbt\ninstr\nc\n", |vm| {
+            vm.set_breakpoint("<script>", 3)
+        });
+
+        assert!(shown.starts_with(" --> <script>:3\n  |\n3 |     return sum\n(debug) "));
+        assert!(shown.contains("add = <fn add/2>\na = 1\nb = 2\nsum = 3\n"));
+        assert!(shown.contains("<fn add/2> line 3\n<script> line 6\n"));
+        assert!(shown.contains("GET_LOCAL"));
+        assert_eq!(vm.global("x"), Some(&Value::Number(3.0)));
+    }
+
+    #[test]
+    fn stepping_pauses_at_every_line() {
+        let (_, shown) = debug("s\ns\ns\ns\n", |vm| vm.set_breakpoint("<script>", 6));
+
+        let lines: Vec<&str> = shown
+            .split(" --> <script>:")
+            .skip(1)
+            .map(|pause| pause.lines().next().unwrap())
+            .collect();
+        assert_eq!(lines, ["6", "2", "3", "7"]);
+    }
+}
@@ -1,19 +1,71 @@
 use std::fmt;
 
+/// A limit from `VmOptions` on how much a script can do.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Budget {
+    Instructions(u64),
+    HeapBytes(usize),
+}
+
 #[derive(Debug)]
 pub enum RuntimeError {
     ArgumentTypes,
+    /// A unary operator applied to a value it doesn't work on.
+    OperandType(&'static str, String),
+    /// A binary operator applied to values it doesn't work on.
+    OperandTypes(&'static str, String, String),
+    /// A binary operator used on an instance whose class doesn't define a method for it.
+    UnsupportedOperator(String, &'static str),
     StackEmpty,
     BadStackIndex(usize, usize),
     UndefinedGlobal(String),
-    UndefinedProperty(String),
+    /// A property name and the type of the value it was looked up on.
+    UndefinedProperty(String, String),
     ReturnFromTopLevel,
+    WrongArity(u8, u8),
+    /// `main` has more parameters than the one it can be passed the arguments in.
+    MainArity(u8),
+    AssertionFailed(String),
+    NoProperties(String),
+    NotCallable(String),
+    UnknownOpcode(u8),
+    ZeroRangeStep,
+    IndexOutOfBounds(f64, usize),
+    UnhashableKey(String),
+    CannotUnpack(usize, String),
+    MissingKey(String),
+    NotIterable(String),
+    IteratorExhausted,
+    /// A value converted to a Rust type wasn't of the Green type that converts to it.
+    WrongType(&'static str, String),
+    TooManyFrames(usize),
+    StackFull(usize),
+    BudgetExceeded(Budget),
+    Interrupted,
+    /// Returned by a hook to stop the script, e.g. when it ran out of time.
+    Stopped(String),
+    Output(std::io::Error),
 }
 
 impl fmt::Display for RuntimeError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
             Self::ArgumentTypes => write!(f, "Incompatible types for operation"),
+            Self::OperandType(op, type_name) => {
+                write!(
+                    f,
+                    "Operand of `{}` must be a number, not {}.",
+                    op, type_name
+                )
+            }
+            Self::OperandTypes(op, left, right) => write!(
+                f,
+                "Operands of `{}` must be numbers, not {} and {}.",
+                op, left, right
+            ),
+            Self::UnsupportedOperator(class, op) => {
+                write!(f, "Instance of {} does not support `{}`.", class, op)
+            }
             Self::StackEmpty => write!(f, "Tried to pop value from empty stack"),
             Self::BadStackIndex(wanted, len) => write!(
                 f,
@@ -23,15 +75,61 @@ impl fmt::Display for RuntimeError {
             Self::UndefinedGlobal(name) => {
                 write!(f, "Tried to access undefined variable `{}`", name)
             }
-            Self::UndefinedProperty(name) => write!(
+            Self::UndefinedProperty(name, type_name) => write!(
                 f,
-                "Tried to access undefined property `{}` on instance",
-                name
+                "Tried to access undefined property `{}` on {}",
+                name, type_name
             ),
-            Self::ReturnFromTopLevel => write!(
+            Self::ReturnFromTopLevel => write!(f, "Cannot return from top-level.",),
+            Self::WrongArity(expected, got) => {
+                write!(f, "Expected {} arguments but got {}.", expected, got)
+            }
+            Self::MainArity(arity) => write!(
                 f,
-                "Cannot return from top-level.",
+                "`main` can have one parameter, for the arguments, but has {}.",
+                arity
             ),
+            Self::AssertionFailed(message) => write!(f, "Assertion failed {}", message),
+            Self::NoProperties(type_name) => {
+                write!(f, "Only instances have properties, not {}.", type_name)
+            }
+            Self::NotCallable(type_name) => {
+                write!(f, "Can only call functions and classes, not {}.", type_name)
+            }
+            Self::UnknownOpcode(byte) => write!(f, "Unknown opcode {:#04x}.", byte),
+            Self::ZeroRangeStep => write!(f, "Range step cannot be zero."),
+            Self::IndexOutOfBounds(index, len) => {
+                write!(f, "Index {} is out of bounds for length {}.", index, len)
+            }
+            Self::UnhashableKey(type_name) => {
+                write!(f, "Can't use a value of type {} as a map key.", type_name)
+            }
+            Self::CannotUnpack(count, found) => {
+                write!(f, "Can't unpack {} into {} variables.", found, count)
+            }
+            Self::MissingKey(key) => write!(f, "Map has no key `{}` to unpack.", key),
+            Self::NotIterable(type_name) => {
+                write!(f, "Can't loop over a value of type {}.", type_name)
+            }
+            Self::IteratorExhausted => write!(f, "Iterator has no more values."),
+            Self::WrongType(expected, found) => {
+                write!(f, "Expected a value of type {}, not {}.", expected, found)
+            }
+            Self::TooManyFrames(max) => {
+                write!(f, "Stack overflow: more than {} nested calls.", max)
+            }
+            Self::StackFull(max) => {
+                write!(f, "Stack overflow: more than {} values on the stack.", max)
+            }
+            Self::BudgetExceeded(Budget::Instructions(max)) => {
+                write!(f, "Budget exceeded: ran more than {} instructions.", max)
+            }
+            Self::BudgetExceeded(Budget::HeapBytes(max)) => {
+                write!(f, "Budget exceeded: allocated more than {} bytes.", max)
+            }
+            Self::Interrupted => write!(f, "Interrupted."),
+            Self::Stopped(reason) => write!(f, "Stopped: {}", reason),
+            Self::Output(err) => write!(f, "Could not write output: {}", err),
         }
     }
-}
\ No newline at end of file
+}
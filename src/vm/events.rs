@@ -0,0 +1,135 @@
+use crate::compiler::value::Value;
+use crate::vm::vm::RunResult;
+use crate::vm::VM;
+use std::collections::HashMap;
+
+/// The functions subscribed to each event with `on`, in the order they subscribed.
+#[derive(Default)]
+pub(crate) struct Events {
+    handlers: HashMap<String, Vec<(u64, Value)>>,
+    next_id: u64,
+}
+
+impl Events {
+    fn subscribe(&mut self, event: &str, handler: Value) -> u64 {
+        self.next_id += 1;
+        self.handlers
+            .entry(event.to_string())
+            .or_default()
+            .push((self.next_id, handler));
+        self.next_id
+    }
+
+    fn unsubscribe(&mut self, id: u64) -> bool {
+        for handlers in self.handlers.values_mut() {
+            if let Some(index) = handlers
+                .iter()
+                .position(|(handler_id, _)| *handler_id == id)
+            {
+                handlers.remove(index);
+                return true;
+            }
+        }
+        false
+    }
+
+    fn subscribers(&self, event: &str) -> Vec<Value> {
+        self.handlers
+            .get(event)
+            .map(|handlers| handlers.iter().map(|(_, handler)| *handler).collect())
+            .unwrap_or_default()
+    }
+
+    /// The subscribed functions, which the garbage collector must keep alive.
+    pub(crate) fn callbacks(&self) -> impl Iterator<Item = &Value> {
+        self.handlers.values().flatten().map(|(_, handler)| handler)
+    }
+}
+
+impl VM {
+    /// Calls `handler` with the arguments of every `emit` of `event` from now on, whether the
+    /// host or a script emits it. Returns an id to unsubscribe with.
+    pub fn on(&mut self, event: &str, handler: Value) -> u64 {
+        self.events.subscribe(event, handler)
+    }
+
+    /// Stops a handler from being called, returning whether it was still subscribed.
+    pub fn off(&mut self, id: u64) -> bool {
+        self.events.unsubscribe(id)
+    }
+
+    /// Calls the handlers of `event` with `args`, in the order they subscribed, and returns how
+    /// many there were. If a handler fails, the ones after it aren't called and the VM is left
+    /// as it was before the emit, ready to run more code.
+    pub fn emit(&mut self, event: &str, args: Vec<Value>) -> RunResult<usize> {
+        let (depth, height) = (self.frames.len(), self.stack.len());
+        if self.natives_running == 0 {
+            self.error_location = None;
+        }
+        let result = self.emit_event(event, args);
+        if result.is_err() {
+            self.unwind(depth, height);
+        }
+        result
+    }
+
+    /// Like `emit`, for natives, whose errors fail the script instead.
+    pub(crate) fn emit_event(&mut self, event: &str, args: Vec<Value>) -> RunResult<usize> {
+        // Handlers subscribed while the event is handled wait for the next one.
+        let handlers = self.events.subscribers(event);
+
+        // The arguments stay on the stack meanwhile, so the garbage collector sees them.
+        let height = self.stack.len();
+        self.stack.extend(args.iter().cloned());
+        for &handler in &handlers {
+            self.call_and_run(handler, args.clone())?;
+        }
+        self.stack.truncate(height);
+        Ok(handlers.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::compiler::value::Value;
+    use crate::vm::VM;
+
+    #[test]
+    fn events_reach_handlers_from_the_host_and_scripts() {
+        let input = r#"
+        var saved = 0
+        var last = "none"
+        def on_save(name, size)
+            saved = saved + size
+            last = name
+        end
+        def broken()
+        end
+
+        var id = events.on("save", on_save)
+        var called = events.emit("save", ["a.txt", 2])
+        var unheard = events.emit("load", [])
+        "#;
+
+        let mut vm = VM::new();
+        vm.interpret(input);
+        assert_eq!(vm.globals["called"], Value::Number(1.0));
+        assert_eq!(vm.globals["unheard"], Value::Number(0.0));
+
+        let args = vec![Value::string("b.txt".to_string()), Value::Number(3.0)];
+        assert_eq!(vm.emit("save", args.clone()).unwrap(), 1);
+        assert_eq!(vm.globals["saved"], Value::Number(5.0));
+        assert_eq!(vm.globals["last"].as_string(), "b.txt");
+
+        // A failing handler leaves the VM usable.
+        let broken = vm.globals["broken"];
+        let broken_id = vm.on("save", broken);
+        assert!(vm.emit("save", args.clone()).is_err());
+        assert!(vm.stack.is_empty());
+        assert_eq!(vm.frame_depth(), 0);
+
+        vm.interpret("events.off(id)\n");
+        assert!(vm.off(broken_id));
+        assert_eq!(vm.emit("save", args).unwrap(), 0);
+    }
+}
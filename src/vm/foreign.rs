@@ -0,0 +1,125 @@
+use crate::compiler::object::{Foreign, ForeignType, NativeFn, NativeFunction};
+use crate::compiler::value::Value;
+use crate::vm::vm::RunResult;
+use crate::vm::VM;
+use std::any::{Any, TypeId};
+use std::marker::PhantomData;
+use std::rc::Rc;
+
+impl VM {
+    /// Lets values of the Rust type `T` be handed to scripts, where they are called `name` and
+    /// have `methods`. A method gets the value it was called on as its first argument, before
+    /// the `arity` arguments the script passes, and can reach the data with `as_foreign`.
+    pub fn register_foreign<T: Any>(&mut self, name: &str, methods: &[(&str, u8, NativeFn)]) {
+        let mut builder = self.build_foreign::<T>(name);
+        for &(method, arity, fun) in methods {
+            builder = builder.native(method, arity, fun);
+        }
+        builder.register();
+    }
+
+    /// Starts describing how scripts see the Rust type `T`: which of its fields they can read
+    /// and write and which methods they can call. Nothing changes until `register` is called.
+    pub fn build_foreign<T: Any>(&mut self, name: &str) -> ForeignBuilder<'_, T> {
+        ForeignBuilder {
+            vm: self,
+            kind: ForeignType::new(name.to_string()),
+            marker: PhantomData,
+        }
+    }
+
+    /// Wraps `data` in a value scripts can hold and call its type's methods on. The data is
+    /// dropped once no script refers to it any more.
+    ///
+    /// Panics if `T` wasn't registered with `register_foreign`.
+    pub fn foreign<T: Any>(&mut self, data: T) -> Value {
+        let kind = match self.foreign_types.get(&TypeId::of::<T>()) {
+            Some(kind) => kind.clone(),
+            None => panic!(
+                "{} wasn't registered as a foreign type.",
+                std::any::type_name::<T>()
+            ),
+        };
+        Value::Foreign(self.alloc(Foreign::new(kind, Box::new(data))))
+    }
+}
+
+/// Generates the getters, setters and method bindings of a foreign type from closures over the
+/// Rust struct, so they don't each have to unwrap the value by hand. Made by
+/// `VM::build_foreign`.
+pub struct ForeignBuilder<'vm, T> {
+    vm: &'vm mut VM,
+    kind: ForeignType,
+    marker: PhantomData<T>,
+}
+
+impl<T: Any> ForeignBuilder<'_, T> {
+    /// Lets scripts read `value.name`.
+    pub fn getter(mut self, name: &str, get: impl Fn(&T) -> Value + 'static) -> Self {
+        let getter = move |data: &dyn Any| Ok(get(downcast(data)));
+        self.kind.add_getter(name, Box::new(getter));
+        self
+    }
+
+    /// Lets scripts assign `value.name = x`. The setter can refuse a value by returning an
+    /// error, like `RuntimeError::ArgumentTypes` for a value of the wrong type.
+    pub fn setter(
+        mut self,
+        name: &str,
+        set: impl Fn(&mut T, Value) -> RunResult<()> + 'static,
+    ) -> Self {
+        let setter = move |data: &mut dyn Any, value| set(downcast_mut(data), value);
+        self.kind.add_setter(name, Box::new(setter));
+        self
+    }
+
+    /// A field scripts can both read and write.
+    pub fn field(
+        self,
+        name: &str,
+        get: impl Fn(&T) -> Value + 'static,
+        set: impl Fn(&mut T, Value) -> RunResult<()> + 'static,
+    ) -> Self {
+        self.getter(name, get).setter(name, set)
+    }
+
+    /// A method taking `arity` arguments, called with the data of the value it was looked up on.
+    pub fn method(
+        mut self,
+        name: &str,
+        arity: u8,
+        fun: impl Fn(&mut VM, &mut T, &[Value]) -> RunResult<Value> + 'static,
+    ) -> Self {
+        let method = NativeFunction::closure(name.to_string(), arity, move |vm, args| {
+            let mut receiver = args[0].as_foreign()?;
+            let data = receiver.get_mut::<T>().expect(WRONG_TYPE);
+            fun(vm, data, &args[1..])
+        });
+        self.kind.add_method(method);
+        self
+    }
+
+    /// A method written as a plain native, which gets the value it was called on as its first
+    /// argument.
+    pub fn native(mut self, name: &str, arity: u8, fun: NativeFn) -> Self {
+        let method = NativeFunction::new(name.to_string(), arity, fun);
+        self.kind.add_method(method);
+        self
+    }
+
+    /// Registers the type, replacing any earlier registration of `T`.
+    pub fn register(self) {
+        let kind = Rc::new(self.kind);
+        self.vm.foreign_types.insert(TypeId::of::<T>(), kind);
+    }
+}
+
+const WRONG_TYPE: &str = "A foreign value always holds the data of the type it was made with.";
+
+fn downcast<T: Any>(data: &dyn Any) -> &T {
+    data.downcast_ref().expect(WRONG_TYPE)
+}
+
+fn downcast_mut<T: Any>(data: &mut dyn Any) -> &mut T {
+    data.downcast_mut().expect(WRONG_TYPE)
+}
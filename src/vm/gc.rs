@@ -1,35 +1,231 @@
+use crate::compiler::object::Map;
+use crate::compiler::value::Value;
+use crate::vm::obj::Gc;
 use std::any::Any;
+use std::collections::HashSet;
 use std::mem;
-use crate::vm::obj::Gc;
 
 impl super::VM {
     /// Allocate a garbage-collected value on the heap.
     ///
     /// This method is how to obtain a `Gc` pointer (not exported from this crate and has no public
-    /// constructor). Values allocated with this method will be owned (and eventually freed) by the
-    /// VM. If the value lives until the VM goes out of scope, it will be freed in the VM's `Drop`
-    /// implementation.
+    /// constructor). Values allocated with this method are owned by the VM, and freed by
+    /// `collect_garbage` once nothing refers to them, or when the VM is dropped.
     ///
     /// For a usage example, see [`NativeFun`](./type.NativeFun.html).
     pub fn alloc<T: Any>(&mut self, obj: T) -> Gc<T> {
-        // if self.should_collect() {
-        //     self.collect_garbage();
-        // }
-        //
-        // let size = mem::size_of::<T>();
-        // self.total_allocations += size;
-        //
+        self.alloc_buffered(obj, 0)
+    }
+
+    /// Allocates an object that owns `buffer_bytes` more on the heap, like the characters of a
+    /// string, counting those toward `heap_bytes` too.
+    fn alloc_buffered<T: Any>(&mut self, obj: T, buffer_bytes: usize) -> Gc<T> {
+        let size = mem::size_of::<T>() + buffer_bytes;
         let ptr = Gc::new(obj);
-        // self.objects.push(ptr.as_any());
-        //
-        // #[cfg(feature = "trace-gc")]
-        // log::debug!(
-        //     "{:p} allocate {} bytes for {}",
-        //     ptr,
-        //     size,
-        //     std::any::type_name::<T>()
-        // ); FIXME TODO
+        ptr.set_size(size);
+        self.heap.push(ptr.as_any());
+        self.heap_bytes += size;
 
         ptr
     }
-}
\ No newline at end of file
+
+    /// A string owned by the VM, freed by `collect_garbage` once nothing refers to it.
+    pub fn string(&mut self, s: String) -> Value {
+        let bytes = s.capacity();
+        Value::String(self.alloc_buffered(s, bytes))
+    }
+
+    /// An array owned by the VM, like `string`.
+    pub fn array(&mut self, items: Vec<Value>) -> Value {
+        let bytes = vec_bytes(&items);
+        Value::Array(self.alloc_buffered(items, bytes))
+    }
+
+    /// A tuple owned by the VM, like `string`.
+    pub fn tuple(&mut self, items: Vec<Value>) -> Value {
+        let bytes = vec_bytes(&items);
+        Value::Tuple(self.alloc_buffered(items, bytes))
+    }
+
+    /// A map owned by the VM, like `string`.
+    pub fn map(&mut self, map: Map) -> Value {
+        let bytes = map.buffer_bytes();
+        Value::Map(self.alloc_buffered(map, bytes))
+    }
+
+    /// Counts an array again after it grew. Arrays the VM doesn't own are left alone.
+    pub(crate) fn resized_array(&mut self, array: Gc<Vec<Value>>) {
+        self.resized(array, vec_bytes(&array));
+    }
+
+    /// Counts a map again after it grew, like `resized_array`.
+    pub(crate) fn resized_map(&mut self, map: Gc<Map>) {
+        self.resized(map, map.buffer_bytes());
+    }
+
+    fn resized<T>(&mut self, ptr: Gc<T>, buffer_bytes: usize) {
+        let old = ptr.size();
+        if old == 0 {
+            return;
+        }
+        let new = mem::size_of::<T>() + buffer_bytes;
+        ptr.set_size(new);
+        self.heap_bytes = self.heap_bytes - old + new;
+    }
+
+    /// Bytes taken by the objects allocated with `alloc` that haven't been freed, counting the
+    /// buffers of the strings, arrays, tuples and maps made with `string`, `array`, `tuple` and
+    /// `map` too.
+    pub fn heap_bytes(&self) -> usize {
+        self.heap_bytes
+    }
+
+    /// Frees the objects allocated with `alloc` that can't be reached from the stack, the
+    /// globals, the calls in progress, the pending timers or the event handlers any more, and
+    /// returns the number of bytes freed.
+    ///
+    /// Values natives hold outside the stack aren't seen, so this must only run when no native
+    /// is in the middle of a call other than the one asking for it.
+    pub fn collect_garbage(&mut self) -> usize {
+        let mut reached = HashSet::new();
+        let mut pending: Vec<Value> = self.stack.clone();
+        pending.extend(self.globals.values().cloned());
+        pending.extend(
+            self.frames
+                .iter()
+                .map(|frame| Value::Closure(*frame.closure())),
+        );
+        pending.push(Value::Struct(self.error));
+        pending.extend(self.timers.callbacks().cloned());
+        pending.extend(self.events.callbacks().cloned());
+
+        while let Some(value) = pending.pop() {
+            trace(value, &mut reached, &mut pending);
+        }
+
+        let mut freed = 0;
+        for object in mem::take(&mut self.heap) {
+            if reached.contains(&object.addr()) {
+                self.heap.push(object);
+            } else {
+                freed += object.size();
+                object.free();
+            }
+        }
+        self.heap_bytes -= freed;
+        freed
+    }
+}
+
+/// Frees every object the VM allocated, reachable or not. Values taken out of the VM can't be
+/// used once it's dropped.
+impl Drop for super::VM {
+    fn drop(&mut self) {
+        for object in mem::take(&mut self.heap) {
+            object.free();
+        }
+    }
+}
+
+/// Marks the object a value points to as reached and queues the values it refers to, unless it
+/// was reached before.
+fn trace(value: Value, reached: &mut HashSet<usize>, pending: &mut Vec<Value>) {
+    let addr = match &value {
+        Value::Number(_) | Value::True | Value::False | Value::Nil => return,
+        Value::String(s) => s.addr(),
+        Value::Array(a) | Value::Tuple(a) => a.addr(),
+        Value::Range(r) => r.addr(),
+        Value::Iterator(i) => i.addr(),
+        Value::Map(m) => m.addr(),
+        Value::Closure(c) => c.addr(),
+        Value::Function(f) => f.addr(),
+        Value::Native(n) => n.addr(),
+        Value::Class(c) => c.addr(),
+        Value::Instance(i) => i.addr(),
+        Value::Struct(s) => s.addr(),
+        Value::Protocol(p) => p.addr(),
+        Value::StructInstance(s) => s.addr(),
+        Value::Module(m) => m.addr(),
+        Value::BoundMethod(b) => b.addr(),
+        Value::Foreign(f) => f.addr(),
+    };
+    if !reached.insert(addr) {
+        return;
+    }
+
+    match value {
+        Value::Array(array) | Value::Tuple(array) => pending.extend(array.iter().cloned()),
+        Value::Map(map) => {
+            for (key, value) in map.entries() {
+                pending.push(*key);
+                pending.push(*value);
+            }
+        }
+        Value::Closure(closure) => pending.push(Value::Function(closure.function)),
+        Value::Function(function) => pending.extend(function.chunk().constants().iter().cloned()),
+        Value::Class(class) => {
+            pending.extend(class.methods().map(Value::Closure));
+            pending.extend(class.statics());
+        }
+        Value::Instance(instance) => {
+            pending.push(Value::Class(instance.class));
+            pending.extend(instance.values());
+        }
+        Value::StructInstance(instance) => {
+            pending.push(Value::Struct(instance.def));
+            pending.extend(instance.fields.iter().cloned());
+        }
+        Value::Module(module) => pending.extend(module.members().cloned()),
+        Value::Iterator(iter) => pending.extend(iter.pending().iter().cloned()),
+        Value::BoundMethod(bound) => {
+            pending.push(bound.receiver);
+            pending.push(Value::Closure(bound.method));
+        }
+        Value::Native(native) => pending.extend(native.receiver()),
+        _ => {}
+    }
+}
+
+fn vec_bytes(items: &Vec<Value>) -> usize {
+    items.capacity() * mem::size_of::<Value>()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::compiler::value::Value;
+    use crate::vm::VM;
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    /// Counts how many of its values are alive.
+    struct Tracked(Rc<Cell<usize>>);
+
+    impl Tracked {
+        fn new(live: &Rc<Cell<usize>>) -> Self {
+            live.set(live.get() + 1);
+            Tracked(live.clone())
+        }
+    }
+
+    impl Drop for Tracked {
+        fn drop(&mut self) {
+            self.0.set(self.0.get() - 1);
+        }
+    }
+
+    #[test]
+    fn dropping_the_vm_frees_everything_it_allocated() {
+        let live = Rc::new(Cell::new(0));
+        let mut vm = VM::new();
+        vm.interpret("var names = [\"a\", \"b\"]\nvar point = {\"x\": 1}\n");
+
+        for _ in 0..3 {
+            vm.alloc(Tracked::new(&live));
+        }
+        vm.alloc(vec![Value::Number(1.0)]);
+        assert_eq!(live.get(), 3);
+
+        drop(vm);
+        assert_eq!(live.get(), 0);
+    }
+}
@@ -0,0 +1,211 @@
+use crate::vm::vm::RunResult;
+use crate::vm::VM;
+
+/// Where a script is when a hook runs.
+pub struct HookEvent<'a> {
+    /// The function being called, returned from or run, `<script>` for the top level.
+    pub function: &'a str,
+    /// The file the function was written in, if it was compiled from one.
+    pub file: Option<&'a str>,
+    /// The line reached, or 0 when unknown.
+    pub line: usize,
+    /// How many calls are in progress, the script itself included.
+    pub depth: usize,
+}
+
+/// A callback set with `VM::on_call`, `on_return` or `on_line`. An error it returns stops the
+/// script with that error, like a runtime error in the script itself.
+pub type Hook = Box<dyn FnMut(&HookEvent) -> RunResult<()>>;
+
+#[derive(Clone, Copy)]
+enum HookKind {
+    Call,
+    Return,
+    Line,
+}
+
+/// The hooks an embedder has set.
+#[derive(Default)]
+pub(crate) struct Hooks {
+    call: Option<Hook>,
+    ret: Option<Hook>,
+    line: Option<Hook>,
+    lines: LineTracker,
+}
+
+impl Hooks {
+    fn slot(&mut self, kind: HookKind) -> &mut Option<Hook> {
+        match kind {
+            HookKind::Call => &mut self.call,
+            HookKind::Return => &mut self.ret,
+            HookKind::Line => &mut self.line,
+        }
+    }
+}
+
+/// The line each call in progress was last seen on, so something happens when a line is
+/// reached rather than at each of its instructions, or again once a call it makes returns.
+#[derive(Default)]
+pub(crate) struct LineTracker(Vec<usize>);
+
+impl LineTracker {
+    /// Whether running the current frame, `depth` deep, has reached a line it wasn't on.
+    pub(crate) fn entered_line(&mut self, depth: usize, line: usize) -> bool {
+        self.0.resize(depth, 0);
+        let entered = line != 0 && self.0[depth - 1] != line;
+        self.0[depth - 1] = line;
+        entered
+    }
+}
+
+impl VM {
+    /// Calls `hook` whenever a script function is called, before any of it runs. The script
+    /// itself counts as a call too.
+    pub fn on_call(&mut self, hook: impl FnMut(&HookEvent) -> RunResult<()> + 'static) {
+        self.hooks.call = Some(Box::new(hook));
+    }
+
+    /// Calls `hook` whenever a script function returns, with the line it returned from.
+    pub fn on_return(&mut self, hook: impl FnMut(&HookEvent) -> RunResult<()> + 'static) {
+        self.hooks.ret = Some(Box::new(hook));
+    }
+
+    /// Calls `hook` whenever the script reaches a line, as the debugger steps through them.
+    pub fn on_line(&mut self, hook: impl FnMut(&HookEvent) -> RunResult<()> + 'static) {
+        self.hooks.line = Some(Box::new(hook));
+    }
+
+    /// Removes the hooks set with `on_call`, `on_return` and `on_line`.
+    pub fn clear_hooks(&mut self) {
+        self.hooks = Hooks::default();
+    }
+
+    pub(crate) fn call_hook(&mut self) -> RunResult<()> {
+        self.run_hook(HookKind::Call, 0)
+    }
+
+    /// Runs the return hook, before the returning call's frame is dropped.
+    pub(crate) fn return_hook(&mut self) -> RunResult<()> {
+        let offset = self.frame().ip().saturating_sub(1);
+        self.run_hook(HookKind::Return, offset)
+    }
+
+    /// Called before each instruction while there's a line hook, running it if the instruction
+    /// starts a line.
+    pub(crate) fn line_hook(&mut self) -> RunResult<()> {
+        let offset = *self.frame().ip();
+        let line = self.current_chunk().line_at(offset);
+        if self.hooks.lines.entered_line(self.frames.len(), line) {
+            self.run_hook(HookKind::Line, offset)?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn has_line_hook(&self) -> bool {
+        self.hooks.line.is_some()
+    }
+
+    /// Runs a hook, if it's set, about the innermost call at the instruction at `offset`.
+    fn run_hook(&mut self, kind: HookKind, offset: usize) -> RunResult<()> {
+        let mut hook = match self.hooks.slot(kind).take() {
+            Some(hook) => hook,
+            None => return Ok(()),
+        };
+
+        let function = &self.frame().closure().function;
+        let chunk = function.chunk();
+        let event = HookEvent {
+            function: if function.name().is_empty() {
+                "<script>"
+            } else {
+                function.name()
+            },
+            file: chunk.file().map(|file| self.sources.get(file).name()),
+            line: chunk.line_at(offset),
+            depth: self.frames.len(),
+        };
+        let result = hook(&event);
+        *self.hooks.slot(kind) = Some(hook);
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::value::Value;
+    use crate::vm::errors::RuntimeError;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    const SOURCE: &str = r#"
+def square(n)
+    return n * n
+end
+
+def spin(n)
+    var total = 0
+    for i in 0 to n do
+        total = total + square(i)
+    end
+    return total
+end
+"#;
+
+    #[test]
+    fn hooks_see_calls_returns_and_lines() {
+        let mut vm = VM::new();
+        vm.interpret(SOURCE);
+
+        let events = Rc::new(RefCell::new(vec![]));
+        let (calls, returns) = (events.clone(), events.clone());
+        vm.on_call(move |event| {
+            calls
+                .borrow_mut()
+                .push(format!("call {} {}", event.function, event.depth));
+            Ok(())
+        });
+        vm.on_return(move |event| {
+            returns
+                .borrow_mut()
+                .push(format!("return {} {}", event.function, event.line));
+            Ok(())
+        });
+        let spin = *vm.global("spin").unwrap();
+        let total = vm.call_function(spin, &[Value::Number(2.0)]).unwrap();
+        assert_eq!(total, Value::Number(1.0));
+        assert_eq!(
+            *events.borrow(),
+            [
+                "call spin 1",
+                "call square 2",
+                "return square 3",
+                "call square 2",
+                "return square 3",
+                "return spin 11",
+            ]
+        );
+
+        // A line hook can hold a script to a budget.
+        vm.clear_hooks();
+        let lines = Rc::new(RefCell::new(0));
+        let counted = lines.clone();
+        vm.on_line(move |_| {
+            *counted.borrow_mut() += 1;
+            if *counted.borrow() > 50 {
+                return Err(RuntimeError::Stopped("out of time".to_string()));
+            }
+            Ok(())
+        });
+        let err = vm
+            .call_function(spin, &[Value::Number(1000.0)])
+            .unwrap_err();
+        assert_eq!(err.to_string(), "Stopped: out of time");
+        assert_eq!(*lines.borrow(), 51);
+        assert!(vm.stack().is_empty());
+
+        vm.clear_hooks();
+        let total = vm.call_function(spin, &[Value::Number(3.0)]).unwrap();
+        assert_eq!(total, Value::Number(5.0));
+    }
+}
@@ -0,0 +1,71 @@
+use crate::compiler::object::{BoundMethod, Iter, NativeFunction};
+use crate::compiler::value::Value;
+use crate::vm::errors::RuntimeError;
+use crate::vm::vm::RunResult;
+use crate::vm::VM;
+
+impl VM {
+    /// The iterator a loop over `value` steps through with `has_next()` and `next()`. Arrays,
+    /// tuples, maps, strings and ranges get a builtin iterator. A class instance, or a foreign
+    /// value, is asked for one by calling its `iter()` method, and an iterator is its own.
+    pub(crate) fn iterate(&mut self, value: Value) -> RunResult<Value> {
+        let iter = match value {
+            Value::Array(items) | Value::Tuple(items) => Iter::values(items.to_vec()),
+            Value::Map(map) => Iter::values(
+                map.entries()
+                    .iter()
+                    .map(|(key, value)| self.array(vec![*key, *value]))
+                    .collect(),
+            ),
+            Value::String(s) => {
+                Iter::values(s.chars().map(|c| self.string(c.to_string())).collect())
+            }
+            Value::Range(range) => Iter::range(*range),
+            Value::Iterator(_) => return Ok(value),
+            Value::Instance(instance) => {
+                let method = instance
+                    .class
+                    .method("iter")
+                    .ok_or_else(|| RuntimeError::NotIterable(value.type_name()))?;
+                let bound = self.alloc(BoundMethod::new(value, method));
+                return self.call_and_run(Value::BoundMethod(bound), vec![]);
+            }
+            Value::Foreign(foreign) => {
+                let method = foreign
+                    .kind()
+                    .method("iter")
+                    .ok_or_else(|| RuntimeError::NotIterable(value.type_name()))?;
+                let bound = self.alloc(method.bind(value));
+                return self.call_and_run(Value::Native(bound), vec![]);
+            }
+            _ => return Err(RuntimeError::NotIterable(value.type_name())),
+        };
+        Ok(Value::Iterator(self.alloc(iter)))
+    }
+}
+
+/// The methods of builtin iterators, `has_next()` and `next()`.
+pub(crate) fn iterator_method(name: &str) -> Option<NativeFunction> {
+    let fun = match name {
+        "has_next" => has_next,
+        "next" => next,
+        _ => return None,
+    };
+    Some(NativeFunction::new(name.to_string(), 0, fun))
+}
+
+/// has_next(): whether `next()` has a value left to give.
+fn has_next(_vm: &mut VM, args: &[Value]) -> RunResult<Value> {
+    match &args[0] {
+        Value::Iterator(iter) => Ok(iter.has_next().into()),
+        _ => Err(RuntimeError::ArgumentTypes),
+    }
+}
+
+/// next(): the next value, failing once there are none left.
+fn next(_vm: &mut VM, args: &[Value]) -> RunResult<Value> {
+    match args[0] {
+        Value::Iterator(mut iter) => iter.next().ok_or(RuntimeError::IteratorExhausted),
+        _ => Err(RuntimeError::ArgumentTypes),
+    }
+}
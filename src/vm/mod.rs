@@ -1,49 +1,568 @@
-use crate::compiler::compiler::Compiler;
-use crate::compiler::object::GreenClosure;
+use crate::compiler::cache::ModuleCache;
+use crate::compiler::chunk;
+use crate::compiler::compiler::{CompileError, Compiler};
+use crate::compiler::object::{
+    ForeignType, GreenClosure, Module, NativeFn, NativeFunction, Struct, StructInstance,
+};
+use crate::compiler::options::CompileOptions;
 use crate::compiler::value::Value;
-use crate::syntax::parser::GreenParser;
+use crate::source_map::{FileId, SourceMap};
+use crate::stdlib;
+use crate::syntax::parser::{GreenParser, ModuleAst};
+use crate::vm::debugger::Debugger;
+use crate::vm::errors::RuntimeError;
+use crate::vm::events::Events;
 use crate::vm::frame::CallFrame;
+use crate::vm::hooks::Hooks;
+use crate::vm::interrupt::InterruptHandle;
+use crate::vm::obj::Gc;
+use crate::vm::timers::Timers;
+use crate::vm::trace::{PrintTracer, Tracer};
+use crate::vm::vm::RunResult;
+use std::any::{Any, TypeId};
+use std::cell::RefCell;
 use std::collections::HashMap;
+use std::io;
+use std::io::Write;
+use std::mem;
 use std::process::exit;
-use crate::vm::obj::Gc;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
 
+mod debugger;
 pub mod errors;
+mod events;
+mod foreign;
 mod frame;
-mod run;
-pub mod vm;
 pub mod gc;
+pub mod hooks;
+pub mod interrupt;
+mod iter;
 pub mod obj;
+mod run;
+mod timers;
+pub mod trace;
+pub mod vm;
+
+pub use foreign::ForeignBuilder;
+
+/// The output of a VM while `capture_output` runs.
+#[derive(Clone, Default)]
+struct Captured(Rc<RefCell<Vec<u8>>>);
+
+impl Write for Captured {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
 
 pub struct VM {
     stack: Vec<Value>,
     frames: Vec<CallFrame>,
     globals: HashMap<String, Value>,
+    options: VmOptions,
+    /// Where `print` writes to.
+    output: Box<dyn Write>,
+    timings: Timings,
+    /// The `Error(message)` struct natives return failures as, so scripts can check for them.
+    error: Gc<Struct>,
+    /// The command-line arguments after the script path, returned by `os.args()`.
+    args: Vec<String>,
+    /// The text of the scripts run and the modules they imported.
+    sources: SourceMap,
+    /// Set once there's a breakpoint or the script is stepped through.
+    debugger: Option<Debugger>,
+    tracer: Option<Box<dyn Tracer>>,
+    /// The callbacks set with `on_call`, `on_return` and `on_line`.
+    hooks: Hooks,
+    /// Every object allocated with `alloc` and not yet freed. Each knows its own size.
+    heap: Vec<Gc<dyn Any>>,
+    heap_bytes: usize,
+    /// How many natives are in the middle of a call, so garbage is only collected when no
+    /// native holds values the collector can't see.
+    natives_running: usize,
+    /// How many instructions have run, counted against `VmOptions::max_instructions`.
+    instructions: u64,
+    interrupt: InterruptHandle,
+    /// Callbacks scheduled by `after` and `every`.
+    timers: Timers,
+    /// Handlers subscribed to events with `on`.
+    events: Events,
+    /// The file and line the last runtime error happened on.
+    error_location: Option<(Option<FileId>, usize)>,
+    /// The Rust types registered with `register_foreign`.
+    foreign_types: HashMap<TypeId, Rc<ForeignType>>,
+    /// Where compiled scripts are kept between runs, if anywhere.
+    cache: Option<ModuleCache>,
+    /// How many digits `print` and `str` show after the decimal point, or `None` for as many
+    /// as it takes to read the number back exactly.
+    precision: Option<usize>,
+}
+
+/// Limits on the resources a script can use, how it is compiled and how much it says while
+/// running.
+#[derive(Debug, Clone, PartialEq)]
+pub struct VmOptions {
+    /// How many calls can be in progress at once, the script itself included.
+    pub max_frames: usize,
+    /// How many values the stack can hold, checked whenever a function is called.
+    pub stack_size: usize,
+    /// How many instructions the VM may run over its lifetime, for scripts that can't be
+    /// trusted to finish.
+    pub max_instructions: Option<u64>,
+    /// How many bytes of objects the VM may have allocated and not freed at once, counting the
+    /// buffers of its strings, arrays, tuples and maps.
+    pub max_heap_bytes: Option<usize>,
+    /// Discards printed values and skips the compiler's warnings and disassembly, so timing
+    /// a script measures the VM rather than the terminal.
+    pub quiet: bool,
+    /// Steps through the script in the debugger from its first line.
+    pub debug: bool,
+    /// Logs every instruction executed, with the stack, to stderr.
+    pub trace: bool,
+    /// Which programs `os.exec` may run.
+    pub exec: ExecPolicy,
+    pub compile: CompileOptions,
+}
+
+/// Which programs scripts can run with `os.exec`. Embedders running scripts they don't trust
+/// should limit them, as a program can do anything the host process can.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ExecPolicy {
+    Any,
+    /// Only the programs named, matched exactly against the name a script gives.
+    Only(Vec<String>),
+    None,
+}
+
+impl ExecPolicy {
+    pub fn allows(&self, program: &str) -> bool {
+        match self {
+            ExecPolicy::Any => true,
+            ExecPolicy::Only(programs) => programs.iter().any(|allowed| allowed == program),
+            ExecPolicy::None => false,
+        }
+    }
+}
+
+impl Default for VmOptions {
+    fn default() -> Self {
+        VmOptions {
+            max_frames: 1024,
+            stack_size: 1024 * 256,
+            max_instructions: None,
+            max_heap_bytes: None,
+            quiet: false,
+            debug: false,
+            trace: false,
+            exec: ExecPolicy::Any,
+            compile: CompileOptions::default(),
+        }
+    }
+}
+
+/// How long each phase of running a script took.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Timings {
+    pub parse: Duration,
+    pub compile: Duration,
+    /// Running the script, and its `main` function if that was called.
+    pub execute: Duration,
+}
+
+impl Timings {
+    pub fn total(&self) -> Duration {
+        self.parse + self.compile + self.execute
+    }
+}
+
+impl Default for VM {
+    fn default() -> Self {
+        VM::new()
+    }
 }
 
-impl<'source> VM {
+impl VM {
     pub fn new() -> Self {
-        VM {
+        VM::with_options(VmOptions::default())
+    }
+
+    pub fn with_options(options: VmOptions) -> Self {
+        VM::with_output(options, io::stdout())
+    }
+
+    /// Creates a VM whose `print` writes to `output`, or nowhere if the options are quiet.
+    pub fn with_output(options: VmOptions, output: impl Write + 'static) -> Self {
+        let output: Box<dyn Write> = if options.quiet {
+            Box::new(io::sink())
+        } else {
+            Box::new(output)
+        };
+
+        let (debug, trace) = (options.debug, options.trace);
+        let mut vm = VM {
             stack: Vec::with_capacity(256),
             frames: Vec::with_capacity(256),
             globals: HashMap::new(),
+            options,
+            output,
+            timings: Timings::default(),
+            error: Gc::new(Struct::new(
+                "Error".to_string(),
+                vec!["message".to_string()],
+            )),
+            args: vec![],
+            sources: SourceMap::new(),
+            debugger: debug.then(|| Debugger::new(true)),
+            tracer: None,
+            hooks: Hooks::default(),
+            heap: vec![],
+            heap_bytes: 0,
+            natives_running: 0,
+            instructions: 0,
+            interrupt: InterruptHandle::default(),
+            timers: Timers::default(),
+            events: Events::default(),
+            error_location: None,
+            foreign_types: HashMap::new(),
+            cache: None,
+            precision: None,
+        };
+        if trace {
+            vm.set_tracer(PrintTracer::new(io::stderr()));
+        }
+        stdlib::define_natives(&mut vm);
+        vm
+    }
+
+    /// Tells `tracer` about every instruction executed from now on.
+    pub fn set_tracer(&mut self, tracer: impl Tracer + 'static) {
+        self.tracer = Some(Box::new(tracer));
+    }
+
+    /// Keeps the scripts run by `interpret` compiled in `cache`, and runs them from there while
+    /// they stay unchanged.
+    pub fn set_cache(&mut self, cache: ModuleCache) {
+        self.cache = Some(cache);
+    }
+
+    /// Shows numbers printed from now on with `precision` digits after the decimal point, or
+    /// in their shortest exact form for `None`. The decimal separator is always `.`, whatever
+    /// the system's locale.
+    pub fn set_precision(&mut self, precision: Option<usize>) {
+        self.precision = precision;
+    }
+
+    pub fn precision(&self) -> Option<usize> {
+        self.precision
+    }
+
+    /// A handle other threads can use to stop the script this VM is running.
+    pub fn interrupt_handle(&self) -> InterruptHandle {
+        self.interrupt.clone()
+    }
+
+    /// Defines a global, or replaces the value of an existing one.
+    pub fn bind_global(&mut self, name: &str, value: Value) {
+        self.globals.insert(name.to_string(), value);
+    }
+
+    pub fn global(&self, name: &str) -> Option<&Value> {
+        self.globals.get(name)
+    }
+
+    /// Every global defined so far, builtins included, in no particular order.
+    pub fn globals(&self) -> impl Iterator<Item = (&str, &Value)> {
+        self.globals
+            .iter()
+            .map(|(name, value)| (name.as_str(), value))
+    }
+
+    /// The values on the stack, bottom first.
+    pub fn stack(&self) -> &[Value] {
+        &self.stack
+    }
+
+    /// Defines a global function implemented in Rust.
+    pub fn define_native(&mut self, name: &str, arity: u8, fun: NativeFn) {
+        let native = self.alloc(NativeFunction::new(name.to_string(), arity, fun));
+        self.globals.insert(name.to_string(), Value::Native(native));
+    }
+
+    /// Defines a global module whose members are functions implemented in Rust, accessed as
+    /// `module.name`.
+    pub fn define_module(&mut self, module: &str, natives: &[(&str, u8, NativeFn)]) {
+        let mut members = Module::new(module.to_string());
+        for (name, arity, fun) in natives {
+            let native = self.alloc(NativeFunction::new(name.to_string(), *arity, *fun));
+            members.insert(name.to_string(), Value::Native(native));
+        }
+
+        let module_value = Value::Module(self.alloc(members));
+        self.globals.insert(module.to_string(), module_value);
+    }
+
+    /// Adds a value other than a native function to a global module.
+    pub fn define_member(&mut self, module: &str, name: &str, value: Value) {
+        match self.globals.get(module) {
+            Some(Value::Module(m)) => m.clone().insert(name.to_string(), value),
+            _ => panic!("No module {}.", module),
+        }
+    }
+
+    /// The struct of the errors returned by `error`.
+    pub fn error_struct(&self) -> Gc<Struct> {
+        self.error
+    }
+
+    /// An `Error` with the given message, for natives to return when they fail in a way the
+    /// script can handle.
+    pub fn error(&mut self, message: impl Into<String>) -> Value {
+        let fields = vec![self.string(message.into())];
+        Value::StructInstance(self.alloc(StructInstance::new(self.error, fields)))
+    }
+
+    /// Re-exports a member of a global module as a global of the same name.
+    pub fn reexport(&mut self, module: &str, name: &str) {
+        let value = match self.globals.get(module) {
+            Some(Value::Module(m)) => m.get(name),
+            _ => None,
+        };
+
+        match value {
+            Some(value) => self.globals.insert(name.to_string(), value),
+            None => panic!("No member {} in module {}.", name, module),
+        };
+    }
+
+    /// Sets the command-line arguments scripts see through `os.args()`.
+    pub fn set_args(&mut self, args: Vec<String>) {
+        self.args = args;
+    }
+
+    /// How many calls are in progress, the script itself included.
+    pub fn frame_depth(&self) -> usize {
+        self.frames.len()
+    }
+
+    /// How many natives are in the middle of a call, the one asking included.
+    pub fn natives_running(&self) -> usize {
+        self.natives_running
+    }
+
+    /// How many instructions the VM has run so far.
+    pub fn instructions(&self) -> u64 {
+        self.instructions
+    }
+
+    pub fn args(&self) -> &[String] {
+        &self.args
+    }
+
+    /// Which programs `os.exec` may run.
+    pub fn exec_policy(&self) -> &ExecPolicy {
+        &self.options.exec
+    }
+
+    /// Where `print` writes to, for natives that write output too.
+    pub fn output(&mut self) -> &mut dyn Write {
+        &mut *self.output
+    }
+
+    /// Runs `f` with everything `print` writes collected instead of written out, returning the
+    /// text along with what `f` returned. Captures can nest.
+    pub fn capture_output<T>(&mut self, f: impl FnOnce(&mut VM) -> T) -> (T, String) {
+        let buffer = Captured::default();
+        let output = mem::replace(&mut self.output, Box::new(buffer.clone()));
+        let result = f(self);
+        self.output = output;
+
+        let text = String::from_utf8_lossy(&buffer.0.borrow()).into_owned();
+        (result, text)
+    }
+
+    /// How long the phases of the last script run by `interpret` took.
+    pub fn timings(&self) -> Timings {
+        self.timings
+    }
+
+    /// How scripts are compiled. Quiet VMs don't print warnings or disassembly.
+    fn compile_options(&self) -> CompileOptions {
+        let mut options = self.options.compile;
+        if self.options.quiet {
+            options.warnings = false;
+            options.disassemble = false;
         }
+        options
+    }
+
+    /// The text of every script run and module imported so far.
+    pub fn sources(&self) -> &SourceMap {
+        &self.sources
     }
 
-    pub fn interpret<T: AsRef<str> + 'source>(&mut self, source: T) {
-        // TODO Return errors
-        let module = match GreenParser::parse(source.as_ref()) {
-            Ok(m) => m,
-            Err(err) => {
-                println!("{}", err);
+    /// Runs a script and returns the value of its last expression, or of a top-level `return`.
+    /// If it doesn't parse, doesn't compile or fails while running, prints what went wrong and
+    /// exits with status 1.
+    pub fn interpret<T: AsRef<str>>(&mut self, source: T) -> Value {
+        self.interpret_named("<script>", source)
+    }
+
+    /// Runs a script like `interpret`, naming it in diagnostics by `name`, e.g. its path.
+    pub fn interpret_named<T: AsRef<str>>(&mut self, name: &str, source: T) -> Value {
+        match self.run_script(name, source) {
+            Ok(value) => value,
+            Err(report) => {
+                print!("{}", report);
                 exit(1);
             }
+        }
+    }
+
+    /// Runs a script like `interpret_named`, but returns the report of what went wrong and
+    /// where instead of exiting, leaving the VM ready to run more code.
+    pub fn run_script<T: AsRef<str>>(&mut self, name: &str, source: T) -> Result<Value, String> {
+        let start = Instant::now();
+        let file = self.sources.add(name, source.as_ref());
+        let options = self.compile_options();
+        let cached = match &self.cache {
+            Some(cache) => cache.load(file, options, &mut self.sources),
+            None => None,
+        };
+
+        let (function, parsed) = match cached {
+            Some(function) => {
+                if options.disassemble {
+                    print!("{}", chunk::disassemble(&function));
+                }
+                (function, Instant::now())
+            }
+            None => {
+                let module = match GreenParser::parse(self.sources.get(file).text()) {
+                    Ok(m) => m,
+                    Err(err) => {
+                        let mut report = format!("{}\n", err);
+                        if let Some(line) = err.line() {
+                            report.push_str(&self.sources.excerpt(file, line));
+                        }
+                        return Err(report);
+                    }
+                };
+                let parsed = Instant::now();
+                let function =
+                    Compiler::compile_sources(module, Some(file), options, &mut self.sources)
+                        .map_err(|errors| self.compile_error_report(file, &errors))?;
+                if let Some(cache) = &self.cache {
+                    cache.store(file, options, &self.sources, &function);
+                }
+                (function, parsed)
+            }
+        };
+        let compiled = Instant::now();
+
+        let closure = self.alloc(GreenClosure::new(function));
+        let result = self.call_function(Value::Closure(closure), &[]);
+
+        self.timings = Timings {
+            parse: parsed - start,
+            compile: compiled - parsed,
+            execute: compiled.elapsed(),
         };
-        let function = Compiler::compile(module);
+        result.map_err(|err| self.error_report(&err))
+    }
+
+    /// Describes compile errors along with the lines they are on, in `file` unless they say
+    /// otherwise.
+    fn compile_error_report(&self, file: FileId, errors: &[CompileError]) -> String {
+        let reports: Vec<String> = errors
+            .iter()
+            .map(|err| {
+                let excerpt = self.sources.excerpt(err.file.unwrap_or(file), err.line);
+                format!("{}\n{}", err, excerpt)
+            })
+            .collect();
+        reports.concat()
+    }
+
+    /// Compiles and runs a script parsed already, without caching it. `source` is the text it
+    /// was parsed from, for diagnostics. If it doesn't compile or fails while running, returns
+    /// the report of what went wrong and where, and leaves the VM ready to run more code.
+    pub fn run_module(
+        &mut self,
+        name: &str,
+        source: &str,
+        module: ModuleAst,
+    ) -> Result<Value, String> {
+        let file = self.sources.add(name, source);
+        let options = self.compile_options();
+        let function = Compiler::compile_sources(module, Some(file), options, &mut self.sources)
+            .map_err(|errors| self.compile_error_report(file, &errors))?;
+        let closure = self.alloc(GreenClosure::new(function));
+        self.call_function(Value::Closure(closure), &[])
+            .map_err(|err| self.error_report(&err))
+    }
+
+    /// Describes a runtime error along with the line of the script it happened on:
+    ///
+    /// ```text
+    /// Runtime error: Tried to access undefined variable `cuont`
+    ///  --> count.green:3
+    ///   |
+    /// 3 |     print cuont
+    /// ```
+    pub fn error_report(&self, err: &RuntimeError) -> String {
+        let mut report = format!("Runtime error: {}\n", err);
+        match self.error_location {
+            Some((Some(file), line)) => report.push_str(&self.sources.excerpt(file, line)),
+            Some((None, line)) if line > 0 => report.push_str(&format!(" --> line {}\n", line)),
+            _ => {}
+        }
+        report
+    }
 
-        let closure = self.alloc(GreenClosure::new(Gc::new(function)).clone());
-        self.push(Value::Closure(closure));
-        self.call_value(0);
+    /// Calls a function, class or other callable value with `args`, runs it to completion and
+    /// returns its result. Natives can call back into the script with it too: the call returns
+    /// to the native once it finishes, however deeply it nests. If the call fails, the VM is
+    /// left as it was before, ready to run more code, and `error_report` tells where it failed.
+    pub fn call_function(&mut self, callee: Value, args: &[Value]) -> RunResult<Value> {
+        let (depth, height) = (self.frames.len(), self.stack.len());
+        if self.natives_running == 0 {
+            self.error_location = None;
+        }
+        let result = self.call_and_run(callee, args.to_vec());
+        if result.is_err() {
+            self.unwind(depth, height);
+        }
+        result
+    }
+
+    /// Calls the script's `main` function, if it defines one, and returns its result. `main`
+    /// takes no parameters, or one for the arguments as an array of strings. If it fails, the
+    /// VM is left ready to run more code, as after `call_function`.
+    pub fn call_main(&mut self, args: Vec<String>) -> RunResult<Option<Value>> {
+        let main = match self.globals.get("main") {
+            Some(Value::Closure(main)) => *main,
+            _ => return Ok(None),
+        };
 
-        self.run().unwrap();
+        let args = match *main.function.arity() {
+            0 => vec![],
+            1 => {
+                let args = args.into_iter().map(|arg| self.string(arg)).collect();
+                vec![self.array(args)]
+            }
+            arity => {
+                self.error_location = None;
+                return Err(RuntimeError::MainArity(arity));
+            }
+        };
+        let start = Instant::now();
+        let result = self.call_function(Value::Closure(main), &args);
+        self.timings.execute += start.elapsed();
+        result.map(Some)
     }
 }
@@ -8,6 +8,8 @@ use std::{
 #[derive(Debug)]
 struct ObjBox<T: ?Sized> {
     mark: Cell<bool>,
+    /// Bytes the VM counts for the object in `heap_bytes`, or 0 if the VM doesn't own it.
+    size: Cell<usize>,
     value: T,
 }
 
@@ -18,6 +20,7 @@ impl<T> Gc<T> {
     pub(crate) fn new(value: T) -> Self {
         Self(Box::into_raw(Box::new(ObjBox {
             mark: Cell::new(false),
+            size: Cell::new(0),
             value,
         })))
     }
@@ -40,6 +43,15 @@ impl<T: ?Sized> Gc<T> {
         }
     }
 
+    pub fn ptr_eq(a: &Self, b: &Self) -> bool {
+        std::ptr::eq(a.0, b.0)
+    }
+
+    /// Address of the object, the same for every pointer to it whatever its type.
+    pub(crate) fn addr(&self) -> usize {
+        self.0 as *const () as usize
+    }
+
     pub(crate) fn is_marked(&self) -> bool {
         self.deref_non_null().mark.get()
     }
@@ -49,19 +61,21 @@ impl<T: ?Sized> Gc<T> {
     }
 
     pub(crate) fn mark(&self) {
-        #[cfg(feature = "trace-gc")]
-        log::debug!("{:p} mark", self.0);
-
         self.deref_non_null().mark.set(true);
     }
 
-    pub(crate) fn free(self) {
-        #[cfg(feature = "trace-gc")]
-        log::debug!("{:p} free", self.0);
+    pub(crate) fn size(&self) -> usize {
+        self.deref_non_null().size.get()
+    }
+
+    pub(crate) fn set_size(&self, size: usize) {
+        self.deref_non_null().size.set(size);
+    }
 
+    pub(crate) fn free(self) {
         unsafe {
             // drop inner wrapper, and thus the value it owns
-            Box::from_raw(self.0);
+            drop(Box::from_raw(self.0));
         }
     }
 }
@@ -74,7 +88,7 @@ impl<T: Any> Gc<T> {
 
 impl<T: ?Sized> Clone for Gc<T> {
     fn clone(&self) -> Self {
-        Self(self.0)
+        *self
     }
 }
 
@@ -102,4 +116,4 @@ impl<T: ?Sized> fmt::Pointer for Gc<T> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         self.0.fmt(f)
     }
-}
\ No newline at end of file
+}
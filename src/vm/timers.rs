@@ -0,0 +1,148 @@
+use crate::compiler::value::Value;
+use crate::vm::vm::RunResult;
+use crate::vm::VM;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// A callback scheduled with `after` or `every`.
+struct Timer {
+    id: u64,
+    due: Instant,
+    /// How often the callback repeats, or `None` if it runs once.
+    interval: Option<Duration>,
+    callback: Value,
+}
+
+/// The callbacks waiting to run, checked by the VM between instructions and while sleeping.
+#[derive(Default)]
+pub(crate) struct Timers {
+    pending: Vec<Timer>,
+    next_id: u64,
+    /// Set while callbacks run, so a callback that runs long doesn't start the next one inside
+    /// itself.
+    firing: bool,
+}
+
+impl Timers {
+    /// Runs `callback` after `delay`, then every `interval` if there is one. Returns an id to
+    /// cancel it with.
+    pub(crate) fn schedule(
+        &mut self,
+        delay: Duration,
+        interval: Option<Duration>,
+        callback: Value,
+    ) -> u64 {
+        self.next_id += 1;
+        self.pending.push(Timer {
+            id: self.next_id,
+            due: Instant::now() + delay,
+            interval,
+            callback,
+        });
+        self.next_id
+    }
+
+    /// Stops a timer from running again, returning whether it was still pending.
+    pub(crate) fn cancel(&mut self, id: u64) -> bool {
+        let before = self.pending.len();
+        self.pending.retain(|timer| timer.id != id);
+        self.pending.len() != before
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    fn next_due(&self) -> Option<Instant> {
+        self.pending.iter().map(|timer| timer.due).min()
+    }
+
+    /// Takes the callback of the timer that has been due longest, if any is due at `now`,
+    /// scheduling it again if it repeats.
+    fn take_due(&mut self, now: Instant) -> Option<Value> {
+        let index = (0..self.pending.len())
+            .filter(|&i| self.pending[i].due <= now)
+            .min_by_key(|&i| self.pending[i].due)?;
+
+        let timer = &mut self.pending[index];
+        let callback = timer.callback;
+        match timer.interval {
+            Some(interval) => timer.due = now + interval,
+            None => {
+                self.pending.remove(index);
+            }
+        }
+        Some(callback)
+    }
+
+    /// The callbacks still to run, which the garbage collector must keep alive.
+    pub(crate) fn callbacks(&self) -> impl Iterator<Item = &Value> {
+        self.pending.iter().map(|timer| &timer.callback)
+    }
+}
+
+impl VM {
+    /// Runs `callback` after `delay`, then every `interval` if there is one. Returns an id to
+    /// cancel it with.
+    pub fn schedule(
+        &mut self,
+        delay: Duration,
+        interval: Option<Duration>,
+        callback: Value,
+    ) -> u64 {
+        self.timers.schedule(delay, interval, callback)
+    }
+
+    /// Stops a timer from running again, returning whether it was still pending.
+    pub fn cancel_timer(&mut self, id: u64) -> bool {
+        self.timers.cancel(id)
+    }
+
+    /// Runs the callbacks of the timers that are due, unless one is running already.
+    pub(crate) fn fire_timers(&mut self) -> RunResult<()> {
+        if self.timers.firing {
+            return Ok(());
+        }
+
+        self.timers.firing = true;
+        let mut result = Ok(());
+        while let Some(callback) = self.timers.take_due(Instant::now()) {
+            result = self.call_and_run(callback, vec![]).map(|_| ());
+            if result.is_err() {
+                break;
+            }
+        }
+        self.timers.firing = false;
+        result
+    }
+
+    /// Waits until `deadline`, running timers as they come due meanwhile.
+    pub(crate) fn sleep_until(&mut self, deadline: Instant) -> RunResult<()> {
+        loop {
+            self.fire_timers()?;
+
+            let now = Instant::now();
+            if now >= deadline {
+                return Ok(());
+            }
+            let wake = match self.timers.next_due() {
+                Some(due) if !self.timers.firing => due.min(deadline),
+                _ => deadline,
+            };
+            thread::sleep(wake.saturating_duration_since(now));
+        }
+    }
+
+    /// Waits for and runs the timers a script left scheduled, until none are left or the VM is
+    /// interrupted.
+    pub fn run_timers(&mut self) {
+        while let Some(due) = self.timers.next_due() {
+            if self.interrupt.take() {
+                return;
+            }
+            // Sleep in short steps so an interrupt is noticed soon.
+            let wake = due.min(Instant::now() + Duration::from_millis(100));
+            self.sleep_until(wake).unwrap();
+        }
+    }
+}
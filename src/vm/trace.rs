@@ -0,0 +1,212 @@
+use crate::compiler::chunk::Chunk;
+use crate::compiler::opcode::Opcode;
+use crate::compiler::value::Value;
+use std::cell::RefCell;
+use std::fmt;
+use std::io::Write;
+use std::rc::Rc;
+
+/// The instruction the VM is about to execute, and the state it executes it in.
+pub struct TraceStep<'a> {
+    pub chunk: &'a Chunk,
+    /// Offset of the instruction in the chunk's code.
+    pub ip: usize,
+    pub opcode: Opcode,
+    /// The whole value stack, bottom first.
+    pub stack: &'a [Value],
+    /// How many calls are in progress, the script itself included.
+    pub depth: usize,
+}
+
+/// Told about every instruction the VM executes, see `VM::set_tracer`.
+pub trait Tracer {
+    fn trace(&mut self, step: &TraceStep);
+}
+
+/// Logs each instruction like clox's `DEBUG_TRACE_EXECUTION`: the stack, then the call depth
+/// and the disassembled instruction.
+pub struct PrintTracer<W: Write> {
+    out: W,
+}
+
+impl<W: Write> PrintTracer<W> {
+    pub fn new(out: W) -> Self {
+        PrintTracer { out }
+    }
+}
+
+impl<W: Write> Tracer for PrintTracer<W> {
+    fn trace(&mut self, step: &TraceStep) {
+        let mut stack = String::from("          ");
+        for value in step.stack {
+            stack.push_str(&format!("[ {} ]", value));
+        }
+
+        let _ = writeln!(self.out, "{}", stack);
+        let _ = write!(
+            self.out,
+            "{:>2} {}",
+            step.depth,
+            step.chunk.instruction(step.ip)
+        );
+    }
+}
+
+/// Counts how many times each opcode is executed, for `--profile`. Clones share their counts, so
+/// one can be given to `VM::set_tracer` and another kept to read them from.
+#[derive(Clone)]
+pub struct OpcodeHistogram(Rc<RefCell<Vec<u64>>>);
+
+impl Default for OpcodeHistogram {
+    fn default() -> Self {
+        OpcodeHistogram(Rc::new(RefCell::new(vec![0; u8::MAX as usize + 1])))
+    }
+}
+
+impl OpcodeHistogram {
+    pub fn new() -> Self {
+        OpcodeHistogram::default()
+    }
+
+    /// The opcodes executed at least once and how often, most frequent first.
+    pub fn counts(&self) -> Vec<(Opcode, u64)> {
+        let counts = self.0.borrow();
+        let mut counts: Vec<(Opcode, u64)> = (counts.iter().enumerate())
+            .filter(|(_, count)| **count > 0)
+            .map(|(byte, count)| (Opcode::from(byte as u8), *count))
+            .collect();
+        counts.sort_by(|(_, a), (_, b)| b.cmp(a));
+        counts
+    }
+
+    pub fn total(&self) -> u64 {
+        self.0.borrow().iter().sum()
+    }
+}
+
+impl Tracer for OpcodeHistogram {
+    fn trace(&mut self, step: &TraceStep) {
+        self.0.borrow_mut()[step.opcode as usize] += 1;
+    }
+}
+
+/// A table of the counts with the share of all instructions each opcode makes up.
+impl fmt::Display for OpcodeHistogram {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let total = self.total().max(1) as f64;
+        for (opcode, count) in self.counts() {
+            let share = count as f64 / total * 100.0;
+            writeln!(
+                f,
+                "{:<20} {:>12} {:>6.1}%",
+                format!("{:?}", opcode),
+                count,
+                share
+            )?;
+        }
+        writeln!(f, "{:<20} {:>12}", "total", self.total())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::options::{CompileOptions, OptLevel};
+    use crate::vm::{VmOptions, VM};
+
+    /// Records the opcode, stack height and depth of each step.
+    #[derive(Clone, Default)]
+    struct Recorder(Rc<RefCell<Vec<(Opcode, usize, usize)>>>);
+
+    impl Tracer for Recorder {
+        fn trace(&mut self, step: &TraceStep) {
+            let record = (step.opcode, step.stack.len(), step.depth);
+            self.0.borrow_mut().push(record);
+        }
+    }
+
+    #[test]
+    fn tracers_see_every_instruction_executed() {
+        let mut vm = VM::with_options(VmOptions {
+            quiet: true,
+            compile: CompileOptions {
+                opt_level: OptLevel::O0,
+                ..CompileOptions::default()
+            },
+            ..VmOptions::default()
+        });
+        let recorder = Recorder::default();
+        vm.set_tracer(recorder.clone());
+
+        vm.interpret("def double(n)\n    return n * 2\nend\ndouble(4)\n");
+
+        use Opcode::*;
+        assert_eq!(
+            *recorder.0.borrow(),
+            [
+                (Closure, 1, 1),
+                (DefineGlobal, 2, 1),
+                (GetGlobal, 1, 1),
+                (Constant, 2, 1),
+                (Call, 3, 1),
+                (GetLocal, 3, 2),
+                (Constant, 4, 2),
+                (Multiply, 5, 2),
+                (Return, 4, 2),
+                (Return, 2, 1),
+            ]
+        );
+    }
+
+    #[test]
+    fn histogram_counts_opcodes_executed() {
+        let mut vm = VM::with_options(VmOptions {
+            quiet: true,
+            compile: CompileOptions {
+                opt_level: OptLevel::O0,
+                ..CompileOptions::default()
+            },
+            ..VmOptions::default()
+        });
+        let histogram = OpcodeHistogram::new();
+        vm.set_tracer(histogram.clone());
+
+        // `to` leaves out the end, so the loop body runs 9 times.
+        vm.interpret("var total = 0\nfor i in 1 to 10 do total = total + i end\n");
+
+        let counts = histogram.counts();
+        let count = |opcode| counts.iter().find(|(o, _)| *o == opcode).map(|(_, n)| *n);
+        assert_eq!(count(Opcode::SetGlobal), Some(9));
+        assert_eq!(count(Opcode::Call), None);
+        assert_eq!(
+            counts.iter().map(|(_, n)| n).sum::<u64>(),
+            histogram.total()
+        );
+        assert!(counts.windows(2).all(|pair| pair[0].1 >= pair[1].1));
+
+        let report = histogram.to_string();
+        assert!(report.contains("SetGlobal"));
+        assert!(report.ends_with(&format!("{:<20} {:>12}\n", "total", histogram.total())));
+    }
+
+    #[test]
+    fn print_tracer_logs_the_stack_and_instruction() {
+        let mut out = vec![];
+        let mut tracer = PrintTracer::new(&mut out);
+        let mut chunk = Chunk::new();
+        chunk.write(Opcode::Add, 1);
+
+        tracer.trace(&TraceStep {
+            chunk: &chunk,
+            ip: 0,
+            opcode: Opcode::Add,
+            stack: &[Value::Number(1.0), Value::Number(2.0)],
+            depth: 1,
+        });
+
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "          [ 1 ][ 2 ]\n 1 0000    1 ADD\n"
+        );
+    }
+}
@@ -1,92 +1,266 @@
-use crate::compiler::chunk::Chunk;
-use crate::compiler::object::{Class, GreenClosure, Instance, Object};
+use crate::compiler::chunk::{Chunk, PropertyCache};
+use crate::compiler::object::{
+    BoundMethod, Class, GreenClosure, Instance, Map, NativeFunction, Range, Struct, StructInstance,
+};
 use crate::compiler::opcode::Opcode;
 use crate::compiler::value::Value;
-use crate::vm::errors::RuntimeError;
+use crate::stdlib::array_method;
+use crate::vm::errors::{Budget, RuntimeError};
 use crate::vm::frame::CallFrame;
-use crate::vm::VM;
-use std::collections::HashMap;
+use crate::vm::iter::iterator_method;
 use crate::vm::obj::Gc;
+use crate::vm::trace::TraceStep;
+use crate::vm::VM;
+use std::io::Write;
+use std::mem;
 
 pub type RunResult<T> = Result<T, RuntimeError>;
 
+type Handler = fn(&mut VM) -> RunResult<()>;
+
+/// How many instructions run between checks for an interrupt or timers that are due.
+const INTERRUPT_INTERVAL: u64 = 1024;
+
+/// Instruction handlers indexed by opcode byte. Bytes without an opcode report an error instead
+/// of panicking.
+const DISPATCH: [Handler; 256] = {
+    let mut table: [Handler; 256] = [VM::unknown_opcode; 256];
+    table[Opcode::Return as usize] = VM::ret;
+    table[Opcode::Constant as usize] = VM::constant;
+    table[Opcode::ConstantLong as usize] = VM::constant_long;
+    table[Opcode::Add as usize] = VM::add;
+    table[Opcode::Subtract as usize] = VM::subtract;
+    table[Opcode::Multiply as usize] = VM::multiply;
+    table[Opcode::Divide as usize] = VM::divide;
+    table[Opcode::Print as usize] = VM::print;
+    table[Opcode::Equal as usize] = VM::equal;
+    table[Opcode::Greater as usize] = VM::greater;
+    table[Opcode::Less as usize] = VM::less;
+    table[Opcode::Not as usize] = VM::not;
+    table[Opcode::Negate as usize] = VM::negate;
+    table[Opcode::DefineGlobal as usize] = VM::define_global;
+    table[Opcode::GetGlobal as usize] = VM::get_global;
+    table[Opcode::SetGlobal as usize] = VM::set_global;
+    table[Opcode::JumpIfFalse as usize] = VM::jump_if_false;
+    table[Opcode::Jump as usize] = VM::jump;
+    table[Opcode::Pop as usize] = VM::pop_instruction;
+    table[Opcode::GetLocal as usize] = VM::get_local;
+    table[Opcode::SetLocal as usize] = VM::set_local;
+    table[Opcode::Nil as usize] = VM::nil;
+    table[Opcode::Call as usize] = VM::call_instruction;
+    table[Opcode::Closure as usize] = VM::closure;
+    table[Opcode::Loop as usize] = VM::loop_;
+    table[Opcode::NewArray as usize] = VM::new_array;
+    table[Opcode::IndexSubscript as usize] = VM::index_subscript;
+    table[Opcode::StoreSubscript as usize] = VM::store_subscript;
+    table[Opcode::Class as usize] = VM::class;
+    table[Opcode::GetProperty as usize] = VM::get_property;
+    table[Opcode::SetProperty as usize] = VM::set_property;
+    table[Opcode::Is as usize] = VM::is;
+    table[Opcode::AssertFail as usize] = VM::assert_fail;
+    table[Opcode::Method as usize] = VM::method;
+    table[Opcode::NewArrayLong as usize] = VM::new_array_long;
+    table[Opcode::ArrayPush as usize] = VM::array_push;
+    table[Opcode::ArrayPushRange as usize] = VM::array_push_range;
+    table[Opcode::Modulo as usize] = VM::modulo;
+    table[Opcode::NewMap as usize] = VM::new_map;
+    table[Opcode::AddLocalConstant as usize] = VM::add_local_constant;
+    table[Opcode::ConstantCall as usize] = VM::constant_call;
+    table[Opcode::LessJumpIfFalse as usize] = VM::less_jump_if_false;
+    table[Opcode::GreaterJumpIfFalse as usize] = VM::greater_jump_if_false;
+    table[Opcode::EqualJumpIfFalse as usize] = VM::equal_jump_if_false;
+    table[Opcode::JumpTable as usize] = VM::jump_table;
+    table[Opcode::Field as usize] = VM::field;
+    table[Opcode::PopN as usize] = VM::pop_n;
+    table[Opcode::NewTuple as usize] = VM::new_tuple;
+    table[Opcode::UnpackTuple as usize] = VM::unpack_tuple;
+    table[Opcode::UnpackArray as usize] = VM::unpack_array;
+    table[Opcode::UnpackMap as usize] = VM::unpack_map;
+    table[Opcode::NewRange as usize] = VM::new_range;
+    table[Opcode::Static as usize] = VM::static_member;
+    table
+};
+
 impl VM {
     pub(crate) fn run(&mut self) -> RunResult<()> {
-        while !self.is_at_end() {
-            let instruction = Opcode::from(self.read_byte());
-            match instruction {
-                Opcode::Constant => self.constant(),
-                Opcode::Add => self.add()?,
-                Opcode::Subtract => self.subtract()?,
-                Opcode::Multiply => self.multiply()?,
-                Opcode::Divide => self.divide()?,
-                Opcode::Greater => self.greater()?,
-                Opcode::Less => self.less()?,
-                Opcode::Equal => self.equal()?,
-                Opcode::Not => self.not()?,
-                Opcode::Negate => self.negate()?,
-                Opcode::DefineGlobal => self.define_global()?,
-                Opcode::GetGlobal => self.get_global()?,
-                Opcode::SetGlobal => self.set_global()?,
-                Opcode::GetLocal => self.get_local()?,
-                Opcode::SetLocal => self.set_local()?,
-                Opcode::GetProperty => self.get_property()?,
-                Opcode::SetProperty => self.set_property()?,
-                Opcode::Class => self.class(),
-                Opcode::Closure => self.closure(),
-                Opcode::JumpIfFalse => self.jump_if_false()?,
-                Opcode::Jump => self.jump()?,
-                Opcode::Loop => self.loop_(),
-                Opcode::Call => self.call_instruction(),
-                Opcode::NewArray => self.new_array()?,
-                Opcode::IndexSubscript => self.index_subscript()?,
-                Opcode::StoreSubscript => self.store_subscript()?,
-                Opcode::Return => self.ret()?,
-                Opcode::Print => self.print()?,
-                Opcode::Pop => {
-                    self.pop()?;
-                }
-                Opcode::Nil => self.nil(),
-            };
+        self.error_location = None;
+        let result = self.run_until(0);
+        if result.is_err() {
+            self.unwind(0, 0);
+        }
+        result
+    }
+
+    /// Drops the calls and values an error abandoned above `depth` frames and `height` values,
+    /// so the VM can run something else. Remembers the line the innermost of those calls failed
+    /// on for `error_report`, unless a call nested deeper already did.
+    pub(crate) fn unwind(&mut self, depth: usize, height: usize) {
+        if self.error_location.is_none() {
+            let failed = self.frames.get(depth..).and_then(|frames| frames.last());
+            self.error_location = failed.map(|frame| {
+                let chunk = frame.closure().function.chunk();
+                // The instruction pointer is past the instruction that failed.
+                (chunk.file(), chunk.line_at(frame.ip().saturating_sub(1)))
+            });
+        }
+        self.frames.truncate(depth);
+        self.stack.truncate(height);
+    }
+
+    /// Executes instructions until the number of call frames drops to `depth`.
+    fn run_until(&mut self, depth: usize) -> RunResult<()> {
+        while self.frames.len() > depth {
+            if self.debugger.is_some() {
+                self.debug_instruction();
+            }
+            if self.has_line_hook() {
+                self.line_hook()?;
+            }
+            if let Some(tracer) = &mut self.tracer {
+                let frame = self.frames.last().expect("frames to be nonempty");
+                let chunk = frame.closure().function.chunk();
+                let ip = *frame.ip();
+                tracer.trace(&TraceStep {
+                    chunk,
+                    ip,
+                    opcode: Opcode::from(chunk.code()[ip]),
+                    stack: &self.stack,
+                    depth: self.frames.len(),
+                });
+            }
+
+            self.check_budget()?;
+            let byte = self.read_byte();
+            DISPATCH[byte as usize](self)?;
+        }
+
+        Ok(())
+    }
+
+    /// Counts the instruction about to run, failing if it or the objects allocated so far go
+    /// over the limits in the options, or now and then if the script was interrupted. Timers
+    /// that came due meanwhile run now and then too.
+    fn check_budget(&mut self) -> RunResult<()> {
+        self.instructions += 1;
+        if self.instructions.is_multiple_of(INTERRUPT_INTERVAL) {
+            if self.interrupt.take() {
+                return Err(RuntimeError::Interrupted);
+            }
+            if !self.timers.is_empty() {
+                self.fire_timers()?;
+            }
+        }
+        if let Some(max) = self.options.max_instructions {
+            if self.instructions > max {
+                return Err(RuntimeError::BudgetExceeded(Budget::Instructions(max)));
+            }
+        }
+        self.check_heap_budget(0)
+    }
+
+    /// Fails if the heap, with `extra` more bytes, would be over the limit in the options.
+    fn check_heap_budget(&self, extra: usize) -> RunResult<()> {
+        if let Some(max) = self.options.max_heap_bytes {
+            if self.heap_bytes.saturating_add(extra) > max {
+                return Err(RuntimeError::BudgetExceeded(Budget::HeapBytes(max)));
+            }
         }
+        Ok(())
+    }
+
+    fn unknown_opcode(&mut self) -> RunResult<()> {
+        let byte = self.current_chunk().code()[*self.frame().ip() - 1];
+        Err(RuntimeError::UnknownOpcode(byte))
+    }
+
+    fn pop_instruction(&mut self) -> RunResult<()> {
+        self.pop()?;
+        Ok(())
+    }
+
+    fn pop_n(&mut self) -> RunResult<()> {
+        let count = self.read_byte() as usize;
+        let len = self
+            .stack
+            .len()
+            .checked_sub(count)
+            .ok_or(RuntimeError::StackEmpty)?;
+        self.stack.truncate(len);
+        Ok(())
+    }
 
+    fn constant(&mut self) -> RunResult<()> {
+        let constant = *self.read_constant();
+        self.push(constant);
         Ok(())
     }
 
-    fn constant(&mut self) {
-        let constant = self.read_constant().clone();
+    fn constant_long(&mut self) -> RunResult<()> {
+        let constant = *self.read_constant_long();
         self.push(constant);
+        Ok(())
     }
 
     fn add(&mut self) -> RunResult<()> {
+        if self.invoke_operator("add")? {
+            return Ok(());
+        }
+
         let b = self.pop()?;
         let a = self.pop()?;
-        self.push(a + b);
+        self.push((a + b)?);
         Ok(())
     }
 
     fn subtract(&mut self) -> RunResult<()> {
+        if self.invoke_operator("sub")? {
+            return Ok(());
+        }
+
         let b = self.pop()?;
         let a = self.pop()?;
-        self.push(a - b);
+        self.push((a - b)?);
         Ok(())
     }
 
     fn multiply(&mut self) -> RunResult<()> {
+        if self.invoke_operator("mul")? {
+            return Ok(());
+        }
+
         let b = self.pop()?;
         let a = self.pop()?;
-        self.push(a * b);
+        self.push((a * b)?);
         Ok(())
     }
 
     fn divide(&mut self) -> RunResult<()> {
+        if self.invoke_operator("div")? {
+            return Ok(());
+        }
+
+        let b = self.pop()?;
+        let a = self.pop()?;
+        self.push((a / b)?);
+        Ok(())
+    }
+
+    fn modulo(&mut self) -> RunResult<()> {
+        if self.invoke_operator("mod")? {
+            return Ok(());
+        }
+
         let b = self.pop()?;
         let a = self.pop()?;
-        self.push(a / b);
+        self.push((a % b)?);
         Ok(())
     }
 
     fn equal(&mut self) -> RunResult<()> {
+        if self.invoke_operator("eq")? {
+            return Ok(());
+        }
+
         let b = self.pop()?;
         let a = self.pop()?;
         self.push((a == b).into());
@@ -94,16 +268,27 @@ impl VM {
     }
 
     fn greater(&mut self) -> RunResult<()> {
+        // `a > b` is `b < a`, so swap the operands when the right one defines `lt`.
+        if self.operator_method(self.peek_offset(0), "lt").is_some() {
+            let len = self.stack.len();
+            self.stack.swap(len - 1, len - 2);
+            return self.less();
+        }
+
         let b = self.pop()?;
         let a = self.pop()?;
-        self.push((a > b).into());
+        self.push(a.greater(b)?.into());
         Ok(())
     }
 
     fn less(&mut self) -> RunResult<()> {
+        if self.invoke_operator("lt")? {
+            return Ok(());
+        }
+
         let b = self.pop()?;
         let a = self.pop()?;
-        self.push((a < b).into());
+        self.push(a.less(b)?.into());
         Ok(())
     }
 
@@ -115,11 +300,31 @@ impl VM {
 
     fn negate(&mut self) -> RunResult<()> {
         let a = self.pop()?;
-        self.push(-a);
+        self.push((-a)?);
         Ok(())
     }
 
+    fn operator_method(&self, value: Value, name: &str) -> Option<Gc<GreenClosure>> {
+        match value {
+            Value::Instance(instance) => instance.class.method(name),
+            _ => None,
+        }
+    }
+
+    /// Calls the method `name` on the left operand of a binary operator if the operand is an
+    /// instance defining it. Stack before: [a, b] and after the call returns: [a.name(b)]
+    fn invoke_operator(&mut self, name: &str) -> RunResult<bool> {
+        match self.operator_method(self.peek_offset(1), name) {
+            Some(method) => {
+                self.call(method, 1)?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
     fn ret(&mut self) -> RunResult<()> {
+        self.return_hook()?;
         if let Some(frame) = self.frames.pop() {
             let result = self.pop()?;
             self.stack.truncate(*frame.stack_start());
@@ -131,17 +336,14 @@ impl VM {
     }
 
     fn define_global(&mut self) -> RunResult<()> {
-        if let Some(value) = self.pop()? {
-            let var_name = self.read_string().to_string();
-            self.globals.insert(var_name, value);
-            Ok(())
-        } else {
-            Err(RuntimeError::BadStackIndex(10, self.stack.len())) // TODO 10
-        }
+        let value = self.pop()?;
+        let var_name = self.read_string().to_string();
+        self.globals.insert(var_name, value);
+        Ok(())
     }
 
     fn get_global(&mut self) -> RunResult<()> {
-        let name = self.read_constant().as_string().clone();
+        let name = self.read_string().clone();
 
         if let Some(value) = self.globals.get(&name).cloned() {
             self.push(value);
@@ -152,10 +354,10 @@ impl VM {
     }
 
     fn set_global(&mut self) -> RunResult<()> {
-        let name = self.read_constant().as_string().clone();
+        let name = self.read_string().clone();
 
         if self.globals.contains_key(&name) {
-            let value = self.peek()?.clone();
+            let value = *self.peek()?;
             self.globals.insert(name, value);
             Ok(())
         } else {
@@ -178,14 +380,124 @@ impl VM {
         Ok(())
     }
 
+    fn jump_table(&mut self) -> RunResult<()> {
+        let index = self.read_byte();
+        let subject = self.pop()?;
+        let target = self
+            .current_chunk()
+            .jump_table(index.into())
+            .target(&subject);
+        *self.frame_mut().ip_mut() = target;
+        Ok(())
+    }
+
+    /// Runs `GetLocal; Constant; Add` in one go when both operands are numbers. Otherwise only
+    /// the `GetLocal` runs, and the original instructions after it do the rest.
+    fn add_local_constant(&mut self) -> RunResult<()> {
+        self.get_local()?;
+
+        // The `Constant` opcode is followed by its index.
+        let index = self.current_chunk().code()[*self.frame().ip() + 1];
+        let sum = match (
+            self.stack.last(),
+            self.current_chunk().read_constant(index.into()),
+        ) {
+            (Some(Value::Number(a)), Value::Number(b)) => a + b,
+            _ => return Ok(()),
+        };
+
+        *self.stack.last_mut().expect("local was just pushed") = Value::Number(sum);
+        // Skip over the `Constant` and `Add`.
+        *self.frame_mut().ip_mut() += 3;
+        Ok(())
+    }
+
+    /// Runs `Constant; Call`.
+    fn constant_call(&mut self) -> RunResult<()> {
+        self.constant()?;
+        // Skip the `Call` opcode, leaving its arity to be read.
+        *self.frame_mut().ip_mut() += 1;
+        self.call_instruction()
+    }
+
+    fn less_jump_if_false(&mut self) -> RunResult<()> {
+        self.compare_jump_if_false(|a, b| a < b, VM::less)
+    }
+
+    fn greater_jump_if_false(&mut self) -> RunResult<()> {
+        self.compare_jump_if_false(|a, b| a > b, VM::greater)
+    }
+
+    fn equal_jump_if_false(&mut self) -> RunResult<()> {
+        self.compare_jump_if_false(|a, b| a == b, VM::equal)
+    }
+
+    /// Runs a comparison and the `JumpIfFalse` after it when both operands are numbers.
+    /// Anything else may call an operator method, so only the comparison runs.
+    fn compare_jump_if_false(
+        &mut self,
+        compare: fn(f64, f64) -> bool,
+        fallback: Handler,
+    ) -> RunResult<()> {
+        let result = match self.stack.as_slice() {
+            [.., Value::Number(a), Value::Number(b)] => compare(*a, *b),
+            _ => return fallback(self),
+        };
+
+        self.stack.truncate(self.stack.len() - 2);
+        self.push(result.into());
+        // Skip the `JumpIfFalse` opcode, leaving its offset to be read.
+        *self.frame_mut().ip_mut() += 1;
+        self.jump_if_false()
+    }
+
     fn print(&mut self) -> RunResult<()> {
         let popped = self.pop()?;
-        println!("{:?}", popped);
-        Ok(())
+        let string = self.stringify(popped)?;
+        writeln!(self.output, "{}", string).map_err(RuntimeError::Output)
+    }
+
+    /// Converts a value to the string shown by `print` and `str`, calling the `tostring` method
+    /// of instances that define one.
+    pub fn stringify(&mut self, value: Value) -> RunResult<String> {
+        self.format(value, self.precision())
+    }
+
+    /// Converts a value to a string like `stringify`, showing its numbers with `precision`
+    /// digits after the decimal point.
+    pub fn format(&mut self, value: Value, precision: Option<usize>) -> RunResult<String> {
+        if let Some(tostring) = self.operator_method(value, "tostring") {
+            self.push(value);
+            self.call(tostring, 0)?;
+            self.run_until(self.frames.len() - 1)?;
+
+            let string = self.pop()?;
+            return Ok(string.to_string());
+        }
+
+        Ok(match precision {
+            Some(precision) => format!("{:.*}", precision, value),
+            None => value.to_string(),
+        })
+    }
+
+    /// Calls a function, class or other callable with `args` and runs it to completion,
+    /// returning its result.
+    pub(crate) fn call_and_run(&mut self, callee: Value, args: Vec<Value>) -> RunResult<Value> {
+        let depth = self.frames.len();
+        let arity = args.len() as u8;
+        self.push(callee);
+        self.stack.extend(args);
+        self.call_value(arity)?;
+        if self.frames.len() > depth {
+            self.run_until(depth)?;
+        }
+        self.pop()
     }
 
-    fn nil(&mut self) {
+    fn nil(&mut self) -> RunResult<()> {
         self.push(Value::Nil);
+        Ok(())
     }
 
     fn get_local(&mut self) -> RunResult<()> {
@@ -202,88 +514,302 @@ impl VM {
     }
 
     fn set_local(&mut self) -> RunResult<()> {
-        let value = self.peek()?.clone();
+        let value = *self.peek()?;
         let start = *self.frame().stack_start();
         let slot = self.read_byte() as usize;
         self.stack[start + slot] = value;
         Ok(())
     }
 
-    fn call_instruction(&mut self) {
+    fn call_instruction(&mut self) -> RunResult<()> {
         let arity = self.read_byte();
-        self.call_value(arity);
+        self.call_value(arity)
     }
 
-    fn closure(&mut self) {
-        match self.read_constant().clone() {
+    fn closure(&mut self) -> RunResult<()> {
+        match *self.read_constant_long() {
             Value::Function(fun) => {
                 let closure = GreenClosure::new(fun);
                 let clos = self.alloc(closure);
                 self.push(Value::Closure(clos));
+                Ok(())
             }
-            _ => todo!(), // TODO
+            _ => Err(RuntimeError::ArgumentTypes),
         }
     }
 
-    fn call(&mut self, closure: Gc<GreenClosure>, arity: u8) {
+    fn call(&mut self, closure: Gc<GreenClosure>, arity: u8) -> RunResult<()> {
         if arity != *closure.function.arity() {
-            panic!( // TODO Error
-                    "Expected {} arguments but got {}.",
-                    closure.function.arity(),
-                    arity
-            );
+            return Err(RuntimeError::WrongArity(*closure.function.arity(), arity));
+        }
+
+        if self.frames.len() >= self.options.max_frames {
+            return Err(RuntimeError::TooManyFrames(self.options.max_frames));
+        }
+        if self.stack.len() > self.options.stack_size {
+            return Err(RuntimeError::StackFull(self.options.stack_size));
         }
 
         let last = self.stack.len();
         let frame_start = last - (arity + 1) as usize;
 
         self.frames.push(CallFrame::new(closure, frame_start));
+        self.call_hook()
+    }
+
+    fn call_native(&mut self, native: Gc<NativeFunction>, arity: u8) -> RunResult<()> {
+        if arity != *native.arity() {
+            return Err(RuntimeError::WrongArity(*native.arity(), arity));
+        }
+
+        // Stack before: [native, arg1, ..., argN] and after: [result]
+        let mut args = self.stack.split_off(self.stack.len() - arity as usize);
+        self.pop()?;
+        if let Some(receiver) = native.receiver() {
+            args.insert(0, receiver);
+        }
+
+        self.natives_running += 1;
+        let result = native.call(self, &args);
+        self.natives_running -= 1;
+
+        self.push(result?);
+        Ok(())
     }
 
-    pub(crate) fn call_value(&mut self, arity: u8) {
+    pub(crate) fn call_value(&mut self, arity: u8) -> RunResult<()> {
         let frame_start = self.stack.len() - (arity + 1) as usize;
-        let callee = self.stack[frame_start].clone();
+        let callee = self.stack[frame_start];
 
         match callee {
-            Value::Closure(c) => self.call(c, arity),
+            Value::Closure(c) => self.call(c, arity)?,
+            Value::Native(n) => self.call_native(n, arity)?,
             Value::Class(c) => {
                 let instance = Value::Instance(self.alloc(Instance::new(c)));
+                self.stack[frame_start] = instance;
 
-                let l = self.stack.len();
-                self.stack[l - usize::from(arity) - 1] = instance;
+                if let Some(init) = c.method("init") {
+                    self.call(init, arity)?;
+                } else if arity != 0 {
+                    return Err(RuntimeError::WrongArity(0, arity));
+                }
             }
-            _ => panic!("Can only call functions"), // TODO Error
+            Value::BoundMethod(bound) => {
+                self.stack[frame_start] = bound.receiver;
+                self.call(bound.method, arity)?;
+            }
+            Value::Struct(s) => self.construct_struct(s, arity)?,
+            _ => return Err(RuntimeError::NotCallable(callee.type_name())),
+        }
+        Ok(())
+    }
+
+    fn construct_struct(&mut self, def: Gc<Struct>, arity: u8) -> RunResult<()> {
+        let field_count = def.fields().len() as u8;
+        if arity != field_count {
+            return Err(RuntimeError::WrongArity(field_count, arity));
         }
+
+        // Stack before: [struct, field1, ..., fieldN] and after: [instance]
+        let fields = self.stack.split_off(self.stack.len() - arity as usize);
+        self.pop()?;
+
+        let instance = self.alloc(StructInstance::new(def, fields));
+        self.push(Value::StructInstance(instance));
+        Ok(())
     }
 
-    fn loop_(&mut self) {
+    fn loop_(&mut self) -> RunResult<()> {
         let offset = self.read_short();
         *self.frame_mut().ip_mut() -= offset as usize;
+        Ok(())
     }
 
     fn new_array(&mut self) -> RunResult<()> {
-        // Stack before: [item1, item2, ..., itemN] and after: [array]
-        let mut array = vec![];
         let item_count = self.read_byte();
+        self.collect_array(item_count.into())
+    }
+
+    fn new_array_long(&mut self) -> RunResult<()> {
+        let item_count = self.read_short();
+        self.collect_array(item_count.into())
+    }
+
+    fn collect_array(&mut self, item_count: usize) -> RunResult<()> {
+        // Stack before: [item1, item2, ..., itemN] and after: [array]
+        if item_count > self.stack.len() {
+            return Err(RuntimeError::StackEmpty);
+        }
 
         // Move items from stack to array
-        for _ in 0..item_count {
-            array.push(self.pop()?);
+        let array = self.stack.split_off(self.stack.len() - item_count);
+
+        let array = self.array(array);
+        self.push(array);
+        Ok(())
+    }
+
+    fn new_tuple(&mut self) -> RunResult<()> {
+        // Stack before: [item1, item2, ..., itemN] and after: [tuple]
+        let item_count = self.read_byte() as usize;
+        if item_count > self.stack.len() {
+            return Err(RuntimeError::StackEmpty);
+        }
+
+        let items = self.stack.split_off(self.stack.len() - item_count);
+        let tuple = self.tuple(items);
+        self.push(tuple);
+        Ok(())
+    }
+
+    fn unpack_tuple(&mut self) -> RunResult<()> {
+        // Stack before: [tuple] and after: [item1, item2, ..., itemN]
+        let item_count = self.read_byte() as usize;
+        match self.pop()? {
+            Value::Tuple(items) if items.len() == item_count => {
+                self.stack.extend(items.iter().copied());
+                Ok(())
+            }
+            Value::Tuple(items) => Err(RuntimeError::CannotUnpack(
+                item_count,
+                format!("a tuple of {} values", items.len()),
+            )),
+            value => Err(RuntimeError::CannotUnpack(item_count, value.type_name())),
+        }
+    }
+
+    fn unpack_array(&mut self) -> RunResult<()> {
+        // Stack before: [array] and after: [item1, item2, ..., itemN]
+        let item_count = self.read_byte() as usize;
+        match self.pop()? {
+            Value::Array(items) if items.len() == item_count => {
+                self.stack.extend(items.iter().copied());
+                Ok(())
+            }
+            Value::Array(items) => Err(RuntimeError::CannotUnpack(
+                item_count,
+                format!("an array of {} values", items.len()),
+            )),
+            value => Err(RuntimeError::CannotUnpack(item_count, value.type_name())),
+        }
+    }
+
+    fn unpack_map(&mut self) -> RunResult<()> {
+        // Stack before: [map, key1, key2, ..., keyN] and after: [value1, value2, ..., valueN]
+        let key_count = self.read_byte() as usize;
+        if key_count >= self.stack.len() {
+            return Err(RuntimeError::StackEmpty);
+        }
+        let keys = self.stack.split_off(self.stack.len() - key_count);
+        let map = match self.pop()? {
+            Value::Map(map) => map,
+            value => return Err(RuntimeError::CannotUnpack(key_count, value.type_name())),
+        };
+
+        for key in keys {
+            match map.get(&key)? {
+                Some(value) => self.push(value),
+                None => return Err(RuntimeError::MissingKey(key.to_string())),
+            }
+        }
+        Ok(())
+    }
+
+    fn array_push(&mut self) -> RunResult<()> {
+        // Stack before: [array, item] and after: [array]
+        let item = self.pop()?;
+        match self.stack.last() {
+            Some(Value::Array(mut array)) => {
+                array.push(item);
+                self.resized_array(array);
+            }
+            _ => return Err(RuntimeError::ArgumentTypes),
+        }
+        Ok(())
+    }
+
+    fn array_push_range(&mut self) -> RunResult<()> {
+        // Stack before: [array, start, end, step] and after: [array]
+        let step = self.pop()?;
+        let end = self.pop()?;
+        let start = self.pop()?;
+        let range = match (start, end, step) {
+            (Value::Number(start), Value::Number(end), Value::Number(step)) => {
+                Range::new(start, end, step)?
+            }
+            _ => return Err(RuntimeError::ArgumentTypes),
+        };
+        // Big ranges would take all the memory before the next budget check sees them.
+        self.check_heap_budget(range.len() * mem::size_of::<Value>())?;
+
+        match self.stack.last() {
+            Some(Value::Array(mut array)) => {
+                array.extend(range.numbers().map(Value::Number));
+                self.resized_array(array);
+            }
+            _ => return Err(RuntimeError::ArgumentTypes),
+        }
+        Ok(())
+    }
+
+    fn new_range(&mut self) -> RunResult<()> {
+        // Stack before: [start, end, step] and after: [range]
+        let step = self.pop()?;
+        let end = self.pop()?;
+        let start = self.pop()?;
+        let range = match (start, end, step) {
+            (Value::Number(start), Value::Number(end), Value::Number(step)) => {
+                Range::new(start, end, step)?
+            }
+            _ => return Err(RuntimeError::ArgumentTypes),
+        };
+
+        let range = self.alloc(range);
+        self.push(Value::Range(range));
+        Ok(())
+    }
+
+    /// The numbers from `start` up to, but not including, `end`, counting by `step`. A negative
+    /// step counts down.
+    pub(crate) fn range(start: f64, end: f64, step: f64) -> RunResult<Vec<Value>> {
+        let range = Range::new(start, end, step)?;
+        Ok(range.numbers().map(Value::Number).collect())
+    }
+
+    fn new_map(&mut self) -> RunResult<()> {
+        // Stack before: [key1, value1, ..., keyN, valueN] and after: [map]
+        let entry_count = self.read_byte() as usize;
+        if entry_count * 2 > self.stack.len() {
+            return Err(RuntimeError::StackEmpty);
         }
 
-        array.reverse();
+        let items = self.stack.split_off(self.stack.len() - entry_count * 2);
+        let mut map = Map::new();
+        for entry in items.chunks(2) {
+            map.insert(entry[0], entry[1])?;
+        }
 
-        self.push(Value::Array(array));
+        let map = self.map(map);
+        self.push(map);
         Ok(())
     }
 
     fn index_subscript(&mut self) -> RunResult<()> {
         // Stack before: [array, index] and after: [index(array, index)]
-        let index = self.pop()?.as_number();
-        let array = self.pop()?.as_array();
+        let index = self.pop()?;
+        let result = match (self.pop()?, index) {
+            (Value::Array(array) | Value::Tuple(array), Value::Number(index)) => {
+                array[element_index(index, array.len())?]
+            }
+            (Value::Range(range), Value::Number(index)) => {
+                let index = element_index(index, range.len())?;
+                Value::Number(range.get(index).expect("index is in bounds"))
+            }
+            // Missing keys read as nil.
+            (Value::Map(map), key) => map.get(&key)?.unwrap_or(Value::Nil),
+            _ => return Err(RuntimeError::ArgumentTypes),
+        };
 
-        // Stack before: [array, index] and after: [index(array, index)]
-        let result = array[index as usize].clone();
         self.push(result);
         Ok(())
     }
@@ -291,59 +817,321 @@ impl VM {
     fn store_subscript(&mut self) -> RunResult<()> {
         // Stack before: [array, index, item] and after: [item]
         let item = self.pop()?;
-        let index = self.pop()?.as_number();
-        let mut array = self.pop()?.as_array();
+        let index = self.pop()?;
 
-        // Stack before: [array, index] and after: [index(array, index)]
-        array[index as usize] = item;
-        let result = array.clone();
-        self.push(Value::Array(result));
+        // Arrays and maps are shared, so the store is visible through every reference to them.
+        match (self.pop()?, index) {
+            (Value::Array(mut array), Value::Number(index)) => {
+                let index = element_index(index, array.len())?;
+                array[index] = item;
+            }
+            (Value::Map(mut map), key) => {
+                map.insert(key, item)?;
+                self.resized_map(map);
+            }
+            _ => return Err(RuntimeError::ArgumentTypes),
+        }
+        self.push(item);
 
         Ok(())
     }
 
-    fn class(&mut self) {
-        let name = self.read_constant().as_string();
+    fn class(&mut self) -> RunResult<()> {
+        let name = self.read_string();
         let cls = Class::new(name.clone());
         let class = Value::Class(self.alloc(cls));
         self.push(class);
+        Ok(())
     }
 
-    fn get_property(&mut self) -> RunResult<()> {
-        if !self.peek()?.is_instance() {
-            panic!("Only instances have properties."); // TODO Error
+    fn method(&mut self) -> RunResult<()> {
+        // Stack before: [class, closure] and after: [class]
+        let name = self.read_string().clone();
+        let method = self.pop()?;
+
+        match (*self.peek()?, method) {
+            (Value::Class(mut class), Value::Closure(method)) => {
+                class.add_method(name, method);
+                Ok(())
+            }
+            _ => Err(RuntimeError::ArgumentTypes),
+        }
+    }
+
+    fn field(&mut self) -> RunResult<()> {
+        // Stack before: [class] and after: [class]
+        let name = self.read_string().clone();
+
+        match *self.peek()? {
+            Value::Class(mut class) => {
+                class.declare_field(name);
+                Ok(())
+            }
+            _ => Err(RuntimeError::ArgumentTypes),
+        }
+    }
+
+    fn static_member(&mut self) -> RunResult<()> {
+        // Stack before: [class, value] and after: [class]
+        let name = self.read_string().clone();
+        let value = self.pop()?;
+
+        match *self.peek()? {
+            Value::Class(mut class) => {
+                class.set_static(name, value);
+                Ok(())
+            }
+            _ => Err(RuntimeError::ArgumentTypes),
         }
+    }
+
+    fn is(&mut self) -> RunResult<()> {
+        // Stack before: [value, type] and after: [bool]
+        let target = self.pop()?;
+        let value = self.pop()?;
+
+        let result = match target {
+            Value::String(type_name) => value.type_name() == *type_name,
+            Value::Class(class) => match value {
+                Value::Instance(instance) => Gc::ptr_eq(&instance.class, &class),
+                _ => false,
+            },
+            Value::Struct(def) => match value {
+                Value::StructInstance(instance) => Gc::ptr_eq(&instance.def, &def),
+                _ => false,
+            },
+            Value::Protocol(protocol) => match value {
+                Value::Instance(instance) => protocol
+                    .missing_method(|name| Some(*instance.class.method(name)?.function.arity()))
+                    .is_none(),
+                _ => false,
+            },
+            _ => return Err(RuntimeError::ArgumentTypes),
+        };
+
+        self.push(result.into());
+        Ok(())
+    }
+
+    fn get_property(&mut self) -> RunResult<()> {
+        // Stack before: [instance] and after: [value]
+        let name_index = self.read_short();
+        let cache = self.read_byte();
+
+        let receiver = self.pop()?;
+        match receiver {
+            Value::Instance(i) => {
+                if let Some(value) = self
+                    .cached_slot(cache, &i.class)
+                    .and_then(|s| i.get_slot(s))
+                {
+                    self.push(value);
+                    return Ok(());
+                }
+
+                let name = self.string_at(name_index);
+                let field = i
+                    .class
+                    .slot(name)
+                    .and_then(|slot| Some((slot, i.get_slot(slot)?)));
+                if let Some((slot, value)) = field {
+                    self.remember_slot(cache, &i.class, slot);
+                    self.push(value);
+                    Ok(())
+                } else if let Some(method) = i.class.method(name) {
+                    let bound = BoundMethod::new(Value::Instance(i), method);
+                    let bound = self.alloc(bound);
+                    self.push(Value::BoundMethod(bound));
+                    Ok(())
+                } else {
+                    Err(RuntimeError::UndefinedProperty(
+                        name.to_string(),
+                        receiver.type_name(),
+                    ))
+                }
+            }
+            Value::Class(class) => {
+                let name = self.string_at(name_index);
+
+                if let Some(value) = class.static_member(name) {
+                    self.push(value);
+                    Ok(())
+                } else {
+                    Err(RuntimeError::UndefinedProperty(
+                        name.to_string(),
+                        receiver.type_name(),
+                    ))
+                }
+            }
+            Value::StructInstance(s) => {
+                let name = self.string_at(name_index);
+
+                if let Some(slot) = s.def.field_slot(name) {
+                    self.push(s.fields[slot]);
+                    Ok(())
+                } else {
+                    Err(RuntimeError::UndefinedProperty(
+                        name.to_string(),
+                        receiver.type_name(),
+                    ))
+                }
+            }
+            Value::Module(m) => {
+                let name = self.string_at(name_index);
 
-        match self.stack.pop() {
-            Some(Value::Instance(i)) => {
-                let name = self.read_string();
+                if let Some(value) = m.get(name) {
+                    self.push(value);
+                    Ok(())
+                } else {
+                    Err(RuntimeError::UndefinedProperty(
+                        name.to_string(),
+                        receiver.type_name(),
+                    ))
+                }
+            }
+            Value::Foreign(f) => {
+                let name = self.string_at(name_index);
 
-                if let Some(value) = i.fields.get(name) {
-                    self.push(value.clone());
+                if let Some(value) = f.property(name) {
+                    let value = value?;
+                    self.push(value);
+                    Ok(())
+                } else if let Some(method) = f.kind().method(name) {
+                    let bound = method.bind(Value::Foreign(f));
+                    let bound = self.alloc(bound);
+                    self.push(Value::Native(bound));
                     Ok(())
                 } else {
-                    Err(RuntimeError::UndefinedProperty(name.to_string()))
+                    Err(RuntimeError::UndefinedProperty(
+                        name.to_string(),
+                        receiver.type_name(),
+                    ))
                 }
             }
-            _ => todo!(), // TODO
+            Value::Array(array) => {
+                let name = self.string_at(name_index);
+
+                let method = array_method(name).ok_or_else(|| {
+                    RuntimeError::UndefinedProperty(name.to_string(), receiver.type_name())
+                })?;
+                let bound = self.alloc(method.bind(Value::Array(array)));
+                self.push(Value::Native(bound));
+                Ok(())
+            }
+            Value::Iterator(iter) => {
+                let name = self.string_at(name_index);
+
+                let method = iterator_method(name).ok_or_else(|| {
+                    RuntimeError::UndefinedProperty(name.to_string(), receiver.type_name())
+                })?;
+                let bound = self.alloc(method.bind(Value::Iterator(iter)));
+                self.push(Value::Native(bound));
+                Ok(())
+            }
+            _ => Err(RuntimeError::NoProperties(receiver.type_name())),
         }
     }
 
     fn set_property(&mut self) -> RunResult<()> {
-        // Stack before: [instance, value, property] and after: [index(array, index)] TODO After
+        // Stack before: [instance, value] and after: [value]
         let value = self.pop()?;
-
-        let mut instance = self.pop()?.as_instance()?;
-        let property = self.read_string();
-
-        instance.fields.insert(property.to_string(), value.clone());
+        let name_index = self.read_short();
+        let cache = self.read_byte();
+
+        let receiver = self.pop()?;
+        match receiver {
+            Value::StructInstance(mut s) => {
+                let property = self.string_at(name_index);
+
+                // Structs have a fixed set of fields.
+                let slot = s.def.field_slot(property).ok_or_else(|| {
+                    RuntimeError::UndefinedProperty(property.to_string(), receiver.type_name())
+                })?;
+                s.fields[slot] = value;
+            }
+            Value::Instance(mut instance) => {
+                if let Some(slot) = self.cached_slot(cache, &instance.class) {
+                    instance.set_slot(slot, value);
+                } else {
+                    let property = self.string_at(name_index);
+
+                    // Classes that declare their fields only have those.
+                    if instance.class.has_fields() && !instance.class.has_field(property) {
+                        return Err(RuntimeError::UndefinedProperty(
+                            property.to_string(),
+                            receiver.type_name(),
+                        ));
+                    }
+                    let slot = instance.class.add_slot(property);
+                    instance.set_slot(slot, value);
+                    self.remember_slot(cache, &instance.class, slot);
+                }
+            }
+            Value::Class(mut class) => {
+                let property = self.string_at(name_index);
+
+                // Only the class's statics can be assigned.
+                if class.static_member(property).is_none() {
+                    return Err(RuntimeError::UndefinedProperty(
+                        property.to_string(),
+                        receiver.type_name(),
+                    ));
+                }
+                class.set_static(property.to_string(), value);
+            }
+            Value::Foreign(mut f) => {
+                let property = self.string_at(name_index);
+                f.set_property(property, value)?;
+            }
+            _ => return Err(RuntimeError::NoProperties(receiver.type_name())),
+        }
         self.push(value);
 
         Ok(())
     }
 
+    fn assert_fail(&mut self) -> RunResult<()> {
+        // Stack before: [message]
+        let description = self.read_constant_long().as_string().clone();
+        let message = match self.pop()? {
+            Value::Nil => description,
+            Value::String(message) => format!("{}: {}", description, *message),
+            value => format!("{}: {:?}", description, value),
+        };
+
+        Err(RuntimeError::AssertionFailed(message))
+    }
+
     fn read_string(&mut self) -> &String {
-        self.read_constant().as_string()
+        let index = self.read_short();
+        self.string_at(index)
+    }
+
+    fn string_at(&self, index: u16) -> &String {
+        self.current_chunk()
+            .strings()
+            .get(index.into())
+            .expect("Name index out of range of the module's string table.")
+    }
+
+    /// The slot the property cache `cache` of the current chunk remembers for `class`, if it
+    /// last saw an instance of that class.
+    fn cached_slot(&self, cache: u8, class: &Class) -> Option<usize> {
+        let cached = self.current_chunk().property_cache(cache)?.get();
+        if cached.class == class.id() {
+            Some(cached.slot)
+        } else {
+            None
+        }
+    }
+
+    fn remember_slot(&self, cache: u8, class: &Class, slot: usize) {
+        if let Some(cached) = self.current_chunk().property_cache(cache) {
+            cached.set(PropertyCache {
+                class: class.id(),
+                slot,
+            });
+        }
     }
 
     fn read_constant(&mut self) -> &Value {
@@ -351,6 +1139,11 @@ impl VM {
         self.current_chunk().read_constant(constant_index.into())
     }
 
+    fn read_constant_long(&mut self) -> &Value {
+        let constant_index = self.read_short();
+        self.current_chunk().read_constant(constant_index.into())
+    }
+
     fn read_byte(&mut self) -> u8 {
         let index = *self.frame().ip();
         let byte = self.current_chunk_mut().code()[index];
@@ -381,16 +1174,16 @@ impl VM {
         self.stack.last().ok_or(RuntimeError::StackEmpty)
     }
 
-    fn peek_offset(&mut self, offset: usize) -> Value {
+    fn peek_offset(&self, offset: usize) -> Value {
         let index = self.stack.len() - 1 - offset; // TODO Error
-        self.stack[index as usize].clone()
+        self.stack[index]
     }
 
-    fn pop(&mut self) -> RunResult<Value> {
+    pub(crate) fn pop(&mut self) -> RunResult<Value> {
         self.stack.pop().ok_or(RuntimeError::StackEmpty)
     }
 
-    fn frame(&self) -> &CallFrame {
+    pub(crate) fn frame(&self) -> &CallFrame {
         self.frames.last().expect("frames to be nonempty") // TODO Error
     }
 
@@ -398,8 +1191,8 @@ impl VM {
         self.frames.last_mut().expect("frames to be nonempty") // TODO Error
     }
 
-    fn current_chunk(&self) -> &Chunk {
-        &self.frame().closure().function.chunk()
+    pub(crate) fn current_chunk(&self) -> &Chunk {
+        self.frame().closure().function.chunk()
     }
 
     fn current_chunk_mut(&mut self) -> &mut Chunk {
@@ -407,10 +1200,27 @@ impl VM {
     }
 }
 
-#[cfg(test)]
-mod tests {
+/// `index` as a position among `len` elements. It must be a whole number from 0 up to `len`.
+fn element_index(index: f64, len: usize) -> RunResult<usize> {
+    if index >= 0.0 && index.fract() == 0.0 && index < len as f64 {
+        Ok(index as usize)
+    } else {
+        Err(RuntimeError::IndexOutOfBounds(index, len))
+    }
+}
+
+#[cfg(test)]
+mod tests {
     use super::*;
+    use crate::compiler::compiler::Compiler;
+    use crate::compiler::module_resolver::WORKING_DIR;
     use crate::compiler::object::GreenFunction;
+    use crate::compiler::options::{CompileOptions, OptLevel};
+    use crate::syntax::parser::GreenParser;
+    use crate::vm::VmOptions;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use std::time::Duration;
 
     #[test]
     fn it_works() {
@@ -430,4 +1240,2213 @@ mod tests {
         //
         // vm.run().unwrap();
     }
+
+    #[test]
+    fn expression_statements_do_not_grow_stack() {
+        let input = r#"
+        def noop()
+        end
+
+        var result = 0
+        do
+            var i = 0
+            while i < 100 do
+                noop()
+                i + 1
+                i = i + 1
+            end
+
+            var marker = 42
+            result = marker
+        end
+        "#;
+
+        let mut vm = VM::new();
+        vm.interpret(input);
+
+        // Leaked values would shift `marker` away from its local slot.
+        assert_eq!(vm.globals.get("result"), Some(&Value::Number(42.0)));
+        // Only the script's own return value is left behind.
+        assert!(vm.stack.is_empty());
+    }
+
+    #[test]
+    fn if_else_evaluates_to_taken_branch() {
+        let input = r#"
+        var x = if 1 < 2 do 1 else 2 end
+        var y = if 1 > 2 do
+            1
+        else
+            var a = 3
+            a * 2
+        end
+        "#;
+
+        let mut vm = VM::new();
+        vm.interpret(input);
+
+        assert_eq!(vm.globals.get("x"), Some(&Value::Number(1.0)));
+        assert_eq!(vm.globals.get("y"), Some(&Value::Number(6.0)));
+        assert!(vm.stack.is_empty());
+    }
+
+    #[test]
+    fn block_evaluates_to_last_expression() {
+        let input = r#"
+        var result = 0
+        do
+            var a = 1
+            var b = do
+                var c = 2
+                var d = 3
+                a + c + d
+            end
+            result = b
+        end
+        "#;
+
+        let mut vm = VM::new();
+        vm.interpret(input);
+
+        assert_eq!(vm.globals.get("result"), Some(&Value::Number(6.0)));
+        assert!(vm.stack.is_empty());
+    }
+
+    #[test]
+    fn typeof_returns_type_names() {
+        let input = r#"
+        class Point
+        end
+
+        var number = typeof(1)
+        var string = typeof("foo")
+        var array = typeof([1, 2])
+        var function = typeof(typeof)
+        var klass = typeof(Point)
+        var instance = typeof(Point())
+        "#;
+
+        let mut vm = VM::new();
+        vm.interpret(input);
+
+        assert_eq!(vm.globals["number"].as_string(), "Number");
+        assert_eq!(vm.globals["string"].as_string(), "String");
+        assert_eq!(vm.globals["array"].as_string(), "Array");
+        assert_eq!(vm.globals["function"].as_string(), "Function");
+        assert_eq!(vm.globals["klass"].as_string(), "Class");
+        assert_eq!(vm.globals["instance"].as_string(), "Point");
+    }
+
+    #[test]
+    fn is_checks_builtin_types_and_classes() {
+        let input = r#"
+        class Point
+        end
+
+        class Line
+        end
+
+        var point = Point()
+        var a = 1 is Number
+        var b = "foo" is Number
+        var c = point is Point
+        var d = point is Line
+        var e = Point is Class
+        "#;
+
+        let mut vm = VM::new();
+        vm.interpret(input);
+
+        assert!(matches!(vm.globals["a"], Value::True));
+        assert!(matches!(vm.globals["b"], Value::False));
+        assert!(matches!(vm.globals["c"], Value::True));
+        assert!(matches!(vm.globals["d"], Value::False));
+        assert!(matches!(vm.globals["e"], Value::True));
+    }
+
+    #[test]
+    fn loop_locals_are_popped_every_iteration() {
+        let input = r#"
+        def count()
+            var i = 0
+            while i < 10000 do
+                var a = i
+                var b = a + 1
+                if a > 0 do
+                    var c = b
+                end
+                i = i + 1
+            end
+
+            for j in 0 to 10000 do
+                var k = j
+                if k > 0 do
+                    var l = k
+                end
+            end
+
+            var marker = 42
+            return marker
+        end
+
+        var result = count()
+        "#;
+
+        let mut vm = VM::new();
+        vm.interpret(input);
+
+        // Leaked locals would shift `marker` away from its local slot.
+        assert_eq!(vm.globals.get("result"), Some(&Value::Number(42.0)));
+        assert!(vm.stack.is_empty());
+    }
+
+    #[test]
+    fn for_loop_variable_is_scoped_to_the_loop() {
+        let input = r#"
+        for x in 0 to 3 do
+        end
+        "#;
+
+        let mut vm = VM::new();
+        vm.interpret(input);
+
+        assert!(!vm.globals.contains_key("x"));
+        assert!(vm.stack.is_empty());
+    }
+
+    #[test]
+    fn if_without_else_evaluates_to_nil_when_skipped() {
+        let input = r#"
+        var taken = if 1 < 2 do 1 end
+        var skipped = 0
+        var marker = 0
+        do
+            var before = 1
+            skipped = if 1 > 2 do 1 end
+            if 1 > 2 do 1 end
+            if 1 < 2 do 1 end
+            var after = 42
+            marker = after
+        end
+        "#;
+
+        let mut vm = VM::new();
+        vm.interpret(input);
+
+        assert_eq!(vm.globals.get("taken"), Some(&Value::Number(1.0)));
+        assert!(matches!(vm.globals["skipped"], Value::Nil));
+        assert_eq!(vm.globals.get("marker"), Some(&Value::Number(42.0)));
+        assert!(vm.stack.is_empty());
+    }
+
+    #[test]
+    fn functions_can_call_themselves_by_name() {
+        let input = r#"
+        def fib(n)
+            if n < 2 do return n end
+            return fib(n - 1) + fib(n - 2)
+        end
+        var a = fib(10)
+
+        def outer()
+            def fact(n)
+                if n < 2 do return 1 end
+                return n * fact(n - 1)
+            end
+            return fact(5)
+        end
+        var b = outer()
+
+        def count(n)
+            if n < 1 do return 0 end
+            return 1 + count(n - 1)
+        end
+        var g = count
+        count = 0
+        var c = g(3)
+        "#;
+
+        let mut vm = VM::new();
+        vm.interpret(input);
+
+        assert_eq!(vm.globals.get("a"), Some(&Value::Number(55.0)));
+        assert_eq!(vm.globals.get("b"), Some(&Value::Number(120.0)));
+        assert_eq!(vm.globals.get("c"), Some(&Value::Number(3.0)));
+    }
+
+    #[test]
+    fn passing_asserts_do_nothing() {
+        let input = r#"
+        var x = 1
+        assert x == 1
+        assert x < 2, "x should be small"
+        do
+            var y = 2
+            assert y == 2
+        end
+        var marker = 42
+        "#;
+
+        let mut vm = VM::new();
+        vm.interpret(input);
+
+        assert_eq!(vm.globals.get("marker"), Some(&Value::Number(42.0)));
+        assert!(vm.stack.is_empty());
+    }
+
+    #[test]
+    fn failing_assert_reports_line_and_condition() {
+        let input = r#"
+        var x = 1
+        assert x == 2, "x should be two"
+        "#;
+
+        let mut vm = VM::new();
+        let report = vm.run_script("<script>", input).unwrap_err();
+        assert!(
+            report.contains("on line 3: x == 2: x should be two"),
+            "{}",
+            report
+        );
+    }
+
+    #[test]
+    fn runtime_errors_report_the_line_they_happened_on() {
+        let mut vm = VM::with_options(VmOptions {
+            quiet: true,
+            ..VmOptions::default()
+        });
+        let report = vm
+            .run_script("<script>", "var count = 1\nprint count\nprint cuont\n")
+            .unwrap_err();
+        assert_eq!(
+            report,
+            "Runtime error: Tried to access undefined variable `cuont`\n --> <script>:3\n  |\n3 | print cuont\n"
+        );
+    }
+
+    #[test]
+    fn operators_report_the_types_they_cant_work_on() {
+        let run = |source: &str| {
+            let mut vm = VM::with_options(VmOptions {
+                quiet: true,
+                ..VmOptions::default()
+            });
+            vm.run_script("<script>", source).unwrap_err()
+        };
+
+        let report = run("1 + \"a\"\n");
+        assert!(
+            report.contains("Operands of `+` must be numbers, not Number and String."),
+            "{}",
+            report
+        );
+        let report = run("\"a\" < 1\n");
+        assert!(
+            report.contains("Operands of `<` must be numbers, not String and Number."),
+            "{}",
+            report
+        );
+        let report = run("-\"a\"\n");
+        assert!(
+            report.contains("Operand of `-` must be a number, not String."),
+            "{}",
+            report
+        );
+    }
+
+    #[test]
+    fn undefined_properties_name_the_receivers_type() {
+        let mut vm = VM::with_options(VmOptions {
+            quiet: true,
+            ..VmOptions::default()
+        });
+        let report = vm.run_script("<script>", "[1, 2].shove(3)\n").unwrap_err();
+        assert!(
+            report.contains("Tried to access undefined property `shove` on Array"),
+            "{}",
+            report
+        );
+    }
+
+    #[test]
+    fn assert_eq_compares_contents_and_lists_differences() {
+        let run = |source: &str| {
+            let mut vm = VM::with_options(VmOptions {
+                quiet: true,
+                ..VmOptions::default()
+            });
+            let function = Compiler::compile(GreenParser::parse(source).unwrap()).unwrap();
+            let closure = vm.alloc(GreenClosure::new(function));
+            vm.push(Value::Closure(closure));
+            vm.call_value(0)?;
+            vm.run()
+        };
+
+        let equal = r#"
+        struct Point(x, y)
+        assert_eq([1, [2, "three"]], [1, [2, "three"]])
+        assert_eq(map([["a", 1], ["b", 2]]), map([["b", 2], ["a", 1]]))
+        assert_eq(Point(1, 2), Point(1, 2))
+        "#;
+        assert!(run(equal).is_ok());
+
+        let arrays = run("assert_eq([1, 2, [3]], [1, 5, [4], 6])\n").unwrap_err();
+        assert_eq!(
+            arrays.to_string(),
+            "Assertion failed assert_eq: values differ\n  value[1]: 2 != 5\n  value[2][0]: 3 != 4\n  value[3]: missing, expected 6"
+        );
+
+        let source = r#"
+        assert_eq(map([["a", 1], ["c", "x"]]), map([["a", 2], ["b", 3]]))
+        "#;
+        assert_eq!(
+            run(source).unwrap_err().to_string(),
+            "Assertion failed assert_eq: values differ\n  value[\"a\"]: 1 != 2\n  value[\"b\"]: missing, expected 3\n  value[\"c\"]: unexpected \"x\""
+        );
+
+        let source =
+            "assert_eq(\"one\ntwo\nthree\nfour\nfive\nsix\", \"one\n2\nthree\nfour\nfive\n6\")\n";
+        assert_eq!(
+            run(source).unwrap_err().to_string(),
+            "Assertion failed assert_eq: values differ\n  value: strings differ (- expected, + actual)\n      one\n    - 2\n    + two\n      three\n    ...\n      five\n    - 6\n    + six"
+        );
+    }
+
+    #[test]
+    fn top_level_functions_are_hoisted() {
+        let input = r#"
+        var a = even(10)
+        var b = odd(7)
+
+        def even(n)
+            if n == 0 do return true end
+            return odd(n - 1)
+        end
+
+        def odd(n)
+            if n == 0 do return false end
+            return even(n - 1)
+        end
+        "#;
+
+        let mut vm = VM::new();
+        vm.interpret(input);
+
+        assert!(matches!(vm.globals["a"], Value::True));
+        assert!(matches!(vm.globals["b"], Value::True));
+    }
+
+    #[test]
+    fn top_level_classes_are_hoisted() {
+        let input = r#"
+        var p = make()
+        var a = p is Point
+        var b = Point()
+
+        def make()
+            return Point()
+        end
+
+        class Point
+        end
+        "#;
+
+        let mut vm = VM::new();
+        vm.interpret(input);
+
+        assert!(matches!(vm.globals["a"], Value::True));
+        assert_eq!(vm.globals["b"].type_name(), "Point");
+    }
+
+    #[test]
+    fn static_initializers_see_the_variables_before_their_class() {
+        let input = r#"
+        var base = 10
+
+        class C
+            static var start = base
+        end
+
+        var start = C.start
+        "#;
+
+        let mut vm = VM::with_options(VmOptions {
+            quiet: true,
+            ..VmOptions::default()
+        });
+        vm.run_script("<script>", input).unwrap();
+
+        assert_eq!(vm.globals["start"], Value::Number(10.0));
+    }
+
+    #[test]
+    fn interpret_returns_value_of_script() {
+        let mut vm = VM::new();
+        let value = vm.interpret("var x = 2\nx * 3\n");
+        assert_eq!(value, Value::Number(6.0));
+
+        let value = vm.interpret("return x + 1\nx\n");
+        assert_eq!(value, Value::Number(3.0));
+
+        let value = vm.interpret("var y = 1\n");
+        assert!(matches!(value, Value::Nil));
+        assert!(vm.stack.is_empty());
+    }
+
+    #[test]
+    fn structs_have_fixed_fields() {
+        let input = r#"
+        struct Point(x, y)
+
+        var p = Point(1, 2)
+        p.y = 5
+        var sum = p.x + p.y
+        var a = p is Point
+        var b = typeof(Point)
+        var c = typeof(p)
+        "#;
+
+        let mut vm = VM::new();
+        vm.interpret(input);
+
+        assert_eq!(vm.globals.get("sum"), Some(&Value::Number(6.0)));
+        assert!(matches!(vm.globals["a"], Value::True));
+        assert_eq!(vm.globals["b"].as_string(), "Struct");
+        assert_eq!(vm.globals["c"].as_string(), "Point");
+    }
+
+    #[test]
+    fn structs_reject_unknown_fields() {
+        let input = r#"
+        struct Point(x, y)
+        var p = Point(1, 2)
+        p.z = 3
+        "#;
+
+        let mut vm = VM::new();
+        let report = vm.run_script("<script>", input).unwrap_err();
+        assert!(
+            report.contains("Tried to access undefined property `z` on Point"),
+            "{}",
+            report
+        );
+    }
+
+    #[test]
+    fn classes_declare_their_fields() {
+        let input = r#"
+        class Point
+            var x: Number
+            var y: Number
+
+            def init(x, y)
+                self.x = x
+                self.y = y
+            end
+
+            def sum()
+                return self.x + self.y
+            end
+        end
+
+        var p = Point(1, 2)
+        p.y = 5
+        var sum = p.sum()
+        "#;
+
+        let mut vm = VM::new();
+        vm.interpret(input);
+        assert_eq!(vm.globals.get("sum"), Some(&Value::Number(6.0)));
+        match &vm.globals["Point"] {
+            Value::Class(class) => assert_eq!(class.fields(), ["x", "y"]),
+            _ => panic!("Expected a class"),
+        }
+    }
+
+    #[test]
+    fn classes_have_static_members() {
+        let input = r#"
+        class Counter
+            var n: Number
+            static var created = 0
+            static var label = "counter"
+
+            def init()
+                self.n = 0
+                Counter.created = Counter.created + 1
+            end
+
+            static def make_two()
+                return [Counter(), Counter()]
+            end
+        end
+
+        var pair = Counter.make_two()
+        var c = Counter()
+        var created = Counter.created
+        var label = Counter.label
+        "#;
+
+        let mut vm = VM::new();
+        vm.interpret(input);
+        assert_eq!(vm.globals.get("created"), Some(&Value::Number(3.0)));
+        assert_eq!(vm.globals["label"].as_string(), "counter");
+        match vm.globals["c"] {
+            Value::Instance(c) => assert_eq!(c.get_property("created"), None),
+            _ => panic!("Expected an instance"),
+        }
+    }
+
+    #[test]
+    fn only_statics_can_be_assigned_on_classes() {
+        let input = r#"
+        class Counter
+            static var created = 0
+        end
+
+        Counter.total = 1
+        "#;
+
+        let mut vm = VM::new();
+        let report = vm.run_script("<script>", input).unwrap_err();
+        assert!(
+            report.contains("Tried to access undefined property `total` on Class"),
+            "{}",
+            report
+        );
+    }
+
+    #[test]
+    fn is_checks_protocols_by_their_methods() {
+        let input = r#"
+        protocol Shape
+            def area()
+            def scale(factor)
+        end
+
+        class Square implements Shape
+            var side: Number
+            def init(side) do self.side = side end
+            def area() do return self.side * self.side end
+            def scale(factor) do return Square(self.side * factor) end
+        end
+
+        class Circle
+            def area() do return 3 end
+            def scale(factor) do return self end
+        end
+
+        class Label
+            def area(x) do return x end
+        end
+
+        var square = Square(2) is Shape
+        var circle = Circle() is Shape
+        var label = Label() is Shape
+        var number = 4 is Shape
+        var kind = typeof(Shape)
+        var declared = Shape is Protocol
+        "#;
+
+        let mut vm = VM::new();
+        vm.interpret(input);
+        assert_eq!(vm.globals["square"], Value::True);
+        assert_eq!(vm.globals["circle"], Value::True);
+        assert_eq!(vm.globals["label"], Value::False);
+        assert_eq!(vm.globals["number"], Value::False);
+        assert_eq!(vm.globals["kind"].as_string(), "Protocol");
+        assert_eq!(vm.globals["declared"], Value::True);
+    }
+
+    #[test]
+    fn macros_expand_hygienically() {
+        let input = r#"
+        macro unless(cond, body) -> if !cond do body end
+        macro swap(a, b) -> do
+            var tmp = a
+            a = b
+            b = tmp
+        end
+
+        var tmp = 1
+        var other = 2
+        swap(tmp, other)
+
+        var hits = 0
+        unless(tmp > 5, hits = hits + 1)
+        unless(tmp < 5, hits = hits + 10)
+        "#;
+
+        let mut vm = VM::new();
+        vm.interpret(input);
+        assert_eq!(vm.globals.get("tmp"), Some(&Value::Number(2.0)));
+        assert_eq!(vm.globals.get("other"), Some(&Value::Number(1.0)));
+        assert_eq!(vm.globals.get("hits"), Some(&Value::Number(1.0)));
+
+        let err = GreenParser::parse("macro twice(x) -> x + x\ntwice(1, 2)\n").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Macro 'twice' takes 1 arguments, on line: 2"
+        );
+        let err = GreenParser::parse("macro reset(x) -> x = 0\nreset(1)\n").unwrap_err();
+        assert_eq!(err.line(), Some(2));
+    }
+
+    #[test]
+    fn instances_reject_undeclared_fields() {
+        let input = r#"
+        class Point
+            var x: Number
+        end
+
+        var p = Point()
+        p.z = 3
+        "#;
+
+        let mut vm = VM::new();
+        let report = vm.run_script("<script>", input).unwrap_err();
+        assert!(
+            report.contains("Tried to access undefined property `z` on Point"),
+            "{}",
+            report
+        );
+    }
+
+    #[test]
+    fn methods_can_only_use_declared_fields() {
+        let input = r#"
+        class Point
+            var x: Number
+
+            def get()
+                return self.z
+            end
+
+            def set()
+                self.get = 1
+            end
+        end
+        "#;
+
+        let errors = Compiler::compile(GreenParser::parse(input).unwrap()).unwrap_err();
+        let errors: Vec<(usize, &str)> = errors
+            .iter()
+            .map(|err| (err.line, err.message.as_str()))
+            .collect();
+        assert_eq!(
+            errors,
+            [
+                (6, "Point has no field or method called z."),
+                (10, "Point has no field called get."),
+            ]
+        );
+    }
+
+    #[test]
+    fn fields_are_assigned_literals_of_their_type() {
+        let input = r#"
+        class Point
+            var x: Number
+
+            def init()
+                self.x = "one"
+            end
+        end
+        "#;
+
+        let errors = Compiler::compile(GreenParser::parse(input).unwrap()).unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].line, 6);
+        assert_eq!(
+            errors[0].message,
+            "Field x of Point is a Number, but is assigned a String."
+        );
+    }
+
+    #[test]
+    fn imports_that_cant_be_read_are_compile_errors() {
+        let _cwd = WORKING_DIR.lock().unwrap_or_else(|err| err.into_inner());
+        let module = format!("not_utf8_{}", std::process::id());
+        let path = format!("lib/{}.green", module);
+        std::fs::write(&path, b"print \"caf\xe9\"\n").unwrap();
+        let mut vm = VM::with_options(VmOptions {
+            quiet: true,
+            ..VmOptions::default()
+        });
+        let report = vm.run_script("<script>", format!("import {}\n", module));
+        std::fs::remove_file(&path).unwrap();
+
+        let report = report.unwrap_err();
+        assert!(
+            report.contains(&format!("Could not import {}", module)),
+            "{}",
+            report
+        );
+        assert!(report.contains(&path), "{}", report);
+        assert!(
+            report.contains("file is not valid UTF-8, from line 1"),
+            "{}",
+            report
+        );
+
+        let report = vm
+            .run_script("<script>", "print 1\nimport no.such.module\n")
+            .unwrap_err();
+        assert!(
+            report.contains("Could not import no.such.module"),
+            "{}",
+            report
+        );
+        assert!(report.contains("on line: 2"), "{}", report);
+    }
+
+    #[test]
+    fn call_main_passes_args() {
+        let input = r#"
+        def main(args)
+            return args[1]
+        end
+        "#;
+
+        let mut vm = VM::new();
+        vm.interpret(input);
+        let result = vm.call_main(vec!["a".to_string(), "b".to_string()]);
+
+        assert_eq!(result.unwrap().unwrap().as_string(), "b");
+        assert!(VM::new().call_main(vec![]).unwrap().is_none());
+    }
+
+    #[test]
+    fn call_main_reports_a_main_with_too_many_parameters() {
+        let mut vm = VM::new();
+        vm.interpret("def main(a, b)\nend\n");
+
+        let err = vm.call_main(vec![]).unwrap_err();
+        assert!(matches!(err, RuntimeError::MainArity(2)));
+        assert_eq!(
+            vm.run_script("<script>", "1 + 1").unwrap(),
+            Value::Number(2.0)
+        );
+    }
+
+    #[test]
+    fn call_main_returns_runtime_errors_from_main() {
+        let mut vm = VM::new();
+        vm.interpret("def main()\n    return missing\nend\n");
+
+        let err = vm.call_main(vec![]).unwrap_err();
+        let report = vm.error_report(&err);
+        assert!(
+            report.contains("undefined variable `missing`"),
+            "{}",
+            report
+        );
+    }
+
+    #[test]
+    fn builtins_are_namespaced_in_modules() {
+        let input = r#"
+        var a = core.typeof(1)
+        var b = typeof(core)
+        var same_fn = typeof(core.typeof)
+        "#;
+
+        let mut vm = VM::new();
+        vm.interpret(input);
+
+        assert_eq!(vm.globals["a"].as_string(), "Number");
+        assert_eq!(vm.globals["b"].as_string(), "Module");
+        assert_eq!(vm.globals["same_fn"].as_string(), "Function");
+    }
+
+    #[test]
+    fn classes_overload_operators_with_methods() {
+        let input = r#"
+        class Vec
+            def init(x)
+                self.x = x
+            end
+
+            def add(other)
+                return Vec(self.x + other.x)
+            end
+
+            def eq(other)
+                return self.x == other.x
+            end
+
+            def lt(other)
+                return self.x < other.x
+            end
+
+            def tostring()
+                return "vec"
+            end
+        end
+
+        var v = Vec(1) + Vec(2)
+        var sum = v.x
+        var a = Vec(3) == v
+        var b = Vec(3) != v
+        var c = Vec(1) < Vec(2)
+        var d = Vec(1) > Vec(2)
+        var e = Vec(1) >= Vec(2)
+        print v
+        "#;
+
+        let mut vm = VM::new();
+        vm.interpret(input);
+
+        assert_eq!(vm.globals.get("sum"), Some(&Value::Number(3.0)));
+        assert!(matches!(vm.globals["a"], Value::True));
+        assert!(matches!(vm.globals["b"], Value::False));
+        assert!(matches!(vm.globals["c"], Value::True));
+        assert!(matches!(vm.globals["d"], Value::False));
+        assert!(matches!(vm.globals["e"], Value::False));
+        assert!(vm.stack.is_empty());
+    }
+
+    #[test]
+    fn comparing_instances_without_lt_is_an_error() {
+        let input = r#"
+        class A
+        end
+
+        var bigger = A() > A()
+        "#;
+
+        let mut vm = VM::with_options(VmOptions {
+            quiet: true,
+            ..VmOptions::default()
+        });
+        let report = vm.run_script("<script>", input).unwrap_err();
+        assert!(
+            report.contains("Instance of A does not support `>`."),
+            "{}",
+            report
+        );
+    }
+
+    #[test]
+    fn superinstructions_fall_back_to_operator_methods() {
+        let input = r#"
+        class Counter
+            def init(n)
+                self.n = n
+            end
+
+            def add(n)
+                return Counter(self.n + n)
+            end
+
+            def eq(n)
+                return self.n == n
+            end
+
+            def lt(n)
+                return self.n < n
+            end
+        end
+
+        def run()
+            var c = Counter(0)
+            var hits = 0
+            while c < 3 do
+                c = c + 1
+                if c == 2 do hits = hits + 1 end
+            end
+            return [c.n, hits]
+        end
+
+        var result = run()
+        "#;
+
+        let mut vm = VM::new();
+        vm.interpret(input);
+
+        assert_eq!(format!("{}", vm.globals["result"]), "[3, 1]");
+        assert!(vm.stack.is_empty());
+    }
+
+    #[test]
+    fn match_runs_the_first_arm_with_an_equal_case() {
+        let input = r#"
+        class Num
+            def init(n)
+                self.n = n
+            end
+
+            def eq(n)
+                return self.n == n
+            end
+        end
+
+        def name(n)
+            return match n
+            case 0 do "zero"
+            case 1, 3 do "odd"
+            case 2, 4 do
+                var half = n / 2
+                half
+            case 1 do "never"
+            else "many"
+            end
+        end
+
+        def route(cmd)
+            match cmd
+            case "add" do return 1
+            case "sub" do return 2
+            case "mul" do return 3
+            case "div" do return 4
+            end
+            return 0
+        end
+
+        var names = [name(0), name(1), name(4), name(7), name(2.5), name("x"), name(Num(3))]
+        var routes = [route("add"), route("div"), route("nope"), route(1)]
+        var nothing = match 5
+        case 6 do 1
+        end
+        "#;
+
+        // Optimizing turns both matches into jump tables, which must behave the same.
+        for opt_level in OptLevel::ALL {
+            let module = GreenParser::parse(input).unwrap();
+            let function = Compiler::compile_with(
+                module,
+                CompileOptions {
+                    opt_level,
+                    ..CompileOptions::default()
+                },
+            )
+            .unwrap();
+
+            let mut vm = VM::new();
+            let closure = vm.alloc(GreenClosure::new(function));
+            vm.push(Value::Closure(closure));
+            vm.call_value(0).unwrap();
+            vm.run().unwrap();
+
+            assert_eq!(
+                format!("{}", vm.globals["names"]),
+                r#"["zero", "odd", 2, "many", "many", "many", "odd"]"#
+            );
+            assert_eq!(format!("{}", vm.globals["routes"]), "[1, 4, 0, 0]");
+            assert!(matches!(vm.globals["nothing"], Value::Nil));
+        }
+    }
+
+    #[test]
+    fn match_destructures_arrays_and_instances() {
+        let input = r#"
+        class Point
+            def init(x, y)
+                self.x = x
+                self.y = y
+            end
+        end
+
+        struct Pair(a, b)
+
+        def describe(value)
+            return match value
+            case [] do "empty"
+            case [first, [inner, *_], *rest] do [first, inner, rest]
+            case [first, *rest] do [first, rest]
+            case Point(x, y: 0) do ["x axis", x]
+            case Point(x, y) do [x, y]
+            case Pair(a, b) do [b, a]
+            case _ do "other"
+            end
+        end
+
+        var arrays = [describe([]), describe([1, [2, 3], 4, 5]), describe([1])]
+        var instances = [describe(Point(3, 0)), describe(Point(3, 4)), describe(Pair(5, 6))]
+        var other = describe(7)
+        "#;
+
+        let mut vm = VM::new();
+        vm.interpret(input);
+
+        assert_eq!(
+            format!("{}", vm.globals["arrays"]),
+            r#"["empty", [1, 2, [4, 5]], [1, []]]"#
+        );
+        assert_eq!(
+            format!("{}", vm.globals["instances"]),
+            r#"[["x axis", 3], [3, 4], [6, 5]]"#
+        );
+        assert_eq!(format!("{}", vm.globals["other"]), "other");
+        assert!(vm.stack.is_empty());
+    }
+
+    #[test]
+    fn property_access_on_non_instances_is_an_error() {
+        let mut vm = VM::new();
+        vm.interpret("var s = \"foo\"\nvar n = 1\n");
+
+        for (source, expected) in [
+            ("s.length\n", "Only instances have properties, not String."),
+            ("n.x = 2\n", "Only instances have properties, not Number."),
+            ("n(1)\n", "Can only call functions and classes, not Number."),
+        ] {
+            let module = GreenParser::parse(source).unwrap();
+            let function = Compiler::compile(module).unwrap();
+            let closure = vm.alloc(GreenClosure::new(function));
+            vm.push(Value::Closure(closure));
+            vm.call_value(0).unwrap();
+
+            let error = vm.run().unwrap_err();
+            assert_eq!(error.to_string(), expected);
+
+            vm.frames.clear();
+            vm.stack.clear();
+        }
+    }
+
+    #[test]
+    fn array_literals_can_have_more_than_255_elements() {
+        // Variables keep the elements out of the constant pool.
+        let input = format!(
+            "var x = 1\nvar a = [{}, 2]\na[299] + a[0]\n",
+            vec!["x"; 299].join(", ")
+        );
+
+        let mut vm = VM::new();
+        let result = vm.interpret(input);
+
+        assert_eq!(result.as_number(), 3.0);
+        assert!(vm.stack.is_empty());
+    }
+
+    #[test]
+    fn array_literals_are_limited_to_u16_elements() {
+        let input = format!("var x = 1\n[{}]\n", vec!["x"; 65536].join(", "));
+        let options = CompileOptions {
+            disassemble: false,
+            ..CompileOptions::default()
+        };
+
+        let errors =
+            Compiler::compile_with(GreenParser::parse(&input).unwrap(), options).unwrap_err();
+        let messages: Vec<&str> = errors.iter().map(|err| err.message.as_str()).collect();
+        assert_eq!(
+            messages,
+            ["Array literal has 65536 elements, but can have at most 65535"]
+        );
+    }
+
+    #[test]
+    fn constants_past_the_first_256_are_loaded_with_a_long_operand() {
+        let numbers: Vec<String> = (0..300).map(|i| format!("{}.5", i)).collect();
+        let input = format!(
+            "var numbers = [{}]\nvar last = numbers[299]\n",
+            numbers.join(", ")
+        );
+
+        let mut vm = VM::new();
+        vm.interpret(input);
+        assert_eq!(vm.global("last"), Some(&Value::Number(299.5)));
+    }
+
+    #[test]
+    fn unknown_opcodes_are_runtime_errors() {
+        let mut function = GreenFunction::new();
+        function.chunk_mut().write_byte(0xff);
+
+        let mut vm = VM::new();
+        let closure = vm.alloc(GreenClosure::new(Gc::new(function)));
+        vm.push(Value::Closure(closure));
+        vm.call_value(0).unwrap();
+
+        let error = vm.run().unwrap_err();
+        assert_eq!(error.to_string(), "Unknown opcode 0xff.");
+    }
+
+    #[test]
+    fn str_formats_values_for_display() {
+        let input = r#"
+        class Point
+        end
+
+        class Named
+            def tostring()
+                return "named"
+            end
+        end
+
+        struct Pair(a, b)
+
+        def add(a, b)
+            return a + b
+        end
+
+        var number = str(5)
+        var fraction = str(2.5)
+        var boolean = str(true)
+        var array = str([1, "two", [3]])
+        var fun = str(add)
+        var native = str(typeof)
+        var instance = str(Point())
+        var named = str(Named())
+        var pair = str(Pair(1, 2))
+        "#;
+
+        let mut vm = VM::new();
+        vm.interpret(input);
+
+        let expected = [
+            ("number", "5"),
+            ("fraction", "2.5"),
+            ("boolean", "true"),
+            ("array", "[1, \"two\", [3]]"),
+            ("fun", "<fn add/2>"),
+            ("native", "<native typeof/1>"),
+            ("instance", "Point instance"),
+            ("named", "named"),
+            ("pair", "Pair(1, 2)"),
+        ];
+        for (name, string) in expected {
+            assert_eq!(vm.globals[name].as_string(), string);
+        }
+    }
+
+    #[test]
+    fn comprehensions_build_arrays() {
+        let input = r#"
+        def squares(n)
+            return [x * x for x in 0 to n if x % 2 == 0]
+        end
+
+        var words = ["a", "bb", "ccc"]
+        var evens = str(squares(10))
+        var lengths = str([len(w) for w in words])
+        var countdown = str([x for x in 6 downTo 0 step 2])
+        var nested = str([[y for y in 0 to x] for x in 1 to 3])
+        "#;
+
+        let mut vm = VM::new();
+        vm.interpret(input);
+
+        let expected = [
+            ("evens", "[0, 4, 16, 36, 64]"),
+            ("lengths", "[1, 2, 3]"),
+            ("countdown", "[6, 4, 2]"),
+            ("nested", "[[0], [0, 1]]"),
+        ];
+        for (name, string) in expected {
+            assert_eq!(vm.globals[name].as_string(), string);
+        }
+        assert!(vm.stack.is_empty());
+    }
+
+    #[test]
+    fn maps_store_values_by_key() {
+        let input = r#"
+        var prices = {"apple": 2, "pear": 3, 1: true,}
+        prices["plum"] = 5
+        prices["apple"] = 4
+
+        var shown = str(prices)
+        var apple = prices["apple"]
+        var missing = str(prices["kiwi"])
+        var size = len(prices)
+        var kind = typeof({})
+        "#;
+
+        let mut vm = VM::new();
+        vm.interpret(input);
+
+        let shown = "{\"apple\": 4, \"pear\": 3, 1: true, \"plum\": 5}";
+        assert_eq!(vm.globals["shown"].as_string(), shown);
+        assert_eq!(vm.globals["apple"].as_number(), 4.0);
+        assert_eq!(vm.globals["missing"].as_string(), "nil");
+        assert_eq!(vm.globals["size"].as_number(), 4.0);
+        assert_eq!(vm.globals["kind"].as_string(), "Map");
+    }
+
+    #[test]
+    fn ranges_are_values() {
+        let input = r#"
+        var r = 1 to 10 step 3
+        var down = 5 downTo 0
+        var shown = str(r)
+        var shown_down = str(down)
+        var numbers = str(array(r))
+        var third = r[2]
+        var size = len(down)
+        var squares = str([x * x for x in r])
+        var same = r == (1 to 10 step 3)
+        var kind = typeof(r)
+        var expanded = str([0 to 3, 10])
+        "#;
+
+        let mut vm = VM::new();
+        vm.interpret(input);
+
+        let expected = [
+            ("shown", "1 to 10 step 3"),
+            ("shown_down", "5 downTo 0"),
+            ("numbers", "[1, 4, 7]"),
+            ("squares", "[1, 16, 49]"),
+            ("kind", "Range"),
+            ("expanded", "[0, 1, 2, 10]"),
+        ];
+        for (name, string) in expected {
+            assert_eq!(vm.globals[name].as_string(), string);
+        }
+        assert_eq!(vm.globals["third"], Value::Number(7.0));
+        assert_eq!(vm.globals["size"], Value::Number(5.0));
+        assert_eq!(vm.globals["same"], Value::True);
+    }
+
+    #[test]
+    fn indexing_past_the_end_of_a_range_fails() {
+        let mut vm = VM::with_options(VmOptions {
+            quiet: true,
+            ..VmOptions::default()
+        });
+        let report = vm
+            .run_script("<script>", "var r = 0 to 3\nprint r[3]\n")
+            .unwrap_err();
+        assert!(
+            report.contains("Index 3 is out of bounds for length 3."),
+            "{}",
+            report
+        );
+    }
+
+    #[test]
+    fn array_indices_must_be_whole_numbers_in_bounds() {
+        let cases = [
+            (
+                "var a = [1, 2]\nprint a[5]\n",
+                "Index 5 is out of bounds for length 2.",
+            ),
+            (
+                "var a = [1, 2]\nprint a[-1]\n",
+                "Index -1 is out of bounds for length 2.",
+            ),
+            (
+                "var a = [1, 2]\nprint a[0.5]\n",
+                "Index 0.5 is out of bounds for length 2.",
+            ),
+            (
+                "var a = [1, 2]\na[2] = 3\n",
+                "Index 2 is out of bounds for length 2.",
+            ),
+            (
+                "var a = [1, 2]\na[-1] = 3\n",
+                "Index -1 is out of bounds for length 2.",
+            ),
+            (
+                "var a = [1, 2]\na[1.5] = 3\n",
+                "Index 1.5 is out of bounds for length 2.",
+            ),
+            (
+                "def pair()\n    return 1, 2\nend\nvar t = pair()\nprint t[2]\n",
+                "Index 2 is out of bounds for length 2.",
+            ),
+        ];
+        for (source, message) in cases {
+            let mut vm = VM::with_options(VmOptions {
+                quiet: true,
+                ..VmOptions::default()
+            });
+            let report = vm.run_script("<script>", source).unwrap_err();
+            assert!(report.contains(message), "{}", report);
+        }
+    }
+
+    #[test]
+    fn map_comprehensions_and_constructor_build_maps() {
+        let input = r#"
+        var prices = {"apple": 2, "pear": 3}
+        var doubled = str({k: v * 2 for k, v in prices})
+        var cheap = str({k: v for k, v in prices if v < 3})
+        var squares = str({x: x * x for x in 1 to 4})
+        var from_pairs = str(map([["a", 1], ["b", 2]]))
+        var keys = str([pair[0] for pair in prices])
+        "#;
+
+        let mut vm = VM::new();
+        vm.interpret(input);
+
+        let expected = [
+            ("doubled", "{\"apple\": 4, \"pear\": 6}"),
+            ("cheap", "{\"apple\": 2}"),
+            ("squares", "{1: 1, 2: 4, 3: 9}"),
+            ("from_pairs", "{\"a\": 1, \"b\": 2}"),
+            ("keys", "[\"apple\", \"pear\"]"),
+        ];
+        for (name, string) in expected {
+            assert_eq!(vm.globals[name].as_string(), string);
+        }
+        assert!(vm.stack.is_empty());
+    }
+
+    #[test]
+    fn comprehensions_loop_over_a_snapshot_of_their_source() {
+        let input = r#"
+        var xs = [1, 2, 3]
+        def bump(x) do xs[2] = 30; return x end
+        var seen = str([bump(x) for x in xs])
+        var after = str(xs)
+
+        var m = {"a": 1}
+        def grow(v) do m["a"] = 10; m["b"] = 2; return v end
+        var values = str([grow(v) for k, v in m])
+        var grown = str(m)
+        "#;
+
+        let mut vm = VM::new();
+        vm.interpret(input);
+
+        let expected = [
+            ("seen", "[1, 2, 3]"),
+            ("after", "[1, 2, 30]"),
+            ("values", "[1]"),
+            ("grown", "{\"a\": 10, \"b\": 2}"),
+        ];
+        for (name, string) in expected {
+            assert_eq!(vm.globals[name].as_string(), string);
+        }
+    }
+
+    #[test]
+    fn array_methods_call_script_functions() {
+        let input = r#"
+        def double(x) do return x * 2 end
+        def odd(x) do return x % 2 == 1 end
+        def add(a, b) do return a + b end
+        def descending(a, b) do return b - a end
+
+        var xs = [3, 1, 2]
+        var doubled = str(xs.map(double))
+        var odds = str(xs.filter(odd))
+        var total = xs.reduce(add, 10)
+        var sorted = str(xs.sort())
+        var names = str(["b", "c", "a"].sort())
+        var reversed = str(xs.sort_by(descending))
+        var has = xs.contains(2)
+        var lacks = xs.contains(5)
+        var at = xs.index_of(2)
+        var nowhere = xs.index_of(5) is Nil
+        var unchanged = str(xs)
+        "#;
+
+        let mut vm = VM::new();
+        vm.interpret(input);
+
+        let expected = [
+            ("doubled", "[6, 2, 4]"),
+            ("odds", "[3, 1]"),
+            ("sorted", "[1, 2, 3]"),
+            ("names", "[\"a\", \"b\", \"c\"]"),
+            ("reversed", "[3, 2, 1]"),
+            ("unchanged", "[3, 1, 2]"),
+        ];
+        for (name, string) in expected {
+            assert_eq!(vm.globals[name].as_string(), string);
+        }
+        assert_eq!(vm.globals["total"], Value::Number(16.0));
+        assert_eq!(vm.globals["has"], Value::True);
+        assert_eq!(vm.globals["lacks"], Value::False);
+        assert_eq!(vm.globals["at"], Value::Number(2.0));
+        assert_eq!(vm.globals["nowhere"], Value::True);
+        assert!(vm.stack.is_empty());
+    }
+
+    #[test]
+    fn for_loops_iterate_over_collections_and_classes() {
+        let input = r#"
+        class Countdown
+            var n: Number
+
+            def init(n)
+                self.n = n
+            end
+
+            def iter()
+                return self
+            end
+
+            def has_next()
+                return self.n > 0
+            end
+
+            def next()
+                self.n = self.n - 1
+                return self.n + 1
+            end
+        end
+
+        var total = 0
+        for x in [1, 2, 3] do total = total + x end
+        var letters = 0
+        for c in "héllo" do letters = letters + 1 end
+        var values = 0
+        for k, v in {"a": 1, "b": 2} do values = values + v end
+        var r = 0 to 4
+        var counted = 0
+        for x in r do counted = counted + x end
+        var digits = 0
+        for x in Countdown(3) do digits = digits * 10 + x end
+        var doubled = str([x * 2 for x in Countdown(2)])
+
+        var it = iter([7])
+        var first = it.next()
+        var more = it.has_next()
+        "#;
+
+        let mut vm = VM::new();
+        vm.interpret(input);
+
+        assert_eq!(vm.globals["total"], Value::Number(6.0));
+        assert_eq!(vm.globals["letters"], Value::Number(5.0));
+        assert_eq!(vm.globals["values"], Value::Number(3.0));
+        assert_eq!(vm.globals["counted"], Value::Number(6.0));
+        assert_eq!(vm.globals["digits"], Value::Number(321.0));
+        assert_eq!(vm.globals["doubled"].as_string(), "[4, 2]");
+        assert_eq!(vm.globals["first"], Value::Number(7.0));
+        assert_eq!(vm.globals["more"], Value::False);
+        assert!(vm.stack.is_empty());
+    }
+
+    #[test]
+    fn looping_over_a_number_fails() {
+        let mut vm = VM::with_options(VmOptions {
+            quiet: true,
+            ..VmOptions::default()
+        });
+        let report = vm
+            .run_script("<script>", "for x in 5 do end\n")
+            .unwrap_err();
+        assert!(
+            report.contains("Can't loop over a value of type Number."),
+            "{}",
+            report
+        );
+    }
+
+    #[test]
+    fn map_keys_must_be_hashable() {
+        let mut vm = VM::new();
+        let module = GreenParser::parse("var m = {[1]: 2}\n").unwrap();
+        let function = Compiler::compile(module).unwrap();
+        let closure = vm.alloc(GreenClosure::new(function));
+        vm.push(Value::Closure(closure));
+        vm.call_value(0).unwrap();
+
+        let error = vm.run().unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Can't use a value of type Array as a map key."
+        );
+    }
+
+    #[test]
+    fn blocks_nested_in_expressions_keep_their_locals_apart() {
+        let input = r#"
+        def f(a)
+            var b = 10
+            return a + b + do
+                var c = 100
+                c
+            end
+        end
+
+        var sum = f(1)
+        var inner = str([1, [x for x in 0 to 2]])
+        "#;
+
+        let mut vm = VM::new();
+        vm.interpret(input);
+
+        assert_eq!(vm.globals["sum"].as_number(), 111.0);
+        assert_eq!(vm.globals["inner"].as_string(), "[1, [0, 1]]");
+        assert!(vm.stack.is_empty());
+    }
+
+    #[test]
+    fn arrays_are_shared_by_reference() {
+        let input = r#"
+        var a = [1, 2]
+        var b = a
+        var stored = b[0] = 5
+        a[0] + stored
+        "#;
+
+        let mut vm = VM::new();
+        let result = vm.interpret(input);
+
+        assert_eq!(result.as_number(), 10.0);
+        assert!(vm.stack.is_empty());
+    }
+
+    #[test]
+    fn ranges_expand_in_array_literals() {
+        let input = r#"
+        def count(n)
+            return [0 to n]
+        end
+
+        var up = str([1 to 4])
+        var mixed = str([0, 5 downTo 2, 9])
+        var stepped = str([0 to 10 step 4])
+        var called = str(count(3))
+        var plain = str(range(1, 3))
+        var nested = str([range(1, 3)])
+        "#;
+
+        let mut vm = VM::new();
+        vm.interpret(input);
+
+        let expected = [
+            ("up", "[1, 2, 3]"),
+            ("mixed", "[0, 5, 4, 3, 9]"),
+            ("stepped", "[0, 4, 8]"),
+            ("called", "[0, 1, 2]"),
+            ("plain", "[1, 2]"),
+            ("nested", "[[1, 2]]"),
+        ];
+        for (name, string) in expected {
+            assert_eq!(vm.globals[name].as_string(), string);
+        }
+    }
+
+    #[test]
+    fn range_calls_in_array_literals_call_the_scripts_range() {
+        let input = r#"
+        def range(a, b)
+            return a * b
+        end
+
+        var products = str([range(2, 3), range(4, 5)])
+        "#;
+
+        let mut vm = VM::new();
+        vm.interpret(input);
+
+        assert_eq!(vm.globals["products"].as_string(), "[6, 20]");
+    }
+
+    #[test]
+    fn property_ops_follow_stack_contract() {
+        let input = r#"
+        class Node
+        end
+
+        def make()
+            var n = Node()
+            n.next = Node()
+            n.next.next = Node()
+            return n
+        end
+
+        var a = make()
+        var items = [a]
+        var marker = 0
+        do
+            var before = 1
+            a.next.next.value = 3
+            var result = items[0].next.next.value = a.next.next.value + 1
+            var after = 42
+            marker = after + result
+        end
+        var value = a.next.next.value
+        "#;
+
+        let mut vm = VM::new();
+        vm.interpret(input);
+
+        assert_eq!(vm.globals.get("value"), Some(&Value::Number(4.0)));
+        assert_eq!(vm.globals.get("marker"), Some(&Value::Number(46.0)));
+        assert!(vm.stack.is_empty());
+    }
+
+    #[test]
+    fn property_caches_follow_the_class_of_each_instance() {
+        let input = r#"
+        class A
+        end
+        class B
+        end
+
+        var a = A()
+        a.x = 1
+        a.y = 2
+        var b = B()
+        b.y = 20
+        b.x = 10
+
+        def total(o) do return o.x + o.y end
+
+        var sum = 0
+        for i in 0 to 10 do
+            sum = sum + total(a) + total(b)
+            a.x = a.x + 1
+        end
+        var c = A()
+        c.y = 5
+        "#;
+
+        let mut vm = VM::new();
+        vm.interpret(input);
+
+        assert_eq!(vm.globals.get("sum"), Some(&Value::Number(375.0)));
+        let (a, b, c) = match (vm.globals["a"], vm.globals["b"], vm.globals["c"]) {
+            (Value::Instance(a), Value::Instance(b), Value::Instance(c)) => (a, b, c),
+            _ => panic!("Expected instances"),
+        };
+        assert_eq!(a.get_property("x"), Some(Value::Number(11.0)));
+        assert_eq!(b.get_property("x"), Some(Value::Number(10.0)));
+        assert_eq!(c.get_property("x"), None);
+        assert_eq!(c.fields().collect::<Vec<_>>(), [("y", Value::Number(5.0))]);
+
+        // The call site last saw a `B`, where `x` is in the second slot.
+        let total = match vm.globals["total"] {
+            Value::Closure(closure) => closure.function,
+            _ => panic!("Expected a function"),
+        };
+        let cached = total.chunk().property_cache(0).unwrap().get();
+        assert_eq!(cached.class, b.class.id());
+        assert_eq!(cached.slot, 1);
+    }
+
+    #[test]
+    fn deep_property_call_and_subscript_chains() {
+        let input = r#"
+        class Leaf
+        end
+
+        class Branch
+            def init()
+                self.baz = [Leaf(), Leaf()]
+            end
+        end
+
+        class Root
+            def bar()
+                return self.branch
+            end
+        end
+
+        var foo = Root()
+        foo.branch = Branch()
+        foo.bar().baz[1].qux = 1
+        var a = foo.bar().baz[1].qux + 1
+        var b = -foo.branch.baz[1].qux
+        var c = [10, 20][1]
+        "#;
+
+        let mut vm = VM::new();
+        vm.interpret(input);
+
+        assert_eq!(vm.globals.get("a"), Some(&Value::Number(2.0)));
+        assert_eq!(vm.globals.get("b"), Some(&Value::Number(-1.0)));
+        assert_eq!(vm.globals.get("c"), Some(&Value::Number(20.0)));
+    }
+
+    #[test]
+    fn call_depth_and_stack_size_are_limited_by_the_options() {
+        let run = |options: VmOptions, source: &str| {
+            let mut vm = VM::with_options(options);
+            vm.interpret("def down(n)\nif n == 0 do return 0 end\nreturn down(n - 1)\nend\n");
+
+            let function = Compiler::compile(GreenParser::parse(source).unwrap()).unwrap();
+            let closure = vm.alloc(GreenClosure::new(function));
+            vm.push(Value::Closure(closure));
+            vm.call_value(0)?;
+            vm.run()?;
+            vm.pop()
+        };
+        let options = VmOptions {
+            max_frames: 64,
+            stack_size: 1024,
+            ..VmOptions::default()
+        };
+
+        assert_eq!(run(options.clone(), "down(60)\n").unwrap().as_number(), 0.0);
+        let error = run(options, "down(100)\n").unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Stack overflow: more than 64 nested calls."
+        );
+
+        let options = VmOptions {
+            max_frames: 1000,
+            stack_size: 100,
+            ..VmOptions::default()
+        };
+        let error = run(options, "down(100)\n").unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Stack overflow: more than 100 values on the stack."
+        );
+        assert!(run(VmOptions::default(), "down(1000)\n").is_ok());
+    }
+
+    #[test]
+    fn budgets_stop_scripts_that_run_or_allocate_too_much() {
+        let run = |options: VmOptions, source: &str| {
+            let mut vm = VM::with_options(options);
+            let function = Compiler::compile(GreenParser::parse(source).unwrap()).unwrap();
+            let closure = vm.alloc(GreenClosure::new(function));
+            vm.push(Value::Closure(closure));
+            vm.call_value(0)?;
+            vm.run()?;
+            Ok::<u64, RuntimeError>(vm.instructions())
+        };
+        let spin = "while true do\nend\n";
+        let build = "var list = 0\nwhile true do\nlist = Error(list)\nend\n";
+
+        let options = VmOptions {
+            max_instructions: Some(1000),
+            ..VmOptions::default()
+        };
+        let error = run(options.clone(), spin).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Budget exceeded: ran more than 1000 instructions."
+        );
+        assert!(run(options, "var x = 1 + 2\n").unwrap() < 10);
+
+        let options = VmOptions {
+            max_heap_bytes: Some(VM::new().heap_bytes() + 4096),
+            ..VmOptions::default()
+        };
+        let error = run(options.clone(), build).unwrap_err();
+        assert!(matches!(
+            error,
+            RuntimeError::BudgetExceeded(Budget::HeapBytes(_))
+        ));
+
+        // Arrays and strings count the buffers they own, not just themselves.
+        let large_array = "var a = [0 to 20000000]\n";
+        let error = run(options.clone(), large_array).unwrap_err();
+        assert!(matches!(
+            error,
+            RuntimeError::BudgetExceeded(Budget::HeapBytes(_))
+        ));
+        let long_strings = "var s = \"ab\"\nwhile true do\ns = str([s, s])\nend\n";
+        let error = run(options.clone(), long_strings).unwrap_err();
+        assert!(matches!(
+            error,
+            RuntimeError::BudgetExceeded(Budget::HeapBytes(_))
+        ));
+        assert!(run(options, "var a = [0 to 100]\n").is_ok());
+    }
+
+    #[test]
+    fn interrupts_stop_a_script_from_another_thread() {
+        let mut vm = VM::new();
+        let handle = vm.interrupt_handle();
+        let interrupter = std::thread::spawn(move || {
+            std::thread::sleep(std::time::Duration::from_millis(20));
+            handle.interrupt();
+        });
+
+        let function =
+            Compiler::compile(GreenParser::parse("while true do\nend\n").unwrap()).unwrap();
+        let closure = vm.alloc(GreenClosure::new(function));
+        vm.push(Value::Closure(closure));
+        vm.call_value(0).unwrap();
+        let error = vm.run().unwrap_err();
+        interrupter.join().unwrap();
+        assert!(matches!(error, RuntimeError::Interrupted));
+
+        // The interrupt is used up, so the VM can run again.
+        assert_eq!(vm.interpret("return 1 + 2\n").as_number(), 3.0);
+    }
+
+    #[test]
+    fn print_writes_to_the_output_unless_quiet() {
+        #[derive(Clone, Default)]
+        struct Shared(Rc<RefCell<Vec<u8>>>);
+
+        impl Write for Shared {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.0.borrow_mut().write(buf)
+            }
+
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let printed = |options: VmOptions| {
+            let output = Shared::default();
+            VM::with_output(options, output.clone()).interpret("print [1, \"two\"]\nprint 1 < 2\n");
+            let bytes = output.0.borrow().clone();
+            String::from_utf8(bytes).unwrap()
+        };
+
+        assert_eq!(printed(VmOptions::default()), "[1, \"two\"]\ntrue\n");
+        let quiet = VmOptions {
+            quiet: true,
+            ..VmOptions::default()
+        };
+        assert_eq!(printed(quiet), "");
+    }
+
+    #[test]
+    fn capture_returns_what_a_function_prints() {
+        let input = r#"
+        def greet()
+            print "hello"
+            print [1, "two"]
+        end
+        def outer()
+            print "before"
+            var inner = capture(greet)
+            print len(inner)
+        end
+
+        var greeting = capture(greet)
+        var nested = capture(outer)
+        "#;
+
+        let mut vm = VM::with_options(VmOptions {
+            quiet: true,
+            ..VmOptions::default()
+        });
+        vm.interpret(input);
+        assert_eq!(vm.globals["greeting"].as_string(), "hello\n[1, \"two\"]\n");
+        assert_eq!(vm.globals["nested"].as_string(), "before\n17\n");
+
+        let (_, printed) = vm.capture_output(|vm| vm.interpret("print greeting == nested\n"));
+        assert_eq!(printed, "false\n");
+    }
+
+    #[test]
+    fn precision_applies_to_printed_numbers() {
+        let input = r#"
+        struct Point(x, y)
+        print 1 / 3
+        set_precision(2)
+        print 1 / 3
+        print [2, 0.005, "1.5"]
+        print Point(1.25, -3)
+        print str(10)
+        print format(2 / 3, 4)
+        print format(1, 0)
+        reset_precision()
+        print 0.1 + 0.2
+        "#;
+
+        let mut vm = VM::with_options(VmOptions {
+            quiet: true,
+            ..VmOptions::default()
+        });
+        let (_, printed) = vm.capture_output(|vm| vm.interpret(input));
+        assert_eq!(
+            printed,
+            "0.3333333333333333\n0.33\n[2.00, 0.01, \"1.5\"]\nPoint(1.25, -3.00)\n10.00\n\
+             0.6667\n1\n0.30000000000000004\n"
+        );
+
+        vm.set_precision(Some(1));
+        assert_eq!(vm.stringify(Value::Number(1234.56)).unwrap(), "1234.6");
+        let err = vm.call_function(vm.globals["set_precision"], &[Value::Number(-1.0)]);
+        assert!(err.is_err());
+    }
+
+    #[test]
+    fn scripts_call_the_methods_of_foreign_values() {
+        struct Counter {
+            total: f64,
+            dropped: Rc<RefCell<bool>>,
+        }
+
+        impl Drop for Counter {
+            fn drop(&mut self) {
+                *self.dropped.borrow_mut() = true;
+            }
+        }
+
+        fn add(_vm: &mut VM, args: &[Value]) -> RunResult<Value> {
+            let mut counter = args[0].as_foreign()?;
+            let counter = counter
+                .get_mut::<Counter>()
+                .ok_or(RuntimeError::ArgumentTypes)?;
+            counter.total += args[1].as_number();
+            Ok(Value::Number(counter.total))
+        }
+
+        fn total(_vm: &mut VM, args: &[Value]) -> RunResult<Value> {
+            let counter = args[0].as_foreign()?;
+            let counter = counter
+                .get::<Counter>()
+                .ok_or(RuntimeError::ArgumentTypes)?;
+            Ok(Value::Number(counter.total))
+        }
+
+        let mut vm = VM::new();
+        vm.register_foreign::<Counter>("Counter", &[("add", 1, add), ("total", 0, total)]);
+        let dropped = Rc::new(RefCell::new(false));
+        let counter = vm.foreign(Counter {
+            total: 0.0,
+            dropped: dropped.clone(),
+        });
+        vm.bind_global("counter", counter);
+
+        let input = r#"
+        counter.add(2)
+        var add = counter.add
+        add(3)
+        var total = counter.total()
+        var kind = typeof(counter)
+        var shown = str(counter)
+        "#;
+        vm.interpret(input);
+
+        assert_eq!(vm.globals["total"], Value::Number(5.0));
+        assert_eq!(vm.globals["kind"].as_string(), "Counter");
+        assert_eq!(vm.globals["shown"].as_string(), "<Counter>");
+
+        vm.interpret("counter = 0\nadd = 0\nruntime.gc()\n");
+        assert!(*dropped.borrow());
+    }
+
+    #[test]
+    fn foreign_values_only_have_their_registered_methods() {
+        let mut vm = VM::with_options(VmOptions {
+            quiet: true,
+            ..VmOptions::default()
+        });
+        vm.register_foreign::<String>("Name", &[]);
+        let name = vm.foreign("green".to_string());
+        vm.bind_global("name", name);
+        let report = vm.run_script("<script>", "name.missing()\n").unwrap_err();
+        assert!(
+            report.contains("Tried to access undefined property `missing` on Name"),
+            "{}",
+            report
+        );
+    }
+
+    #[test]
+    fn foreign_builder_binds_fields_and_methods() {
+        struct Point {
+            x: f64,
+            y: f64,
+        }
+
+        let mut vm = VM::new();
+        vm.build_foreign::<Point>("Point")
+            .field(
+                "x",
+                |p| Value::Number(p.x),
+                |p, value| {
+                    p.x = match value {
+                        Value::Number(x) => x,
+                        _ => return Err(RuntimeError::ArgumentTypes),
+                    };
+                    Ok(())
+                },
+            )
+            .getter("y", |p| Value::Number(p.y))
+            .method("scale", 1, |_vm, p, args| {
+                p.x *= args[0].as_number();
+                p.y *= args[0].as_number();
+                Ok(Value::Nil)
+            })
+            .register();
+        let point = vm.foreign(Point { x: 1.0, y: 2.0 });
+        vm.bind_global("point", point);
+
+        let input = r#"
+        point.x = 3
+        point.scale(2)
+        var x = point.x
+        var y = point.y
+        "#;
+        vm.interpret(input);
+
+        assert_eq!(vm.globals["x"], Value::Number(6.0));
+        assert_eq!(vm.globals["y"], Value::Number(4.0));
+    }
+
+    #[test]
+    fn call_function_runs_script_functions_from_rust() {
+        fn twice(vm: &mut VM, args: &[Value]) -> RunResult<Value> {
+            let once = vm.call_function(args[0], &[args[1]])?;
+            vm.call_function(args[0], &[once])
+        }
+
+        let input = r#"
+        def square(x) do return x * x end
+        def fourth(x) do return twice(square, x) end
+        def fail(x)
+            return x + missing
+        end
+        def fail_twice(x) do return twice(fail, x) end
+
+        var nested = twice(fourth, 2)
+        "#;
+
+        let mut vm = VM::new();
+        vm.define_native("twice", 2, twice);
+        vm.interpret(input);
+        assert_eq!(vm.globals["nested"], Value::Number(65536.0));
+
+        let square = vm.globals["square"];
+        let result = vm.call_function(square, &[Value::Number(7.0)]);
+        assert_eq!(result.unwrap(), Value::Number(49.0));
+
+        // A failing call leaves the VM usable, and reports the line it failed on.
+        let fail = vm.globals["fail"];
+        let err = vm.call_function(fail, &[Value::Number(1.0)]).unwrap_err();
+        assert!(vm.stack.is_empty());
+        assert_eq!(vm.frame_depth(), 0);
+        assert!(vm.error_report(&err).contains("<script>:5"));
+
+        // Also when the call fails inside a native that called back into the script.
+        let fail_twice = vm.globals["fail_twice"];
+        let err = vm
+            .call_function(fail_twice, &[Value::Number(1.0)])
+            .unwrap_err();
+        assert!(vm.error_report(&err).contains("<script>:5"));
+        assert_eq!(vm.frame_depth(), 0);
+
+        let fourth = vm.globals["fourth"];
+        let result = vm.call_function(fourth, &[Value::Number(3.0)]);
+        assert_eq!(result.unwrap(), Value::Number(81.0));
+        assert!(vm.stack.is_empty());
+    }
+
+    #[test]
+    fn timings_cover_the_script_and_its_main() {
+        let mut vm = VM::new();
+        vm.interpret("def main()\nvar i = 0\nwhile i < 1000 do i = i + 1 end\nend\n");
+
+        let script = vm.timings();
+        assert!(script.parse > Duration::ZERO && script.compile > Duration::ZERO);
+
+        vm.call_main(vec![]).unwrap();
+        let timings = vm.timings();
+        assert_eq!(
+            (timings.parse, timings.compile),
+            (script.parse, script.compile)
+        );
+        assert!(timings.execute > script.execute);
+        assert_eq!(
+            timings.total(),
+            timings.parse + timings.compile + timings.execute
+        );
+    }
+
+    #[test]
+    fn functions_return_tuples_that_var_unpacks() {
+        let input = r#"
+        def divmod(a, b)
+            return (a - a % b) / b, a % b
+        end
+
+        var q, r = divmod(17, 5)
+        var pair = divmod(9, 2)
+        var shown = str(pair)
+        var same = pair == divmod(9, 2)
+        var kind = typeof(pair)
+        var first = pair[0]
+
+        def swapped()
+            var x, y = divmod(7, 2)
+            return y, x
+        end
+        var a, b = swapped()
+        "#;
+
+        let mut vm = VM::new();
+        vm.interpret(input);
+
+        assert_eq!(vm.globals["q"], Value::Number(3.0));
+        assert_eq!(vm.globals["r"], Value::Number(2.0));
+        assert_eq!(vm.globals["shown"].as_string(), "(4, 1)");
+        assert_eq!(vm.globals["same"], Value::True);
+        assert_eq!(vm.globals["kind"].as_string(), "Tuple");
+        assert_eq!(vm.globals["first"], Value::Number(4.0));
+        assert_eq!(vm.globals["a"], Value::Number(1.0));
+        assert_eq!(vm.globals["b"], Value::Number(3.0));
+    }
+
+    #[test]
+    fn var_destructures_arrays_and_maps() {
+        let input = r#"
+        var [a, b] = [1, 2]
+        var {x, y} = {"y": 4, "x": 3, "z": 5}
+
+        def sum(pair)
+            var [first, second] = pair
+            var {total} = {"total": first + second}
+            return total
+        end
+        var total = sum([10, 20])
+        "#;
+
+        let mut vm = VM::new();
+        vm.interpret(input);
+
+        assert_eq!(vm.globals["a"], Value::Number(1.0));
+        assert_eq!(vm.globals["b"], Value::Number(2.0));
+        assert_eq!(vm.globals["x"], Value::Number(3.0));
+        assert_eq!(vm.globals["y"], Value::Number(4.0));
+        assert_eq!(vm.globals["total"], Value::Number(30.0));
+        assert!(!vm.globals.contains_key("z"));
+    }
+
+    #[test]
+    fn destructuring_checks_the_shape_of_the_value() {
+        let run = |source: &str| {
+            let mut vm = VM::with_options(VmOptions {
+                quiet: true,
+                ..VmOptions::default()
+            });
+            let function = Compiler::compile(GreenParser::parse(source).unwrap()).unwrap();
+            let closure = vm.alloc(GreenClosure::new(function));
+            vm.push(Value::Closure(closure));
+            vm.call_value(0)?;
+            vm.run()
+        };
+
+        let error = |source: &str| run(source).unwrap_err().to_string();
+        assert_eq!(
+            error("var [a, b] = [1, 2, 3]\n"),
+            "Can't unpack an array of 3 values into 2 variables."
+        );
+        assert_eq!(
+            error("var [a] = {\"a\": 1}\n"),
+            "Can't unpack Map into 1 variables."
+        );
+        assert_eq!(
+            error("var {x, y} = {\"x\": 1}\n"),
+            "Map has no key `y` to unpack."
+        );
+        assert_eq!(
+            error("var {x} = [1]\n"),
+            "Can't unpack Array into 1 variables."
+        );
+    }
+
+    #[test]
+    fn unpacking_needs_a_variable_per_value() {
+        let mut vm = VM::with_options(VmOptions {
+            quiet: true,
+            ..VmOptions::default()
+        });
+        let report = vm
+            .run_script(
+                "<script>",
+                "def pair() do return 1, 2 end\nvar a, b, c = pair()\n",
+            )
+            .unwrap_err();
+        assert!(
+            report.contains("Can't unpack a tuple of 2 values into 3 variables."),
+            "{}",
+            report
+        );
+    }
 }
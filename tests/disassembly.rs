@@ -0,0 +1,79 @@
+//! Compiles every program in `tests/disassembly/` at each optimization level and compares its
+//! disassembly with the `.O<level>.disasm` file next to it, so changes to the generated code
+//! show up as a diff. Set `GREEN_BLESS=1` to write the current disassembly as the expected one
+//! instead.
+
+use green::compiler::chunk::disassemble;
+use green::compiler::compiler::Compiler;
+use green::compiler::options::{CompileOptions, OptLevel};
+use green::syntax::parser::GreenParser;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+fn programs() -> Vec<PathBuf> {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/disassembly");
+    let mut programs: Vec<PathBuf> = fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "green"))
+        .collect();
+    programs.sort();
+    programs
+}
+
+fn compile(program: &Path, opt_level: OptLevel) -> String {
+    let source = fs::read_to_string(program).unwrap();
+    let module = GreenParser::parse(&source).unwrap();
+
+    let options = CompileOptions {
+        opt_level,
+        warnings: false,
+        disassemble: false,
+        ..CompileOptions::default()
+    };
+    disassemble(&Compiler::compile_with(module, options).unwrap())
+}
+
+#[test]
+fn disassembly_matches_the_snapshots() {
+    let bless = env::var_os("GREEN_BLESS").is_some();
+    let mut failures = vec![];
+
+    for program in programs() {
+        for opt_level in OptLevel::ALL {
+            let snapshot_path = program.with_extension(format!("{:?}.disasm", opt_level));
+            let actual = compile(&program, opt_level);
+            assert_eq!(
+                actual,
+                compile(&program, opt_level),
+                "{} compiles differently each time at {:?}",
+                program.display(),
+                opt_level
+            );
+
+            if bless {
+                fs::write(&snapshot_path, actual).unwrap();
+                continue;
+            }
+
+            let expected = fs::read_to_string(&snapshot_path).unwrap_or_else(|_| {
+                panic!(
+                    "{} has no snapshot, run with GREEN_BLESS=1 to create it",
+                    snapshot_path.display()
+                )
+            });
+            if actual != expected {
+                failures.push(format!(
+                    "{} at {:?}\n--- expected\n{}--- actual\n{}",
+                    program.display(),
+                    opt_level,
+                    expected,
+                    actual
+                ));
+            }
+        }
+    }
+
+    assert!(failures.is_empty(), "\n{}", failures.join("\n"));
+}
@@ -0,0 +1,93 @@
+//! Runs every script in `tests/scripts/` at each optimization level and compares what it prints
+//! with the `.expected` file next to it. Set `GREEN_BLESS=1` to write the current output as the
+//! expected output instead.
+
+use green::compiler::options::{CompileOptions, OptLevel};
+use green::vm::{VmOptions, VM};
+use std::cell::RefCell;
+use std::env;
+use std::fs;
+use std::io;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+/// Collects everything written to it, shared so it can still be read once the VM owns a clone.
+#[derive(Clone, Default)]
+struct Captured(Rc<RefCell<Vec<u8>>>);
+
+impl Write for Captured {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fn scripts() -> Vec<PathBuf> {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/scripts");
+    let mut scripts: Vec<PathBuf> = fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "green"))
+        .collect();
+    scripts.sort();
+    scripts
+}
+
+fn run(script: &Path, opt_level: OptLevel) -> String {
+    let source = fs::read_to_string(script).unwrap();
+    let output = Captured::default();
+
+    let options = VmOptions {
+        compile: CompileOptions {
+            opt_level,
+            ..CompileOptions::default()
+        },
+        ..VmOptions::default()
+    };
+    let mut vm = VM::with_output(options, output.clone());
+    vm.interpret(source);
+
+    let printed = output.0.borrow();
+    String::from_utf8(printed.clone()).unwrap()
+}
+
+#[test]
+fn scripts_print_the_expected_output() {
+    let bless = env::var_os("GREEN_BLESS").is_some();
+    let mut failures = vec![];
+
+    for script in scripts() {
+        let expected_path = script.with_extension("expected");
+
+        if bless {
+            fs::write(&expected_path, run(&script, OptLevel::O0)).unwrap();
+            continue;
+        }
+
+        let expected = fs::read_to_string(&expected_path).unwrap_or_else(|_| {
+            panic!(
+                "{} has no .expected file, run with GREEN_BLESS=1 to create it",
+                script.display()
+            )
+        });
+        for opt_level in OptLevel::ALL {
+            let actual = run(&script, opt_level);
+            if actual != expected {
+                failures.push(format!(
+                    "{} at {:?}\n--- expected\n{}--- actual\n{}",
+                    script.display(),
+                    opt_level,
+                    expected,
+                    actual
+                ));
+            }
+        }
+    }
+
+    assert!(failures.is_empty(), "\n{}", failures.join("\n"));
+}
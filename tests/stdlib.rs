@@ -0,0 +1,29 @@
+//! Runs the `test` blocks of every script in `tests/stdlib/`, which check the standard library
+//! from Green itself.
+
+use green::bench::find_scripts;
+use green::testing::test_script;
+use std::fs;
+use std::path::Path;
+
+#[test]
+fn stdlib_tests_pass() {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/stdlib");
+    let mut failures = vec![];
+    let mut count = 0;
+
+    for script in find_scripts(&dir).unwrap() {
+        let source = fs::read_to_string(&script).unwrap();
+        let name = script.display().to_string();
+        let results = test_script(&name, &source)
+            .unwrap_or_else(|err| panic!("Could not parse {}: {}", name, err));
+
+        count += results.len();
+        for result in results.into_iter().filter(|result| !result.passed()) {
+            failures.push(format!("{}\n{}", name, result));
+        }
+    }
+
+    assert!(count > 0, "No tests found in {}", dir.display());
+    assert!(failures.is_empty(), "\n{}", failures.join("\n"));
+}